@@ -0,0 +1,117 @@
+#[macro_use]
+extern crate criterion;
+extern crate gemspt_rs;
+
+use criterion::{Criterion, black_box};
+
+use gemspt_rs::vec::{Vec, Dot, Normalize};
+use gemspt_rs::random::Random;
+use gemspt_rs::ray::Ray;
+use gemspt_rs::material::{
+    Material, MaterialTrait, LambertianMaterialSimple, LambertianMaterial, PhongMaterial, BlinnMaterial,
+    PlasticMaterial, GlassMaterial, ThinFilmMaterial, RoughMetalMaterial, MixMaterial, ScaledMaterial};
+use gemspt_rs::radiance::{radiance, radiance_nee};
+use gemspt_rs::scenes::cornell_box;
+
+type Color = Vec;
+
+// 乱数の種は固定して、毎回同じ入力で計測する。
+const SEED: u64 = 0x5EED;
+
+fn bench_dot(c: &mut Criterion) {
+    let a = Vec { x: 0.3, y: -1.2, z: 2.5 };
+    let b = Vec { x: -0.7, y: 0.4, z: 1.1 };
+    c.bench_function("Vec::dot", |bencher| {
+        bencher.iter(|| Vec::dot(black_box(&a), black_box(&b)))
+    });
+}
+
+// PhongMaterial::sampleでの方向の組み立て。演算子を連ねた場合とVec::mul_addを使った場合を比べる。
+fn bench_basis_combination(c: &mut Criterion) {
+    let tangent = Vec { x: 1.0, y: 0.0, z: 0.0 };
+    let binormal = Vec { x: 0.0, y: 0.0, z: 1.0 };
+    let normal = Vec { x: 0.0, y: 1.0, z: 0.0 };
+    let (tx, ty, tz) = (0.48, 0.36, 0.8);
+
+    c.bench_function("basis_combination/operators", |bencher| {
+        bencher.iter(|| {
+            let (tangent, binormal, normal) = (black_box(&tangent), black_box(&binormal), black_box(&normal));
+            tangent * black_box(tx) + normal * black_box(tz) + binormal * black_box(ty)
+        })
+    });
+
+    c.bench_function("basis_combination/mul_add", |bencher| {
+        bencher.iter(|| {
+            let (tangent, binormal, normal) = (black_box(&tangent), black_box(&binormal), black_box(&normal));
+            Vec::mul_add(tangent, black_box(tx), &Vec::mul_add(binormal, black_box(ty), &(normal * black_box(tz))))
+        })
+    });
+}
+
+fn bench_material_sample(c: &mut Criterion) {
+    let gray = Color { x: 0.7, y: 0.7, z: 0.7 };
+    let materials: std::vec::Vec<(&str, Material)> = vec![
+        ("LambertianMaterialSimple",
+            Material::LambertianMaterialSimple(LambertianMaterialSimple::new(gray.clone()))),
+        ("LambertianMaterial",
+            Material::LambertianMaterial(LambertianMaterial::new(gray.clone()))),
+        ("PhongMaterial",
+            Material::PhongMaterial(PhongMaterial::new(gray.clone(), 100.0))),
+        ("BlinnMaterial",
+            Material::BlinnMaterial(BlinnMaterial::new(gray.clone(), 100.0))),
+        ("PlasticMaterial",
+            Material::PlasticMaterial(PlasticMaterial::new(gray.clone(), 0.3, 100.0))),
+        ("PlasticMaterial (clearcoat)",
+            Material::PlasticMaterial(PlasticMaterial::new(gray.clone(), 0.3, 100.0).with_clearcoat(1.0, 0.1))),
+        ("GlassMaterial",
+            Material::GlassMaterial(GlassMaterial::new(gray.clone(), 1.5))),
+        ("ThinFilmMaterial",
+            Material::ThinFilmMaterial(ThinFilmMaterial::new(gray.clone(), 400.0, 1.33))),
+        ("RoughMetalMaterial",
+            Material::RoughMetalMaterial(RoughMetalMaterial::gold())),
+        ("MixMaterial",
+            Material::MixMaterial(MixMaterial::new(
+                Material::LambertianMaterial(LambertianMaterial::new(gray.clone())),
+                Material::PhongMaterial(PhongMaterial::new(gray.clone(), 100.0)),
+                0.5).unwrap())),
+        ("ScaledMaterial",
+            Material::ScaledMaterial(ScaledMaterial::new(
+                Material::LambertianMaterial(LambertianMaterial::new(gray.clone())),
+                Color { x: 0.5, y: 0.5, z: 0.5 }))),
+    ];
+
+    // 法線に対して斜めに入射するレイ。
+    let input: Vec = Vec::normalize(Vec { x: 1.0, y: -1.0, z: 0.3 });
+    let normal = Vec { x: 0.0, y: 1.0, z: 0.0 };
+
+    for &(name, ref material) in materials.iter() {
+        let mut random = Random::new(SEED);
+        c.bench_function(&format!("Material::sample/{}", name), |bencher| {
+            bencher.iter(|| {
+                let mut pdf: f64 = -1.0;
+                let mut brdf_value: Color = Color { x: 0.0, y: 0.0, z: 0.0 };
+                let dir = material.sample(&mut random, black_box(&input), black_box(&normal), &mut pdf, &mut brdf_value);
+                (dir, pdf, brdf_value)
+            })
+        });
+    }
+}
+
+fn bench_path_trace(c: &mut Criterion) {
+    let (scene, camera) = cornell_box();
+    let (width, height) = (64, 48);
+    let dir: Vec = camera.ray_direction(width as f64 * 0.5, height as f64 * 0.5, width, height);
+
+    let mut random = Random::new(SEED);
+    c.bench_function("radiance/cornell_box", |bencher| {
+        bencher.iter(|| radiance(&scene, &Ray::new(camera.position(), &dir), &mut random, &0))
+    });
+
+    let mut random = Random::new(SEED);
+    c.bench_function("radiance_nee/cornell_box", |bencher| {
+        bencher.iter(|| radiance_nee(&scene, &Ray::new(camera.position(), &dir), &mut random, &0))
+    });
+}
+
+criterion_group!(benches, bench_dot, bench_basis_combination, bench_material_sample, bench_path_trace);
+criterion_main!(benches);
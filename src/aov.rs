@@ -0,0 +1,402 @@
+#![allow(dead_code)]
+
+use std;
+
+use scene::{Scene, SceneObject, intersect_scene};
+use camera::Camera;
+use ray::Ray;
+use hitpoint::Hitpoint;
+use constant::K_INF;
+use vec::Vec;
+use shape::Shape;
+
+// 合成用の補助バッファ（AOV）を出力する。
+// いずれもピクセルの中心に向けたカメラレイ一本で最初に当たった点の情報を使う。
+// バッファの並びはrender_imageの画像と同じ（上の行から順）。
+
+// レイが何にも当たらなかったピクセルのID。
+pub const BACKGROUND_ID: i32 = -1;
+
+// イメージセンサー上の(x, y)のピクセルの中心に向けたカメラレイが最初に当たる物体と交差点。
+pub fn primary_hit<'a>(
+    scene: &'a Scene, camera: &Camera, x: i32, y: i32, width: i32, height: i32) -> (Option<&'a SceneObject>, Hitpoint) {
+    let dir = camera.ray_direction(x as f64 + 0.5, y as f64 + 0.5, width, height);
+    intersect_scene(scene, &Ray::new(camera.position(), &dir))
+}
+
+// ピクセルごとに最初に当たった物体のIDを出力する。
+// IDはシーン中の物体のインデックスなので、同じシーンなら何度レンダリングしても変わらない。
+pub fn render_object_id(scene: &Scene, camera: &Camera, width: i32, height: i32) -> std::vec::Vec<i32> {
+    let mut ids = vec![BACKGROUND_ID; (width * height) as usize];
+    for y in 0..height {
+        for x in 0..width {
+            let image_index = ((height - y - 1) * width + x) as usize;
+            if let (Some(object), _) = primary_hit(scene, camera, x, y, width, height) {
+                ids[image_index] = scene.iter()
+                    .position(|o| std::ptr::eq(o, object))
+                    .map(|index| index as i32)
+                    .unwrap_or(BACKGROUND_ID);
+            }
+        }
+    }
+    ids
+}
+
+// ピクセルごとにカメラから最初に当たった点までの距離を出力する。
+// 何にも当たらなかったピクセルはK_INFになる。
+pub fn render_depth(scene: &Scene, camera: &Camera, width: i32, height: i32) -> std::vec::Vec<f64> {
+    let mut depth = vec![K_INF; (width * height) as usize];
+    for y in 0..height {
+        for x in 0..width {
+            let image_index = ((height - y - 1) * width + x) as usize;
+            if let (Some(_), hitpoint) = primary_hit(scene, camera, x, y, width, height) {
+                depth[image_index] = hitpoint.distance;
+            }
+        }
+    }
+    depth
+}
+
+// ピクセルごとに最初に当たった点のワールド座標を出力する。
+// 何にも当たらなかったピクセルは各成分がK_INFになる。
+pub fn render_position(scene: &Scene, camera: &Camera, width: i32, height: i32) -> std::vec::Vec<Vec> {
+    let mut position = vec![Vec { x: K_INF, y: K_INF, z: K_INF }; (width * height) as usize];
+    for y in 0..height {
+        for x in 0..width {
+            let image_index = ((height - y - 1) * width + x) as usize;
+            if let (Some(_), hitpoint) = primary_hit(scene, camera, x, y, width, height) {
+                position[image_index] = hitpoint.position;
+            }
+        }
+    }
+    position
+}
+
+// ピクセルごとに最初に当たった物体のアルベドを出力する。デノイザーのガイドに使う。
+// 鏡面やガラスでも意味のある値になるよう、reflectanceではなくalbedo_hintを使う。
+// 何にも当たらなかったピクセルは黒になる。
+pub fn render_albedo(scene: &Scene, camera: &Camera, width: i32, height: i32) -> std::vec::Vec<Vec> {
+    let mut albedo = vec![Vec { x: 0.0, y: 0.0, z: 0.0 }; (width * height) as usize];
+    for y in 0..height {
+        for x in 0..width {
+            let image_index = ((height - y - 1) * width + x) as usize;
+            if let (Some(object), _) = primary_hit(scene, camera, x, y, width, height) {
+                albedo[image_index] = object.get_material().albedo_hint();
+            }
+        }
+    }
+    albedo
+}
+
+// ピクセルごとに最初に当たった点の法線を、各成分を(n + 1) / 2で[0, 1]に写した色として出力する。
+// 形状やメッシュの法線の向きの確認に使う。+Z向きの法線は(0.5, 0.5, 1.0)になる。
+// 法線はHitpoint::normal（三角形や四角形では裏から見ても表側の向き）。何にも当たらなかったピクセルは黒になる。
+pub fn render_normals(scene: &Scene, camera: &Camera, width: i32, height: i32) -> std::vec::Vec<Vec> {
+    let mut normals = vec![Vec { x: 0.0, y: 0.0, z: 0.0 }; (width * height) as usize];
+    for y in 0..height {
+        for x in 0..width {
+            let image_index = ((height - y - 1) * width + x) as usize;
+            if let (Some(_), hitpoint) = primary_hit(scene, camera, x, y, width, height) {
+                normals[image_index] = (hitpoint.normal + Vec { x: 1.0, y: 1.0, z: 1.0 }) * 0.5;
+            }
+        }
+    }
+    normals
+}
+
+// ピクセルごとに最初に当たった点のテクスチャ座標(u, v)を、色(u, v, 0)として出力する。
+// checkerにSome(n)を渡すと、uvの正方形をn x nの市松模様に分け、一つおきのマスを半分の明るさにして重ねる。
+// テクスチャの継ぎ目や歪みは、マスのずれや伸びとして見える。
+// 何にも当たらなかったピクセルは黒になる。
+pub fn render_uv(
+    scene: &Scene, camera: &Camera, width: i32, height: i32, checker: Option<u32>) -> std::vec::Vec<Vec> {
+    let mut uv = vec![Vec { x: 0.0, y: 0.0, z: 0.0 }; (width * height) as usize];
+    for y in 0..height {
+        for x in 0..width {
+            let image_index = ((height - y - 1) * width + x) as usize;
+            if let (Some(_), hitpoint) = primary_hit(scene, camera, x, y, width, height) {
+                let (u, v) = hitpoint.uv;
+                let brightness: f64 = match checker {
+                    Some(n) => {
+                        let cell_u = (u * n as f64).floor() as i64;
+                        let cell_v = (v * n as f64).floor() as i64;
+                        if (cell_u + cell_v) % 2 == 0 { 1.0 } else { 0.5 }
+                    },
+                    None => 1.0,
+                };
+                uv[image_index] = Vec { x: u, y: v, z: 0.0 } * brightness;
+            }
+        }
+    }
+    uv
+}
+
+// レンダリングした画像imageに、三角形の辺をワイヤーフレームとして重ねる。メッシュの確認に使う。
+// ピクセルの中心に向けたカメラレイが三角形（メッシュのものを含む）に当たり、
+// その点の重心座標の最小値がthresholdより小さいとき、ピクセルをWIREFRAME_BRIGHTNESS倍に暗くする。
+// 三角形のテクスチャ座標は重心座標そのものなので（Triangle::intersect）、(u, v, 1 - u - v)の最小値を見る。
+// 線の太さは三角形の大きさに比例する。球や四角形に当たったピクセルはそのまま。
+pub fn overlay_wireframe(
+    scene: &Scene, camera: &Camera, image: &mut [Vec], width: i32, height: i32, threshold: f64) {
+    for y in 0..height {
+        for x in 0..width {
+            let image_index = ((height - y - 1) * width + x) as usize;
+            if let (Some(object), hitpoint) = primary_hit(scene, camera, x, y, width, height) {
+                if !is_triangle_shape(object.get_shape()) {
+                    continue;
+                }
+                let (u, v) = hitpoint.uv;
+                let min_barycentric: f64 = u.min(v).min(1.0 - u - v);
+                if min_barycentric < threshold {
+                    image[image_index] = &image[image_index] * WIREFRAME_BRIGHTNESS;
+                }
+            }
+        }
+    }
+}
+
+// overlay_wireframeで辺のピクセルに掛ける明るさ。
+pub const WIREFRAME_BRIGHTNESS: f64 = 0.1;
+
+// 三角形でできた形状か。InstanceとInstanceGroupはメッシュを置いたものなので三角形。
+fn is_triangle_shape(shape: &Shape) -> bool {
+    match shape {
+        &Shape::Triangle ( _ ) | &Shape::Instance ( _ ) | &Shape::InstanceGroup ( _ ) => true,
+        &Shape::Sphere ( _ ) | &Shape::Quad ( _ ) => false,
+    }
+}
+
+// ピクセルごとのスクリーン空間での動きベクトルを出力する。
+// 最初に当たった点を現在のカメラと前のフレームのカメラprevious_cameraでそれぞれ投影し、
+// (現在のピクセル座標 - 前のフレームでのピクセル座標)をピクセル単位で返す。
+// 物体は動かないものとする。何にも当たらなかったピクセルと、前のフレームでカメラの後ろにあった点は(0, 0)。
+pub fn render_motion_vector(
+    scene: &Scene, camera: &Camera, previous_camera: &Camera,
+    width: i32, height: i32) -> std::vec::Vec<(f64, f64)> {
+    let mut motion = vec![(0.0, 0.0); (width * height) as usize];
+    for y in 0..height {
+        for x in 0..width {
+            let image_index = ((height - y - 1) * width + x) as usize;
+            if let (Some(_), hitpoint) = primary_hit(scene, camera, x, y, width, height) {
+                let current = camera.project(&hitpoint.position, width, height);
+                let previous = previous_camera.project(&hitpoint.position, width, height);
+                if let (Some((cx, cy)), Some((px, py))) = (current, previous) {
+                    motion[image_index] = (cx - px, cy - py);
+                }
+            }
+        }
+    }
+    motion
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use material::{Material, LambertianMaterial};
+    use sphere::Sphere;
+    use triangle::Triangle;
+    use quad::Quad;
+    use std::collections::BTreeSet;
+
+    // 左右に並べた二つの球。カメラからは隅の方で何にも当たらない。
+    fn two_spheres() -> Scene {
+        let gray = || Material::LambertianMaterial(LambertianMaterial::new(Vec { x: 0.5, y: 0.5, z: 0.5 }));
+        vec![
+            SceneObject::new(Shape::Sphere(Sphere::new(1.0, Vec { x: -1.5, y: 0.0, z: 0.0 })), gray()),
+            SceneObject::new(Shape::Sphere(Sphere::new(1.0, Vec { x: 1.5, y: 0.0, z: 0.0 })), gray()),
+        ]
+    }
+
+    fn front_camera() -> Camera {
+        Camera::new(
+            Vec { x: 0.0, y: 0.0, z: 10.0 },
+            Vec { x: 0.0, y: 0.0, z: 0.0 },
+            Vec { x: 0.0, y: 1.0, z: 0.0 })
+    }
+
+    // 二つの物体のシーンでは、物体ごとのIDが二つと背景のIDだけが出てくる。
+    #[test]
+    fn two_objects_give_two_ids_and_background() {
+        let scene = two_spheres();
+        let (width, height): (i32, i32) = (32, 24);
+        let ids = render_object_id(&scene, &front_camera(), width, height);
+
+        let distinct: BTreeSet<i32> = ids.iter().cloned().collect();
+        let expected: BTreeSet<i32> = vec![BACKGROUND_ID, 0, 1].into_iter().collect();
+        assert_eq!(distinct, expected);
+
+        // 左の球は左半分に、右の球は右半分にだけ写る。
+        for (index, id) in ids.iter().enumerate() {
+            let x: i32 = index as i32 % width;
+            match *id {
+                0 => assert!(x < width / 2, "{}", index),
+                1 => assert!(x >= width / 2, "{}", index),
+                _ => {},
+            }
+        }
+
+        // 同じシーンなら何度出力しても同じIDになる。
+        assert_eq!(render_object_id(&scene, &front_camera(), width, height), ids);
+    }
+
+    // カメラに近い球の方が、遠い球より小さな距離になる。
+    #[test]
+    fn nearer_sphere_has_smaller_depth() {
+        let gray = Material::LambertianMaterial(LambertianMaterial::new(Vec { x: 0.5, y: 0.5, z: 0.5 }));
+        let scene: Scene = vec![
+            SceneObject::new(Shape::Sphere(Sphere::new(1.0, Vec { x: -1.5, y: 0.0, z: 3.0 })), gray.clone()),
+            SceneObject::new(Shape::Sphere(Sphere::new(1.0, Vec { x: 1.5, y: 0.0, z: -3.0 })), gray),
+        ];
+        let (width, height): (i32, i32) = (32, 24);
+        let depth = render_depth(&scene, &front_camera(), width, height);
+        let ids = render_object_id(&scene, &front_camera(), width, height);
+
+        let nearest = |id: i32| -> f64 {
+            ids.iter().zip(depth.iter())
+                .filter(|&(&i, _)| i == id)
+                .map(|(_, &d)| d)
+                .fold(K_INF, f64::min)
+        };
+        // 球の中心はカメラからおよそ7.2と13.1の距離にあり、手前の面まではそれより半径の分だけ近い。
+        let (near, far): (f64, f64) = (nearest(0), nearest(1));
+        assert!(near > 6.1 && near < 6.5, "{}", near);
+        assert!(far > 12.0 && far < 12.5, "{}", far);
+        assert!(near < far);
+
+        // 背景のピクセルの距離は無限大になる。
+        for (id, d) in ids.iter().zip(depth.iter()) {
+            assert_eq!(*id == BACKGROUND_ID, *d == K_INF);
+        }
+    }
+
+    // カメラを前のフレームから横に平行移動すると、動かない点は移動量に応じた分だけ画面上でずれる。
+    #[test]
+    fn translated_camera_gives_expected_motion() {
+        // カメラの正面、距離10にある大きな壁。
+        let gray = Material::LambertianMaterial(LambertianMaterial::new(Vec { x: 0.5, y: 0.5, z: 0.5 }));
+        let scene: Scene = vec![
+            SceneObject::new(Shape::Quad(Quad::new(
+                Vec { x: -50.0, y: -50.0, z: 0.0 }, Vec { x: 100.0, y: 0.0, z: 0.0 }, Vec { x: 0.0, y: 100.0, z: 0.0 })), gray),
+        ];
+        let previous_camera = Camera::new(
+            Vec { x: -1.0, y: 0.0, z: 10.0 },
+            Vec { x: -1.0, y: 0.0, z: 0.0 },
+            Vec { x: 0.0, y: 1.0, z: 0.0 });
+        let (width, height): (i32, i32) = (32, 24);
+        let motion = render_motion_vector(&scene, &front_camera(), &previous_camera, width, height);
+
+        // 距離10の点は、前のカメラでは1 * 45 / 10 = 4.5だけセンサーの右にずれて写っていた。
+        // センサーの高さ30がheightピクセルに当たるので、ピクセル単位では4.5 * height / 30。
+        let expected: f64 = -4.5 * height as f64 / 30.0;
+        for &(dx, dy) in &motion {
+            assert!((dx - expected).abs() < 1e-9, "{} {}", dx, expected);
+            assert!(dy.abs() < 1e-9, "{}", dy);
+        }
+
+        // 位置のAOVは壁の上の点になる。
+        for position in render_position(&scene, &front_camera(), width, height) {
+            assert!(position.z.abs() < 1e-9, "{:?}", position);
+        }
+    }
+
+    // +Zから見た原点の球では、中心のピクセルの法線はほぼ+Zなので色は(0.5, 0.5, 1.0)に近い。
+    // 球に当たらない隅のピクセルは黒。
+    #[test]
+    fn sphere_facing_the_camera_shows_plus_z_at_its_center() {
+        let gray = Material::LambertianMaterial(LambertianMaterial::new(Vec { x: 0.5, y: 0.5, z: 0.5 }));
+        let scene: Scene = vec![SceneObject::new(Shape::Sphere(Sphere::new(1.0, Vec { x: 0.0, y: 0.0, z: 0.0 })), gray)];
+        let (width, height): (i32, i32) = (33, 33);
+        let normals = render_normals(&scene, &front_camera(), width, height);
+
+        let center = &normals[(height / 2 * width + width / 2) as usize];
+        assert!((center.x - 0.5).abs() < 0.05 && (center.y - 0.5).abs() < 0.05 && center.z > 0.99, "{:?}", center);
+        let corner = &normals[0];
+        assert!(corner.x == 0.0 && corner.y == 0.0 && corner.z == 0.0, "{:?}", corner);
+    }
+
+    // 球のテクスチャ座標は、NaNを含まず[0, 1]に入り、正面では隣り合うピクセルでなめらかに変わる。
+    // 継ぎ目（-X側の経線）と極は輪郭の近くにあるので、法線が十分カメラを向いたピクセルだけを比べる。
+    // 市松模様を重ねても、暗くなるのは半分の明るさのマスだけ。
+    #[test]
+    fn sphere_uv_is_a_continuous_gradient_without_nans() {
+        let gray = Material::LambertianMaterial(LambertianMaterial::new(Vec { x: 0.5, y: 0.5, z: 0.5 }));
+        let scene: Scene = vec![SceneObject::new(Shape::Sphere(Sphere::new(1.0, Vec { x: 0.0, y: 0.0, z: 0.0 })), gray)];
+        let (width, height): (i32, i32) = (96, 96);
+        let camera = front_camera();
+        let uv = render_uv(&scene, &camera, width, height, None);
+        let normals = render_normals(&scene, &camera, width, height);
+
+        for pixel in uv.iter() {
+            assert!(pixel.x.is_finite() && pixel.y.is_finite(), "{:?}", pixel);
+            assert!(pixel.x >= 0.0 && pixel.x <= 1.0 && pixel.y >= 0.0 && pixel.y <= 1.0, "{:?}", pixel);
+        }
+        let facing = |index: usize| normals[index].z > 0.8;
+        let mut compared: i32 = 0;
+        for y in 0..height - 1 {
+            for x in 0..width - 1 {
+                let index = (y * width + x) as usize;
+                for &neighbor in [index + 1, index + width as usize].iter() {
+                    if facing(index) && facing(neighbor) {
+                        let du: f64 = (uv[index].x - uv[neighbor].x).abs();
+                        let dv: f64 = (uv[index].y - uv[neighbor].y).abs();
+                        assert!(du < 0.05 && dv < 0.05, "{} {} {:?} {:?}", x, y, uv[index], uv[neighbor]);
+                        compared += 1;
+                    }
+                }
+            }
+        }
+        assert!(compared > 100, "{}", compared);
+
+        let checkered = render_uv(&scene, &camera, width, height, Some(8));
+        for (plain, checked) in uv.iter().zip(checkered.iter()) {
+            let same: bool = plain.x == checked.x && plain.y == checked.y;
+            let halved: bool = (plain.x * 0.5 - checked.x).abs() < 1e-12 && (plain.y * 0.5 - checked.y).abs() < 1e-12;
+            assert!(same || halved, "{:?} {:?}", plain, checked);
+        }
+    }
+
+    // カメラに向いた大きな三角形に重ねると、辺の近く（重心座標の最小値がしきい値未満）のピクセルだけが暗くなり、
+    // 内側のピクセルと三角形に当たらないピクセルはそのまま。
+    #[test]
+    fn wireframe_darkens_pixels_near_triangle_edges_only() {
+        let gray = Material::LambertianMaterial(LambertianMaterial::new(Vec { x: 0.5, y: 0.5, z: 0.5 }));
+        let triangle = Triangle::new(
+            Vec { x: -3.0, y: -2.5, z: 0.0 }, Vec { x: 3.0, y: -2.5, z: 0.0 }, Vec { x: 0.0, y: 3.0, z: 0.0 });
+        let scene: Scene = vec![SceneObject::new(Shape::Triangle(triangle), gray)];
+        let (width, height): (i32, i32) = (64, 64);
+        let camera = front_camera();
+        let threshold: f64 = 0.05;
+        let white = Vec { x: 1.0, y: 1.0, z: 1.0 };
+        let mut image = vec![white.clone(); (width * height) as usize];
+        overlay_wireframe(&scene, &camera, &mut image, width, height, threshold);
+        let ids = render_object_id(&scene, &camera, width, height);
+        let barycentric = render_uv(&scene, &camera, width, height, None);
+
+        let (mut edge, mut interior): (i32, i32) = (0, 0);
+        for index in 0..image.len() {
+            let darkened: bool = image[index].x < 1.0;
+            if ids[index] == BACKGROUND_ID {
+                assert!(!darkened, "{}", index);
+                continue;
+            }
+            let (u, v) = (barycentric[index].x, barycentric[index].y);
+            let min_barycentric: f64 = u.min(v).min(1.0 - u - v);
+            if min_barycentric < threshold {
+                assert!((image[index].x - WIREFRAME_BRIGHTNESS).abs() < 1e-12, "{}", index);
+                edge += 1;
+            } else {
+                assert!(!darkened, "{}", index);
+                interior += 1;
+            }
+        }
+        assert!(edge > 20 && interior > edge, "{} {}", edge, interior);
+
+        // 重心の上のピクセルは内側、底辺のすぐ内側の点の上のピクセルは辺の上。
+        let pixel_at = |point: Vec| {
+            let (x, y) = camera.project(&point, width, height).unwrap();
+            ((height - y as i32 - 1) * width + x as i32) as usize
+        };
+        assert!(image[pixel_at(Vec { x: 0.0, y: -2.0 / 3.0, z: 0.0 })].x == 1.0);
+        assert!(image[pixel_at(Vec { x: 0.0, y: -2.45, z: 0.0 })].x < 1.0);
+    }
+}
@@ -0,0 +1,281 @@
+// コマンドラインからレンダリングするためのフロントエンド。
+// シーン、解像度、サンプル数、出力先などを引数で指定して、画像をファイルに保存する。
+// 例: gemspt --preset cornell-box --width 320 --height 240 --spp 4 --output cornell.ppm
+
+extern crate gemspt_rs;
+#[cfg(feature = "rayon")]
+extern crate rayon;
+
+use std::sync::atomic::AtomicBool;
+use std::time::{Duration, Instant};
+
+use gemspt_rs::scene::{Scene, SceneRendering, generate_scene, validate_scene};
+use gemspt_rs::scenes::cornell_box;
+use gemspt_rs::camera::Camera;
+use gemspt_rs::ray::Ray;
+use gemspt_rs::sampler::Sampler;
+use gemspt_rs::vec::Vec;
+use gemspt_rs::radiance::{radiance_with_max_depth, DEPTH_LIMIT};
+use gemspt_rs::render::{render_with_progress, DEFAULT_SEED};
+use gemspt_rs::ppm::{save_ppm_file_with_metadata, ImageMetadata};
+
+const USAGE: &str = "\
+usage: gemspt [options]
+
+options:
+  --preset NAME      built-in scene: cornell-box, diffuse, specular, glass (default: cornell-box)
+  --scene PATH       load the scene from a glTF file instead (requires the gltf feature)
+  --width N          image width in pixels (default: 640)
+  --height N         image height in pixels (default: 480)
+  --spp N            samples per subpixel (default: 1)
+  --subpixels N      N x N subpixels per pixel (default: 4)
+  --max-depth N      maximum number of bounces (default: 10)
+  --seed N           random seed (default: the library's DEFAULT_SEED)
+  --threads N        worker threads; 1 renders serially, 0 uses all cores (default: 0)
+  --output PATH      output file (default: image.ppm)
+  --format FORMAT    ppm or exr (default: from the output extension; exr requires the exr feature)
+  --help             show this message";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Format {
+    Ppm,
+    Exr,
+}
+
+#[derive(Debug)]
+struct Options {
+    preset: String,
+    scene_path: Option<String>,
+    width: i32,
+    height: i32,
+    num_sample_per_subpixel: i32,
+    num_subpixel: i32,
+    max_depth: i32,
+    seed: u64,
+    threads: usize,
+    output: String,
+    format: Option<Format>,
+}
+
+impl Options {
+    fn default_options() -> Options {
+        Options {
+            preset: "cornell-box".to_string(),
+            scene_path: None,
+            width: 640,
+            height: 480,
+            num_sample_per_subpixel: 1,
+            num_subpixel: 4,
+            max_depth: DEPTH_LIMIT,
+            seed: DEFAULT_SEED,
+            threads: 0,
+            output: "image.ppm".to_string(),
+            format: None,
+        }
+    }
+
+    // 引数を読む。--helpならOk(None)。
+    fn parse(args: &[String]) -> Result<Option<Options>, String> {
+        let mut options = Options::default_options();
+        let mut index: usize = 0;
+        while index < args.len() {
+            let flag: &str = &args[index];
+            if flag == "--help" || flag == "-h" {
+                return Ok(None)
+            }
+            let value: &str = match args.get(index + 1) {
+                Some(value) => value,
+                None => return Err(format!("missing value for {}", flag))
+            };
+            match flag {
+                "--preset"    => options.preset = value.to_string(),
+                "--scene"     => options.scene_path = Some(value.to_string()),
+                "--width"     => options.width = parse_number(flag, value)?,
+                "--height"    => options.height = parse_number(flag, value)?,
+                "--spp"       => options.num_sample_per_subpixel = parse_number(flag, value)?,
+                "--subpixels" => options.num_subpixel = parse_number(flag, value)?,
+                "--max-depth" => options.max_depth = parse_number(flag, value)?,
+                "--seed"      => options.seed = parse_number(flag, value)?,
+                "--threads"   => options.threads = parse_number(flag, value)?,
+                "--output"    => options.output = value.to_string(),
+                "--format"    => options.format = Some(parse_format(value)?),
+                _ => return Err(format!("unknown option {}", flag))
+            }
+            index += 2;
+        }
+        Ok(Some(options))
+    }
+
+    // 出力形式。指定が無ければ出力先の拡張子から決める。
+    fn output_format(&self) -> Format {
+        match self.format {
+            Some(format) => format,
+            None => if self.output.to_lowercase().ends_with(".exr") { Format::Exr } else { Format::Ppm },
+        }
+    }
+}
+
+fn parse_number<T: std::str::FromStr>(flag: &str, value: &str) -> Result<T, String> {
+    value.parse::<T>().map_err(|_| format!("invalid value {} for {}", value, flag))
+}
+
+fn parse_format(value: &str) -> Result<Format, String> {
+    match value {
+        "ppm" => Ok(Format::Ppm),
+        "exr" => Ok(Format::Exr),
+        _ => Err(format!("unknown format {}", value))
+    }
+}
+
+// シーンとカメラを用意する。glTFのシーンにはカメラが無いので、既定のカメラを使う。
+fn load_scene(options: &Options) -> Result<(Scene, Camera), String> {
+    if let Some(ref path) = options.scene_path {
+        return load_scene_file(path).map(|scene| (scene, Camera::default_camera()))
+    }
+
+    match options.preset.as_str() {
+        "cornell-box" => Ok(cornell_box()),
+        "diffuse"     => Ok((generate_scene(SceneRendering::SceneDiffuseOnly), Camera::default_camera())),
+        "specular"    => Ok((generate_scene(SceneRendering::SceneSpecular), Camera::default_camera())),
+        "glass"       => Ok((generate_scene(SceneRendering::SceneGlass), Camera::default_camera())),
+        _ => Err(format!("unknown preset {}", options.preset))
+    }
+}
+
+#[cfg(feature = "gltf")]
+fn load_scene_file(path: &str) -> Result<Scene, String> {
+    gemspt_rs::gltf::load(path)
+}
+
+#[cfg(not(feature = "gltf"))]
+fn load_scene_file(path: &str) -> Result<Scene, String> {
+    Err(format!("cannot load {}: scene files require the gltf feature", path))
+}
+
+// スレッド数を設定する。1なら並列処理をしない。
+#[cfg(feature = "rayon")]
+fn configure_threads(threads: usize) -> Result<bool, String> {
+    if threads > 1 {
+        rayon::ThreadPoolBuilder::new().num_threads(threads).build_global()
+            .map_err(|why| format!("couldn't set up {} threads: {}", threads, why))?;
+    }
+    Ok(threads != 1)
+}
+
+#[cfg(not(feature = "rayon"))]
+fn configure_threads(threads: usize) -> Result<bool, String> {
+    if threads > 1 {
+        eprintln!("warning: built without the rayon feature, rendering on one thread");
+    }
+    Ok(false)
+}
+
+fn save_image(options: &Options, image: &[Vec]) -> Result<(), String> {
+    match options.output_format() {
+        Format::Ppm => {
+            let metadata = ImageMetadata {
+                spp: options.num_sample_per_subpixel * options.num_subpixel * options.num_subpixel,
+                seed: options.seed, width: options.width, height: options.height };
+            save_ppm_file_with_metadata(&options.output, image, options.width, options.height, Some(&metadata))
+                .map_err(|why| why.to_string())
+        },
+        Format::Exr => save_exr(options, image),
+    }
+}
+
+#[cfg(feature = "exr")]
+fn save_exr(options: &Options, image: &[Vec]) -> Result<(), String> {
+    let (r, g, b) = gemspt_rs::image::split_channels(image);
+    gemspt_rs::image::write_exr(
+        &options.output, options.width, options.height, &[("R", &r[..]), ("G", &g[..]), ("B", &b[..])])
+}
+
+#[cfg(not(feature = "exr"))]
+fn save_exr(options: &Options, _image: &[Vec]) -> Result<(), String> {
+    Err(format!("cannot write {}: exr output requires the exr feature", options.output))
+}
+
+// 進捗バーの幅（文字数）。
+const PROGRESS_BAR_WIDTH: usize = 40;
+
+// 例: [################........................]  40% 00:12 elapsed, ETA 00:18
+// 残り時間は、ここまでの一行あたりの時間が続くとして見積もる。
+fn progress_bar(done: usize, total: usize, elapsed: Duration) -> String {
+    let fraction: f64 = if total == 0 { 1.0 } else { done as f64 / total as f64 };
+    let filled: usize = ((fraction * PROGRESS_BAR_WIDTH as f64) as usize).min(PROGRESS_BAR_WIDTH);
+    let bar: String = "#".repeat(filled) + &".".repeat(PROGRESS_BAR_WIDTH - filled);
+    let elapsed_seconds: f64 = elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 * 1e-9;
+    let eta: String = if done == 0 {
+        "--:--".to_string()
+    } else {
+        format_seconds(elapsed_seconds * (total - done) as f64 / done as f64)
+    };
+    format!("[{}] {:3.0}% {} elapsed, ETA {}", bar, fraction * 100.0, format_seconds(elapsed_seconds), eta)
+}
+
+// 秒数を分:秒（1時間以上なら時:分:秒）にする。
+fn format_seconds(seconds: f64) -> String {
+    let seconds: u64 = seconds.round() as u64;
+    if seconds >= 3600 {
+        format!("{}:{:02}:{:02}", seconds / 3600, seconds / 60 % 60, seconds % 60)
+    } else {
+        format!("{:02}:{:02}", seconds / 60, seconds % 60)
+    }
+}
+
+fn run(options: &Options) -> Result<(), String> {
+    // 書き出せない形式なら、レンダリングする前に止める。
+    if options.output_format() == Format::Exr && !cfg!(feature = "exr") {
+        return Err(format!("cannot write {}: exr output requires the exr feature", options.output))
+    }
+    let (scene, camera) = load_scene(options)?;
+    if let Err(warnings) = validate_scene(&scene) {
+        for warning in warnings {
+            eprintln!("warning: {}", warning);
+        }
+    }
+    let is_parallel: bool = configure_threads(options.threads)?;
+
+    let num_samples: i32 = options.num_sample_per_subpixel * options.num_subpixel * options.num_subpixel;
+    println!("{}x{} {} spp", options.width, options.height, num_samples);
+
+    let max_depth: i32 = options.max_depth;
+    let radiance_fn = |scene: &Scene, ray: &Ray, random: &mut dyn Sampler| -> Vec {
+        radiance_with_max_depth(scene, ray, random, &0, max_depth)
+    };
+    let cancel = AtomicBool::new(false);
+    let start = Instant::now();
+    let progress = |done: usize, total: usize| {
+        eprint!("\r{}\x1b[0K", progress_bar(done, total, start.elapsed()));
+    };
+    let (image, _, _) = render_with_progress(
+        &scene, &camera, options.width, options.height,
+        options.num_sample_per_subpixel, options.num_subpixel,
+        is_parallel, &cancel, options.seed, &radiance_fn, Some(&progress))
+        .map_err(|why| why.to_string())?;
+    eprintln!();
+
+    save_image(options, &image)
+}
+
+fn main() {
+    let args: std::vec::Vec<String> = std::env::args().skip(1).collect();
+    let options = match Options::parse(&args) {
+        Ok(Some(options)) => options,
+        Ok(None) => {
+            println!("{}", USAGE);
+            return
+        },
+        Err(why) => {
+            eprintln!("error: {}\n\n{}", why, USAGE);
+            std::process::exit(2);
+        }
+    };
+
+    if let Err(why) = run(&options) {
+        eprintln!("couldn't render: {}", why);
+        std::process::exit(1);
+    }
+
+    println!("Saved {}", options.output);
+}
@@ -0,0 +1,224 @@
+#![allow(dead_code)]
+
+use std;
+
+use vec::Vec;
+use ray::Ray;
+use constant::K_INF;
+
+// 葉に入れるプリミティブの最大数。
+const MAX_LEAF_SIZE: usize = 4;
+
+#[derive(Debug)]
+struct Node {
+    lower: Vec,
+    upper: Vec,
+    // 葉なら(first, count)はprimitivesの範囲。節ならfirstが右の子の番号で、左の子は直後にある。count == 0。
+    first: usize,
+    count: usize,
+}
+
+// 境界ボックスの階層（Bounding Volume Hierarchy）。
+// プリミティブそのものは持たず、プリミティブの番号だけを持つ。
+// 持ち主（Meshなど）が番号からプリミティブを引いて交差判定する。
+#[derive(Debug)]
+pub struct Bvh {
+    nodes: std::vec::Vec<Node>,
+    primitives: std::vec::Vec<usize>,
+}
+
+impl Bvh {
+    // 各プリミティブの境界ボックス(最小の角, 最大の角)から作る。
+    // 重心が最も広がっている軸で、重心の中央値で二分していく。
+    pub fn new(bounds: &[(Vec, Vec)]) -> Bvh {
+        let mut bvh = Bvh { nodes: std::vec::Vec::new(), primitives: (0..bounds.len()).collect() };
+        if !bounds.is_empty() {
+            let centroids: std::vec::Vec<Vec> = bounds.iter().map(|(lower, upper)| lower + upper).map(|sum| sum * 0.5).collect();
+            bvh.build(bounds, &centroids, 0, bounds.len());
+        }
+        bvh
+    }
+
+    // 全体の境界ボックス。空なら無限に裏返った箱になる。
+    pub fn bounds(&self) -> (Vec, Vec) {
+        match self.nodes.first() {
+            Some(root) => (root.lower.clone(), root.upper.clone()),
+            None => (Vec { x: K_INF, y: K_INF, z: K_INF }, Vec { x: -K_INF, y: -K_INF, z: -K_INF })
+        }
+    }
+
+    // rayと交差しうるプリミティブについてintersect(番号, 今までで最も近い距離)を呼ぶ。
+    // intersectは交差すればその距離を返す。最も近い交差の(番号, 距離)を返す。
+    // 今までで最も近い交差より遠い箱は調べない。
+    pub fn intersect<F>(&self, ray: &Ray, mut intersect: F) -> Option<(usize, f64)>
+        where F: FnMut(usize, f64) -> Option<f64> {
+        if self.nodes.is_empty() {
+            return None
+        }
+
+        let inv_dir = Vec { x: 1.0 / ray.dir.x, y: 1.0 / ray.dir.y, z: 1.0 / ray.dir.z };
+        let mut closest: Option<(usize, f64)> = None;
+        let mut distance: f64 = K_INF;
+        let mut stack: std::vec::Vec<usize> = vec![0];
+
+        while let Some(index) = stack.pop() {
+            let node = &self.nodes[index];
+            if !hit_box(&node.lower, &node.upper, ray.org, &inv_dir, distance) {
+                continue;
+            }
+
+            if node.count > 0 {
+                for &primitive in &self.primitives[node.first..node.first + node.count] {
+                    if let Some(d) = intersect(primitive, distance) {
+                        if d < distance {
+                            distance = d;
+                            closest = Some((primitive, d));
+                        }
+                    }
+                }
+            } else {
+                stack.push(node.first);
+                stack.push(index + 1);
+            }
+        }
+
+        closest
+    }
+
+    // rayと距離max_distanceより手前で交差しうるプリミティブについてhit(番号)を呼ぶ。
+    // hitはmax_distanceより手前で交差すればtrueを返す。一つでもtrueになったらそこで打ち切ってtrueを返す。
+    // 影のレイのように、最も近い交差ではなく交差の有無だけが分かればよいときに使う。
+    pub fn intersect_any<F>(&self, ray: &Ray, max_distance: f64, mut hit: F) -> bool
+        where F: FnMut(usize) -> bool {
+        if self.nodes.is_empty() {
+            return false
+        }
+
+        let inv_dir = Vec { x: 1.0 / ray.dir.x, y: 1.0 / ray.dir.y, z: 1.0 / ray.dir.z };
+        let mut stack: std::vec::Vec<usize> = vec![0];
+
+        while let Some(index) = stack.pop() {
+            let node = &self.nodes[index];
+            if !hit_box(&node.lower, &node.upper, ray.org, &inv_dir, max_distance) {
+                continue;
+            }
+
+            if node.count > 0 {
+                if self.primitives[node.first..node.first + node.count].iter().any(|&primitive| hit(primitive)) {
+                    return true
+                }
+            } else {
+                stack.push(node.first);
+                stack.push(index + 1);
+            }
+        }
+
+        false
+    }
+
+    // primitives[start..end]を葉または節にして、そのノードの番号を返す。
+    fn build(&mut self, bounds: &[(Vec, Vec)], centroids: &[Vec], start: usize, end: usize) -> usize {
+        let mut lower = Vec { x: K_INF, y: K_INF, z: K_INF };
+        let mut upper = Vec { x: -K_INF, y: -K_INF, z: -K_INF };
+        let mut centroid_lower = lower.clone();
+        let mut centroid_upper = upper.clone();
+        for &primitive in &self.primitives[start..end] {
+            let (ref l, ref u) = bounds[primitive];
+            lower = min(&lower, l);
+            upper = max(&upper, u);
+            centroid_lower = min(&centroid_lower, &centroids[primitive]);
+            centroid_upper = max(&centroid_upper, &centroids[primitive]);
+        }
+
+        let index: usize = self.nodes.len();
+        self.nodes.push(Node { lower, upper, first: start, count: end - start });
+
+        let extent: Vec = &centroid_upper - &centroid_lower;
+        if end - start <= MAX_LEAF_SIZE || (extent.x <= 0.0 && extent.y <= 0.0 && extent.z <= 0.0) {
+            return index
+        }
+
+        let axis: usize = if extent.x >= extent.y && extent.x >= extent.z { 0 } else if extent.y >= extent.z { 1 } else { 2 };
+        let mid: usize = (start + end) / 2;
+        self.primitives[start..end].sort_by(|&a, &b| {
+            component(&centroids[a], axis).partial_cmp(&component(&centroids[b], axis)).unwrap_or(std::cmp::Ordering::Equal)
+        });
+
+        self.build(bounds, centroids, start, mid);
+        let right: usize = self.build(bounds, centroids, mid, end);
+        self.nodes[index].first = right;
+        self.nodes[index].count = 0;
+        index
+    }
+}
+
+fn component(v: &Vec, axis: usize) -> f64 {
+    match axis {
+        0 => v.x,
+        1 => v.y,
+        _ => v.z,
+    }
+}
+
+fn min(a: &Vec, b: &Vec) -> Vec {
+    Vec { x: a.x.min(b.x), y: a.y.min(b.y), z: a.z.min(b.z) }
+}
+
+fn max(a: &Vec, b: &Vec) -> Vec {
+    Vec { x: a.x.max(b.x), y: a.y.max(b.y), z: a.z.max(b.z) }
+}
+
+// スラブ法。箱と[0, max_distance]の範囲で交差するか。
+fn hit_box(lower: &Vec, upper: &Vec, org: &Vec, inv_dir: &Vec, max_distance: f64) -> bool {
+    let mut t_min: f64 = 0.0;
+    let mut t_max: f64 = max_distance;
+    for axis in 0..3 {
+        let inv: f64 = component(inv_dir, axis);
+        let o: f64 = component(org, axis);
+        let mut t0: f64 = (component(lower, axis) - o) * inv;
+        let mut t1: f64 = (component(upper, axis) - o) * inv;
+        if t0 > t1 {
+            std::mem::swap(&mut t0, &mut t1);
+        }
+        // 丸め誤差で箱の縁に当たるレイを取りこぼさないよう、遠い側を少し広げる。
+        t1 *= 1.0 + 4.0 * f64::EPSILON;
+        // 箱の面に沿ったレイでは0 * ∞がNaNになる。NaNは比較がfalseになるので範囲を狭めない。
+        if t0 > t_min { t_min = t0; }
+        if t1 < t_max { t_max = t1; }
+        if t_min > t_max {
+            return false
+        }
+    }
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 一列に並んだ箱を端から貫くレイ。交差の有無だけなら、全ての箱を調べずに最初の交差で打ち切る。
+    #[test]
+    fn intersect_any_stops_at_the_first_hit() {
+        let bounds: std::vec::Vec<(Vec, Vec)> = (0..64)
+            .map(|i| (Vec { x: i as f64, y: -0.5, z: -0.5 }, Vec { x: i as f64 + 0.5, y: 0.5, z: 0.5 }))
+            .collect();
+        let bvh = Bvh::new(&bounds);
+        let org: Vec = Vec { x: -1.0, y: 0.0, z: 0.0 };
+        let dir: Vec = Vec { x: 1.0, y: 0.0, z: 0.0 };
+        let ray = Ray::new(&org, &dir);
+        // 箱そのものをプリミティブとし、手前の面までの距離で交差する。
+        let distance = |primitive: usize| bounds[primitive].0.x - org.x;
+
+        let mut closest_calls: usize = 0;
+        let closest = bvh.intersect(&ray, |primitive, _| { closest_calls += 1; Some(distance(primitive)) });
+        assert_eq!(closest.map(|(primitive, _)| primitive), Some(0));
+
+        let mut any_calls: usize = 0;
+        assert!(bvh.intersect_any(&ray, 100.0, |primitive| { any_calls += 1; distance(primitive) < 100.0 }));
+        assert!(any_calls < closest_calls, "{} {}", any_calls, closest_calls);
+        assert!(any_calls <= MAX_LEAF_SIZE);
+
+        // max_distanceより手前に何も無ければfalse。
+        assert!(!bvh.intersect_any(&ray, 0.5, |primitive| distance(primitive) < 0.5));
+    }
+}
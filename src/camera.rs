@@ -0,0 +1,149 @@
+#![allow(dead_code)]
+
+use std;
+
+use vec::{Vec, Dot, Normalize, Cross};
+
+// ピンホールカメラ
+// カメラ位置からイメージセンサーまでsensor_distだけ離れたところにセンサーを置き、
+// センサー上の点に向けてレイを飛ばす。
+#[derive(Debug, Clone)]
+pub struct Camera {
+    position: Vec,
+    dir: Vec,
+    sensor_x_axis: Vec, // イメージセンサーを張る単位ベクトル。
+    sensor_y_axis: Vec,
+    sensor_height: f64, // ワールド座標系でのイメージセンサーの大きさ。横幅は解像度のアスペクト比に合わせる。
+    sensor_dist: f64,   // イメージセンサーまでの距離。
+}
+
+impl Camera {
+    pub fn new(position: Vec, lookat: Vec, up: Vec) -> Camera {
+        let dir = Vec::normalize(&lookat - &position);
+        let sensor_x_axis = Vec::normalize(Vec::cross(&dir, &up));
+        let sensor_y_axis = Vec::normalize(Vec::cross(&sensor_x_axis, &dir));
+
+        Camera {
+            position,
+            dir,
+            sensor_x_axis,
+            sensor_y_axis,
+            sensor_height: 30.0,
+            sensor_dist: 45.0,
+        }
+    }
+
+    // これまでのシーンで使っていたカメラ。
+    pub fn default_camera() -> Camera {
+        Camera::new(
+            Vec { x: 7.0, y: 3.0, z: 7.0 },
+            Vec { x: 0.0, y: 1.0, z: 0.0 },
+            Vec { x: 0.0, y: 1.0, z: 0.0 })
+    }
+
+    pub fn position(&self) -> &Vec {
+        &self.position
+    }
+
+    pub fn dir(&self) -> &Vec {
+        &self.dir
+    }
+
+    // イメージセンサー上の位置。
+    // (x, y)はピクセル単位の座標で、(0, 0)がセンサーの左下、(width, height)が右上になる。
+    pub fn position_on_sensor(&self, x: f64, y: f64, width: i32, height: i32) -> Vec {
+        let sensor_width = self.sensor_height * width as f64 / height as f64; // アスペクト比調整。
+        let sensor_center = &self.position + &self.dir * self.sensor_dist;
+
+        sensor_center +
+            &self.sensor_x_axis * (sensor_width * (x / width as f64 - 0.5)) +
+            &self.sensor_y_axis * (self.sensor_height * (y / height as f64 - 0.5))
+    }
+
+    // イメージセンサー上の(x, y)に向けてレイを飛ばす方向。
+    pub fn ray_direction(&self, x: f64, y: f64, width: i32, height: i32) -> Vec {
+        Vec::normalize(self.position_on_sensor(x, y, width, height) - &self.position)
+    }
+
+    // width x heightの画像向けに、ピクセルごとのレイの方向を前計算しておく。
+    pub fn primary_ray_cache(&self, width: i32, height: i32) -> PrimaryRayCache {
+        let sensor_width = self.sensor_height * width as f64 / height as f64;
+        let step_x = &self.sensor_x_axis * (sensor_width / width as f64);
+        let step_y = &self.sensor_y_axis * (self.sensor_height / height as f64);
+
+        let mut corners = std::vec::Vec::with_capacity((width * height) as usize);
+        for y in 0..height {
+            for x in 0..width {
+                corners.push(self.position_on_sensor(x as f64, y as f64, width, height) - &self.position);
+            }
+        }
+
+        PrimaryRayCache { width, corners, step_x, step_y }
+    }
+
+    // ワールド座標系の点pointをイメージセンサー上に投影し、ピクセル単位の座標(x, y)を返す。
+    // position_on_sensorの逆変換になる。カメラの後ろにある点はNone。
+    pub fn project(&self, point: &Vec, width: i32, height: i32) -> Option<(f64, f64)> {
+        let to_point = point - &self.position;
+        let depth = Vec::dot(&to_point, &self.dir);
+        if depth <= 0.0 {
+            return None
+        }
+
+        // カメラ位置から見てイメージセンサーと交わる点の、センサー中心からのずれ。
+        let offset = &to_point * (self.sensor_dist / depth) - &self.dir * self.sensor_dist;
+        let sensor_width = self.sensor_height * width as f64 / height as f64;
+        let x = (Vec::dot(&offset, &self.sensor_x_axis) / sensor_width + 0.5) * width as f64;
+        let y = (Vec::dot(&offset, &self.sensor_y_axis) / self.sensor_height + 0.5) * height as f64;
+        Some((x, y))
+    }
+}
+
+// カメラレイの方向のキャッシュ。
+// センサー上の位置はピクセル座標の一次式なので、ピクセルの左下の角に向かう（正規化前の）方向を持っておけば、
+// ピクセル内のずれはセンサーの軸方向のベクトルを足すだけで求まる。
+// カメラを動かさずに多数のサンプルを取るときに、サンプルごとの計算を減らせる。
+pub struct PrimaryRayCache {
+    width: i32,
+    corners: std::vec::Vec<Vec>, // (x, y)のピクセルの角に向かう方向。センサーの下の行から順に並ぶ。
+    step_x: Vec,                 // 1ピクセル分のセンサー上の移動量。
+    step_y: Vec,
+}
+
+impl PrimaryRayCache {
+    // (x, y)のピクセル内の(offset_x, offset_y)（[0, 1)）に向けてレイを飛ばす方向。
+    // Camera::ray_direction(x + offset_x, y + offset_y, width, height)と（丸め誤差を除いて）等しい。
+    pub fn ray_direction(&self, x: i32, y: i32, offset_x: f64, offset_y: f64) -> Vec {
+        let corner = &self.corners[(y * self.width + x) as usize];
+        Vec::normalize(corner + &self.step_x * offset_x + &self.step_y * offset_y)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 前計算したカメラレイの方向は、ピクセルのサブピクセル中心（ジッターなし）で毎回求めた方向と一致する。
+    #[test]
+    fn cached_primary_rays_match_uncached() {
+        let camera = Camera::default_camera();
+        let (width, height): (i32, i32) = (17, 11);
+        let cache = camera.primary_ray_cache(width, height);
+        let num_subpixel: i32 = 2;
+        let rate: f64 = 1.0 / num_subpixel as f64;
+        for y in 0..height {
+            for x in 0..width {
+                for sy in 0..num_subpixel {
+                    for sx in 0..num_subpixel {
+                        let r1: f64 = sx as f64 * rate + rate / 2.0;
+                        let r2: f64 = sy as f64 * rate + rate / 2.0;
+                        let cached: Vec = cache.ray_direction(x, y, r1, r2);
+                        let direct: Vec = camera.ray_direction(x as f64 + r1, y as f64 + r2, width, height);
+                        let error: f64 = (&cached - &direct).length();
+                        assert!(error < 1e-12, "{} {} {:?} {:?}", x, y, cached, direct);
+                    }
+                }
+            }
+        }
+    }
+}
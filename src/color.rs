@@ -0,0 +1,59 @@
+#![allow(dead_code)]
+
+use vec::Vec;
+
+// 色はVecで表し、x, y, zをそれぞれ線形なRGBの赤、緑、青とする。
+pub type Color = Vec;
+
+// よく使う色。いずれも線形なRGBの値。
+pub const BLACK: Color   = Color { x: 0.0, y: 0.0, z: 0.0 };
+pub const WHITE: Color   = Color { x: 1.0, y: 1.0, z: 1.0 };
+pub const GRAY: Color    = Color { x: 0.5, y: 0.5, z: 0.5 };
+pub const RED: Color     = Color { x: 1.0, y: 0.0, z: 0.0 };
+pub const GREEN: Color   = Color { x: 0.0, y: 1.0, z: 0.0 };
+pub const BLUE: Color    = Color { x: 0.0, y: 0.0, z: 1.0 };
+pub const YELLOW: Color  = Color { x: 1.0, y: 1.0, z: 0.0 };
+pub const CYAN: Color    = Color { x: 0.0, y: 1.0, z: 1.0 };
+pub const MAGENTA: Color = Color { x: 1.0, y: 0.0, z: 1.0 };
+
+impl Color {
+    // 各チャンネルがvの灰色。
+    pub fn gray(v: f64) -> Color {
+        Color { x: v, y: v, z: v }
+    }
+
+    // 0から255までのsRGBの値（カラーピッカーや画像の8bitの値）を、線形なRGBに変換した色。
+    pub fn rgb8(r: u8, g: u8, b: u8) -> Color {
+        Color { x: srgb_to_linear(r as f64 / 255.0), y: srgb_to_linear(g as f64 / 255.0), z: srgb_to_linear(b as f64 / 255.0) }
+    }
+}
+
+// sRGBの値（[0, 1]）を線形な値にする。暗い所は直線、それ以外は2.4乗の曲線になる（IEC 61966-2-1）。
+pub fn srgb_to_linear(v: f64) -> f64 {
+    if v <= 0.04045 {
+        v / 12.92
+    } else {
+        ((v + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn near(a: &Color, b: &Color) -> bool {
+        (a - b).length() < 1e-12
+    }
+
+    // 8bitの白と黒は線形でも1と0で、8bitの中間の灰色（188）は線形ではほぼ0.5になる。
+    #[test]
+    fn rgb8_converts_srgb_to_linear() {
+        assert!(near(&Color::rgb8(255, 255, 255), &WHITE));
+        assert!(near(&Color::rgb8(0, 0, 0), &BLACK));
+        assert!(near(&Color::rgb8(255, 0, 0), &RED));
+        let gray: Color = Color::rgb8(188, 188, 188);
+        assert!((gray.x - GRAY.x).abs() < 0.01, "{:?}", gray);
+        // 暗い所は直線の区間。
+        assert!((srgb_to_linear(0.04) - 0.04 / 12.92).abs() < 1e-15);
+    }
+}
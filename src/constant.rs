@@ -3,4 +3,24 @@
 use std::f64;
 
 pub const K_PI: f64 = f64::consts::PI;
+pub const K_2PI: f64 = 2.0 * f64::consts::PI;
+pub const K_INV_PI: f64 = f64::consts::FRAC_1_PI;
+pub const K_INV_2PI: f64 = 0.5 * f64::consts::FRAC_1_PI;
 pub const K_INF: f64 = f64::INFINITY;
+
+// 判定を甘くするための相対的な微小量。
+// シャドウレイで光源自身に当たらないよう、判定する距離を(1 - K_EPSILON)倍に縮めるのに使う。
+pub const K_EPSILON: f64 = 1e-4;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 逆数の定数は元の定数の逆数になっている。
+    #[test]
+    fn inverse_constants_are_inverses() {
+        assert!((K_INV_PI * K_PI - 1.0).abs() < 1e-15);
+        assert!((K_INV_2PI * K_2PI - 1.0).abs() < 1e-15);
+        assert!((K_2PI - 2.0 * K_PI).abs() < 1e-15);
+    }
+}
@@ -0,0 +1,218 @@
+#![allow(dead_code)]
+
+use vec::{Vec, Dot};
+use material::MaterialTrait;
+use sampler::{Sampler, SobolSampler};
+
+type Color = Vec;
+
+// BRDFのサンプリングが偏っていないかを、白い炉のテスト（white furnace test）で確かめる。
+// 全方向から一様に明るさ1の光が来る環境では、面の明るさは見る方向の方向半球反射率になる。
+// sampleで選んだ方向のeval * cosΘ / eval_pdfを平均すると、eval_pdfがsampleの選ぶ方向の分布と一致していれば
+// 反射率に収束し、エネルギーを保存する反射率1の面なら1になる。
+// sampleが返すBRDFの値とpdfではなく、光源サンプリングとMISで使うevalとeval_pdfの組を確かめる。
+// （MixMaterialのように、sampleは選んだ項だけのBRDFとpdfを返すマテリアルもある。）
+
+// furnace_testで使うサンプラーのシード値。
+pub const FURNACE_SEED: u64 = 0;
+
+// inの方向から見たときの、白い炉の中での明るさのモンテカルロ推定値 Σ eval * |cosΘ| / eval_pdf / samples。
+// eval_pdfが0の方向は寄与0として数える。δ関数を含むマテリアルはevalで評価できないのでpanicする。
+pub fn furnace_test(material: &dyn MaterialTrait, input: &Vec, normal: &Vec, samples: usize) -> Color {
+    assert!(!material.is_delta(), "furnace_test cannot evaluate materials with a delta BRDF");
+    let mut sum: Color = Color { x: 0.0, y: 0.0, z: 0.0 };
+    if samples == 0 {
+        return sum
+    }
+
+    let mut random = SobolSampler::new(FURNACE_SEED);
+    for i in 0..samples {
+        random.start_pixel(0, i as u64);
+        let mut pdf: f64 = -1.0;
+        let mut brdf_value: Color = Color { x: 0.0, y: 0.0, z: 0.0 };
+        let dir: Vec = material.sample(&mut random, input, normal, &mut pdf, &mut brdf_value);
+        let pdf: f64 = material.eval_pdf(input, normal, &dir);
+        if pdf > 0.0 {
+            sum = sum + material.eval(input, normal, &dir) * (Vec::dot(normal, &dir).abs() / pdf);
+        }
+    }
+    sum / samples as f64
+}
+
+// furnace_testの推定値が、チャンネルごとにexpectedとtolerance以内で一致することを確かめる。
+// 一致しなければpanicする。
+pub fn assert_furnace(
+    material: &dyn MaterialTrait, input: &Vec, normal: &Vec, samples: usize, expected: &Color, tolerance: f64) {
+    let estimate: Color = furnace_test(material, input, normal, samples);
+    let diff: Color = &estimate - expected;
+    let error: f64 = diff.x.abs().max(diff.y.abs()).max(diff.z.abs());
+    if error.is_nan() || error > tolerance {
+        panic!("furnace test estimate {:?} differs from {:?} by {:e} > {:e}", estimate, expected, error, tolerance);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use material::{LambertianMaterial, PhongMaterial, BlinnMaterial, PlasticMaterial, RoughMetalMaterial, ConductorMaterial};
+
+    const SAMPLES: usize = 4096;
+    const TOLERANCE: f64 = 0.01;
+
+    fn gray(value: f64) -> Color {
+        Color { x: value, y: value, z: value }
+    }
+
+    // 真上から見下ろす方向と、上向きの法線。
+    fn normal_incidence() -> (Vec, Vec) {
+        (Vec { x: 0.0, y: 0.0, z: -1.0 }, Vec { x: 0.0, y: 0.0, z: 1.0 })
+    }
+
+    // 反射率1の拡散面は、どの方向から見ても入ってきた光をすべて返す。
+    #[test]
+    fn white_lambertian_returns_one() {
+        let material = LambertianMaterial::new(gray(1.0));
+        let (input, normal) = normal_incidence();
+        assert_furnace(&material, &input, &normal, SAMPLES, &gray(1.0), TOLERANCE);
+        let oblique: Vec = Vec { x: 0.8, y: 0.0, z: -0.6 };
+        assert_furnace(&material, &oblique, &normal, SAMPLES, &gray(1.0), TOLERANCE);
+    }
+
+    #[test]
+    fn lambertian_returns_its_reflectance() {
+        let reflectance: Color = Color { x: 0.2, y: 0.5, z: 0.8 };
+        let (input, normal) = normal_incidence();
+        assert_furnace(&LambertianMaterial::new(reflectance.clone()), &input, &normal, SAMPLES, &reflectance, TOLERANCE);
+    }
+
+    // 垂直に入射すると反射のローブが地面より下にはみ出さないので、Phongの反射率はreflectanceになる。
+    #[test]
+    fn phong_returns_its_reflectance_at_normal_incidence() {
+        let (input, normal) = normal_incidence();
+        assert_furnace(&PhongMaterial::new(gray(0.9), 20.0), &input, &normal, SAMPLES, &gray(0.9), TOLERANCE);
+    }
+
+    // Blinnは垂直に入射したときの反射率がreflectanceになるように正規化してある。
+    #[test]
+    fn blinn_returns_its_reflectance_at_normal_incidence() {
+        let (input, normal) = normal_incidence();
+        assert_furnace(&BlinnMaterial::new(gray(0.9), 20.0), &input, &normal, SAMPLES, &gray(0.9), TOLERANCE);
+    }
+
+    // 塗膜の無いプラスチックは拡散と光沢（反射率1のPhong）をspecularの割合で混ぜたもの。
+    #[test]
+    fn plastic_mixes_diffuse_and_glossy_albedo() {
+        let (input, normal) = normal_incidence();
+        let diffuse: Color = Color { x: 0.8, y: 0.4, z: 0.2 };
+        let expected: Color = &diffuse * 0.7 + gray(0.3);
+        assert_furnace(&PlasticMaterial::new(diffuse, 0.3, 20.0), &input, &normal, SAMPLES, &expected, TOLERANCE);
+    }
+
+    // RoughMetalMaterialは見る方向のFresnel反射率で拡散反射する。
+    #[test]
+    fn rough_metal_returns_its_fresnel_reflectance() {
+        let (input, normal) = normal_incidence();
+        let gold = RoughMetalMaterial::gold();
+        let expected: Color = gold.fresnel(1.0);
+        assert_furnace(&gold, &input, &normal, SAMPLES, &expected, TOLERANCE);
+    }
+
+    // Fresnel反射率がほぼ1の導体は、多重散乱のエネルギーを補えば反射率1になる。
+    // 補わなければ微小面の間で散乱した分だけ暗くなる。
+    #[test]
+    fn energy_compensated_conductor_returns_one() {
+        let (input, normal) = normal_incidence();
+        let mirror = || ConductorMaterial::new(gray(1.0), gray(1e4), 0.5, 0.5);
+        assert_furnace(&mirror().with_energy_compensation(), &input, &normal, SAMPLES, &gray(1.0), TOLERANCE);
+        assert!(furnace_test(&mirror(), &input, &normal, SAMPLES).x < 1.0 - 2.0 * TOLERANCE);
+    }
+
+    #[test]
+    #[should_panic(expected = "furnace test estimate")]
+    fn assert_furnace_rejects_wrong_albedo() {
+        let (input, normal) = normal_incidence();
+        assert_furnace(&LambertianMaterial::new(gray(0.5)), &input, &normal, SAMPLES, &gray(1.0), TOLERANCE);
+    }
+
+    // 正しい範囲のパラメータなら、白い下地のプラスチックでも半球全体で積分した反射率は1を超えない。
+    #[test]
+    fn plastic_never_reflects_more_than_it_receives() {
+        let normal: Vec = Vec { x: 0.0, y: 0.0, z: 1.0 };
+        for &cos in &[1.0, 0.5, 0.1] {
+            let input: Vec = Vec { x: (1.0_f64 - cos * cos).sqrt(), y: 0.0, z: -cos };
+            for &specular in &[0.0, 0.3, 1.0] {
+                for &n in &[1.0, 20.0, 500.0] {
+                    for &clearcoat in &[0.0, 1.0] {
+                        let material = PlasticMaterial::new(gray(1.0), specular, n).with_clearcoat(clearcoat, 0.3);
+                        let albedo: Color = furnace_test(&material, &input, &normal, 2048);
+                        assert!(albedo.x <= 1.0 + TOLERANCE, "{} {} {} {} {:?}", cos, specular, n, clearcoat, albedo);
+                    }
+                }
+            }
+        }
+    }
+
+    // 黒い下地のプラスチックでは、斜めから見たときの反射は塗膜を強くするほど増える。
+    #[test]
+    fn stronger_clearcoat_reflects_more_at_grazing_angles() {
+        let normal: Vec = Vec { x: 0.0, y: 0.0, z: 1.0 };
+        let cos: f64 = 0.1;
+        let input: Vec = Vec { x: (1.0_f64 - cos * cos).sqrt(), y: 0.0, z: -cos };
+        let albedo = |clearcoat: f64| -> f64 {
+            let material = PlasticMaterial::new(gray(0.0), 0.1, 20.0).with_clearcoat(clearcoat, 0.1);
+            furnace_test(&material, &input, &normal, SAMPLES).x
+        };
+        let reflectances: std::vec::Vec<f64> = [0.0, 0.5, 1.0].iter().map(|&clearcoat| albedo(clearcoat)).collect();
+        assert!(reflectances[0] + TOLERANCE < reflectances[1] && reflectances[1] + TOLERANCE < reflectances[2],
+                "{:?}", reflectances);
+    }
+
+    // Fresnel反射率と同じく、RoughMetalMaterialはほぼ真横から見るとどのチャンネルも真上から見るより明るくなる。
+    // （アルミニウムのように、その手前の角度ではp偏光の反射率が下がって暗くなる金属もある。）
+    #[test]
+    fn rough_metal_is_brighter_at_grazing_angles() {
+        let (input, normal) = normal_incidence();
+        let cos: f64 = 0.02;
+        let grazing: Vec = Vec { x: (1.0_f64 - cos * cos).sqrt(), y: 0.0, z: -cos };
+        for metal in &[RoughMetalMaterial::gold(), RoughMetalMaterial::copper(), RoughMetalMaterial::aluminium()] {
+            let head_on: Color = furnace_test(metal, &input, &normal, SAMPLES);
+            let oblique: Color = furnace_test(metal, &grazing, &normal, SAMPLES);
+            assert!(oblique.x > head_on.x && oblique.y > head_on.y && oblique.z > head_on.z,
+                    "{:?} {:?}", head_on, oblique);
+            assert_furnace(metal, &grazing, &normal, SAMPLES, &metal.fresnel(cos), TOLERANCE);
+        }
+    }
+
+    // 浅い角度で見るとローブが地面の下にはみ出すので、見える半球で正規化し直したPhongのほうが多くの光を返す。
+    #[test]
+    fn clamped_phong_reflects_more_at_grazing_angles() {
+        let normal: Vec = Vec { x: 0.0, y: 0.0, z: 1.0 };
+        let cos: f64 = 0.1;
+        let input: Vec = Vec { x: (1.0_f64 - cos * cos).sqrt(), y: 0.0, z: -cos };
+        for &n in &[5.0, 50.0] {
+            let unclamped: Color = furnace_test(&PhongMaterial::new(gray(0.9), n), &input, &normal, SAMPLES);
+            let clamped: Color = furnace_test(&PhongMaterial::new(gray(0.9), n).clamped(), &input, &normal, SAMPLES);
+            assert!(clamped.x > unclamped.x + TOLERANCE, "{} {:?} {:?}", n, clamped, unclamped);
+        }
+    }
+
+    // 粗い金属は微小面の間での多重散乱を無視すると暗くなるが、エネルギーを補えば
+    // どのチャンネルでも滑らかな面の反射率に近づく。
+    #[test]
+    fn energy_compensation_brings_rough_metal_closer_to_the_smooth_reflectance() {
+        let normal: Vec = Vec { x: 0.0, y: 0.0, z: 1.0 };
+        for &cos in &[1.0, 0.5] {
+            let input: Vec = Vec { x: (1.0_f64 - cos * cos).sqrt(), y: 0.0, z: -cos };
+            for metal in &[RoughMetalMaterial::gold(), RoughMetalMaterial::copper(), RoughMetalMaterial::aluminium()] {
+                let smooth: Color = furnace_test(&ConductorMaterial::from_rough_metal(metal, 0.0, 0.0), &input, &normal, SAMPLES);
+                let rough = || ConductorMaterial::from_rough_metal(metal, 0.8, 0.8);
+                let single: Color = furnace_test(&rough(), &input, &normal, SAMPLES);
+                let compensated: Color = furnace_test(&rough().with_energy_compensation(), &input, &normal, SAMPLES);
+                for &(s, u, c) in &[(smooth.x, single.x, compensated.x), (smooth.y, single.y, compensated.y),
+                                    (smooth.z, single.z, compensated.z)] {
+                    assert!(u < s && (c - s).abs() < (u - s).abs(), "{} {:?} {:?} {:?}", cos, smooth, single, compensated);
+                }
+            }
+        }
+    }
+}
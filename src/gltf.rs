@@ -0,0 +1,243 @@
+#![allow(dead_code)]
+
+use std;
+use std::path::Path;
+
+use gltf_crate;
+
+use vec::Vec;
+use scene::{Scene, SceneObject};
+use shape::Shape;
+use triangle::Triangle;
+use material::{Material, MaterialRef, LambertianMaterial, PhongMaterial, MixMaterial, Lightsource};
+
+type Color = Vec;
+type Matrix = [[f64; 4]; 4]; // 列優先（m[列][行]）。glTFと同じ並び。
+
+// glTF（.gltf, .glb）ファイルを読み込んでシーンにする。
+// 既定のシーン（無ければ最初のシーン）のノードをたどり、三角形メッシュをノードの変換込みで三角形として追加する。
+// マテリアルはmetallic-roughnessのパラメータを既存のマテリアルに対応させる。
+//   emissiveが0でない ... Lightsource
+//   それ以外          ... base colorのLambertianMaterialと、roughnessから指数を決めたPhongMaterialをmetallicで混ぜたもの
+// テクスチャは使わず、係数（base color factorなど）だけを見る。
+// カメラは読み込まないので、別途用意すること。
+pub fn load<P: AsRef<Path>>(path: P) -> Result<Scene, String> {
+    match gltf_crate::import(path) {
+        Ok((document, buffers, _)) => convert_document(&document, &buffers),
+        Err(why) => Err(format!("couldn't load glTF: {}", why))
+    }
+}
+
+// loadと同じだが、メモリ上の.gltf（JSON）か.glbのバイト列から読み込む。
+// 外部ファイルを参照できないので、バッファはglbに含めるかdata URIで埋め込んでおくこと。
+pub fn load_slice(bytes: &[u8]) -> Result<Scene, String> {
+    match gltf_crate::import_slice(bytes) {
+        Ok((document, buffers, _)) => convert_document(&document, &buffers),
+        Err(why) => Err(format!("couldn't load glTF: {}", why))
+    }
+}
+
+fn convert_document(document: &gltf_crate::Document, buffers: &[gltf_crate::buffer::Data]) -> Result<Scene, String> {
+    let materials: std::vec::Vec<MaterialRef> = document.materials().map(|m| convert_material(&m)).collect::<Result<_, String>>()?;
+
+    let scene = match document.default_scene().or_else(|| document.scenes().next()) {
+        Some(scene) => scene,
+        None => return Err("glTF has no scene".to_string())
+    };
+
+    let mut objects: Scene = std::vec::Vec::new();
+    for node in scene.nodes() {
+        add_node(&node, &IDENTITY, buffers, &materials, &mut objects)?;
+    }
+    Ok(objects)
+}
+
+const IDENTITY: Matrix = [
+    [1.0, 0.0, 0.0, 0.0],
+    [0.0, 1.0, 0.0, 0.0],
+    [0.0, 0.0, 1.0, 0.0],
+    [0.0, 0.0, 0.0, 1.0],
+];
+
+// ノードとその子孫のメッシュを追加する。parentは親ノードまでの変換。
+fn add_node(
+    node: &gltf_crate::Node, parent: &Matrix, buffers: &[gltf_crate::buffer::Data],
+    materials: &[MaterialRef], objects: &mut Scene) -> Result<(), String> {
+    let local = node.transform().matrix();
+    let mut local_f64: Matrix = [[0.0; 4]; 4];
+    for c in 0..4 {
+        for r in 0..4 {
+            local_f64[c][r] = local[c][r] as f64;
+        }
+    }
+    let transform: Matrix = multiply(parent, &local_f64);
+
+    if let Some(mesh) = node.mesh() {
+        // 鏡映を含む変換では三角形の向き（表裏）が反転するので、頂点の順番を入れ替えて戻す。
+        let flip: bool = determinant3(&transform) < 0.0;
+
+        for primitive in mesh.primitives() {
+            if primitive.mode() != gltf_crate::mesh::Mode::Triangles {
+                continue;
+            }
+
+            let reader = primitive.reader(|buffer| Some(&buffers[buffer.index()]));
+            let positions: std::vec::Vec<Vec> = match reader.read_positions() {
+                Some(positions) => positions.map(|p| transform_point(&transform, &p)).collect(),
+                None => continue
+            };
+            let indices: std::vec::Vec<u32> = match reader.read_indices() {
+                Some(indices) => indices.into_u32().collect(),
+                None => (0..positions.len() as u32).collect()
+            };
+            // 壊れたファイルで番号が頂点の数を超えていたら、panicせずにErrを返す。
+            if let Some(&index) = indices.iter().find(|&&index| index as usize >= positions.len()) {
+                return Err(format!("index {} is out of range for {} vertices", index, positions.len()))
+            }
+
+            // マテリアルの指定が無いときはglTFの既定のマテリアルになる。
+            let material: MaterialRef = match primitive.material().index() {
+                Some(index) => materials[index].clone(),
+                None => convert_material(&primitive.material())?
+            };
+
+            for face in indices.chunks(3) {
+                if face.len() < 3 {
+                    break;
+                }
+                let v0 = positions[face[0] as usize].clone();
+                let (v1, v2) = if flip {
+                    (positions[face[2] as usize].clone(), positions[face[1] as usize].clone())
+                } else {
+                    (positions[face[1] as usize].clone(), positions[face[2] as usize].clone())
+                };
+                objects.push(SceneObject::new(Shape::Triangle(Triangle::new(v0, v1, v2)), material.clone()));
+            }
+        }
+    }
+
+    for child in node.children() {
+        add_node(&child, &transform, buffers, materials, objects)?;
+    }
+    Ok(())
+}
+
+fn convert_material(material: &gltf_crate::Material) -> Result<MaterialRef, String> {
+    let emissive = material.emissive_factor();
+    if emissive[0] > 0.0 || emissive[1] > 0.0 || emissive[2] > 0.0 {
+        let emission = Color { x: emissive[0] as f64, y: emissive[1] as f64, z: emissive[2] as f64 };
+        return Ok(MaterialRef::from(Material::Lightsource(Lightsource::new(emission))))
+    }
+
+    let pbr = material.pbr_metallic_roughness();
+    let base_color = pbr.base_color_factor();
+    let base_color = Color { x: base_color[0] as f64, y: base_color[1] as f64, z: base_color[2] as f64 };
+    let metallic: f64 = pbr.metallic_factor() as f64;
+
+    let diffuse = Material::LambertianMaterial(LambertianMaterial::new(base_color.clone()));
+    if metallic <= 0.0 {
+        return Ok(MaterialRef::from(diffuse))
+    }
+
+    let specular = Material::PhongMaterial(PhongMaterial::new(base_color, phong_exponent(pbr.roughness_factor() as f64)));
+    if metallic >= 1.0 {
+        return Ok(MaterialRef::from(specular))
+    }
+    Ok(MaterialRef::from(Material::MixMaterial(MixMaterial::new(diffuse, specular, metallic)?)))
+}
+
+// roughnessをPhongの指数に変換する。
+// α = roughness^2 としたときのBeckmann分布とPhongの対応 n = 2 / α^2 - 2 を使う。
+fn phong_exponent(roughness: f64) -> f64 {
+    let alpha: f64 = (roughness * roughness).max(1e-3);
+    (2.0 / (alpha * alpha) - 2.0).max(0.0)
+}
+
+fn multiply(a: &Matrix, b: &Matrix) -> Matrix {
+    let mut m: Matrix = [[0.0; 4]; 4];
+    for c in 0..4 {
+        for r in 0..4 {
+            m[c][r] = (0..4).map(|k| a[k][r] * b[c][k]).sum();
+        }
+    }
+    m
+}
+
+fn transform_point(m: &Matrix, p: &[f32; 3]) -> Vec {
+    let (x, y, z) = (p[0] as f64, p[1] as f64, p[2] as f64);
+    Vec {
+        x: m[0][0] * x + m[1][0] * y + m[2][0] * z + m[3][0],
+        y: m[0][1] * x + m[1][1] * y + m[2][1] * z + m[3][1],
+        z: m[0][2] * x + m[1][2] * y + m[2][2] * z + m[3][2],
+    }
+}
+
+// 左上3x3部分の行列式。
+fn determinant3(m: &Matrix) -> f64 {
+    m[0][0] * (m[1][1] * m[2][2] - m[2][1] * m[1][2]) -
+    m[1][0] * (m[0][1] * m[2][2] - m[2][1] * m[0][2]) +
+    m[2][0] * (m[0][1] * m[1][2] - m[1][1] * m[0][2])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 四角形一枚（三角形二枚）のメッシュと、base colorだけを指定したマテリアル。
+    // バッファ（頂点4個のf32座標と、u16の番号6個）はdata URIで埋め込む。
+    const MINIMAL_GLTF: &str = r#"{
+        "asset": { "version": "2.0" },
+        "scene": 0,
+        "scenes": [ { "nodes": [0] } ],
+        "nodes": [ { "mesh": 0, "translation": [0.0, 0.0, -2.0] } ],
+        "meshes": [ { "primitives": [ { "attributes": { "POSITION": 0 }, "indices": 1, "material": 0 } ] } ],
+        "materials": [ { "pbrMetallicRoughness": { "baseColorFactor": [0.75, 0.5, 0.25, 1.0], "metallicFactor": 0.0 } } ],
+        "buffers": [ {
+            "byteLength": 60,
+            "uri": "data:application/octet-stream;base64,AAAAAAAAAAAAAAAAAACAPwAAAAAAAAAAAACAPwAAgD8AAAAAAAAAAAAAgD8AAAAAAAABAAIAAAACAAMA"
+        } ],
+        "bufferViews": [
+            { "buffer": 0, "byteOffset": 0, "byteLength": 48 },
+            { "buffer": 0, "byteOffset": 48, "byteLength": 12 }
+        ],
+        "accessors": [
+            { "bufferView": 0, "componentType": 5126, "count": 4, "type": "VEC3", "min": [0.0, 0.0, 0.0], "max": [1.0, 1.0, 0.0] },
+            { "bufferView": 1, "componentType": 5123, "count": 6, "type": "SCALAR" }
+        ]
+    }"#;
+
+    // 埋め込んだglTFを読み込むと、三角形二枚がノードの変換の位置に、base colorのマテリアルで置かれる。
+    #[test]
+    fn loads_embedded_gltf() {
+        let scene: Scene = load_slice(MINIMAL_GLTF.as_bytes()).unwrap();
+        assert_eq!(scene.len(), 2);
+
+        for object in scene.iter() {
+            match *object.get_shape() {
+                Shape::Triangle ( ref triangle ) => {
+                    let (lower, upper) = triangle.bounds();
+                    assert!(lower.z == -2.0 && upper.z == -2.0, "{:?} {:?}", lower, upper);
+                },
+                _ => unreachable!("glTF meshes are loaded as triangles"),
+            }
+            let reflectance: &Color = object.get_material().reflectance();
+            assert_eq!((reflectance.x, reflectance.y, reflectance.z), (0.75, 0.5, 0.25));
+        }
+        // 同じプリミティブの三角形はマテリアルを共有する。
+        assert!(MaterialRef::ptr_eq(scene[0].get_material_ref(), scene[1].get_material_ref()));
+
+        assert!(load_slice(b"not a gltf").is_err());
+    }
+
+    // 頂点の数を超える番号（最後の3を9に書き換えたもの）があると、panicせずにErrを返す。
+    #[test]
+    fn rejects_out_of_range_indices() {
+        let broken: String = MINIMAL_GLTF.replace("AAIAAAACAAMA\"", "AAIAAAACAAkA\"");
+        assert_ne!(broken, MINIMAL_GLTF);
+        let error: String = match load_slice(broken.as_bytes()) {
+            Ok(_) => panic!("out-of-range index was accepted"),
+            Err(why) => why,
+        };
+        assert!(error.contains("index 9 is out of range for 4 vertices"), "{}", error);
+    }
+}
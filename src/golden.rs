@@ -0,0 +1,107 @@
+#![allow(dead_code)]
+
+use std;
+use std::sync::atomic::AtomicBool;
+
+use vec::Vec;
+use ppm::{load_ppm_file, to_ldr};
+use render::{render_image_with_variance, RenderError, DEFAULT_SEED};
+use scenes::cornell_box;
+
+type Color = Vec;
+
+// 描画結果が意図せず変わっていないかを、保存しておいた参照画像（ゴールデンイメージ）と比べて確かめる。
+// シード値を固定すれば、同じ設定のレンダリングは並列処理の有無によらず同じ画像になる（render_image_with_variance）。
+// 数学関数の実装の違いなどで環境によってわずかに値が変わりうるので、完全な一致ではなく平均二乗誤差で比べる。
+
+// cornell_box_imageの設定。変えたら参照画像も作り直すこと。
+pub const GOLDEN_WIDTH: i32 = 64;
+pub const GOLDEN_HEIGHT: i32 = 48;
+pub const GOLDEN_NUM_SAMPLE_PER_SUBPIXEL: i32 = 4;
+pub const GOLDEN_NUM_SUBPIXEL: i32 = 2;
+
+// 参照画像として保存してあるコーネルボックスの画像と、比べるときの許容誤差。
+pub const CORNELL_BOX_REFERENCE: &str = "golden/cornell_box.ppm";
+pub const CORNELL_BOX_MAX_MSE: f64 = 1e-4;
+
+// renderedをPPMに保存したときの値（ガンマ補正して8bitにしたもの）と、参照画像referenceとの平均二乗誤差。
+// 値は[0, 1]に正規化し、全ピクセルの全チャンネルで平均する。要素数が違うときは無限大。
+pub fn image_mse(rendered: &[Color], reference: &[Color]) -> f64 {
+    if rendered.len() != reference.len() || rendered.is_empty() {
+        return f64::INFINITY
+    }
+
+    let channel = |linear: f64, encoded: f64| -> f64 {
+        let diff: f64 = to_ldr(linear) as f64 / 255.0 - encoded;
+        diff * diff
+    };
+    let sum: f64 = rendered.iter().zip(reference.iter())
+        .map(|(a, b)| channel(a.x, b.x) + channel(a.y, b.y) + channel(a.z, b.z))
+        .sum();
+    sum / (rendered.len() * 3) as f64
+}
+
+// renderedとreference_pathの参照画像（PPM）の平均二乗誤差がmax_mse以下であることを確かめる。
+// 参照画像が読めないとき、大きさが違うとき、誤差が大きすぎるときはpanicする。
+// 参照画像はsave_ppm_fileで保存した画像をそのまま使う。
+pub fn assert_image_matches(rendered: &[Color], reference_path: &str, max_mse: f64) {
+    let (reference, width, height) = match load_ppm_file(reference_path) {
+        Ok(reference) => reference,
+        Err(why) => panic!("couldn't load reference image {}: {}", reference_path, why)
+    };
+    if rendered.len() != reference.len() {
+        panic!("rendered image has {} pixels, but reference image {} is {} x {}",
+               rendered.len(), reference_path, width, height);
+    }
+
+    let mse: f64 = image_mse(rendered, &reference);
+    if mse.is_nan() || mse > max_mse {
+        panic!("rendered image differs from {}: mean squared error {:e} > {:e}", reference_path, mse, max_mse);
+    }
+}
+
+// ゴールデンイメージ用の設定とシード値で、コーネルボックス（scenes::cornell_box）をレンダリングする。
+// save_ppm_file(CORNELL_BOX_REFERENCE, &image, GOLDEN_WIDTH, GOLDEN_HEIGHT)で参照画像を作り直せる。
+pub fn cornell_box_image() -> Result<std::vec::Vec<Color>, RenderError> {
+    let (scene, camera) = cornell_box();
+    let cancel = AtomicBool::new(false);
+    let (image, _, _) = render_image_with_variance(
+        &scene, &camera, GOLDEN_WIDTH, GOLDEN_HEIGHT,
+        GOLDEN_NUM_SAMPLE_PER_SUBPIXEL, GOLDEN_NUM_SUBPIXEL, true, &cancel, DEFAULT_SEED)?;
+    Ok(image)
+}
+
+// コーネルボックスを描画して、リポジトリの参照画像と一致することを確かめる。
+// reference_pathは参照画像のパス（通常はCORNELL_BOX_REFERENCE）。
+pub fn check_cornell_box(reference_path: &str) -> Result<(), RenderError> {
+    let image = cornell_box_image()?;
+    assert_image_matches(&image, reference_path, CORNELL_BOX_MAX_MSE);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // リポジトリの参照画像と同じコーネルボックスが描ける。
+    #[test]
+    fn cornell_box_matches_reference() {
+        check_cornell_box(CORNELL_BOX_REFERENCE).unwrap();
+    }
+
+    // 参照画像と違う画像は許容誤差を超える。
+    #[test]
+    #[should_panic(expected = "differs from")]
+    fn black_image_does_not_match_reference() {
+        let black: std::vec::Vec<Color> = vec![Color { x: 0.0, y: 0.0, z: 0.0 }; (GOLDEN_WIDTH * GOLDEN_HEIGHT) as usize];
+        assert_image_matches(&black, CORNELL_BOX_REFERENCE, CORNELL_BOX_MAX_MSE);
+    }
+
+    // 大きさの違う画像は比べない。
+    #[test]
+    #[should_panic(expected = "pixels")]
+    fn image_of_another_size_does_not_match_reference() {
+        let small: std::vec::Vec<Color> = vec![Color { x: 0.0, y: 0.0, z: 0.0 }; 4];
+        assert_image_matches(&small, CORNELL_BOX_REFERENCE, CORNELL_BOX_MAX_MSE);
+    }
+}
@@ -1,6 +1,6 @@
 #![allow(dead_code)]
 
-use vec::Vec;
+use vec::{Vec, Dot, create_ortho_normal_basis};
 use constant::K_INF;
 
 #[derive(Debug)]
@@ -8,6 +8,11 @@ pub struct Hitpoint {
     pub distance: f64,
     pub normal: Vec,
     pub position: Vec,
+    // uvとtangent_frameは、intersect_sceneが最も近い交差点についてだけ求める（Shape::surface_details）。
+    pub uv: (f64, f64), // テクスチャ座標。形状ごとの対応はSphere::uv、Triangle::intersectを参照。
+    // 表面の接線と従法線(tangent, bitangent)。tangentはuが増える向きで、bitangent = normal × tangent。
+    // 求めていなければNone。
+    pub tangent_frame: Option<(Vec, Vec)>,
 }
 
 impl Hitpoint {
@@ -16,7 +21,168 @@ impl Hitpoint {
             distance: K_INF,
             normal: Vec { x: 0.0, y: 0.0, z: 0.0 },
             position: Vec { x: 0.0, y: 0.0, z: 0.0 },
+            uv: (0.0, 0.0),
+            tangent_frame: None,
         }
     }
+
+    // 法線と合わせて正規直交基底になる(tangent, bitangent)。
+    // 形状の求めた接線があればそれを、無ければcreate_ortho_normal_basisによる任意の向きのものを返す。
+    pub fn tangent_frame(&self) -> (Vec, Vec) {
+        if let Some((ref tangent, ref bitangent)) = self.tangent_frame {
+            return (tangent.clone(), bitangent.clone())
+        }
+
+        let mut tangent: Vec = Vec { x: 0.0, y: 0.0, z: 0.0 };
+        let mut bitangent: Vec = Vec { x: 0.0, y: 0.0, z: 0.0 };
+        create_ortho_normal_basis(&self.normal, &mut tangent, &mut bitangent);
+        (tangent, bitangent)
+    }
+
+    // dir方向に進むレイが表側（法線の向いている側）から当たったかどうか。
+    // 形状の返す法線は物体の外側を向いているので、trueなら物体に入り、falseなら物体から出るところ。
+    pub fn front_face(&self, dir: &Vec) -> bool {
+        Vec::dot(&self.normal, dir) < 0.0
+    }
+
+    // dir方向に進むレイが裏側から当たったときは、法線をレイの来た側に向け直す。
+    // 接線の基底は右手系のまま（bitangent = normal × tangent）になるよう、bitangentも反転する。
+    pub fn face_forward(&mut self, dir: &Vec) {
+        if self.front_face(dir) {
+            return
+        }
+        self.normal = -&self.normal;
+        if let Some((_, ref mut bitangent)) = self.tangent_frame {
+            *bitangent = -&*bitangent;
+        }
+    }
+
+    // 交差点からdir方向へ二次レイを飛ばすときの始点。
+    // 交差点の位置には丸め誤差があるので、そのまま飛ばすと自分自身に当たってしまう（アクネ）。
+    // dirの側へ法線方向に少しずらした点を返す。
+    // ずらす量は座標の大きさに比例するよう、浮動小数点数のビット表現を整数として足し引きして決める。
+    // そのためシーンの大きさによらず、誤差より大きく、かつ隙間から光が漏れない程度の微小な量になる。
+    // 原点付近では相対的な量が小さくなりすぎるので、一定量だけずらす。
+    // Carsten Wächter, Nikolaus Binder. A Fast and Robust Method for Avoiding Self-Intersection. Ray Tracing Gems, 2019.
+    pub fn offset_origin(&self, dir: &Vec) -> Vec {
+        let normal: Vec = if Vec::dot(&self.normal, dir) < 0.0 { -&self.normal } else { self.normal.clone() };
+        Vec {
+            x: offset_component(self.position.x, normal.x),
+            y: offset_component(self.position.y, normal.y),
+            z: offset_component(self.position.z, normal.z),
+        }
+    }
+}
+
+// この値より原点に近い座標は一定量FLOAT_SCALE * nだけずらす。
+const ORIGIN: f64 = 1.0 / 32.0;
+const FLOAT_SCALE: f64 = 1.0 / 65536.0 / 536870912.0; // 論文のf32向けの値を、f64の精度（2^29倍）に合わせて縮めたもの。
+const INT_SCALE: f64 = 256.0;                        // ULP単位のずらす量。
+
+fn offset_component(p: f64, n: f64) -> f64 {
+    if p.abs() < ORIGIN {
+        return p + FLOAT_SCALE * n
+    }
+
+    // 座標と法線の符号が同じなら絶対値を大きく、違うなら小さくする方向にビット表現を動かす。
+    let offset: i64 = (INT_SCALE * n) as i64;
+    let bits: i64 = p.to_bits() as i64;
+    let moved: i64 = if p < 0.0 { bits - offset } else { bits + offset };
+    f64::from_bits(moved as u64)
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use random::Random;
+    use sampler::Sampler;
+    use sampling::Sampling;
+    use sphere::Sphere;
+    use quad::Quad;
+    use shape::Shape;
+    use scene::{Scene, SceneObject, intersect_scene};
+    use material::{Material, LambertianMaterial};
+    use ray::Ray;
+    use vec::Normalize;
+
+    const SCALES: [f64; 2] = [1.0, 1.0e5];
+
+    fn gray() -> Material {
+        Material::LambertianMaterial(LambertianMaterial::new(Vec { x: 0.5, y: 0.5, z: 0.5 }))
+    }
+
+    // 法線normalとの余弦の絶対値が0.05以上で、符号がsideの方向をランダムに選ぶ。
+    fn direction_on_side(random: &mut Random, normal: &Vec, side: f64) -> Vec {
+        loop {
+            let dir: Vec = Sampling::uniform_sphere_surface(random);
+            let cos: f64 = Vec::dot(&dir, normal);
+            if cos * side > 0.05 {
+                return dir
+            }
+        }
+    }
+
+    // 原点から離れた大きさscaleの球。球面上から外に向けたレイは自分自身に当たらず（アクネが無い）、
+    // 内に向けたレイは反対側の球面に当たる。シーンの大きさが桁違いでも同じ。
+    #[test]
+    fn no_self_intersection_on_spheres_at_any_scale() {
+        let mut random = Random::new(41);
+        for &scale in &SCALES {
+            let sphere = Sphere::new(scale, Vec { x: 0.3 * scale, y: 2.0 * scale, z: -1.7 * scale });
+            for _ in 0..2000 {
+                let (position, normal, _) = sphere.sample_surface(&mut random);
+                let mut hitpoint = Hitpoint::new();
+                hitpoint.position = position;
+                hitpoint.normal = normal;
+
+                let outward: Vec = direction_on_side(&mut random, &hitpoint.normal, 1.0);
+                let origin: Vec = hitpoint.offset_origin(&outward);
+                assert!(sphere.intersect(&Ray::new(&origin, &outward)).is_none(), "{} {:?} {:?}", scale, origin, outward);
+
+                let inward: Vec = direction_on_side(&mut random, &hitpoint.normal, -1.0);
+                let origin: Vec = hitpoint.offset_origin(&inward);
+                let chord: f64 = 2.0 * scale * Vec::dot(&inward, &hitpoint.normal).abs();
+                match sphere.intersect(&Ray::new(&origin, &inward)) {
+                    Some(hit) => assert!((hit.distance - chord).abs() < 1e-6 * scale, "{} {} {}", scale, hit.distance, chord),
+                    None => unreachable!("a ray into a sphere must leave through the far side"),
+                }
+            }
+        }
+    }
+
+    // 床とそれに垂直な壁。壁のすぐそばの床の点から壁に向けたレイは、床に当たらず（アクネが無い）、
+    // 壁をすり抜けずに（漏れが無い）壁に当たる。
+    #[test]
+    fn no_acne_or_leaks_near_corners_at_any_scale() {
+        let mut random = Random::new(43);
+        for &scale in &SCALES {
+            let floor_y: f64 = 0.37 * scale;
+            let wall_x: f64 = 1.3 * scale;
+            let size: f64 = 10.0 * scale;
+            let scene: Scene = vec![
+                SceneObject::new(Shape::Quad(Quad::new(
+                    Vec { x: -size, y: floor_y, z: -size }, Vec { x: 0.0, y: 0.0, z: 2.0 * size }, Vec { x: 2.0 * size, y: 0.0, z: 0.0 })), gray()),
+                SceneObject::new(Shape::Quad(Quad::new(
+                    Vec { x: wall_x, y: floor_y - size, z: -size }, Vec { x: 0.0, y: 2.0 * size, z: 0.0 }, Vec { x: 0.0, y: 0.0, z: 2.0 * size })), gray()),
+            ];
+
+            for _ in 0..2000 {
+                // 壁からscaleの1e-9倍から1e-3倍だけ離れた床の点に、上から当てる。
+                let gap: f64 = scale * 10.0_f64.powf(-9.0 + 6.0 * random.next_1d());
+                let target: Vec = Vec { x: wall_x - gap, y: floor_y, z: (random.next_1d() - 0.5) * scale };
+                let eye: Vec = &target + Vec { x: -0.5 * scale, y: scale, z: 0.2 * scale };
+                let (object, hitpoint) = intersect_scene(&scene, &Ray::new(&eye, &Vec::normalize(&target - &eye)));
+                assert!(std::ptr::eq(object.unwrap(), &scene[0]));
+
+                // 壁に向かって斜め上に飛ばす。
+                let dir: Vec = Vec::normalize(Vec { x: 1.0, y: 0.1 + random.next_1d(), z: random.next_1d() - 0.5 });
+                let origin: Vec = hitpoint.offset_origin(&dir);
+                assert!(origin.x < wall_x && origin.y > floor_y, "{} {} {:?}", scale, gap, origin);
+                let (next, next_hitpoint) = intersect_scene(&scene, &Ray::new(&origin, &dir));
+                assert!(std::ptr::eq(next.unwrap(), &scene[1]), "{} {} {:?}", scale, gap, origin);
+                let expected: f64 = (wall_x - origin.x) / dir.x;
+                assert!((next_hitpoint.distance - expected).abs() <= 1e-6 * expected + 1e-12 * scale, "{} {}", next_hitpoint.distance, expected);
+            }
+        }
+    }
+}
@@ -0,0 +1,90 @@
+#![allow(dead_code)]
+
+use std;
+use std::path::Path;
+
+use exr_crate::prelude::{AnyChannel, AnyChannels, Encoding, FlatSamples, Image, Layer, LayerAttributes, SmallVec, WritableImage};
+
+use vec::Vec;
+
+type Color = Vec;
+
+// 複数のチャンネルを1枚のOpenEXRファイルに書き出す。
+// channelsは(チャンネル名, 値)の組で、値はwidth * height個、画像の上の行から順に並ぶ（render_imageなどと同じ）。
+// 値はリニアなまま32bit浮動小数点数で書き、トーンマッピングやガンマ補正はしない。
+// 例えばビューティーを"R", "G", "B"、アルベドを"albedo.R"などとすれば、AOVをまとめて1ファイルにできる。
+pub fn write_exr<P: AsRef<Path>>(path: P, width: i32, height: i32, channels: &[(&str, &[f32])]) -> Result<(), String> {
+    if width <= 0 || height <= 0 {
+        return Err(format!("invalid image size {} x {}", width, height))
+    }
+    let size: (usize, usize) = (width as usize, height as usize);
+
+    let mut list: SmallVec<[AnyChannel<FlatSamples>; 4]> = SmallVec::new();
+    for &(name, samples) in channels {
+        if samples.len() != size.0 * size.1 {
+            return Err(format!("channel {} has {} samples, expected {}", name, samples.len(), size.0 * size.1))
+        }
+        list.push(AnyChannel::new(name, FlatSamples::F32(samples.to_vec())));
+    }
+    if list.is_empty() {
+        return Err("no channels to write".to_string())
+    }
+
+    let layer = Layer::new(size, LayerAttributes::default(), Encoding::default(), AnyChannels::sort(list));
+    match Image::from_layer(layer).write().to_file(path) {
+        Ok(()) => Ok(()),
+        Err(why) => Err(format!("couldn't write EXR: {}", why))
+    }
+}
+
+// 色のバッファをR, G, Bのチャンネルに分ける。write_exrに渡すため。
+pub fn split_channels(buffer: &[Color]) -> (std::vec::Vec<f32>, std::vec::Vec<f32>, std::vec::Vec<f32>) {
+    (buffer.iter().map(|color| color.x as f32).collect(),
+     buffer.iter().map(|color| color.y as f32).collect(),
+     buffer.iter().map(|color| color.z as f32).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use exr_crate::prelude::read_all_flat_layers_from_file;
+
+    // 小さなEXRを書き出して読み戻すと、チャンネル名と値がそのまま残っている。
+    #[test]
+    fn write_and_read_back_exr() {
+        let (width, height): (i32, i32) = (3, 2);
+        let buffer: std::vec::Vec<Color> = (0..6).map(|i| Color { x: i as f64, y: 0.5 * i as f64, z: 10.0 + i as f64 }).collect();
+        let (r, g, b) = split_channels(&buffer);
+        let depth: std::vec::Vec<f32> = vec![1.0, 2.0, 3.0, 4.0, 5.0, 6.5];
+
+        let path = std::env::temp_dir().join(format!("gemspt-write-exr-{}.exr", std::process::id()));
+        write_exr(&path, width, height, &[("R", &r), ("G", &g), ("B", &b), ("depth", &depth)]).unwrap();
+        let image = read_all_flat_layers_from_file(&path);
+        fs::remove_file(&path).unwrap();
+        let image = image.unwrap();
+
+        assert_eq!(image.layer_data.len(), 1);
+        let layer = &image.layer_data[0];
+        assert_eq!((layer.size.0, layer.size.1), (3, 2));
+        let names: std::vec::Vec<String> = layer.channel_data.list.iter().map(|channel| channel.name.to_string()).collect();
+        assert_eq!(names, vec!["B", "G", "R", "depth"]);
+
+        let value = |name: &str, index: usize| -> f32 {
+            let channel = layer.channel_data.list.iter().find(|channel| channel.name.to_string() == name).unwrap();
+            match channel.sample_data {
+                FlatSamples::F32 ( ref samples ) => samples[index],
+                _ => unreachable!("write_exr writes 32-bit floats"),
+            }
+        };
+        // 上の行から順に並ぶので、(2, 1)のピクセルは5番目。
+        assert_eq!(value("R", 5), 5.0);
+        assert_eq!(value("G", 5), 2.5);
+        assert_eq!(value("B", 4), 14.0);
+        assert_eq!(value("depth", 5), 6.5);
+
+        // サンプルの数が合わないチャンネルは書き出さない。
+        assert!(write_exr(&path, width, height, &[("R", &r[..5])]).is_err());
+        assert!(!path.exists());
+    }
+}
@@ -0,0 +1,243 @@
+#![allow(dead_code)]
+
+use std;
+use std::sync::Arc;
+
+use vec::{Vec, Normalize, Cross};
+use ray::Ray;
+use hitpoint::Hitpoint;
+use sampler::Sampler;
+use mesh::Mesh;
+use transform::{Transform, AnimatedTransform};
+use bvh::Bvh;
+
+// 共有するメッシュを変換して置いたもの。
+// メッシュ（三角形とBVH）はArcで共有するので、一つ置くごとに増えるのは変換の分だけ。
+// 変換はシャッターの開いている間に動いてもよく、レイの時刻での変換で交差判定する（モーションブラー）。
+#[derive(Debug)]
+pub struct Instance {
+    mesh: Arc<Mesh>,
+    // メッシュの座標系からワールド座標系への変換。
+    // 動く変換は数百バイトあるので、ほかの形状と並べるShapeが大きくならないようBoxに入れる。
+    transform: Box<AnimatedTransform>,
+}
+
+impl Instance {
+    pub fn new(mesh: Arc<Mesh>, transform: Transform) -> Instance {
+        Instance { mesh, transform: Box::new(AnimatedTransform::fixed(transform)) }
+    }
+
+    // 時刻0でstart、時刻1でendの変換になるよう動くもの。
+    pub fn new_moving(mesh: Arc<Mesh>, start: Transform, end: Transform) -> Instance {
+        Instance { mesh, transform: Box::new(AnimatedTransform::new(start, end)) }
+    }
+
+    pub fn mesh(&self) -> &Arc<Mesh> {
+        &self.mesh
+    }
+
+    pub fn transform(&self) -> &AnimatedTransform {
+        &self.transform
+    }
+
+    // レイをその時刻でのメッシュの座標系に移して交差判定する。
+    // 方向は正規化しないので、交差点までの距離はワールド座標系のものと一致する。
+    // 交差点の位置、法線、接線はワールド座標系に戻して返す。
+    pub fn intersect(&self, ray: &Ray) -> Option<Hitpoint> {
+        self.intersect_with_t_min(ray, 0.0)
+    }
+
+    // intersectと同じだが、距離がt_min以下の交差は無視する。距離はワールド座標系と同じなので、t_minもそのまま使える。
+    pub fn intersect_with_t_min(&self, ray: &Ray, t_min: f64) -> Option<Hitpoint> {
+        let moved: Transform;
+        let transform: &Transform = if self.transform.is_animated() {
+            moved = self.transform.at(ray.time);
+            &moved
+        } else {
+            self.transform.start()
+        };
+
+        let org: Vec = transform.inverse_point(ray.org);
+        let dir: Vec = transform.inverse_vector(ray.dir);
+        let mut hitpoint = self.mesh.intersect_with_t_min(&Ray::with_time(&org, &dir, ray.time), t_min)?;
+
+        hitpoint.position = transform.point(&hitpoint.position);
+        hitpoint.normal = Vec::normalize(transform.normal(&hitpoint.normal));
+        // 変換後も接線は面に沿うので、法線と直交したまま。
+        hitpoint.tangent_frame = hitpoint.tangent_frame.take().map(|(tangent, _)| {
+            let tangent: Vec = Vec::normalize(transform.vector(&tangent));
+            let bitangent: Vec = Vec::cross(&hitpoint.normal, &tangent);
+            (tangent, bitangent)
+        });
+        Some(hitpoint)
+    }
+
+    // 距離max_distanceより手前でメッシュと交差するか。距離はワールド座標系と同じなので、そのまま使える。
+    pub fn intersect_any(&self, ray: &Ray, max_distance: f64) -> bool {
+        let moved: Transform;
+        let transform: &Transform = if self.transform.is_animated() {
+            moved = self.transform.at(ray.time);
+            &moved
+        } else {
+            self.transform.start()
+        };
+
+        let org: Vec = transform.inverse_point(ray.org);
+        let dir: Vec = transform.inverse_vector(ray.dir);
+        self.mesh.intersect_any(&Ray::with_time(&org, &dir, ray.time), max_distance)
+    }
+
+    // 軸に平行な境界ボックスの(最小の角, 最大の角)。メッシュの箱を変換したもの（動くなら動く範囲全体）を囲む。
+    pub fn bounds(&self) -> (Vec, Vec) {
+        let (lower, upper) = self.mesh.bounds();
+        self.transform.bounds(&lower, &upper)
+    }
+
+    // 時刻0でのワールド座標系での表面積。
+    // 拡大縮小が軸ごとに違うと三角形ごとに面積の倍率が変わるので、三角形ごとに足す。
+    pub fn area(&self) -> f64 {
+        let transform: &Transform = self.transform.start();
+        self.mesh.triangles().iter().map(|triangle| triangle.area() * area_scale(transform, triangle.normal())).sum()
+    }
+
+    // 時刻timeでの表面上の点を選ぶ。メッシュの座標系で一様に選んでワールド座標系に移す。
+    // pdfは面積の倍率で割ってワールド座標系の面積測度にする。
+    // (位置, 法線, 面積測度のpdf)を返す。
+    pub fn sample_surface(&self, random: &mut dyn Sampler, time: f64) -> (Vec, Vec, f64) {
+        let transform: Transform = self.transform.at(time);
+        let (position, normal, pdf) = self.mesh.sample_surface(random);
+        let scale: f64 = area_scale(&transform, &normal);
+        (transform.point(&position), Vec::normalize(transform.normal(&normal)), pdf / scale)
+    }
+
+    // sample_surfaceで時刻timeに、ワールド座標系での法線がnormalの点を選ぶ面積測度のpdf。
+    // メッシュの座標系での法線は M^T normal の向きなので、そこから面積の倍率を求める。
+    pub fn surface_pdf(&self, normal: &Vec, time: f64) -> f64 {
+        let transform: Transform = self.transform.at(time);
+        let local_normal: Vec = Vec::normalize(transform.inverse().normal(normal));
+        1.0 / self.mesh.area() / area_scale(&transform, &local_normal)
+    }
+}
+
+// 法線normal（単位ベクトル）に垂直な面の、変換による面積の倍率 |det M| |M^-T n|。
+fn area_scale(transform: &Transform, normal: &Vec) -> f64 {
+    transform.determinant().abs() * transform.normal(normal).length()
+}
+
+// 多数のInstanceと、それを囲むトップレベルのBVH。
+// 森の木のように同じメッシュをたくさん置くときに、一つの形状としてシーンに加える。
+#[derive(Debug)]
+pub struct InstanceGroup {
+    instances: std::vec::Vec<Instance>,
+    bvh: Bvh,
+    area_cdf: std::vec::Vec<f64>, // インスタンスの面積の累積和。
+}
+
+impl InstanceGroup {
+    pub fn new(instances: std::vec::Vec<Instance>) -> InstanceGroup {
+        let bounds: std::vec::Vec<(Vec, Vec)> = instances.iter().map(|instance| instance.bounds()).collect();
+        let bvh = Bvh::new(&bounds);
+        let mut sum: f64 = 0.0;
+        let area_cdf: std::vec::Vec<f64> = instances.iter().map(|instance| { sum += instance.area(); sum }).collect();
+        InstanceGroup { instances, bvh, area_cdf }
+    }
+
+    pub fn instances(&self) -> &[Instance] {
+        &self.instances
+    }
+
+    pub fn intersect(&self, ray: &Ray) -> Option<Hitpoint> {
+        self.intersect_with_t_min(ray, 0.0)
+    }
+
+    // intersectと同じだが、距離がt_min以下の交差は無視する。
+    pub fn intersect_with_t_min(&self, ray: &Ray, t_min: f64) -> Option<Hitpoint> {
+        let instances = &self.instances;
+        let mut closest: Option<Hitpoint> = None;
+        self.bvh.intersect(ray, |index, max_distance| {
+            match instances[index].intersect_with_t_min(ray, t_min) {
+                Some(hitpoint) => {
+                    if hitpoint.distance < max_distance {
+                        let distance: f64 = hitpoint.distance;
+                        closest = Some(hitpoint);
+                        Some(distance)
+                    } else {
+                        None
+                    }
+                },
+                None => None
+            }
+        });
+        closest
+    }
+
+    // 距離max_distanceより手前でどれかのインスタンスと交差するか。最初に見つけた交差で打ち切る。
+    pub fn intersect_any(&self, ray: &Ray, max_distance: f64) -> bool {
+        let instances = &self.instances;
+        self.bvh.intersect_any(ray, max_distance, |index| instances[index].intersect_any(ray, max_distance))
+    }
+
+    pub fn bounds(&self) -> (Vec, Vec) {
+        self.bvh.bounds()
+    }
+
+    pub fn area(&self) -> f64 {
+        self.area_cdf.last().cloned().unwrap_or(0.0)
+    }
+
+    // 時刻0での面積に比例してインスタンスを選び、そのインスタンスの時刻timeでの表面上の点を選ぶ。
+    // (位置, 法線, 面積測度のpdf)を返す。
+    pub fn sample_surface(&self, random: &mut dyn Sampler, time: f64) -> (Vec, Vec, f64) {
+        let area: f64 = self.area();
+        let target: f64 = random.next_1d() * area;
+        let index: usize = match self.area_cdf.binary_search_by(|sum| sum.partial_cmp(&target).unwrap_or(std::cmp::Ordering::Less)) {
+            Ok(index) => index,
+            Err(index) => index,
+        };
+        let index: usize = std::cmp::min(index, self.instances.len() - 1);
+
+        // 選んだインスタンスの面積は累積和の差から求める（Instance::areaは三角形の数に比例する時間がかかる）。
+        let instance_area: f64 = self.area_cdf[index] - if index > 0 { self.area_cdf[index - 1] } else { 0.0 };
+        let (position, normal, pdf) = self.instances[index].sample_surface(random, time);
+        (position, normal, pdf * instance_area / area)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use triangle::Triangle;
+    use vec::Dot;
+
+    // 三角形一枚のメッシュをN個置いても、メッシュは一つを共有するだけで、どれも変換した位置で当たる。
+    #[test]
+    fn many_instances_share_one_mesh_and_are_hit_where_placed() {
+        let mesh = Arc::new(Mesh::new(vec![Triangle::new(
+            Vec { x: -0.5, y: -0.5, z: 0.0 }, Vec { x: 0.5, y: -0.5, z: 0.0 }, Vec { x: 0.0, y: 0.5, z: 0.0 })]));
+        let count: usize = 1000;
+        let offset = |i: usize| -> Vec { Vec { x: (i % 40) as f64 * 2.0, y: (i / 40) as f64 * 2.0, z: -((i % 7) as f64) } };
+        let instances: std::vec::Vec<Instance> = (0..count)
+            .map(|i| Instance::new(mesh.clone(), Transform::rotation(&Vec { x: 0.0, y: 0.0, z: 1.0 }, 0.1 * i as f64)
+                .then(&Transform::translation(&offset(i)))))
+            .collect();
+
+        // 幾何は一つのメッシュのまま。インスタンスごとに増えるのは変換だけ。
+        assert_eq!(Arc::strong_count(&mesh), count + 1);
+        assert!(instances.iter().all(|instance| Arc::ptr_eq(instance.mesh(), &mesh)));
+        assert_eq!(mesh.triangles().len(), 1);
+
+        let dir: Vec = Vec { x: 0.0, y: 0.0, z: -1.0 };
+        for (i, instance) in instances.iter().enumerate() {
+            // 三角形の重心は回転の中心なので、変換後は平行移動した先にある。
+            let center: Vec = &offset(i) + Vec { x: 0.0, y: -1.0 / 6.0, z: 0.0 }.rotate_around(&Vec { x: 0.0, y: 0.0, z: 1.0 }, 0.1 * i as f64);
+            let origin: Vec = &center + Vec { x: 0.0, y: 0.0, z: 10.0 };
+            let hitpoint: Hitpoint = instance.intersect(&Ray::new(&origin, &dir)).unwrap();
+            assert!((&hitpoint.position - &center).length() < 1e-9, "{} {:?} {:?}", i, hitpoint.position, center);
+            assert!((Vec::dot(&hitpoint.normal, &Vec { x: 0.0, y: 0.0, z: 1.0 }) - 1.0).abs() < 1e-12);
+
+            // 隣のインスタンスの位置には当たらない。
+            let beside: Vec = &origin + Vec { x: 1.0, y: 0.0, z: 0.0 };
+            assert!(instance.intersect(&Ray::new(&beside, &dir)).is_none(), "{}", i);
+        }
+    }
+}
@@ -0,0 +1,45 @@
+#[cfg(feature = "rayon")]
+extern crate rayon;
+#[cfg(feature = "serde")]
+extern crate serde;
+#[cfg(all(test, feature = "serde"))]
+extern crate serde_json;
+#[cfg(feature = "gltf")]
+extern crate gltf as gltf_crate;
+#[cfg(feature = "exr")]
+extern crate exr as exr_crate;
+
+pub mod constant;
+pub mod vec;
+pub mod color;
+pub mod hitpoint;
+pub mod random;
+pub mod ray;
+pub mod raydifferential;
+pub mod sampler;
+pub mod sampling;
+pub mod sphere;
+pub mod triangle;
+pub mod quad;
+pub mod transform;
+pub mod bvh;
+pub mod mesh;
+pub mod instance;
+pub mod shape;
+pub mod material;
+pub mod ppm;
+pub mod scene;
+pub mod lighttree;
+pub mod radiance;
+pub mod render;
+pub mod camera;
+pub mod aov;
+pub mod post;
+pub mod scenes;
+pub mod golden;
+pub mod furnace;
+pub mod texture;
+#[cfg(feature = "gltf")]
+pub mod gltf;
+#[cfg(feature = "exr")]
+pub mod image;
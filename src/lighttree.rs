@@ -0,0 +1,266 @@
+#![allow(dead_code)]
+
+use std;
+use std::collections::HashMap;
+
+use vec::{Vec, Dot};
+use scene::{Scene, SceneObject};
+use sampler::Sampler;
+use constant::K_INF;
+
+// 葉でないノードは子を二つ持つ。
+#[derive(Debug)]
+struct Node {
+    lower: Vec,
+    upper: Vec,
+    power: f64,     // 含まれる光源の放射束の目安（輝度 * 面積）の和。
+    // 葉ならlightはシーン中の光源の番号。節ならleft, rightが子のノードの番号。
+    light: usize,
+    left: usize,
+    right: usize,
+    parent: Option<usize>,
+    is_leaf: bool,
+}
+
+// 多数の光源から、シェーディング点への寄与の大きそうなものを選ぶための光源の階層（light tree）。
+// 光源を境界ボックスで二分木にまとめ、根から順に、二つの子のうち寄与の目安（重要度）に比例する確率で辿る。
+// 重要度は、放射束の目安を境界ボックスまでの距離の二乗で割り、
+// シェーディング点の法線から見て境界球が地平線より下にある分だけ小さくしたもの。
+// 境界球がすべて地平線より下にある光源は寄与しえないので選ばない。それ以外の光源は必ず正の確率で選ばれる。
+// Alejandro Conty Estevez, Christopher Kulla. Importance Sampling of Many Lights with Adaptive Tree Splitting. 2018.
+#[derive(Debug)]
+pub struct LightTree {
+    nodes: std::vec::Vec<Node>,
+    leaves: HashMap<usize, usize>, // シーン中の光源の番号から、その葉のノードの番号。
+}
+
+impl LightTree {
+    // scene中の光源（SceneObject::is_light）から作る。
+    pub fn new(scene: &Scene) -> LightTree {
+        let mut lights: std::vec::Vec<(usize, Vec, Vec, f64)> = scene.iter().enumerate()
+            .filter(|&(_, object)| object.is_light())
+            .map(|(index, object)| {
+                let (lower, upper) = object.get_shape().bounds();
+                let power: f64 = object.get_material().emission().luminance().max(0.0) * object.get_shape().area();
+                (index, lower, upper, power)
+            })
+            .collect();
+
+        let mut tree = LightTree { nodes: std::vec::Vec::new(), leaves: HashMap::new() };
+        if !lights.is_empty() {
+            let count: usize = lights.len();
+            tree.build(&mut lights[..], 0, count, None);
+        }
+        tree
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty()
+    }
+
+    // 位置point、法線normalのシェーディング点のための光源を選ぶ。
+    // 選んだ光源とその選択確率を返す。光源が無いか、どの光源も寄与しえなければNone。
+    pub fn sample<'a>(
+        &self, scene: &'a Scene, point: &Vec, normal: &Vec, random: &mut dyn Sampler) -> Option<(&'a SceneObject, f64)> {
+        if self.nodes.is_empty() || self.importance(&self.nodes[0], point, normal) <= 0.0 {
+            return None
+        }
+
+        let mut u: f64 = random.next_1d();
+        let mut pdf: f64 = 1.0;
+        let mut index: usize = 0;
+        while !self.nodes[index].is_leaf {
+            let node = &self.nodes[index];
+            let left: f64 = self.importance(&self.nodes[node.left], point, normal);
+            let right: f64 = self.importance(&self.nodes[node.right], point, normal);
+            // 子の境界球は親より小さいので、親が寄与しえても子がどちらも寄与しえないことがある。
+            if left + right <= 0.0 {
+                return None
+            }
+            let probability_left: f64 = left / (left + right);
+            // 選んだ側の区間に引き伸ばして、次の段の選択に使う。
+            if right <= 0.0 || (left > 0.0 && u < probability_left) {
+                u = (u / probability_left).min(1.0);
+                pdf *= probability_left;
+                index = node.left;
+            } else {
+                u = ((u - probability_left) / (1.0 - probability_left)).min(1.0);
+                pdf *= 1.0 - probability_left;
+                index = node.right;
+            }
+        }
+
+        Some((&scene[self.nodes[index].light], pdf))
+    }
+
+    // 位置point、法線normalのシェーディング点で、sampleがシーン中のlight番目の物体を選ぶ確率。
+    // BRDFサンプリングで当たった光源について、光源サンプリングのpdfを求めるのに使う（MIS）。
+    // 光源でない物体や、その点に寄与しえないとしてsampleが選ばない光源では0。
+    pub fn pdf(&self, point: &Vec, normal: &Vec, light: usize) -> f64 {
+        let mut index: usize = match self.leaves.get(&light) {
+            Some(&leaf) => leaf,
+            None => return 0.0
+        };
+        if self.importance(&self.nodes[0], point, normal) <= 0.0 {
+            return 0.0
+        }
+
+        // 葉から根まで遡って、sampleと同じ選択確率を掛けていく。
+        let mut pdf: f64 = 1.0;
+        while let Some(parent) = self.nodes[index].parent {
+            let node = &self.nodes[parent];
+            let left: f64 = self.importance(&self.nodes[node.left], point, normal);
+            let right: f64 = self.importance(&self.nodes[node.right], point, normal);
+            if left + right <= 0.0 {
+                return 0.0
+            }
+            let chosen: f64 = if index == node.left { left } else { right };
+            pdf *= chosen / (left + right);
+            index = parent;
+        }
+        pdf
+    }
+
+    // lights[start..end]を葉または節にして、そのノードの番号を返す。parentは親のノードの番号。
+    fn build(&mut self, lights: &mut [(usize, Vec, Vec, f64)], start: usize, end: usize, parent: Option<usize>) -> usize {
+        let mut lower = Vec { x: K_INF, y: K_INF, z: K_INF };
+        let mut upper = Vec { x: -K_INF, y: -K_INF, z: -K_INF };
+        let mut power: f64 = 0.0;
+        for &(_, ref l, ref u, p) in &lights[start..end] {
+            lower = Vec { x: lower.x.min(l.x), y: lower.y.min(l.y), z: lower.z.min(l.z) };
+            upper = Vec { x: upper.x.max(u.x), y: upper.y.max(u.y), z: upper.z.max(u.z) };
+            power += p;
+        }
+
+        let index: usize = self.nodes.len();
+        self.nodes.push(Node {
+            lower: lower.clone(), upper: upper.clone(), power,
+            light: lights[start].0, left: 0, right: 0, parent, is_leaf: end - start == 1 });
+        if end - start == 1 {
+            self.leaves.insert(lights[start].0, index);
+            return index
+        }
+
+        // 境界ボックスが最も長い軸で、中心の中央値で二分する。
+        let extent: Vec = &upper - &lower;
+        let axis: usize = if extent.x >= extent.y && extent.x >= extent.z { 0 } else if extent.y >= extent.z { 1 } else { 2 };
+        let center = |light: &(usize, Vec, Vec, f64)| -> f64 {
+            match axis {
+                0 => light.1.x + light.2.x,
+                1 => light.1.y + light.2.y,
+                _ => light.1.z + light.2.z,
+            }
+        };
+        lights[start..end].sort_by(|a, b| center(a).partial_cmp(&center(b)).unwrap_or(std::cmp::Ordering::Equal));
+
+        let mid: usize = (start + end) / 2;
+        let left: usize = self.build(lights, start, mid, Some(index));
+        let right: usize = self.build(lights, mid, end, Some(index));
+        self.nodes[index].left = left;
+        self.nodes[index].right = right;
+        index
+    }
+
+    // ノードに含まれる光源のシェーディング点への寄与の目安。
+    fn importance(&self, node: &Node, point: &Vec, normal: &Vec) -> f64 {
+        let center: Vec = (&node.lower + &node.upper) * 0.5;
+        let radius_squared: f64 = (&node.upper - &center).length_squared();
+        let to_center: Vec = &center - point;
+        let distance_squared: f64 = to_center.length_squared();
+
+        // 境界球の中にいるときは、どの向きからも光が来うる。
+        if distance_squared <= radius_squared {
+            return node.power / radius_squared.max(K_MIN_DISTANCE_SQUARED)
+        }
+
+        // 法線と中心への方向のなす角θから、境界球の見かけの半径θbだけ差し引いた角度で余弦を見積もる。
+        let distance: f64 = distance_squared.sqrt();
+        let cos_theta: f64 = (Vec::dot(normal, &to_center) / distance).clamp(-1.0, 1.0);
+        let sin_theta: f64 = (1.0 - cos_theta * cos_theta).max(0.0).sqrt();
+        let sin_bound: f64 = (radius_squared / distance_squared).sqrt();
+        let cos_bound: f64 = (1.0 - sin_bound * sin_bound).max(0.0).sqrt();
+        // cos(θ - θb)。θ < θbなら境界球が法線の方向を含むので1。
+        let cos_term: f64 = if cos_theta >= cos_bound {
+            1.0
+        } else {
+            cos_theta * cos_bound + sin_theta * sin_bound
+        };
+        if cos_term <= 0.0 {
+            return 0.0
+        }
+
+        node.power * cos_term / distance_squared.max(K_MIN_DISTANCE_SQUARED)
+    }
+}
+
+// 点光源のような大きさの無い光源の近くで、重要度が発散しないようにするための下限。
+const K_MIN_DISTANCE_SQUARED: f64 = 1e-8;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use material::{Material, Lightsource};
+    use random::Random;
+    use scene::SceneBuilder;
+
+    // y = 1の高さにx軸に沿って16個並べた、同じ明るさの小さな球の光源。
+    fn row_of_lights() -> Scene {
+        let mut builder = SceneBuilder::new();
+        for i in 0..16 {
+            let center: Vec = Vec { x: 4.0 * i as f64, y: 1.0, z: 0.0 };
+            builder = builder.add_sphere(center, 0.1, Material::Lightsource(Lightsource::new(Vec { x: 1.0, y: 1.0, z: 1.0 })));
+        }
+        builder.build().unwrap().0
+    }
+
+    // 一つの光源のすぐ下の点では、その光源が他よりずっと多く選ばれ、選ばれる割合はpdfに一致する。
+    // 選んだ光源の寄与をpdfで割った推定値の平均は、全光源の寄与の和になる。
+    #[test]
+    fn nearby_light_is_chosen_most_and_the_estimate_stays_unbiased() {
+        let scene = row_of_lights();
+        let tree = LightTree::new(&scene);
+        let point: Vec = Vec { x: 0.0, y: 0.5, z: 0.0 };
+        let normal: Vec = Vec { x: 0.0, y: 1.0, z: 0.0 };
+        let nearest: usize = scene.iter().position(|object| {
+            let (lower, upper) = object.get_shape().bounds();
+            lower.x < point.x && point.x < upper.x && lower.z < point.z && point.z < upper.z
+        }).unwrap();
+        // 光源からの寄与の代わりに使う、距離の二乗に反比例する量。
+        let contribution = |object: &SceneObject| -> f64 {
+            let (lower, upper) = object.get_shape().bounds();
+            let center: Vec = (&lower + &upper) / 2.0;
+            1.0 / (&center - &point).length_squared()
+        };
+        let total: f64 = scene.iter().map(&contribution).sum();
+        assert!((scene.iter().enumerate().map(|(index, _)| tree.pdf(&point, &normal, index)).sum::<f64>() - 1.0).abs() < 1e-9);
+
+        let samples: usize = 20000;
+        let mut random = Random::new(4);
+        let mut chosen_nearest: usize = 0;
+        let mut sum: f64 = 0.0;
+        let mut sum_squared: f64 = 0.0;
+        for _ in 0..samples {
+            let (light, pdf) = tree.sample(&scene, &point, &normal, &mut random).unwrap();
+            let index: usize = scene.iter().position(|object| std::ptr::eq(object, light)).unwrap();
+            assert!((pdf - tree.pdf(&point, &normal, index)).abs() < 1e-12);
+            if index == nearest {
+                chosen_nearest += 1;
+            }
+            let value: f64 = contribution(light) / pdf;
+            sum += value;
+            sum_squared += value * value;
+        }
+
+        let p: f64 = tree.pdf(&point, &normal, nearest);
+        assert!(p > 0.5, "{}", p);
+        for index in (0..scene.len()).filter(|&index| index != nearest) {
+            assert!(p > 10.0 * tree.pdf(&point, &normal, index), "{} {}", p, tree.pdf(&point, &normal, index));
+        }
+        let expected: f64 = p * samples as f64;
+        assert!((chosen_nearest as f64 - expected).abs() < 4.0 * (expected * (1.0 - p)).sqrt(), "{} {}", chosen_nearest, expected);
+
+        let mean: f64 = sum / samples as f64;
+        let stderr: f64 = ((sum_squared / samples as f64 - mean * mean).max(0.0) / samples as f64).sqrt();
+        assert!((mean - total).abs() <= 4.0 * stderr + 1e-9, "{} {} {}", mean, stderr, total);
+    }
+}
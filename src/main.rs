@@ -1,34 +1,29 @@
-extern crate rayon;
+extern crate gemspt_rs;
 
-mod constant;
-mod vec;
-mod hitpoint;
-mod random;
-mod ray;
-mod sampling;
-mod sphere;
-mod material;
-mod ppm;
-mod scene;
-mod radiance;
-mod render;
-
-use scene::{SceneRendering, generate_scene};
-use render::render;
+use gemspt_rs::scene::{SceneRendering, generate_scene};
+use gemspt_rs::render::render;
+use gemspt_rs::camera::Camera;
 
 fn main() {
     println!("gemspt 2015 written in Rust");
 
     let scene = generate_scene(SceneRendering::SceneGlass);
+    let camera = Camera::default_camera();
 
-    render(
+    let result = render(
         &scene,
+        &camera,
         "image.ppm", // 保存ファイル名
         640, 480,    // 解像度
         1,           // サブピクセルごとのサンプリング数
         4,           // サブピクセルの縦横解像度
         true);       // 並列処理の有効化
 
+    if let Err(why) = result {
+        eprintln!("couldn't render: {}", why);
+        std::process::exit(1);
+    }
+
     println!("Done.");
 }
 
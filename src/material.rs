@@ -1,40 +1,167 @@
 #![allow(dead_code)]
 #![allow(unused_variables)]
 
-use random::Random;
-use vec::{Vec, Dot, Reflect, create_ortho_normal_basis};
-use constant::K_PI;
-use sampling::Sampling;
+use std::sync::Arc;
+use std::ops::Deref;
+
+use sampler::Sampler;
+use vec::{Vec, Dot, Normalize, Reflect, Multiply, Cross, OrthoBasis, create_ortho_normal_basis};
+use constant::{K_PI, K_2PI, K_INV_PI, K_INV_2PI};
+use sampling::{Sampling, Distribution2D, ggx_anisotropic_d, ggx_anisotropic_lambda};
+use texture::ImageTexture;
+use post::luminance;
 
 type Color = Vec;
 
 const DELTA: f64 = 1.0;
 
+// RGBの各チャンネルを代表する波長[nm]。
+const WAVELENGTH_R: f64 = 650.0;
+const WAVELENGTH_G: f64 = 532.0;
+const WAVELENGTH_B: f64 = 450.0;
+
 // Lambertian BRDF
 // 所謂完全拡散面
+#[derive(Clone)]
 pub struct LambertianMaterialSimple { emission: Color, reflectance: Color, }
 
 // Lambertian BRDF
 // 所謂完全拡散面
 // インポータンスサンプリング版。
+#[derive(Clone)]
 pub struct LambertianMaterial { emission: Color, reflectance: Color, }
 
 // 正規化Phong BRDF
-pub struct PhongMaterial { emission: Color, reflectance: Color, n: f64, }
+// clampedのときは、ローブのうち地面より上に出ている割合で割って、見える半球の上で正規化し直す。
+// 浅い角度で反射するとローブの一部が地面の下にはみ出してエネルギーが失われ、ハイライトが暗くなるのを防ぐ。
+#[derive(Clone)]
+pub struct PhongMaterial {
+    emission: Color, reflectance: Color, n: f64,
+    clamped: bool, visible_fraction: std::vec::Vec<f64>, // 反射方向と法線のなす角の余弦ごとの、ローブが地面より上に出ている割合。
+}
+
+// 正規化Blinn-Phong BRDF
+// 反射方向の代わりに、入射と出射の方向の中間（ハーフベクトル）と法線のなす角の余弦のn乗を使う。
+// 同じ指数ではPhongより広がったハイライトになり、浅い角度では横に伸びた形になる。
+#[derive(Clone)]
+pub struct BlinnMaterial { emission: Color, reflectance: Color, n: f64, normalization: f64, }
+
+// 拡散反射の下地に光沢を重ねたプラスチックのような面。
+// Lambertian BRDFと正規化Phong BRDFをspecular : (1 - specular)で足し合わせる。
+// 重みの和が1なので、reflectanceが1以下なら反射率の合計も1を超えない。
+// with_clearcoatで、車の塗装のような透明な塗膜（クリアコート）を上に重ねられる。
+#[derive(Clone)]
+pub struct PlasticMaterial {
+    emission: Color, reflectance: Color, diffuse: LambertianMaterial, glossy: PhongMaterial, specular: f64,
+    clearcoat: f64, clearcoat_alpha: f64, // 塗膜の強さ（[0, 1]）とGGX分布の粗さα。
+}
 
 // 理想的なガラス面。
+#[derive(Clone)]
 pub struct GlassMaterial { emission: Color, reflectance: Color, ior: f64, }
 
+// 薄膜干渉を起こす膜（シャボン玉など）。
+// 膜の両側は真空とし、膜の厚さ（nm）と屈折率から波長ごとの反射率を求める。
+#[derive(Clone)]
+pub struct ThinFilmMaterial { emission: Color, reflectance: Color, thickness: f64, ior: f64, }
+
+// 完全に粗い（一様な）金属面。
+// Lambertian BRDFを、見る方向の角度に応じた導体のFresnel反射率で色付けする。
+// GGXなどの微小面モデルを使わずに、手軽にざらついた金属の見た目を作るためのもの。
+// eta, kはRGBの代表波長における複素屈折率 n = eta + ik。reflectanceは垂直入射での反射率。
+#[derive(Clone)]
+pub struct RoughMetalMaterial { emission: Color, reflectance: Color, eta: Color, k: Color, }
+
+// GGX（Trowbridge-Reitz）の微小面モデルによる金属面。
+// 微小面の法線の分布をGGX分布、遮蔽をSmithのモデルとし、微小面での反射率を導体のFresnel反射率とする。
+// alpha_x, alpha_yは接線と従法線の方向の粗さα。異なる値にすると、ヘアライン加工した金属のように
+// ハイライトが粗さの大きい方向に伸びる。
+// 接線は、sample_with_frame、eval_with_frame、eval_pdf_with_frameでは呼び出し側から渡されたもの
+// （交差点の接線Hitpoint::tangent_frameで、uが増える向き）。テクスチャ座標と一緒に向きが決まるので、
+// メッシュを回転させればヘアラインも一緒に回る。接線を受け取らないsample、eval、eval_pdfでは、
+// brush_directionを法線に垂直な面に射影した向きにする。
+// eta, kとreflectanceはRoughMetalMaterialと同じ。
+// 微小面の間で何度も反射する光を考えないので、粗いほど暗くなる。with_energy_compensationで補う。
+#[derive(Clone)]
+pub struct ConductorMaterial {
+    emission: Color, reflectance: Color, eta: Color, k: Color,
+    alpha_x: f64, alpha_y: f64, brush_direction: Vec,
+    energy_compensation: bool,
+    albedo_table: std::vec::Vec<f64>, // 見る方向の余弦ごとの、Fresnel反射率を1としたときの方向アルベドE(μ)。
+    average_albedo: f64,              // E(μ)の半球での平均 E_avg = 2∫E(μ)μdμ。
+    multiple_scattering_color: Color, // 多重散乱の項の色 F_avg² E_avg / (1 - F_avg (1 - E_avg))。
+}
+
+// 二つのマテリアルをfactorで線形補間したマテリアル。
+// factorが0ならa、1ならbと同じになる。
+#[derive(Clone)]
+pub struct MixMaterial { emission: Color, reflectance: Color, a: Box<Material>, b: Box<Material>, factor: f64, }
+
+// 既存のマテリアルの色味や明るさを変えるためのマテリアル。
+// BRDFと放射をscale倍する。サンプリングする方向とpdfは元のマテリアルのまま。
+#[derive(Clone)]
+pub struct ScaledMaterial { emission: Color, reflectance: Color, inner: Box<Material>, scale: Color, }
+
+// 不透明度のテクスチャで形状に穴を開けるマテリアル（葉を描いた板など）。
+// 当たった点のテクスチャ座標の不透明度がthreshold未満なら抜けとして、レイはそこを素通りする
+// （scene::intersect_scene、scene::intersect_scene_any）。抜けでない点ではinnerと同じにふるまう。
+#[derive(Clone)]
+pub struct CutoutMaterial {
+    emission: Color, reflectance: Color, inner: Box<Material>, alpha: Arc<ImageTexture>, threshold: f64,
+}
+
 // 光源としてふるまうマテリアル
-pub struct Lightsource { emission: Color, reflectance: Color, }
+// two_sidedがfalseのときは法線の向いている側（表面）からしか放射しない。
+// visible_to_cameraがfalseのときは、カメラから直接見たときだけ黒く写る（シーンは照らす）。
+#[derive(Clone)]
+pub struct Lightsource { emission: Color, reflectance: Color, two_sided: bool, visible_to_camera: bool, }
+
+// 画像テクスチャの模様で放射する光源（テレビの画面など）。
+// テクセルごとに一定の放射輝度（テクセルの色 * intensity）で、法線の向いている側（表面）からだけ放射する。
+// 光源サンプリングではテクセルの輝度に比例して点を選ぶ。テクスチャ座標で点を選べる形状（Quad）に使うこと。
+// emissionは放射輝度の平均で、光源かどうかの判定や、テクスチャ座標の分からないときの放射に使う。
+#[derive(Clone)]
+pub struct EmissiveTextureMaterial {
+    emission: Color, reflectance: Color, texture: Arc<ImageTexture>, intensity: f64, distribution: Arc<Distribution2D>,
+}
+
+// サンプリングした方向がBRDFのどの項（ローブ）から来たか。
+// 拡散と鏡面の成分を別々のバッファに分けるAOVや、デバッグに使う。
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LobeType {
+    Diffuse,      // 拡散反射
+    Glossy,       // δ関数を含まない光沢反射（Phong、Blinn、塗膜など）
+    Reflection,   // δ関数の鏡面反射
+    Transmission, // δ関数の透過（屈折）
+}
+
+// MaterialTrait::sample_bsdfの結果。
+// direction, pdf, brdf_valueはsampleの返り値と出力引数と同じもの。
+#[derive(Debug, Clone)]
+pub struct BsdfSample {
+    pub direction: Vec,
+    pub pdf: f64,
+    pub brdf_value: Color,
+    pub lobe: LobeType,
+}
 
 // マテリアルインターフェース
+#[derive(Clone)]
 pub enum Material {
     LambertianMaterialSimple(LambertianMaterialSimple),
     LambertianMaterial      (LambertianMaterial),
     PhongMaterial           (PhongMaterial),
+    BlinnMaterial           (BlinnMaterial),
+    PlasticMaterial         (PlasticMaterial),
     GlassMaterial           (GlassMaterial),
+    ThinFilmMaterial        (ThinFilmMaterial),
+    RoughMetalMaterial      (RoughMetalMaterial),
+    ConductorMaterial       (ConductorMaterial),
+    MixMaterial             (MixMaterial),
+    ScaledMaterial          (ScaledMaterial),
+    CutoutMaterial          (CutoutMaterial),
     Lightsource             (Lightsource),
+    EmissiveTextureMaterial (EmissiveTextureMaterial),
 }
 
 pub trait MaterialTrait {
@@ -43,49 +170,650 @@ pub trait MaterialTrait {
 
     // in, outはカメラ側から光を逆方向に追跡したときの入出方向とする。
     // 以下、in = -omega, out = omega'となる。
+    // input, normal, outputはいずれも単位ベクトルであること（各メソッド共通）。
+    // 正規化されていないとdotやreflectの結果が狂うが、実装側では正規化し直さない。
+    // Materialを通して呼んだときは、デバッグビルドでdebug_assert!により確かめる。
     fn eval(&self, input: &Vec, normal: &Vec, output: &Vec) -> Color;
-    fn sample(&self, random: &mut Random, input: &Vec, normal: &Vec, pdf: &mut f64, brdf_value: &mut Color) -> Vec;
+    fn sample(&self, random: &mut dyn Sampler, input: &Vec, normal: &Vec, pdf: &mut f64, brdf_value: &mut Color) -> Vec;
+
+    // sampleと同じだが、選んだ方向がどのローブから来たかも返す。同じ乱数の状態からsampleと同じ方向になる。
+    // 既定では、δ関数を含むマテリアルは出射方向が入射側と同じ側ならReflection、反対側ならTransmission、
+    // それ以外はDiffuseとする。複数のローブを持つマテリアルや光沢面は上書きする。
+    fn sample_bsdf(&self, random: &mut dyn Sampler, input: &Vec, normal: &Vec) -> BsdfSample {
+        let mut pdf: f64 = -1.0;
+        let mut brdf_value: Color = Color { x: 0.0, y: 0.0, z: 0.0 };
+        let direction: Vec = self.sample(random, input, normal, &mut pdf, &mut brdf_value);
+        let lobe: LobeType = if !self.is_delta() {
+            LobeType::Diffuse
+        } else if Vec::dot(normal, input) * Vec::dot(normal, &direction) < 0.0 {
+            LobeType::Reflection
+        } else {
+            LobeType::Transmission
+        };
+
+        BsdfSample { direction, pdf, brdf_value, lobe }
+    }
+
+    // sampleと同じだが、法線まわりの接線と従法線(tangent, bitangent)を呼び出し側から渡す。
+    // 交差点で求めた接線（Hitpoint::tangent_frame）があれば、基底を作り直さずに済む。
+    // 法線まわりの基底を使わないマテリアルは、既定のままsampleを呼べばよい。
+    // create_ortho_normal_basisで作った基底を渡せば、同じ乱数の状態からsampleと同じ結果になる。
+    // delta_lobeには、選んだ方向がδ関数のローブ（鏡面反射や屈折）から来たかを書く。
+    // MixMaterialでδ関数を含む側を選んだときは、is_deltaがfalseでもtrueになる。
+    // その方向は光源サンプリングでは数えないので、先で光源に当たったときのMISの重みは1にする（radiance::radiance_nee）。
+    #[allow(clippy::too_many_arguments)]
+    fn sample_with_frame(
+        &self, random: &mut dyn Sampler, input: &Vec, normal: &Vec, tangent: &Vec, bitangent: &Vec,
+        pdf: &mut f64, brdf_value: &mut Color, delta_lobe: &mut bool) -> Vec {
+        *delta_lobe = self.is_delta();
+        self.sample(random, input, normal, pdf, brdf_value)
+    }
+
+    // evalとeval_pdfと同じだが、sample_with_frameと同じ接線と従法線を呼び出し側から渡す。
+    // sample_with_frameで渡された基底を使うマテリアルは、光源サンプリングやMISで同じ分布を評価するよう上書きする。
+    // 既定ではevalとeval_pdfを呼ぶ。
+    fn eval_with_frame(&self, input: &Vec, normal: &Vec, tangent: &Vec, bitangent: &Vec, output: &Vec) -> Color {
+        self.eval(input, normal, output)
+    }
+
+    fn eval_pdf_with_frame(&self, input: &Vec, normal: &Vec, tangent: &Vec, bitangent: &Vec, output: &Vec) -> f64 {
+        self.eval_pdf(input, normal, output)
+    }
+
+    // sampleと同じだが、法線の側（物体の外側）にある媒質の屈折率outer_iorを呼び出し側から渡す。
+    // ガラスに水を満たしたように誘電体が入れ子になっているとき、境界ごとに正しい屈折率の比を使うためのもの
+    // （radiance::radiance_with_media）。屈折しないマテリアルは、既定のままsampleを呼べばよい。
+    // outer_iorに1.0（真空）を渡せば、同じ乱数の状態からsampleと同じ結果になる。
+    fn sample_with_outer_ior(
+        &self, random: &mut dyn Sampler, input: &Vec, normal: &Vec, outer_ior: f64,
+        pdf: &mut f64, brdf_value: &mut Color) -> Vec {
+        self.sample(random, input, normal, pdf, brdf_value)
+    }
+
+    // BRDFが方向によらず一定なら、その値（Lambertianならρ / π）。そうでなければNone。
+    // 光源サンプリングでevalを呼ばずに済ませるのに使う（radiance::radiance_nee_lambert）。
+    fn constant_brdf(&self) -> Option<Color> {
+        None
+    }
+
+    // 光を屈折させる誘電体なら、その屈折率。そうでなければNone。
+    // 物体の中に入ったレイがどの媒質の中にいるかを、積分器が覚えておくのに使う（radiance::MediumStack）。
+    fn dielectric_ior(&self) -> Option<f64> {
+        None
+    }
+
+    // outの方向をsampleで選んだときのpdf（立体角測度）。
+    // 光源サンプリングなど別の方法で選んだ方向について、BRDFサンプリングのpdfを求めるのに使う。
+    // δ関数を含むマテリアルは任意の方向に対するpdfが0になる。
+    // MISの重みを求めるときは、is_deltaを先に確かめてδ関数を含むマテリアルではこれを呼ばない（radiance::mis_weight_for）。
+    fn eval_pdf(&self, input: &Vec, normal: &Vec, output: &Vec) -> f64;
+
+    // inの方向から見たときの放射輝度。
+    // 光源は完全拡散光源（Lambertian emitter）とし、放射輝度は見る方向によらず一定とする。
+    // 放射強度がcosΘに比例して減衰するLambertの余弦則は、光源サンプリング時の幾何項の側で扱う。
+    fn emitted(&self, input: &Vec, normal: &Vec) -> Color {
+        self.emission().clone()
+    }
+
+    // emittedと同じだが、光源上のテクスチャ座標uvの点での放射輝度。
+    // 場所によって放射が変わるマテリアル（EmissiveTextureMaterial）が実装する。既定ではemittedと同じ。
+    fn emitted_at(&self, input: &Vec, normal: &Vec, uv: (f64, f64)) -> Color {
+        self.emitted(input, normal)
+    }
+
+    // 光源サンプリングで光源上の点をテクスチャ座標で選ぶときの分布。
+    // ((u, v), 単位正方形上の密度)を返し、放射の明るい所ほど選ばれやすくする。
+    // 既定ではNoneで、テクスチャ座標を一様に選ぶ（Shape::point_at_uvに対応する形状のとき）。
+    fn sample_emission_uv(&self, random: &mut dyn Sampler) -> Option<((f64, f64), f64)> {
+        None
+    }
+
+    // sample_emission_uvでテクスチャ座標uvの点を選ぶ、単位正方形上の密度。
+    // BRDFサンプリングで当たった光源上の点について、光源サンプリングのpdfを求めるのに使う（MIS）。
+    // sample_emission_uvがNone（一様に選ぶ）のマテリアルではNone。
+    fn emission_uv_pdf(&self, uv: (f64, f64)) -> Option<f64> {
+        None
+    }
+
+    // BRDFがディラックのδ関数を含むかどうか（鏡面反射、屈折など）。
+    // δ関数を含むマテリアルは光源サンプリングでは寄与を評価できない。
+    fn is_delta(&self) -> bool {
+        false
+    }
+
+    // カメラレイ（深さ0のレイ）が当たったときに放射を数えるか。
+    // falseの光源は画像に直接は写らないが、光源サンプリングや反射を経た光ではシーンを照らす。
+    // 商品写真のように、照明そのものを写したくないときに使う。
+    fn visible_to_camera(&self) -> bool {
+        true
+    }
+
+    // テクスチャ座標uvの点が抜け（レイが素通りする点）かどうか。CutoutMaterialだけがtrueを返しうる。
+    fn is_cutout(&self, uv: (f64, f64)) -> bool {
+        false
+    }
+
+    // is_cutoutがtrueを返しうるか。falseのマテリアルの形状は、交差判定でテクスチャ座標を求めなくてよい。
+    fn has_cutout(&self) -> bool {
+        false
+    }
+
+    // inの方向から見たときの方向半球反射率。BRDF * cosΘを半球（透過するものは全球）で積分したもの。
+    // エネルギーの保存の確認や、デノイザーに渡すアルベドに使う。
+    // 既定ではsampleでsamples回サンプリングしたモンテカルロ積分で求める。解析的に求まるマテリアルは上書きする。
+    fn hemispherical_reflectance(&self, input: &Vec, normal: &Vec, random: &mut dyn Sampler, samples: usize) -> Color {
+        let mut sum: Color = Color { x: 0.0, y: 0.0, z: 0.0 };
+        if samples == 0 {
+            return sum
+        }
+        for _ in 0..samples {
+            let mut pdf: f64 = -1.0;
+            let mut brdf_value: Color = Color { x: 0.0, y: 0.0, z: 0.0 };
+            let dir: Vec = self.sample(random, input, normal, &mut pdf, &mut brdf_value);
+            if pdf > 0.0 {
+                sum = sum + brdf_value * (Vec::dot(normal, &dir).abs() / pdf);
+            }
+        }
+        sum / samples as f64
+    }
+
+    // デノイザーに渡すアルベドAOV用の色。
+    // 拡散面では反射率、ガラスや鏡面では色味（reflectance）、光源では放射を返す。
+    // 鏡面には意味のある拡散反射率が無いため、AOVではreflectanceをそのまま使わずにこちらを使う。
+    fn albedo_hint(&self) -> Color {
+        self.reflectance().clone()
+    }
+}
+
+// シーン中の物体から参照するマテリアル。
+// 複製しても中身は共有されるので、同じマテリアルを多数の物体に割り当てても大きなマテリアルをコピーせずに済む。
+// MaterialTraitはオブジェクト安全なので、Material以外に独自に実装したマテリアルも入れられる。
+#[derive(Clone)]
+pub struct MaterialRef(Arc<dyn MaterialTrait + Send + Sync>);
+
+impl MaterialRef {
+    pub fn new<M: MaterialTrait + Send + Sync + 'static>(material: M) -> MaterialRef {
+        MaterialRef(Arc::new(material))
+    }
+
+    // 同じマテリアルを指しているかどうか。
+    pub fn ptr_eq(a: &MaterialRef, b: &MaterialRef) -> bool {
+        Arc::ptr_eq(&a.0, &b.0)
+    }
+}
+
+impl From<Material> for MaterialRef {
+    fn from(material: Material) -> MaterialRef {
+        MaterialRef::new(material)
+    }
+}
+
+// Box<dyn MaterialTrait>に入れて集めたマテリアルも、そのまま共有できるようにする。
+impl From<Box<dyn MaterialTrait + Send + Sync>> for MaterialRef {
+    fn from(material: Box<dyn MaterialTrait + Send + Sync>) -> MaterialRef {
+        MaterialRef(Arc::from(material))
+    }
+}
+
+impl Deref for MaterialRef {
+    type Target = dyn MaterialTrait + Send + Sync;
+
+    fn deref(&self) -> &Self::Target {
+        &*self.0
+    }
+}
+
+// 単位ベクトルを前提とする引数を、デバッグビルドで確かめる。
+#[inline]
+fn debug_assert_unit(v: &Vec, name: &str) {
+    debug_assert!(v.is_unit(), "{} must be a unit vector: {:?}", name, v);
 }
 
 impl MaterialTrait for Material {
     fn emission(&self) -> &Color {
-        match self {
-            &Material::LambertianMaterialSimple ( ref material ) => { &material.emission() },
-            &Material::LambertianMaterial       ( ref material ) => { &material.emission() },
-            &Material::PhongMaterial            ( ref material ) => { &material.emission() },
-            &Material::GlassMaterial            ( ref material ) => { &material.emission() },
-            &Material::Lightsource              ( ref material ) => { &material.emission() },
+        match *self {
+            Material::LambertianMaterialSimple ( ref material ) => material.emission(),
+            Material::LambertianMaterial       ( ref material ) => material.emission(),
+            Material::PhongMaterial            ( ref material ) => material.emission(),
+            Material::BlinnMaterial            ( ref material ) => material.emission(),
+            Material::PlasticMaterial          ( ref material ) => material.emission(),
+            Material::GlassMaterial            ( ref material ) => material.emission(),
+            Material::ThinFilmMaterial         ( ref material ) => material.emission(),
+            Material::RoughMetalMaterial       ( ref material ) => material.emission(),
+            Material::ConductorMaterial        ( ref material ) => material.emission(),
+            Material::MixMaterial              ( ref material ) => material.emission(),
+            Material::ScaledMaterial           ( ref material ) => material.emission(),
+            Material::CutoutMaterial           ( ref material ) => material.emission(),
+            Material::Lightsource              ( ref material ) => material.emission(),
+            Material::EmissiveTextureMaterial  ( ref material ) => material.emission(),
         }
     }
 
     fn reflectance(&self) -> &Color {
-        match self {
-            &Material::LambertianMaterialSimple ( ref material ) => { &material.reflectance() },
-            &Material::LambertianMaterial       ( ref material ) => { &material.reflectance() },
-            &Material::PhongMaterial            ( ref material ) => { &material.reflectance() },
-            &Material::GlassMaterial            ( ref material ) => { &material.reflectance() },
-            &Material::Lightsource              ( ref material ) => { &material.reflectance() },
+        match *self {
+            Material::LambertianMaterialSimple ( ref material ) => material.reflectance(),
+            Material::LambertianMaterial       ( ref material ) => material.reflectance(),
+            Material::PhongMaterial            ( ref material ) => material.reflectance(),
+            Material::BlinnMaterial            ( ref material ) => material.reflectance(),
+            Material::PlasticMaterial          ( ref material ) => material.reflectance(),
+            Material::GlassMaterial            ( ref material ) => material.reflectance(),
+            Material::ThinFilmMaterial         ( ref material ) => material.reflectance(),
+            Material::RoughMetalMaterial       ( ref material ) => material.reflectance(),
+            Material::ConductorMaterial        ( ref material ) => material.reflectance(),
+            Material::MixMaterial              ( ref material ) => material.reflectance(),
+            Material::ScaledMaterial           ( ref material ) => material.reflectance(),
+            Material::CutoutMaterial           ( ref material ) => material.reflectance(),
+            Material::Lightsource              ( ref material ) => material.reflectance(),
+            Material::EmissiveTextureMaterial  ( ref material ) => material.reflectance(),
         }
     }
 
     fn eval(&self, input: &Vec, normal: &Vec, output: &Vec) -> Color {
-        match self {
-            &Material::LambertianMaterialSimple ( ref material ) => { material.eval(input, normal, output) },
-            &Material::LambertianMaterial       ( ref material ) => { material.eval(input, normal, output) },
-            &Material::PhongMaterial            ( ref material ) => { material.eval(input, normal, output) },
-            &Material::GlassMaterial            ( ref material ) => { material.eval(input, normal, output)},
-            &Material::Lightsource              ( ref material ) => { material.eval(input, normal, output) },
+        debug_assert_unit(input, "input");
+        debug_assert_unit(normal, "normal");
+        debug_assert_unit(output, "output");
+        match *self {
+            Material::LambertianMaterialSimple ( ref material ) => material.eval(input, normal, output),
+            Material::LambertianMaterial       ( ref material ) => material.eval(input, normal, output),
+            Material::PhongMaterial            ( ref material ) => material.eval(input, normal, output),
+            Material::BlinnMaterial            ( ref material ) => material.eval(input, normal, output),
+            Material::PlasticMaterial          ( ref material ) => material.eval(input, normal, output),
+            Material::GlassMaterial            ( ref material ) => material.eval(input, normal, output),
+            Material::ThinFilmMaterial         ( ref material ) => material.eval(input, normal, output),
+            Material::RoughMetalMaterial       ( ref material ) => material.eval(input, normal, output),
+            Material::ConductorMaterial        ( ref material ) => material.eval(input, normal, output),
+            Material::MixMaterial              ( ref material ) => material.eval(input, normal, output),
+            Material::ScaledMaterial           ( ref material ) => material.eval(input, normal, output),
+            Material::CutoutMaterial           ( ref material ) => material.eval(input, normal, output),
+            Material::Lightsource              ( ref material ) => material.eval(input, normal, output),
+            Material::EmissiveTextureMaterial  ( ref material ) => material.eval(input, normal, output),
+        }
+    }
+
+    fn eval_with_frame(&self, input: &Vec, normal: &Vec, tangent: &Vec, bitangent: &Vec, output: &Vec) -> Color {
+        debug_assert_unit(input, "input");
+        debug_assert_unit(normal, "normal");
+        debug_assert_unit(output, "output");
+        match *self {
+            Material::LambertianMaterialSimple ( ref material ) => material.eval_with_frame(input, normal, tangent, bitangent, output),
+            Material::LambertianMaterial       ( ref material ) => material.eval_with_frame(input, normal, tangent, bitangent, output),
+            Material::PhongMaterial            ( ref material ) => material.eval_with_frame(input, normal, tangent, bitangent, output),
+            Material::BlinnMaterial            ( ref material ) => material.eval_with_frame(input, normal, tangent, bitangent, output),
+            Material::PlasticMaterial          ( ref material ) => material.eval_with_frame(input, normal, tangent, bitangent, output),
+            Material::GlassMaterial            ( ref material ) => material.eval_with_frame(input, normal, tangent, bitangent, output),
+            Material::ThinFilmMaterial         ( ref material ) => material.eval_with_frame(input, normal, tangent, bitangent, output),
+            Material::RoughMetalMaterial       ( ref material ) => material.eval_with_frame(input, normal, tangent, bitangent, output),
+            Material::ConductorMaterial        ( ref material ) => material.eval_with_frame(input, normal, tangent, bitangent, output),
+            Material::MixMaterial              ( ref material ) => material.eval_with_frame(input, normal, tangent, bitangent, output),
+            Material::ScaledMaterial           ( ref material ) => material.eval_with_frame(input, normal, tangent, bitangent, output),
+            Material::CutoutMaterial           ( ref material ) => material.eval_with_frame(input, normal, tangent, bitangent, output),
+            Material::Lightsource              ( ref material ) => material.eval_with_frame(input, normal, tangent, bitangent, output),
+            Material::EmissiveTextureMaterial  ( ref material ) => material.eval_with_frame(input, normal, tangent, bitangent, output),
+        }
+    }
+
+    fn sample(&self, random: &mut dyn Sampler, input: &Vec, normal: &Vec,
+        pdf: &mut f64, brdf_value: &mut Color) -> Vec {
+        debug_assert_unit(input, "input");
+        debug_assert_unit(normal, "normal");
+        match *self {
+            Material::LambertianMaterialSimple ( ref material ) => material.sample(random, input, normal, pdf, brdf_value),
+            Material::LambertianMaterial       ( ref material ) => material.sample(random, input, normal, pdf, brdf_value),
+            Material::PhongMaterial            ( ref material ) => material.sample(random, input, normal, pdf, brdf_value),
+            Material::BlinnMaterial            ( ref material ) => material.sample(random, input, normal, pdf, brdf_value),
+            Material::PlasticMaterial          ( ref material ) => material.sample(random, input, normal, pdf, brdf_value),
+            Material::GlassMaterial            ( ref material ) => material.sample(random, input, normal, pdf, brdf_value),
+            Material::ThinFilmMaterial         ( ref material ) => material.sample(random, input, normal, pdf, brdf_value),
+            Material::RoughMetalMaterial       ( ref material ) => material.sample(random, input, normal, pdf, brdf_value),
+            Material::ConductorMaterial        ( ref material ) => material.sample(random, input, normal, pdf, brdf_value),
+            Material::MixMaterial              ( ref material ) => material.sample(random, input, normal, pdf, brdf_value),
+            Material::ScaledMaterial           ( ref material ) => material.sample(random, input, normal, pdf, brdf_value),
+            Material::CutoutMaterial           ( ref material ) => material.sample(random, input, normal, pdf, brdf_value),
+            Material::Lightsource              ( ref material ) => material.sample(random, input, normal, pdf, brdf_value),
+            Material::EmissiveTextureMaterial  ( ref material ) => material.sample(random, input, normal, pdf, brdf_value),
+        }
+    }
+
+    fn sample_with_frame(
+        &self, random: &mut dyn Sampler, input: &Vec, normal: &Vec, tangent: &Vec, bitangent: &Vec,
+        pdf: &mut f64, brdf_value: &mut Color, delta_lobe: &mut bool) -> Vec {
+        debug_assert_unit(input, "input");
+        debug_assert_unit(normal, "normal");
+        match *self {
+            Material::LambertianMaterialSimple ( ref material ) => material.sample_with_frame(random, input, normal, tangent, bitangent, pdf, brdf_value, delta_lobe),
+            Material::LambertianMaterial       ( ref material ) => material.sample_with_frame(random, input, normal, tangent, bitangent, pdf, brdf_value, delta_lobe),
+            Material::PhongMaterial            ( ref material ) => material.sample_with_frame(random, input, normal, tangent, bitangent, pdf, brdf_value, delta_lobe),
+            Material::BlinnMaterial            ( ref material ) => material.sample_with_frame(random, input, normal, tangent, bitangent, pdf, brdf_value, delta_lobe),
+            Material::PlasticMaterial          ( ref material ) => material.sample_with_frame(random, input, normal, tangent, bitangent, pdf, brdf_value, delta_lobe),
+            Material::GlassMaterial            ( ref material ) => material.sample_with_frame(random, input, normal, tangent, bitangent, pdf, brdf_value, delta_lobe),
+            Material::ThinFilmMaterial         ( ref material ) => material.sample_with_frame(random, input, normal, tangent, bitangent, pdf, brdf_value, delta_lobe),
+            Material::RoughMetalMaterial       ( ref material ) => material.sample_with_frame(random, input, normal, tangent, bitangent, pdf, brdf_value, delta_lobe),
+            Material::ConductorMaterial        ( ref material ) => material.sample_with_frame(random, input, normal, tangent, bitangent, pdf, brdf_value, delta_lobe),
+            Material::MixMaterial              ( ref material ) => material.sample_with_frame(random, input, normal, tangent, bitangent, pdf, brdf_value, delta_lobe),
+            Material::ScaledMaterial           ( ref material ) => material.sample_with_frame(random, input, normal, tangent, bitangent, pdf, brdf_value, delta_lobe),
+            Material::CutoutMaterial           ( ref material ) => material.sample_with_frame(random, input, normal, tangent, bitangent, pdf, brdf_value, delta_lobe),
+            Material::Lightsource              ( ref material ) => material.sample_with_frame(random, input, normal, tangent, bitangent, pdf, brdf_value, delta_lobe),
+            Material::EmissiveTextureMaterial  ( ref material ) => material.sample_with_frame(random, input, normal, tangent, bitangent, pdf, brdf_value, delta_lobe),
         }
     }
 
-    fn sample(&self, random: &mut Random, input: &Vec, normal: &Vec,
+    fn sample_with_outer_ior(
+        &self, random: &mut dyn Sampler, input: &Vec, normal: &Vec, outer_ior: f64,
         pdf: &mut f64, brdf_value: &mut Color) -> Vec {
-        match self {
-            &Material::LambertianMaterialSimple ( ref material ) => { material.sample(random, input, normal, pdf, brdf_value) },
-            &Material::LambertianMaterial       ( ref material ) => { material.sample(random, input, normal, pdf, brdf_value) },
-            &Material::PhongMaterial            ( ref material ) => { material.sample(random, input, normal, pdf, brdf_value) },
-            &Material::GlassMaterial            ( ref material ) => { material.sample(random, input, normal, pdf, brdf_value) },
-            &Material::Lightsource              ( ref material ) => { material.sample(random, input, normal, pdf, brdf_value) },
+        debug_assert_unit(input, "input");
+        debug_assert_unit(normal, "normal");
+        match *self {
+            Material::LambertianMaterialSimple ( ref material ) => material.sample_with_outer_ior(random, input, normal, outer_ior, pdf, brdf_value),
+            Material::LambertianMaterial       ( ref material ) => material.sample_with_outer_ior(random, input, normal, outer_ior, pdf, brdf_value),
+            Material::PhongMaterial            ( ref material ) => material.sample_with_outer_ior(random, input, normal, outer_ior, pdf, brdf_value),
+            Material::BlinnMaterial            ( ref material ) => material.sample_with_outer_ior(random, input, normal, outer_ior, pdf, brdf_value),
+            Material::PlasticMaterial          ( ref material ) => material.sample_with_outer_ior(random, input, normal, outer_ior, pdf, brdf_value),
+            Material::GlassMaterial            ( ref material ) => material.sample_with_outer_ior(random, input, normal, outer_ior, pdf, brdf_value),
+            Material::ThinFilmMaterial         ( ref material ) => material.sample_with_outer_ior(random, input, normal, outer_ior, pdf, brdf_value),
+            Material::RoughMetalMaterial       ( ref material ) => material.sample_with_outer_ior(random, input, normal, outer_ior, pdf, brdf_value),
+            Material::ConductorMaterial        ( ref material ) => material.sample_with_outer_ior(random, input, normal, outer_ior, pdf, brdf_value),
+            Material::MixMaterial              ( ref material ) => material.sample_with_outer_ior(random, input, normal, outer_ior, pdf, brdf_value),
+            Material::ScaledMaterial           ( ref material ) => material.sample_with_outer_ior(random, input, normal, outer_ior, pdf, brdf_value),
+            Material::CutoutMaterial           ( ref material ) => material.sample_with_outer_ior(random, input, normal, outer_ior, pdf, brdf_value),
+            Material::Lightsource              ( ref material ) => material.sample_with_outer_ior(random, input, normal, outer_ior, pdf, brdf_value),
+            Material::EmissiveTextureMaterial  ( ref material ) => material.sample_with_outer_ior(random, input, normal, outer_ior, pdf, brdf_value),
+        }
+    }
+
+    fn constant_brdf(&self) -> Option<Color> {
+        match *self {
+            Material::LambertianMaterialSimple ( ref material ) => material.constant_brdf(),
+            Material::LambertianMaterial       ( ref material ) => material.constant_brdf(),
+            Material::PhongMaterial            ( ref material ) => material.constant_brdf(),
+            Material::BlinnMaterial            ( ref material ) => material.constant_brdf(),
+            Material::PlasticMaterial          ( ref material ) => material.constant_brdf(),
+            Material::GlassMaterial            ( ref material ) => material.constant_brdf(),
+            Material::ThinFilmMaterial         ( ref material ) => material.constant_brdf(),
+            Material::RoughMetalMaterial       ( ref material ) => material.constant_brdf(),
+            Material::ConductorMaterial        ( ref material ) => material.constant_brdf(),
+            Material::MixMaterial              ( ref material ) => material.constant_brdf(),
+            Material::ScaledMaterial           ( ref material ) => material.constant_brdf(),
+            Material::CutoutMaterial           ( ref material ) => material.constant_brdf(),
+            Material::Lightsource              ( ref material ) => material.constant_brdf(),
+            Material::EmissiveTextureMaterial  ( ref material ) => material.constant_brdf(),
+        }
+    }
+
+    fn dielectric_ior(&self) -> Option<f64> {
+        match *self {
+            Material::LambertianMaterialSimple ( ref material ) => material.dielectric_ior(),
+            Material::LambertianMaterial       ( ref material ) => material.dielectric_ior(),
+            Material::PhongMaterial            ( ref material ) => material.dielectric_ior(),
+            Material::BlinnMaterial            ( ref material ) => material.dielectric_ior(),
+            Material::PlasticMaterial          ( ref material ) => material.dielectric_ior(),
+            Material::GlassMaterial            ( ref material ) => material.dielectric_ior(),
+            Material::ThinFilmMaterial         ( ref material ) => material.dielectric_ior(),
+            Material::RoughMetalMaterial       ( ref material ) => material.dielectric_ior(),
+            Material::ConductorMaterial        ( ref material ) => material.dielectric_ior(),
+            Material::MixMaterial              ( ref material ) => material.dielectric_ior(),
+            Material::ScaledMaterial           ( ref material ) => material.dielectric_ior(),
+            Material::CutoutMaterial           ( ref material ) => material.dielectric_ior(),
+            Material::Lightsource              ( ref material ) => material.dielectric_ior(),
+            Material::EmissiveTextureMaterial  ( ref material ) => material.dielectric_ior(),
+        }
+    }
+
+    fn sample_bsdf(&self, random: &mut dyn Sampler, input: &Vec, normal: &Vec) -> BsdfSample {
+        debug_assert_unit(input, "input");
+        debug_assert_unit(normal, "normal");
+        match *self {
+            Material::LambertianMaterialSimple ( ref material ) => material.sample_bsdf(random, input, normal),
+            Material::LambertianMaterial       ( ref material ) => material.sample_bsdf(random, input, normal),
+            Material::PhongMaterial            ( ref material ) => material.sample_bsdf(random, input, normal),
+            Material::BlinnMaterial            ( ref material ) => material.sample_bsdf(random, input, normal),
+            Material::PlasticMaterial          ( ref material ) => material.sample_bsdf(random, input, normal),
+            Material::GlassMaterial            ( ref material ) => material.sample_bsdf(random, input, normal),
+            Material::ThinFilmMaterial         ( ref material ) => material.sample_bsdf(random, input, normal),
+            Material::RoughMetalMaterial       ( ref material ) => material.sample_bsdf(random, input, normal),
+            Material::ConductorMaterial        ( ref material ) => material.sample_bsdf(random, input, normal),
+            Material::MixMaterial              ( ref material ) => material.sample_bsdf(random, input, normal),
+            Material::ScaledMaterial           ( ref material ) => material.sample_bsdf(random, input, normal),
+            Material::CutoutMaterial           ( ref material ) => material.sample_bsdf(random, input, normal),
+            Material::Lightsource              ( ref material ) => material.sample_bsdf(random, input, normal),
+            Material::EmissiveTextureMaterial  ( ref material ) => material.sample_bsdf(random, input, normal),
+        }
+    }
+
+    fn eval_pdf(&self, input: &Vec, normal: &Vec, output: &Vec) -> f64 {
+        debug_assert_unit(input, "input");
+        debug_assert_unit(normal, "normal");
+        debug_assert_unit(output, "output");
+        match *self {
+            Material::LambertianMaterialSimple ( ref material ) => material.eval_pdf(input, normal, output),
+            Material::LambertianMaterial       ( ref material ) => material.eval_pdf(input, normal, output),
+            Material::PhongMaterial            ( ref material ) => material.eval_pdf(input, normal, output),
+            Material::BlinnMaterial            ( ref material ) => material.eval_pdf(input, normal, output),
+            Material::PlasticMaterial          ( ref material ) => material.eval_pdf(input, normal, output),
+            Material::GlassMaterial            ( ref material ) => material.eval_pdf(input, normal, output),
+            Material::ThinFilmMaterial         ( ref material ) => material.eval_pdf(input, normal, output),
+            Material::RoughMetalMaterial       ( ref material ) => material.eval_pdf(input, normal, output),
+            Material::ConductorMaterial        ( ref material ) => material.eval_pdf(input, normal, output),
+            Material::MixMaterial              ( ref material ) => material.eval_pdf(input, normal, output),
+            Material::ScaledMaterial           ( ref material ) => material.eval_pdf(input, normal, output),
+            Material::CutoutMaterial           ( ref material ) => material.eval_pdf(input, normal, output),
+            Material::Lightsource              ( ref material ) => material.eval_pdf(input, normal, output),
+            Material::EmissiveTextureMaterial  ( ref material ) => material.eval_pdf(input, normal, output),
+        }
+    }
+
+    fn eval_pdf_with_frame(&self, input: &Vec, normal: &Vec, tangent: &Vec, bitangent: &Vec, output: &Vec) -> f64 {
+        debug_assert_unit(input, "input");
+        debug_assert_unit(normal, "normal");
+        debug_assert_unit(output, "output");
+        match *self {
+            Material::LambertianMaterialSimple ( ref material ) => material.eval_pdf_with_frame(input, normal, tangent, bitangent, output),
+            Material::LambertianMaterial       ( ref material ) => material.eval_pdf_with_frame(input, normal, tangent, bitangent, output),
+            Material::PhongMaterial            ( ref material ) => material.eval_pdf_with_frame(input, normal, tangent, bitangent, output),
+            Material::BlinnMaterial            ( ref material ) => material.eval_pdf_with_frame(input, normal, tangent, bitangent, output),
+            Material::PlasticMaterial          ( ref material ) => material.eval_pdf_with_frame(input, normal, tangent, bitangent, output),
+            Material::GlassMaterial            ( ref material ) => material.eval_pdf_with_frame(input, normal, tangent, bitangent, output),
+            Material::ThinFilmMaterial         ( ref material ) => material.eval_pdf_with_frame(input, normal, tangent, bitangent, output),
+            Material::RoughMetalMaterial       ( ref material ) => material.eval_pdf_with_frame(input, normal, tangent, bitangent, output),
+            Material::ConductorMaterial        ( ref material ) => material.eval_pdf_with_frame(input, normal, tangent, bitangent, output),
+            Material::MixMaterial              ( ref material ) => material.eval_pdf_with_frame(input, normal, tangent, bitangent, output),
+            Material::ScaledMaterial           ( ref material ) => material.eval_pdf_with_frame(input, normal, tangent, bitangent, output),
+            Material::CutoutMaterial           ( ref material ) => material.eval_pdf_with_frame(input, normal, tangent, bitangent, output),
+            Material::Lightsource              ( ref material ) => material.eval_pdf_with_frame(input, normal, tangent, bitangent, output),
+            Material::EmissiveTextureMaterial  ( ref material ) => material.eval_pdf_with_frame(input, normal, tangent, bitangent, output),
+        }
+    }
+
+    fn emitted(&self, input: &Vec, normal: &Vec) -> Color {
+        debug_assert_unit(input, "input");
+        debug_assert_unit(normal, "normal");
+        match *self {
+            Material::LambertianMaterialSimple ( ref material ) => material.emitted(input, normal),
+            Material::LambertianMaterial       ( ref material ) => material.emitted(input, normal),
+            Material::PhongMaterial            ( ref material ) => material.emitted(input, normal),
+            Material::BlinnMaterial            ( ref material ) => material.emitted(input, normal),
+            Material::PlasticMaterial          ( ref material ) => material.emitted(input, normal),
+            Material::GlassMaterial            ( ref material ) => material.emitted(input, normal),
+            Material::ThinFilmMaterial         ( ref material ) => material.emitted(input, normal),
+            Material::RoughMetalMaterial       ( ref material ) => material.emitted(input, normal),
+            Material::ConductorMaterial        ( ref material ) => material.emitted(input, normal),
+            Material::MixMaterial              ( ref material ) => material.emitted(input, normal),
+            Material::ScaledMaterial           ( ref material ) => material.emitted(input, normal),
+            Material::CutoutMaterial           ( ref material ) => material.emitted(input, normal),
+            Material::Lightsource              ( ref material ) => material.emitted(input, normal),
+            Material::EmissiveTextureMaterial  ( ref material ) => material.emitted(input, normal),
+        }
+    }
+
+    fn emitted_at(&self, input: &Vec, normal: &Vec, uv: (f64, f64)) -> Color {
+        debug_assert_unit(input, "input");
+        debug_assert_unit(normal, "normal");
+        match *self {
+            Material::LambertianMaterialSimple ( ref material ) => material.emitted_at(input, normal, uv),
+            Material::LambertianMaterial       ( ref material ) => material.emitted_at(input, normal, uv),
+            Material::PhongMaterial            ( ref material ) => material.emitted_at(input, normal, uv),
+            Material::BlinnMaterial            ( ref material ) => material.emitted_at(input, normal, uv),
+            Material::PlasticMaterial          ( ref material ) => material.emitted_at(input, normal, uv),
+            Material::GlassMaterial            ( ref material ) => material.emitted_at(input, normal, uv),
+            Material::ThinFilmMaterial         ( ref material ) => material.emitted_at(input, normal, uv),
+            Material::RoughMetalMaterial       ( ref material ) => material.emitted_at(input, normal, uv),
+            Material::ConductorMaterial        ( ref material ) => material.emitted_at(input, normal, uv),
+            Material::MixMaterial              ( ref material ) => material.emitted_at(input, normal, uv),
+            Material::ScaledMaterial           ( ref material ) => material.emitted_at(input, normal, uv),
+            Material::CutoutMaterial           ( ref material ) => material.emitted_at(input, normal, uv),
+            Material::Lightsource              ( ref material ) => material.emitted_at(input, normal, uv),
+            Material::EmissiveTextureMaterial  ( ref material ) => material.emitted_at(input, normal, uv),
+        }
+    }
+
+    fn sample_emission_uv(&self, random: &mut dyn Sampler) -> Option<((f64, f64), f64)> {
+        match *self {
+            Material::LambertianMaterialSimple ( ref material ) => material.sample_emission_uv(random),
+            Material::LambertianMaterial       ( ref material ) => material.sample_emission_uv(random),
+            Material::PhongMaterial            ( ref material ) => material.sample_emission_uv(random),
+            Material::BlinnMaterial            ( ref material ) => material.sample_emission_uv(random),
+            Material::PlasticMaterial          ( ref material ) => material.sample_emission_uv(random),
+            Material::GlassMaterial            ( ref material ) => material.sample_emission_uv(random),
+            Material::ThinFilmMaterial         ( ref material ) => material.sample_emission_uv(random),
+            Material::RoughMetalMaterial       ( ref material ) => material.sample_emission_uv(random),
+            Material::ConductorMaterial        ( ref material ) => material.sample_emission_uv(random),
+            Material::MixMaterial              ( ref material ) => material.sample_emission_uv(random),
+            Material::ScaledMaterial           ( ref material ) => material.sample_emission_uv(random),
+            Material::CutoutMaterial           ( ref material ) => material.sample_emission_uv(random),
+            Material::Lightsource              ( ref material ) => material.sample_emission_uv(random),
+            Material::EmissiveTextureMaterial  ( ref material ) => material.sample_emission_uv(random),
+        }
+    }
+
+    fn emission_uv_pdf(&self, uv: (f64, f64)) -> Option<f64> {
+        match *self {
+            Material::LambertianMaterialSimple ( ref material ) => material.emission_uv_pdf(uv),
+            Material::LambertianMaterial       ( ref material ) => material.emission_uv_pdf(uv),
+            Material::PhongMaterial            ( ref material ) => material.emission_uv_pdf(uv),
+            Material::BlinnMaterial            ( ref material ) => material.emission_uv_pdf(uv),
+            Material::PlasticMaterial          ( ref material ) => material.emission_uv_pdf(uv),
+            Material::GlassMaterial            ( ref material ) => material.emission_uv_pdf(uv),
+            Material::ThinFilmMaterial         ( ref material ) => material.emission_uv_pdf(uv),
+            Material::RoughMetalMaterial       ( ref material ) => material.emission_uv_pdf(uv),
+            Material::ConductorMaterial        ( ref material ) => material.emission_uv_pdf(uv),
+            Material::MixMaterial              ( ref material ) => material.emission_uv_pdf(uv),
+            Material::ScaledMaterial           ( ref material ) => material.emission_uv_pdf(uv),
+            Material::CutoutMaterial           ( ref material ) => material.emission_uv_pdf(uv),
+            Material::Lightsource              ( ref material ) => material.emission_uv_pdf(uv),
+            Material::EmissiveTextureMaterial  ( ref material ) => material.emission_uv_pdf(uv),
+        }
+    }
+
+    fn is_delta(&self) -> bool {
+        match *self {
+            Material::LambertianMaterialSimple ( ref material ) => material.is_delta(),
+            Material::LambertianMaterial       ( ref material ) => material.is_delta(),
+            Material::PhongMaterial            ( ref material ) => material.is_delta(),
+            Material::BlinnMaterial            ( ref material ) => material.is_delta(),
+            Material::PlasticMaterial          ( ref material ) => material.is_delta(),
+            Material::GlassMaterial            ( ref material ) => material.is_delta(),
+            Material::ThinFilmMaterial         ( ref material ) => material.is_delta(),
+            Material::RoughMetalMaterial       ( ref material ) => material.is_delta(),
+            Material::ConductorMaterial        ( ref material ) => material.is_delta(),
+            Material::MixMaterial              ( ref material ) => material.is_delta(),
+            Material::ScaledMaterial           ( ref material ) => material.is_delta(),
+            Material::CutoutMaterial           ( ref material ) => material.is_delta(),
+            Material::Lightsource              ( ref material ) => material.is_delta(),
+            Material::EmissiveTextureMaterial  ( ref material ) => material.is_delta(),
+        }
+    }
+
+    fn visible_to_camera(&self) -> bool {
+        match *self {
+            Material::LambertianMaterialSimple ( ref material ) => material.visible_to_camera(),
+            Material::LambertianMaterial       ( ref material ) => material.visible_to_camera(),
+            Material::PhongMaterial            ( ref material ) => material.visible_to_camera(),
+            Material::BlinnMaterial            ( ref material ) => material.visible_to_camera(),
+            Material::PlasticMaterial          ( ref material ) => material.visible_to_camera(),
+            Material::GlassMaterial            ( ref material ) => material.visible_to_camera(),
+            Material::ThinFilmMaterial         ( ref material ) => material.visible_to_camera(),
+            Material::RoughMetalMaterial       ( ref material ) => material.visible_to_camera(),
+            Material::ConductorMaterial        ( ref material ) => material.visible_to_camera(),
+            Material::MixMaterial              ( ref material ) => material.visible_to_camera(),
+            Material::ScaledMaterial           ( ref material ) => material.visible_to_camera(),
+            Material::CutoutMaterial           ( ref material ) => material.visible_to_camera(),
+            Material::Lightsource              ( ref material ) => material.visible_to_camera(),
+            Material::EmissiveTextureMaterial  ( ref material ) => material.visible_to_camera(),
+        }
+    }
+
+    fn is_cutout(&self, uv: (f64, f64)) -> bool {
+        match *self {
+            Material::LambertianMaterialSimple ( ref material ) => material.is_cutout(uv),
+            Material::LambertianMaterial       ( ref material ) => material.is_cutout(uv),
+            Material::PhongMaterial            ( ref material ) => material.is_cutout(uv),
+            Material::BlinnMaterial            ( ref material ) => material.is_cutout(uv),
+            Material::PlasticMaterial          ( ref material ) => material.is_cutout(uv),
+            Material::GlassMaterial            ( ref material ) => material.is_cutout(uv),
+            Material::ThinFilmMaterial         ( ref material ) => material.is_cutout(uv),
+            Material::RoughMetalMaterial       ( ref material ) => material.is_cutout(uv),
+            Material::ConductorMaterial        ( ref material ) => material.is_cutout(uv),
+            Material::MixMaterial              ( ref material ) => material.is_cutout(uv),
+            Material::ScaledMaterial           ( ref material ) => material.is_cutout(uv),
+            Material::CutoutMaterial           ( ref material ) => material.is_cutout(uv),
+            Material::Lightsource              ( ref material ) => material.is_cutout(uv),
+            Material::EmissiveTextureMaterial  ( ref material ) => material.is_cutout(uv),
+        }
+    }
+
+    fn has_cutout(&self) -> bool {
+        match *self {
+            Material::LambertianMaterialSimple ( ref material ) => material.has_cutout(),
+            Material::LambertianMaterial       ( ref material ) => material.has_cutout(),
+            Material::PhongMaterial            ( ref material ) => material.has_cutout(),
+            Material::BlinnMaterial            ( ref material ) => material.has_cutout(),
+            Material::PlasticMaterial          ( ref material ) => material.has_cutout(),
+            Material::GlassMaterial            ( ref material ) => material.has_cutout(),
+            Material::ThinFilmMaterial         ( ref material ) => material.has_cutout(),
+            Material::RoughMetalMaterial       ( ref material ) => material.has_cutout(),
+            Material::ConductorMaterial        ( ref material ) => material.has_cutout(),
+            Material::MixMaterial              ( ref material ) => material.has_cutout(),
+            Material::ScaledMaterial           ( ref material ) => material.has_cutout(),
+            Material::CutoutMaterial           ( ref material ) => material.has_cutout(),
+            Material::Lightsource              ( ref material ) => material.has_cutout(),
+            Material::EmissiveTextureMaterial  ( ref material ) => material.has_cutout(),
+        }
+    }
+
+    fn hemispherical_reflectance(&self, input: &Vec, normal: &Vec, random: &mut dyn Sampler, samples: usize) -> Color {
+        match *self {
+            Material::LambertianMaterialSimple ( ref material ) => material.hemispherical_reflectance(input, normal, random, samples),
+            Material::LambertianMaterial       ( ref material ) => material.hemispherical_reflectance(input, normal, random, samples),
+            Material::PhongMaterial            ( ref material ) => material.hemispherical_reflectance(input, normal, random, samples),
+            Material::BlinnMaterial            ( ref material ) => material.hemispherical_reflectance(input, normal, random, samples),
+            Material::PlasticMaterial          ( ref material ) => material.hemispherical_reflectance(input, normal, random, samples),
+            Material::GlassMaterial            ( ref material ) => material.hemispherical_reflectance(input, normal, random, samples),
+            Material::ThinFilmMaterial         ( ref material ) => material.hemispherical_reflectance(input, normal, random, samples),
+            Material::RoughMetalMaterial       ( ref material ) => material.hemispherical_reflectance(input, normal, random, samples),
+            Material::ConductorMaterial        ( ref material ) => material.hemispherical_reflectance(input, normal, random, samples),
+            Material::MixMaterial              ( ref material ) => material.hemispherical_reflectance(input, normal, random, samples),
+            Material::ScaledMaterial           ( ref material ) => material.hemispherical_reflectance(input, normal, random, samples),
+            Material::CutoutMaterial           ( ref material ) => material.hemispherical_reflectance(input, normal, random, samples),
+            Material::Lightsource              ( ref material ) => material.hemispherical_reflectance(input, normal, random, samples),
+            Material::EmissiveTextureMaterial  ( ref material ) => material.hemispherical_reflectance(input, normal, random, samples),
+        }
+    }
+
+    fn albedo_hint(&self) -> Color {
+        match *self {
+            Material::LambertianMaterialSimple ( ref material ) => material.albedo_hint(),
+            Material::LambertianMaterial       ( ref material ) => material.albedo_hint(),
+            Material::PhongMaterial            ( ref material ) => material.albedo_hint(),
+            Material::BlinnMaterial            ( ref material ) => material.albedo_hint(),
+            Material::PlasticMaterial          ( ref material ) => material.albedo_hint(),
+            Material::GlassMaterial            ( ref material ) => material.albedo_hint(),
+            Material::ThinFilmMaterial         ( ref material ) => material.albedo_hint(),
+            Material::RoughMetalMaterial       ( ref material ) => material.albedo_hint(),
+            Material::ConductorMaterial        ( ref material ) => material.albedo_hint(),
+            Material::MixMaterial              ( ref material ) => material.albedo_hint(),
+            Material::ScaledMaterial           ( ref material ) => material.albedo_hint(),
+            Material::CutoutMaterial           ( ref material ) => material.albedo_hint(),
+            Material::Lightsource              ( ref material ) => material.albedo_hint(),
+            Material::EmissiveTextureMaterial  ( ref material ) => material.albedo_hint(),
         }
     }
 }
@@ -110,26 +838,49 @@ impl MaterialTrait for LambertianMaterialSimple {
     
     // Lambertian BRDFはρ/πになる（ρは反射率）
     fn eval(&self, input: &Vec, normal: &Vec, output: &Vec) -> Color  {
-        &self.reflectance / K_PI
+        &self.reflectance * K_INV_PI
+    }
+
+    fn constant_brdf(&self) -> Option<Color> {
+        Some(&self.reflectance * K_INV_PI)
     }
 
-    // 単純に半球一様サンプリングする。
     fn sample(
-        &self, random: &mut Random, input: &Vec, normal: &Vec,
+        &self, random: &mut dyn Sampler, input: &Vec, normal: &Vec,
         pdf: &mut f64, brdf_value: &mut Color) -> Vec {
 
         let mut binormal: Vec = Vec { x: 0.0, y: 0.0, z: 0.0 };
         let mut tangent: Vec = Vec { x: 0.0, y: 0.0, z: 0.0 };
-        let now_normal: &Vec = normal;
+        create_ortho_normal_basis(normal, &mut tangent, &mut binormal);
+
+        self.sample_with_frame(random, input, normal, &tangent, &binormal, pdf, brdf_value, &mut false)
+    }
+
+    // 単純に半球一様サンプリングする。
+    fn sample_with_frame(
+        &self, random: &mut dyn Sampler, input: &Vec, normal: &Vec, tangent: &Vec, bitangent: &Vec,
+        pdf: &mut f64, brdf_value: &mut Color, delta_lobe: &mut bool) -> Vec {
+        *delta_lobe = false;
 
-        create_ortho_normal_basis(now_normal, &mut tangent, &mut binormal);
-        let dir: Vec = Sampling::uniform_hemisphere_surface(random, now_normal, &mut tangent, &mut binormal);
+        let dir: Vec = Sampling::uniform_hemisphere_surface(random, normal, tangent, bitangent);
 
-        *pdf = 1.0 / (2.0 * K_PI);
+        *pdf = K_INV_2PI;
         *brdf_value = self.eval(input, normal, &dir);
 
         dir
     }
+
+    fn eval_pdf(&self, input: &Vec, normal: &Vec, output: &Vec) -> f64 {
+        if Vec::dot(normal, output) <= 0.0 {
+            return 0.0
+        }
+        K_INV_2PI
+    }
+
+    // Lambertian BRDFでは見る方向によらず反射率そのものになる。
+    fn hemispherical_reflectance(&self, input: &Vec, normal: &Vec, random: &mut dyn Sampler, samples: usize) -> Color {
+        self.reflectance.clone()
+    }
 }
 
 impl LambertianMaterial {
@@ -152,26 +903,50 @@ impl MaterialTrait for LambertianMaterial {
     
     // Lambertian BRDFはρ/πになる（ρは反射率）
     fn eval(&self, input: &Vec, normal: &Vec, output: &Vec) -> Color  {
-        &self.reflectance / K_PI
+        &self.reflectance * K_INV_PI
+    }
+
+    fn constant_brdf(&self) -> Option<Color> {
+        Some(&self.reflectance * K_INV_PI)
     }
 
-    // pdfとしてcosΘ/piを使用してインポータンスサンプリングする。
     fn sample(
-        &self, random: &mut Random, input: &Vec, normal: &Vec,
+        &self, random: &mut dyn Sampler, input: &Vec, normal: &Vec,
         pdf: &mut f64, brdf_value: &mut Color) -> Vec {
 
         let mut binormal: Vec = Vec { x: 0.0, y: 0.0, z: 0.0 };
         let mut tangent: Vec = Vec { x: 0.0, y: 0.0, z: 0.0 };
-        let now_normal: &Vec = normal;
+        create_ortho_normal_basis(normal, &mut tangent, &mut binormal);
+
+        self.sample_with_frame(random, input, normal, &tangent, &binormal, pdf, brdf_value, &mut false)
+    }
 
-        create_ortho_normal_basis(now_normal, &mut tangent, &mut binormal);
-        let dir: Vec = Sampling::cosine_weighted_hemisphere_surface(random, now_normal, &mut tangent, &mut binormal);
+    // pdfとしてcosΘ/piを使用してインポータンスサンプリングする。
+    fn sample_with_frame(
+        &self, random: &mut dyn Sampler, input: &Vec, normal: &Vec, tangent: &Vec, bitangent: &Vec,
+        pdf: &mut f64, brdf_value: &mut Color, delta_lobe: &mut bool) -> Vec {
+        *delta_lobe = false;
+
+        let dir: Vec = Sampling::cosine_weighted_hemisphere_surface(random, normal, tangent, bitangent);
 
-        *pdf = Vec::dot(normal, &dir) / K_PI;
+        *pdf = Vec::dot(normal, &dir) * K_INV_PI;
         *brdf_value = self.eval(input, normal, &dir);
 
         dir
     }
+
+    fn eval_pdf(&self, input: &Vec, normal: &Vec, output: &Vec) -> f64 {
+        let cos_theta: f64 = Vec::dot(normal, output);
+        if cos_theta <= 0.0 {
+            return 0.0
+        }
+        cos_theta * K_INV_PI
+    }
+
+    // Lambertian BRDFでは見る方向によらず反射率そのものになる。
+    fn hemispherical_reflectance(&self, input: &Vec, normal: &Vec, random: &mut dyn Sampler, samples: usize) -> Color {
+        self.reflectance.clone()
+    }
 }
 
 impl PhongMaterial {
@@ -180,10 +955,30 @@ impl PhongMaterial {
             emission: Color { x: 0.0, y: 0.0, z: 0.0 },
             reflectance: reflectance,
             n: n,
+            clamped: false,
+            visible_fraction: std::vec::Vec::new(),
         }
     }
+
+    // ローブを見える半球の上で正規化し直すようにする。
+    // 地面より上に出ている割合は、反射方向の角度ごとに数値積分して表にしておく。
+    pub fn clamped(mut self) -> PhongMaterial {
+        self.clamped = true;
+        self.visible_fraction = (0..VISIBLE_FRACTION_TABLE_SIZE)
+            .map(|i| self.integrate_visible_fraction(i as f64 / (VISIBLE_FRACTION_TABLE_SIZE - 1) as f64))
+            .collect();
+        self
+    }
+
+    pub fn is_clamped(&self) -> bool {
+        self.clamped
+    }
 }
 
+// PhongMaterial::clampedで作る表の大きさと、表の一項目あたりの数値積分の分割数（各次元）。
+const VISIBLE_FRACTION_TABLE_SIZE: usize = 64;
+const VISIBLE_FRACTION_RESOLUTION: usize = 64;
+
 impl MaterialTrait for PhongMaterial {
     fn emission(&self) -> &Color {
         &self.emission
@@ -206,50 +1001,120 @@ impl MaterialTrait for PhongMaterial {
             cosa = 0.0;
         }
 
-        &self.reflectance * (&self.n+ 2.0) / (2.0 * K_PI) * cosa.powf(self.n)
+        let value: Color = &self.reflectance * ((&self.n+ 2.0) * K_INV_2PI) * cosa.powf(self.n);
+        if self.clamped {
+            // 反射方向と法線のなす角は入射方向と法線のなす角に等しい。
+            return value / self.lookup_visible_fraction(Vec::dot(input, normal).abs())
+        }
+        value
     }
 
     // BRDF形状をpdfとして使ってインポータンスサンプリングする。
     fn sample(
-        &self, random: &mut Random, input: &Vec, normal: &Vec,
+        &self, random: &mut dyn Sampler, input: &Vec, normal: &Vec,
         pdf: &mut f64, brdf_value: &mut Color) -> Vec {
 
-        let dir: Vec;
         let reflection_dir: Vec = Vec::reflect(input, normal);
-        let mut binormal: Vec = Vec { x: 0.0, y: 0.0, z: 0.0 };
-        let mut tangent: Vec = Vec { x: 0.0, y: 0.0, z: 0.0 };
-        create_ortho_normal_basis(&reflection_dir, &mut tangent, &mut binormal);
+        let basis: OrthoBasis = OrthoBasis::from_normal(&reflection_dir);
 
-        let u1: f64 = random.next01();
-        let u2: f64 = random.next01();
+        let (u1, u2) = random.next_2d();
 
-        let phi: f64 = &u1 * 2.0 * &K_PI;
+        let phi: f64 = u1 * K_2PI;
         let theta = &u2.powf(1.0 / (&self.n + 1.0)).acos();
 
-        dir = tangent * theta.sin() * phi.cos() + &reflection_dir * theta.cos() + binormal * theta.sin() * phi.sin();
+        let sin_theta: f64 = theta.sin();
+        let dir: Vec = basis.to_world(&Vec { x: sin_theta * phi.cos(), y: sin_theta * phi.sin(), z: theta.cos() });
 
         let mut cosa: f64 = Vec::dot(&reflection_dir, &dir);
         if &cosa < &0.0 {
             cosa = 0.0;
         }
-        *pdf = (&self.n + 1.0) / (2.0 * K_PI) * &cosa.powf(self.n);
+        *pdf = (&self.n + 1.0) * K_INV_2PI * cosa.powf(self.n);
         *brdf_value = self.eval(input, normal, &dir);
 
         dir
     }
+
+    // sampleと同じく反射方向を中心としたcosⁿの分布。
+    // 地面より下の方向はsampleの結果を捨てることになる（evalが0）ので、pdfも0とする。
+    fn eval_pdf(&self, input: &Vec, normal: &Vec, output: &Vec) -> f64 {
+        if Vec::dot(normal, output) < 0.0 {
+            return 0.0
+        }
+        self.lobe_pdf(input, normal, output)
+    }
+
+    fn sample_bsdf(&self, random: &mut dyn Sampler, input: &Vec, normal: &Vec) -> BsdfSample {
+        let mut pdf: f64 = -1.0;
+        let mut brdf_value: Color = Color { x: 0.0, y: 0.0, z: 0.0 };
+        let direction: Vec = self.sample(random, input, normal, &mut pdf, &mut brdf_value);
+
+        BsdfSample { direction, pdf, brdf_value, lobe: LobeType::Glossy }
+    }
 }
 
-impl GlassMaterial {
-    pub fn new(reflectance: Color, ior: f64) -> GlassMaterial {
-        GlassMaterial {
+impl PhongMaterial {
+    // 反射方向と法線のなす角の余弦がcos_reflectionのとき、sampleで選ぶ方向が地面より上になる確率。
+    // sampleと同じ変数変換で、単位正方形を格子に切った点の方向のうち地面より上のものの割合を求める。
+    fn integrate_visible_fraction(&self, cos_reflection: f64) -> f64 {
+        let sin_reflection: f64 = (1.0 - cos_reflection * cos_reflection).max(0.0).sqrt();
+        let normal: Vec = Vec { x: 0.0, y: 0.0, z: 1.0 };
+        let reflection_dir: Vec = Vec { x: sin_reflection, y: 0.0, z: cos_reflection };
+        let mut tangent: Vec = Vec { x: 0.0, y: 0.0, z: 0.0 };
+        let mut binormal: Vec = Vec { x: 0.0, y: 0.0, z: 0.0 };
+        create_ortho_normal_basis(&reflection_dir, &mut tangent, &mut binormal);
+
+        let mut visible: usize = 0;
+        for i in 0..VISIBLE_FRACTION_RESOLUTION {
+            let cos_theta: f64 = ((i as f64 + 0.5) / VISIBLE_FRACTION_RESOLUTION as f64).powf(1.0 / (self.n + 1.0));
+            let sin_theta: f64 = (1.0 - cos_theta * cos_theta).max(0.0).sqrt();
+            for j in 0..VISIBLE_FRACTION_RESOLUTION {
+                let phi: f64 = (j as f64 + 0.5) / VISIBLE_FRACTION_RESOLUTION as f64 * K_2PI;
+                let dir: Vec = Vec::mul_add(
+                    &tangent, sin_theta * phi.cos(),
+                    &Vec::mul_add(&binormal, sin_theta * phi.sin(), &(&reflection_dir * cos_theta)));
+                if Vec::dot(&normal, &dir) > 0.0 {
+                    visible += 1;
+                }
+            }
+        }
+        visible as f64 / (VISIBLE_FRACTION_RESOLUTION * VISIBLE_FRACTION_RESOLUTION) as f64
+    }
+
+    // 表を線形補間して、地面より上に出ている割合を引く。
+    fn lookup_visible_fraction(&self, cos_reflection: f64) -> f64 {
+        let x: f64 = cos_reflection.clamp(0.0, 1.0) * (VISIBLE_FRACTION_TABLE_SIZE - 1) as f64;
+        let i: usize = std::cmp::min(x.floor() as usize, VISIBLE_FRACTION_TABLE_SIZE - 2);
+        let t: f64 = x - i as f64;
+        self.visible_fraction[i] * (1.0 - t) + self.visible_fraction[i + 1] * t
+    }
+
+    // sampleでoutの方向を選ぶpdf。eval_pdfと違い、地面より下の方向でも0にしない。
+    fn lobe_pdf(&self, input: &Vec, normal: &Vec, output: &Vec) -> f64 {
+        let reflection_dir: Vec = Vec::reflect(input, normal);
+        let cosa: f64 = Vec::dot(&reflection_dir, output).max(0.0);
+        (self.n + 1.0) * K_INV_2PI * cosa.powf(self.n)
+    }
+}
+
+impl BlinnMaterial {
+    pub fn new(reflectance: Color, n: f64) -> BlinnMaterial {
+        BlinnMaterial {
             emission: Color { x: 0.0, y: 0.0, z: 0.0 },
-            reflectance: reflectance,
-            ior: ior,
+            reflectance,
+            n,
+            // 垂直に入射したときの反射率がちょうど1になる係数 (n + 2)(n + 4) / 8π(2^(-n/2) + n)。
+            normalization: (n + 2.0) * (n + 4.0) * K_INV_PI / (8.0 * (2.0f64.powf(-n / 2.0) + n)),
         }
     }
+
+    // ハーフベクトルhを選ぶpdf（hの立体角あたり）。法線を中心としたcosⁿの分布。
+    fn half_vector_pdf(&self, cos_h: f64) -> f64 {
+        (self.n + 1.0) * K_INV_2PI * cos_h.max(0.0).powf(self.n)
+    }
 }
 
-impl MaterialTrait for GlassMaterial {
+impl MaterialTrait for BlinnMaterial {
     fn emission(&self) -> &Color {
         &self.emission
     }
@@ -257,97 +1122,1443 @@ impl MaterialTrait for GlassMaterial {
     fn reflectance(&self) -> &Color {
         &self.reflectance
     }
-    
-    // 理想的なガラス面におけるBRDFはディラックのδ関数を使ってδ/cosΘとなる。
-    // δ関数を表現することは出来ないが、モンテカルロ積分においてはpdfにもδ関数が現れるため分母と分子で打ち消し合う。
-    // そこでcosΘと反射率だけ入れておく。
-    // in, outはカメラ側から追跡したときの入出方向なので、光の入射方向はoutになるため、cosθは法線とoutの内積になる。
-    // 反射率や透過率（Fr,Ft）は含まれていないことに注意！
-    fn eval(&self, input: &Vec, normal: &Vec, output: &Vec) -> Color  {
-        &self.reflectance * &DELTA / Vec::dot(normal, output)
+
+    // 正規化Blinn-Phong。
+    fn eval(&self, input: &Vec, normal: &Vec, output: &Vec) -> Color {
+        if Vec::dot(normal, output) < 0.0 {
+            // 次のレイの方向が地面より下の方向だったら0。
+            return Color { x: 0.0, y: 0.0, z: 0.0 }
+        }
+
+        let half: Vec = Vec::normalize(output - input);
+        let cos_h: f64 = Vec::dot(normal, &half).max(0.0);
+        &self.reflectance * self.normalization * cos_h.powf(self.n)
     }
 
     fn sample(
-        &self, random: &mut Random, input: &Vec, normal: &Vec,
+        &self, random: &mut dyn Sampler, input: &Vec, normal: &Vec,
         pdf: &mut f64, brdf_value: &mut Color) -> Vec {
-        let now_normal: Vec = match Vec::dot(normal, input) {
-             x => if x < 0.0 {
-                    normal.clone()
-                 } else {
-                     -normal
-                 }
-             }; // 交差位置の法線（物体からのレイの入出を考慮。
-        let into: bool = Vec::dot(normal, &now_normal) > 0.0; // レイがオブジェクトから出るのか、入るのか。
-        let n1: f64 = 1.0; // 真空の屈折率
-        let n2: &f64 = &self.ior; // オブジェクトの屈折率
-        let n: f64 = if into {
-                n1 / n2
-             } else {
-                n2 / n1
-             };
 
-        // Snellの法則を用いて屈折方向を計算する。
-        let dir_dot_normal: f64 = Vec::dot(input, &now_normal);
-        let cos2t_2: f64 = 1.0 - &n * &n * (1.0 - &dir_dot_normal * &dir_dot_normal);
-        
-        // 全反射
-        let reflection_dir: Vec = Vec::reflect(input, &now_normal);
-        if &cos2t_2 < &0.0 {
-            // pdfはディラックのδ関数なので実数値にはならないが、将来的にモンテカルロ積分において、
-            // 分母と分子の両方にδが表れるため結局打ち消し合うため、1でよい。あくまでδであること忘れないためにDELTAを入れておくが、実態は1。
-            *pdf = DELTA;
-            *brdf_value = self.eval(input, normal, &reflection_dir);
+        let basis: OrthoBasis = OrthoBasis::from_normal(normal);
 
-            return reflection_dir
-        }
+        self.sample_with_frame(random, input, normal, &basis.tangent, &basis.binormal, pdf, brdf_value, &mut false)
+    }
 
-        // 屈折の方向
-        let refraction_dir: Vec = input * &n - &now_normal * (&dir_dot_normal * &n + cos2t_2.sqrt());
-        
-        // Fresnelの式
-        let cost_1: f64 = Vec::dot(&-input, &now_normal);
-        let cost_2: f64 = cos2t_2.sqrt();
-        let r_parallel: f64 = (&n * &cost_1 - &cost_2) / (&n * &cost_1 + &cost_2);
-        let r_perpendicular: f64 = (&cost_1 - &n * &cost_2) / (&cost_1 + &n * &cost_2);
-        let fr: f64 = &0.5 * (&r_parallel * &r_parallel + &r_perpendicular * &r_perpendicular);
+    // ハーフベクトルを法線まわりのcosⁿの分布で選び、入射方向をそれで反射する。
+    // 出射方向の立体角あたりのpdfは、ハーフベクトルのpdfをヤコビアン 4|out・h| で割ったものになる。
+    fn sample_with_frame(
+        &self, random: &mut dyn Sampler, input: &Vec, normal: &Vec, tangent: &Vec, bitangent: &Vec,
+        pdf: &mut f64, brdf_value: &mut Color, delta_lobe: &mut bool) -> Vec {
+        *delta_lobe = false;
 
-        let factor: f64 = (if into {
-            n1 / n2
-        } else {
-            n2 / n1
-        }).powf(2.0); // レイの運ぶ放射輝度は屈折率の異なる物体間を移動するとき、屈折率の比の二乗の分だけ変化する。
-        let ft: f64 = (&1.0 - &fr) * &factor; // 屈折方向の割合。
-        
-        // ロシアンルーレットで屈折か反射かを決定する。
-        // ロシアンルーレットの確率は反射率ということしておく。
-        let probability: f64 = fr;
-        if &random.next01() < &probability { // 反射
-            // pdfはディラックのδ関数なので実数値にはならないが、将来的にモンテカルロ積分において、
-            // 分母と分子の両方にδが表れるため結局打ち消し合うため、1でよい。あくまでδであること忘れないためにDELTAを入れておくが、実態は1。
-            *pdf = DELTA * &probability;
-            *brdf_value = &fr * self.eval(input, normal, &reflection_dir);
+        let (u1, u2) = random.next_2d();
 
-            return reflection_dir;
-        } else { // 屈折
+        let phi: f64 = u1 * K_2PI;
+        let cos_theta: f64 = u2.powf(1.0 / (self.n + 1.0));
+        let sin_theta: f64 = (1.0 - cos_theta * cos_theta).max(0.0).sqrt();
+        let half: Vec = Vec::mul_add(
+            tangent, sin_theta * phi.cos(),
+            &Vec::mul_add(bitangent, sin_theta * phi.sin(), &(normal * cos_theta)));
+
+        let dir: Vec = Vec::reflect(input, &half);
+        let cos_out: f64 = Vec::dot(&dir, &half).abs();
+        *pdf = if cos_out > 0.0 { self.half_vector_pdf(cos_theta) / (4.0 * cos_out) } else { 0.0 };
+        *brdf_value = self.eval(input, normal, &dir);
+
+        dir
+    }
+
+    // 地面より下の方向はsampleの結果を捨てることになる（evalが0）ので、pdfも0とする。
+    fn eval_pdf(&self, input: &Vec, normal: &Vec, output: &Vec) -> f64 {
+        if Vec::dot(normal, output) < 0.0 {
+            return 0.0
+        }
+        let half: Vec = Vec::normalize(output - input);
+        let cos_out: f64 = Vec::dot(output, &half).abs();
+        if cos_out <= 0.0 {
+            return 0.0
+        }
+        self.half_vector_pdf(Vec::dot(normal, &half)) / (4.0 * cos_out)
+    }
+
+    fn sample_bsdf(&self, random: &mut dyn Sampler, input: &Vec, normal: &Vec) -> BsdfSample {
+        let mut pdf: f64 = -1.0;
+        let mut brdf_value: Color = Color { x: 0.0, y: 0.0, z: 0.0 };
+        let direction: Vec = self.sample(random, input, normal, &mut pdf, &mut brdf_value);
+
+        BsdfSample { direction, pdf, brdf_value, lobe: LobeType::Glossy }
+    }
+}
+
+impl PlasticMaterial {
+    // diffuseは下地の色、specularは光沢の強さ（[0, 1]）、nはPhongの指数。
+    pub fn new(diffuse: Color, specular: f64, n: f64) -> PlasticMaterial {
+        let specular: f64 = specular.clamp(0.0, 1.0);
+        PlasticMaterial {
+            emission: Color { x: 0.0, y: 0.0, z: 0.0 },
+            reflectance: diffuse.clone(),
+            diffuse: LambertianMaterial::new(diffuse),
+            glossy: PhongMaterial::new(Color { x: 1.0, y: 1.0, z: 1.0 }, n),
+            specular,
+            clearcoat: 0.0,
+            clearcoat_alpha: 1.0,
+        }
+    }
+
+    // 透明な塗膜を重ねる。strengthは塗膜の強さ（[0, 1]）、roughnessは塗膜の粗さ（[0, 1]、0で鏡面に近い）。
+    // 塗膜は屈折率1.5の誘電体として、Fresnel反射（Schlickの近似）をGGXのマイクロファセットBRDFで反射する。
+    // 塗膜で反射した分だけ、入射と出射のそれぞれで下地に届く光が減る。
+    // let paint = PlasticMaterial::new(red, 0.2, 50.0).with_clearcoat(1.0, 0.05);
+    pub fn with_clearcoat(mut self, strength: f64, roughness: f64) -> PlasticMaterial {
+        let roughness: f64 = roughness.clamp(0.0, 1.0);
+        self.clearcoat = strength.clamp(0.0, 1.0);
+        self.clearcoat_alpha = (roughness * roughness).max(CLEARCOAT_MIN_ALPHA);
+        self
+    }
+
+    // inの方向から来たときに(塗膜, 光沢)の側をサンプリングする確率。残りが下地の側になる。
+    // それぞれの項が反射する光の量（反射率）の見積もりの比にして、寄与の大きい側を多く選ぶ。
+    //   塗膜 ... clearcoat * Fresnel反射率。
+    //   下地 ... (1 - specular) * 反射率の輝度。Lambertian BRDFの反射率は入射角によらない。
+    //   光沢 ... specular * cosΘ。正規化Phongの反射率は鋭いローブほどcosΘ（θは入射角）に近づき、
+    //            斜めから見るとローブが地面の下に隠れて小さくなる。
+    //   下地と光沢は塗膜を透過した分（1 - 塗膜の反射率）だけにする。
+    // どの確率も0にはならない限り推定は偏らない。確率はinだけで決まるので、pdfも同じ確率で求める。
+    // 裏側から見たときは塗膜のBRDFが0で、見える法線の分布からも選べないので、塗膜の側は選ばない。
+    fn lobe_probabilities(&self, input: &Vec, normal: &Vec) -> (f64, f64) {
+        let cos_theta: f64 = Vec::dot(input, normal).abs();
+        let clearcoat_weight: f64 = if Vec::dot(input, normal) < 0.0 {
+            self.clearcoat * schlick_fresnel(CLEARCOAT_F0, cos_theta)
+        } else {
+            0.0
+        };
+        let base: f64 = 1.0 - clearcoat_weight;
+        let diffuse_weight: f64 = base * (1.0 - self.specular) * luminance(&self.reflectance);
+        let glossy_weight: f64 = base * self.specular * cos_theta;
+        let sum: f64 = clearcoat_weight + diffuse_weight + glossy_weight;
+        if sum <= 0.0 {
+            return (0.0, 0.0)
+        }
+        (clearcoat_weight / sum, glossy_weight / sum)
+    }
+
+    // sampleでoutの方向を選ぶpdf。光沢と塗膜の側は地面より下の方向も選びうるので、その分も含める。
+    fn sample_pdf(&self, input: &Vec, normal: &Vec, output: &Vec) -> f64 {
+        let (clearcoat_probability, glossy_probability) = self.lobe_probabilities(input, normal);
+        let mut pdf: f64 = self.diffuse.eval_pdf(input, normal, output) * (1.0 - clearcoat_probability - glossy_probability) +
+            self.glossy.lobe_pdf(input, normal, output) * glossy_probability;
+        if clearcoat_probability > 0.0 {
+            pdf += self.clearcoat_pdf(input, normal, output) * clearcoat_probability;
+        }
+        pdf
+    }
+
+    // 塗膜を透過して下地に届く割合。
+    fn clearcoat_transmission(&self, cos_theta: f64) -> f64 {
+        1.0 - self.clearcoat * schlick_fresnel(CLEARCOAT_F0, cos_theta)
+    }
+
+    // 塗膜のBRDF。clearcoat * D(h) F(v・h) G / (4 cosθi cosθo)。
+    // Bruce Walter, et al. Microfacet Models for Refraction through Rough Surfaces. EGSR 2007.
+    fn clearcoat_eval(&self, input: &Vec, normal: &Vec, output: &Vec) -> f64 {
+        let view: Vec = -input;
+        let cos_i: f64 = Vec::dot(&view, normal);
+        let cos_o: f64 = Vec::dot(output, normal);
+        if cos_i <= 0.0 || cos_o <= 0.0 {
+            return 0.0
+        }
+
+        let half: Vec = Vec::normalize(&view + output);
+        let d: f64 = ggx_d(Vec::dot(&half, normal), self.clearcoat_alpha);
+        let f: f64 = schlick_fresnel(CLEARCOAT_F0, Vec::dot(&view, &half));
+        let g: f64 = ggx_g1(cos_i, self.clearcoat_alpha) * ggx_g1(cos_o, self.clearcoat_alpha);
+        self.clearcoat * d * f * g / (4.0 * cos_i * cos_o)
+    }
+
+    // 見える法線の分布に従ってハーフベクトルを選んだときの、outの方向のpdf G1(v) D(h) / (4 cosθv)。
+    // 等方的なので基底によらず、法線との角度だけで求まる（Sampling::ggx_vndf_pdfを反射の方向にしたもの）。
+    fn clearcoat_pdf(&self, input: &Vec, normal: &Vec, output: &Vec) -> f64 {
+        let view: Vec = -input;
+        let cos_v: f64 = Vec::dot(&view, normal);
+        let half: Vec = Vec::normalize(&view + output);
+        if cos_v <= 0.0 || Vec::dot(&view, &half) <= 0.0 {
+            return 0.0
+        }
+        ggx_g1(cos_v, self.clearcoat_alpha) * ggx_d(Vec::dot(&half, normal), self.clearcoat_alpha) / (4.0 * cos_v)
+    }
+
+    // GGX分布の見える法線の分布からハーフベクトルを選び、inをそれで反射した方向を返す。
+    // lobe_probabilitiesにより、表側から見たときだけ呼ばれる。
+    fn sample_clearcoat(&self, random: &mut dyn Sampler, input: &Vec, normal: &Vec, tangent: &Vec, bitangent: &Vec) -> Vec {
+        let basis: OrthoBasis = OrthoBasis { tangent: tangent.clone(), binormal: bitangent.clone(), normal: normal.clone() };
+        let view: Vec = basis.to_local(&-input);
+        let half: Vec = Sampling::ggx_vndf(random, &view, self.clearcoat_alpha, self.clearcoat_alpha);
+        Vec::reflect(input, &basis.to_world(&half))
+    }
+
+    // 選ばれた方向dirについて、pdfとBRDFを全ての項から求め直す。
+    fn finish_sample(&self, input: &Vec, normal: &Vec, dir: Vec, pdf: &mut f64, brdf_value: &mut Color) -> Vec {
+        // 光沢や塗膜の側で地面より下の方向が選ばれたときはBRDFが0になる（PhongMaterialと同じ）。
+        *pdf = self.sample_pdf(input, normal, &dir);
+        *brdf_value = self.eval(input, normal, &dir);
+        dir
+    }
+}
+
+// 屈折率1.5の誘電体の垂直入射でのFresnel反射率 ((n - 1) / (n + 1))²。
+const CLEARCOAT_F0: f64 = 0.04;
+// αが0に近いとD(h)がδ関数に近づき、数値的に扱えなくなるので下限を設ける。
+const CLEARCOAT_MIN_ALPHA: f64 = 1e-3;
+
+// Schlickの近似によるFresnel反射率。
+// Christophe Schlick. An Inexpensive BRDF Model for Physically-based Rendering. 1994.
+fn schlick_fresnel(f0: f64, cos_theta: f64) -> f64 {
+    let m: f64 = (1.0 - cos_theta.clamp(0.0, 1.0)).powi(5);
+    f0 + (1.0 - f0) * m
+}
+
+// GGX（Trowbridge-Reitz）分布。cos_hはハーフベクトルと法線のなす角の余弦。
+fn ggx_d(cos_h: f64, alpha: f64) -> f64 {
+    if cos_h <= 0.0 {
+        return 0.0
+    }
+    let alpha2: f64 = alpha * alpha;
+    let t: f64 = cos_h * cos_h * (alpha2 - 1.0) + 1.0;
+    alpha2 * K_INV_PI / (t * t)
+}
+
+// GGXのSmithの遮蔽関数。cos_thetaは方向と法線のなす角の余弦。
+fn ggx_g1(cos_theta: f64, alpha: f64) -> f64 {
+    let cos2: f64 = cos_theta * cos_theta;
+    let tan2: f64 = (1.0 - cos2) / cos2;
+    2.0 / (1.0 + (1.0 + alpha * alpha * tan2).sqrt())
+}
+
+impl MaterialTrait for PlasticMaterial {
+    fn emission(&self) -> &Color {
+        &self.emission
+    }
+
+    fn reflectance(&self) -> &Color {
+        &self.reflectance
+    }
+
+    // 二つの項の和に、塗膜を透過する分を掛けて塗膜の項を足す。
+    fn eval(&self, input: &Vec, normal: &Vec, output: &Vec) -> Color {
+        if Vec::dot(normal, output) < 0.0 {
+            return Color { x: 0.0, y: 0.0, z: 0.0 }
+        }
+
+        let base: Color = self.diffuse.eval(input, normal, output) * (1.0 - self.specular) +
+            self.glossy.eval(input, normal, output) * self.specular;
+        if self.clearcoat <= 0.0 {
+            return base
+        }
+
+        let transmission: f64 =
+            self.clearcoat_transmission(Vec::dot(input, normal).abs()) * self.clearcoat_transmission(Vec::dot(normal, output));
+        let coat: f64 = self.clearcoat_eval(input, normal, output);
+        base * transmission + Color { x: coat, y: coat, z: coat }
+    }
+
+    // 選択確率で重み付けしたpdfの和。
+    fn eval_pdf(&self, input: &Vec, normal: &Vec, output: &Vec) -> f64 {
+        if Vec::dot(normal, output) < 0.0 {
+            return 0.0
+        }
+        self.sample_pdf(input, normal, output)
+    }
+
+    // 確率的にどれかの項を選んで方向をサンプリングする。
+    // pdfは選ばなかった側で同じ方向が選ばれる確率も含めたもの（one-sample MIS）にし、BRDFは全ての項の和を使う。
+    fn sample(
+        &self, random: &mut dyn Sampler, input: &Vec, normal: &Vec,
+        pdf: &mut f64, brdf_value: &mut Color) -> Vec {
+        let mut lobe: LobeType = LobeType::Diffuse;
+        self.sample_lobes(random, input, normal, None, pdf, brdf_value, &mut lobe)
+    }
+
+    // 下地と塗膜の側が法線まわりの基底を使う。
+    fn sample_with_frame(
+        &self, random: &mut dyn Sampler, input: &Vec, normal: &Vec, tangent: &Vec, bitangent: &Vec,
+        pdf: &mut f64, brdf_value: &mut Color, delta_lobe: &mut bool) -> Vec {
+        *delta_lobe = false;
+        let mut lobe: LobeType = LobeType::Diffuse;
+        self.sample_lobes(random, input, normal, Some((tangent, bitangent)), pdf, brdf_value, &mut lobe)
+    }
+
+    // 塗膜と光沢の項はGlossy、下地の拡散の項はDiffuseとする。
+    fn sample_bsdf(&self, random: &mut dyn Sampler, input: &Vec, normal: &Vec) -> BsdfSample {
+        let mut pdf: f64 = -1.0;
+        let mut brdf_value: Color = Color { x: 0.0, y: 0.0, z: 0.0 };
+        let mut lobe: LobeType = LobeType::Diffuse;
+        let direction: Vec = self.sample_lobes(random, input, normal, None, &mut pdf, &mut brdf_value, &mut lobe);
+
+        BsdfSample { direction, pdf, brdf_value, lobe }
+    }
+}
+
+impl PlasticMaterial {
+    // sample, sample_with_frame, sample_bsdfの共通部分。選んだ項をlobeに書く。
+    // frameがNoneなら、法線まわりの基底は必要になったときに作る。
+    #[allow(clippy::too_many_arguments)]
+    fn sample_lobes(
+        &self, random: &mut dyn Sampler, input: &Vec, normal: &Vec, frame: Option<(&Vec, &Vec)>,
+        pdf: &mut f64, brdf_value: &mut Color, lobe: &mut LobeType) -> Vec {
+        let mut lobe_pdf: f64 = -1.0;
+        let mut lobe_brdf_value: Color = Color { x: 0.0, y: 0.0, z: 0.0 };
+        let (clearcoat_probability, glossy_probability) = self.lobe_probabilities(input, normal);
+        let u: f64 = random.next_1d();
+        let dir: Vec = if u < clearcoat_probability {
+            *lobe = LobeType::Glossy;
+            match frame {
+                Some((tangent, bitangent)) => self.sample_clearcoat(random, input, normal, tangent, bitangent),
+                None => {
+                    let mut tangent: Vec = Vec { x: 0.0, y: 0.0, z: 0.0 };
+                    let mut bitangent: Vec = Vec { x: 0.0, y: 0.0, z: 0.0 };
+                    create_ortho_normal_basis(normal, &mut tangent, &mut bitangent);
+                    self.sample_clearcoat(random, input, normal, &tangent, &bitangent)
+                }
+            }
+        } else if u < clearcoat_probability + glossy_probability {
+            *lobe = LobeType::Glossy;
+            self.glossy.sample(random, input, normal, &mut lobe_pdf, &mut lobe_brdf_value)
+        } else {
+            *lobe = LobeType::Diffuse;
+            match frame {
+                Some((tangent, bitangent)) =>
+                    self.diffuse.sample_with_frame(random, input, normal, tangent, bitangent, &mut lobe_pdf, &mut lobe_brdf_value, &mut false),
+                None => self.diffuse.sample(random, input, normal, &mut lobe_pdf, &mut lobe_brdf_value),
+            }
+        };
+
+        self.finish_sample(input, normal, dir, pdf, brdf_value)
+    }
+}
+
+impl GlassMaterial {
+    pub fn new(reflectance: Color, ior: f64) -> GlassMaterial {
+        GlassMaterial {
+            emission: Color { x: 0.0, y: 0.0, z: 0.0 },
+            reflectance: reflectance,
+            ior: ior,
+        }
+    }
+
+    // inの方向から当たったときのFresnel反射率。sampleで反射と屈折を分ける割合frで、全反射のときは1。
+    // 真空側から垂直に当たれば ((ior - 1) / (ior + 1))² になり、浅い角度になるほど1に近づく。
+    // normalはsampleと同じく物体の外側を向いているものとし、裏側から当たれば屈折率の比を入れ替える。
+    pub fn fresnel(&self, input: &Vec, normal: &Vec) -> f64 {
+        self.fresnel_with_outer_ior(input, normal, 1.0)
+    }
+
+    // fresnelと同じだが、物体の外側の媒質の屈折率をouter_iorとする（sample_with_outer_iorを参照）。
+    pub fn fresnel_with_outer_ior(&self, input: &Vec, normal: &Vec, outer_ior: f64) -> f64 {
+        let now_normal: Vec = Vec::faceforward(normal, input);
+        let into: bool = Vec::dot(normal, &now_normal) > 0.0;
+        let n1: f64 = outer_ior;
+        let n2: &f64 = &self.ior;
+        let n: f64 = if into { n1 / n2 } else { n2 / n1 };
+
+        let dir_dot_normal: f64 = Vec::dot(input, &now_normal);
+        let cos2t_2: f64 = 1.0 - n * n * (1.0 - dir_dot_normal * dir_dot_normal);
+        if cos2t_2 < 0.0 {
+            return 1.0
+        }
+
+        let cost_1: f64 = Vec::dot(&-input, &now_normal);
+        let cost_2: f64 = cos2t_2.sqrt();
+        let r_parallel: f64 = (n * cost_1 - cost_2) / (n * cost_1 + cost_2);
+        let r_perpendicular: f64 = (cost_1 - n * cost_2) / (cost_1 + n * cost_2);
+        0.5 * (r_parallel * r_parallel + r_perpendicular * r_perpendicular)
+    }
+}
+
+impl MaterialTrait for GlassMaterial {
+    fn emission(&self) -> &Color {
+        &self.emission
+    }
+
+    fn reflectance(&self) -> &Color {
+        &self.reflectance
+    }
+    
+    // 理想的なガラス面におけるBRDFはディラックのδ関数を使ってδ/cosΘとなる。
+    // δ関数を表現することは出来ないが、モンテカルロ積分においてはpdfにもδ関数が現れるため分母と分子で打ち消し合う。
+    // そこでcosΘと反射率だけ入れておく。
+    // in, outはカメラ側から追跡したときの入出方向なので、光の入射方向はoutになるため、cosθは法線とoutの内積になる。
+    // 反射率や透過率（Fr,Ft）は含まれていないことに注意！
+    fn eval(&self, input: &Vec, normal: &Vec, output: &Vec) -> Color  {
+        &self.reflectance * &DELTA / Vec::dot(normal, output)
+    }
+
+    fn is_delta(&self) -> bool {
+        true
+    }
+
+    fn eval_pdf(&self, input: &Vec, normal: &Vec, output: &Vec) -> f64 {
+        0.0
+    }
+
+    fn dielectric_ior(&self) -> Option<f64> {
+        Some(self.ior)
+    }
+
+    // 物体の外側は真空とする。
+    fn sample(
+        &self, random: &mut dyn Sampler, input: &Vec, normal: &Vec,
+        pdf: &mut f64, brdf_value: &mut Color) -> Vec {
+        self.sample_with_outer_ior(random, input, normal, 1.0, pdf, brdf_value)
+    }
+
+    fn sample_with_outer_ior(
+        &self, random: &mut dyn Sampler, input: &Vec, normal: &Vec, outer_ior: f64,
+        pdf: &mut f64, brdf_value: &mut Color) -> Vec {
+        // normalは物体の外側を向いているものとする（形状の返す法線はどれもそうなっている）。
+        // カメラや前の交差点が物体の中にあるときは、レイは裏側から当たるので、法線を反転して屈折率の比を入れ替える。
+        let now_normal: Vec = Vec::faceforward(normal, input); // 交差位置の法線（物体からのレイの入出を考慮。
+        let into: bool = Vec::dot(normal, &now_normal) > 0.0; // レイがオブジェクトから出るのか、入るのか。
+        let n1: f64 = outer_ior; // 物体の外側の媒質の屈折率（sampleからは真空の1.0）
+        let n2: &f64 = &self.ior; // オブジェクトの屈折率
+        let n: f64 = if into {
+                n1 / n2
+             } else {
+                n2 / n1
+             };
+
+        // Snellの法則を用いて屈折方向を計算する。
+        let dir_dot_normal: f64 = Vec::dot(input, &now_normal);
+        let cos2t_2: f64 = 1.0 - &n * &n * (1.0 - &dir_dot_normal * &dir_dot_normal);
+        
+        // 全反射
+        let reflection_dir: Vec = Vec::reflect_with_cos(input, &now_normal, dir_dot_normal);
+        if &cos2t_2 < &0.0 {
+            // pdfはディラックのδ関数なので実数値にはならないが、将来的にモンテカルロ積分において、
+            // 分母と分子の両方にδが表れるため結局打ち消し合うため、1でよい。あくまでδであること忘れないためにDELTAを入れておくが、実態は1。
+            *pdf = DELTA;
+            *brdf_value = self.eval(input, normal, &reflection_dir);
+
+            return reflection_dir
+        }
+
+        // 屈折の方向
+        let refraction_dir: Vec = input * &n - &now_normal * (&dir_dot_normal * &n + cos2t_2.sqrt());
+        
+        // Fresnelの式
+        let fr: f64 = self.fresnel_with_outer_ior(input, normal, outer_ior);
+
+        let factor: f64 = (if into {
+            n1 / n2
+        } else {
+            n2 / n1
+        }).powf(2.0); // レイの運ぶ放射輝度は屈折率の異なる物体間を移動するとき、屈折率の比の二乗の分だけ変化する。
+        let ft: f64 = (&1.0 - &fr) * &factor; // 屈折方向の割合。
+        
+        // ロシアンルーレットで屈折か反射かを決定する。
+        // ロシアンルーレットの確率は反射率ということしておく。
+        let probability: f64 = fr;
+        if random.next_1d() < probability { // 反射
+            // pdfはディラックのδ関数なので実数値にはならないが、将来的にモンテカルロ積分において、
+            // 分母と分子の両方にδが表れるため結局打ち消し合うため、1でよい。あくまでδであること忘れないためにDELTAを入れておくが、実態は1。
+            *pdf = DELTA * &probability;
+            *brdf_value = &fr * self.eval(input, normal, &reflection_dir);
+
+            return reflection_dir;
+        } else { // 屈折
+            // evalのcosΘは屈折の方向で求める。積分器の掛けるcos項と打ち消し合い、重みはft / (1 - probability)になる。
             *pdf = DELTA * (&1.0 - &probability);
-            *brdf_value = &ft * self.eval(input, normal, &reflection_dir);
+            *brdf_value = ft * self.eval(input, normal, &refraction_dir);
+
+            refraction_dir
+        }
+    }
+}
+
+impl ThinFilmMaterial {
+    pub fn new(reflectance: Color, thickness: f64, ior: f64) -> ThinFilmMaterial {
+        ThinFilmMaterial {
+            emission: Color { x: 0.0, y: 0.0, z: 0.0 },
+            reflectance,
+            thickness,
+            ior,
+        }
+    }
+
+    // 波長wavelength[nm]、入射角の余弦cos_iにおける膜の反射率。
+    // 膜の表と裏で反射した光の干渉を、多重反射まで含めたAiryの式で求める。
+    // 膜の両側が同じ媒質なので裏面の振幅反射率は表面の符号反転になり、
+    // R = 2r^2(1 - cosδ) / (1 + r^4 - 2r^2 cosδ), δ = 4π n d cosθt / λ となる。
+    pub fn reflectance_at(&self, wavelength: f64, cos_i: f64) -> f64 {
+        let n: f64 = self.ior;
+        let sin2_t: f64 = (1.0 - cos_i * cos_i) / (n * n);
+        let cos_t: f64 = (1.0 - sin2_t).sqrt();
+
+        // Fresnelの式（振幅反射率）
+        let r_perpendicular: f64 = (cos_i - n * cos_t) / (cos_i + n * cos_t);
+        let r_parallel: f64 = (n * cos_i - cos_t) / (n * cos_i + cos_t);
+
+        // 膜内を往復する光路差による位相差
+        let delta: f64 = 4.0 * K_PI * n * self.thickness * cos_t / wavelength;
+        let cos_delta: f64 = delta.cos();
+        let airy = |r: f64| -> f64 {
+            let r2: f64 = r * r;
+            2.0 * r2 * (1.0 - cos_delta) / (1.0 + r2 * r2 - 2.0 * r2 * cos_delta)
+        };
+
+        0.5 * (airy(r_perpendicular) + airy(r_parallel))
+    }
+
+    // RGBの代表波長における反射率。
+    pub fn film_reflectance(&self, cos_i: f64) -> Color {
+        Color {
+            x: self.reflectance_at(WAVELENGTH_R, cos_i),
+            y: self.reflectance_at(WAVELENGTH_G, cos_i),
+            z: self.reflectance_at(WAVELENGTH_B, cos_i),
+        }
+    }
+}
+
+impl MaterialTrait for ThinFilmMaterial {
+    fn emission(&self) -> &Color {
+        &self.emission
+    }
+
+    fn reflectance(&self) -> &Color {
+        &self.reflectance
+    }
+
+    // GlassMaterialと同じく、δ関数の代わりにcosΘと反射率だけ入れておく。
+    // 干渉による反射率（Fr,Ft）は含まれていないことに注意！
+    fn eval(&self, input: &Vec, normal: &Vec, output: &Vec) -> Color {
+        &self.reflectance * DELTA / Vec::dot(normal, output)
+    }
+
+    fn is_delta(&self) -> bool {
+        true
+    }
+
+    fn eval_pdf(&self, input: &Vec, normal: &Vec, output: &Vec) -> f64 {
+        0.0
+    }
+
+    // 膜は十分薄いとして、透過する光は屈折せずにそのまま直進するものとする。
+    fn sample(
+        &self, random: &mut dyn Sampler, input: &Vec, normal: &Vec,
+        pdf: &mut f64, brdf_value: &mut Color) -> Vec {
+        let cos_i: f64 = Vec::dot(input, normal).abs();
+        let now_normal: Vec = Vec::faceforward(normal, input);
+
+        let fr: Color = self.film_reflectance(cos_i);
+        let ft: Color = Color { x: 1.0 - fr.x, y: 1.0 - fr.y, z: 1.0 - fr.z };
+
+        // ロシアンルーレットで反射か透過かを決定する。
+        // 確率はRGBの反射率の平均としておく。
+        let probability: f64 = (fr.x + fr.y + fr.z) / 3.0;
+        if random.next_1d() < probability { // 反射
+            // now_normalはinputと逆向きなので、dot(now_normal, input) = -cos_i。
+            let reflection_dir: Vec = Vec::reflect_with_cos(input, &now_normal, -cos_i);
+            *pdf = DELTA * probability;
+            *brdf_value = Vec::multiply(&fr, &self.eval(input, normal, &reflection_dir));
+
+            reflection_dir
+        } else { // 透過
+            *pdf = DELTA * (1.0 - probability);
+            *brdf_value = Vec::multiply(&ft, &self.eval(input, normal, input));
+
+            input.clone()
+        }
+    }
+}
+
+impl RoughMetalMaterial {
+    pub fn new(eta: Color, k: Color) -> RoughMetalMaterial {
+        RoughMetalMaterial {
+            emission: Color { x: 0.0, y: 0.0, z: 0.0 },
+            reflectance: conductor_fresnel(1.0, &eta, &k),
+            eta,
+            k,
+        }
+    }
+
+    // 以下、RGBの代表波長における代表的な金属の複素屈折率。
+    pub fn gold() -> RoughMetalMaterial {
+        RoughMetalMaterial::new(
+            Color { x: 0.143, y: 0.374, z: 1.442 },
+            Color { x: 3.983, y: 2.386, z: 1.603 })
+    }
+
+    pub fn copper() -> RoughMetalMaterial {
+        RoughMetalMaterial::new(
+            Color { x: 0.200, y: 0.924, z: 1.102 },
+            Color { x: 3.912, y: 2.452, z: 2.142 })
+    }
+
+    pub fn silver() -> RoughMetalMaterial {
+        RoughMetalMaterial::new(
+            Color { x: 0.155, y: 0.117, z: 0.138 },
+            Color { x: 4.828, y: 3.122, z: 2.147 })
+    }
+
+    pub fn aluminium() -> RoughMetalMaterial {
+        RoughMetalMaterial::new(
+            Color { x: 1.657, y: 0.880, z: 0.521 },
+            Color { x: 9.224, y: 6.270, z: 4.837 })
+    }
+
+    // 見る方向の余弦cos_iにおける反射率。
+    pub fn fresnel(&self, cos_i: f64) -> Color {
+        conductor_fresnel(cos_i, &self.eta, &self.k)
+    }
+}
+
+// 導体（複素屈折率 eta + ik）の、偏光していない光に対するFresnel反射率。
+// 真空側から余弦cos_iで入射したときのs偏光とp偏光の反射率の平均を厳密な式で求める。
+// 垂直入射では ((eta - 1)^2 + k^2) / ((eta + 1)^2 + k^2) になり、入射角が浅くなるにつれて1に近づく。
+pub fn conductor_fresnel_channel(cos_i: f64, eta: f64, k: f64) -> f64 {
+    let cos2: f64 = cos_i.clamp(0.0, 1.0).powi(2);
+    let sin2: f64 = 1.0 - cos2;
+    let eta2: f64 = eta * eta;
+    let k2: f64 = k * k;
+
+    let t0: f64 = eta2 - k2 - sin2;
+    let a2_plus_b2: f64 = (t0 * t0 + 4.0 * eta2 * k2).sqrt();
+    let t1: f64 = a2_plus_b2 + cos2;
+    let a: f64 = (0.5 * (a2_plus_b2 + t0)).max(0.0).sqrt();
+    let t2: f64 = 2.0 * cos2.sqrt() * a;
+    let r_perpendicular: f64 = (t1 - t2) / (t1 + t2);
+
+    let t3: f64 = cos2 * a2_plus_b2 + sin2 * sin2;
+    let t4: f64 = t2 * sin2;
+    let r_parallel: f64 = r_perpendicular * (t3 - t4) / (t3 + t4);
+
+    0.5 * (r_perpendicular + r_parallel)
+}
+
+// conductor_fresnel_channelをRGBのチャンネルごとに求める。
+pub fn conductor_fresnel(cos_i: f64, eta: &Color, k: &Color) -> Color {
+    Color {
+        x: conductor_fresnel_channel(cos_i, eta.x, k.x),
+        y: conductor_fresnel_channel(cos_i, eta.y, k.y),
+        z: conductor_fresnel_channel(cos_i, eta.z, k.z),
+    }
+}
+
+impl MaterialTrait for RoughMetalMaterial {
+    fn emission(&self) -> &Color {
+        &self.emission
+    }
+
+    fn reflectance(&self) -> &Color {
+        &self.reflectance
+    }
+
+    // F(cosΘi) / π。Fresnel反射率は見る方向（input）の角度だけで決める。
+    // 出る方向の角度は考えないので相反性は満たさないが、反射率の合計はF以下になる。
+    fn eval(&self, input: &Vec, normal: &Vec, output: &Vec) -> Color {
+        if Vec::dot(normal, output) < 0.0 {
+            return Color { x: 0.0, y: 0.0, z: 0.0 }
+        }
+        self.fresnel(Vec::dot(input, normal).abs()) * K_INV_PI
+    }
+
+    fn sample(
+        &self, random: &mut dyn Sampler, input: &Vec, normal: &Vec,
+        pdf: &mut f64, brdf_value: &mut Color) -> Vec {
+
+        let mut binormal: Vec = Vec { x: 0.0, y: 0.0, z: 0.0 };
+        let mut tangent: Vec = Vec { x: 0.0, y: 0.0, z: 0.0 };
+        create_ortho_normal_basis(normal, &mut tangent, &mut binormal);
+
+        self.sample_with_frame(random, input, normal, &tangent, &binormal, pdf, brdf_value, &mut false)
+    }
+
+    // LambertianMaterialと同じく、pdfとしてcosΘ/piを使用してインポータンスサンプリングする。
+    fn sample_with_frame(
+        &self, random: &mut dyn Sampler, input: &Vec, normal: &Vec, tangent: &Vec, bitangent: &Vec,
+        pdf: &mut f64, brdf_value: &mut Color, delta_lobe: &mut bool) -> Vec {
+        *delta_lobe = false;
+
+        let dir: Vec = Sampling::cosine_weighted_hemisphere_surface(random, normal, tangent, bitangent);
+
+        *pdf = Vec::dot(normal, &dir) * K_INV_PI;
+        *brdf_value = self.eval(input, normal, &dir);
+
+        dir
+    }
+
+    fn eval_pdf(&self, input: &Vec, normal: &Vec, output: &Vec) -> f64 {
+        let cos_theta: f64 = Vec::dot(normal, output);
+        if cos_theta <= 0.0 {
+            return 0.0
+        }
+        cos_theta * K_INV_PI
+    }
+
+    // 拡散面と同じ分布で方向を選ぶが、金属の反射なので光沢の成分として扱う。
+    fn sample_bsdf(&self, random: &mut dyn Sampler, input: &Vec, normal: &Vec) -> BsdfSample {
+        let mut pdf: f64 = -1.0;
+        let mut brdf_value: Color = Color { x: 0.0, y: 0.0, z: 0.0 };
+        let direction: Vec = self.sample(random, input, normal, &mut pdf, &mut brdf_value);
+
+        BsdfSample { direction, pdf, brdf_value, lobe: LobeType::Glossy }
+    }
+}
+
+impl ConductorMaterial {
+    // alpha_x, alpha_yは接線と従法線の方向のGGX分布の粗さα（0に近いほど鏡面に近い）。
+    // let brushed = ConductorMaterial::from_rough_metal(&RoughMetalMaterial::aluminium(), 0.05, 0.3);
+    pub fn new(eta: Color, k: Color, alpha_x: f64, alpha_y: f64) -> ConductorMaterial {
+        ConductorMaterial {
+            emission: Color { x: 0.0, y: 0.0, z: 0.0 },
+            reflectance: conductor_fresnel(1.0, &eta, &k),
+            eta,
+            k,
+            alpha_x: alpha_x.max(CONDUCTOR_MIN_ALPHA),
+            alpha_y: alpha_y.max(CONDUCTOR_MIN_ALPHA),
+            brush_direction: Vec { x: 1.0, y: 0.0, z: 0.0 },
+            energy_compensation: false,
+            albedo_table: std::vec::Vec::new(),
+            average_albedo: 1.0,
+            multiple_scattering_color: Color { x: 0.0, y: 0.0, z: 0.0 },
+        }
+    }
+
+    // 等方的な面。roughnessは粗さ（[0, 1]）で、PlasticMaterial::with_clearcoatと同じくα = roughness²とする。
+    pub fn isotropic(eta: Color, k: Color, roughness: f64) -> ConductorMaterial {
+        let roughness: f64 = roughness.clamp(0.0, 1.0);
+        ConductorMaterial::new(eta, k, roughness * roughness, roughness * roughness)
+    }
+
+    // RoughMetalMaterialのプリセット（gold, copperなど）の複素屈折率を使う。
+    pub fn from_rough_metal(metal: &RoughMetalMaterial, alpha_x: f64, alpha_y: f64) -> ConductorMaterial {
+        ConductorMaterial::new(metal.eta.clone(), metal.k.clone(), alpha_x, alpha_y)
+    }
+
+    // 接線を渡さないとき（sample、eval、eval_pdf）に、alpha_xの方向（接線）にする向き。
+    // 法線に垂直でなくてよく、正規化もしなくてよい。既定はx軸の向き。
+    pub fn with_brush_direction(mut self, direction: Vec) -> ConductorMaterial {
+        self.brush_direction = direction;
+        self
+    }
+
+    // 微小面の間での多重散乱で失われるエネルギーを、拡散反射のような項で補う（Kulla-Contyの方法）。
+    //   f_ms(v, o) = F_ms (1 - E(μv)) (1 - E(μo)) / (π (1 - E_avg))
+    // を単一散乱のBRDFに足す。Fresnel反射率を1とすれば、どの方向から見ても反射率の合計は1になる。
+    // F_msは多重散乱した光の色で、Fresnel反射率の半球での平均F_avgから求める。
+    // E(μ)は数値積分して表にしておく。異方性のときは見る方向の方位角で平均したE(μ)で近似する。
+    // Christopher Kulla, Alejandro Conty. Revisiting Physically Based Shading at Imageworks. SIGGRAPH 2017 Course.
+    pub fn with_energy_compensation(mut self) -> ConductorMaterial {
+        self.albedo_table = (0..ALBEDO_TABLE_SIZE)
+            .map(|i| integrate_ggx_albedo(i as f64 / (ALBEDO_TABLE_SIZE - 1) as f64, self.alpha_x, self.alpha_y))
+            .collect();
+
+        // 2∫g(μ)μdμを中点則で求める。
+        let mut average_albedo: f64 = 0.0;
+        let mut average_fresnel: Color = Color { x: 0.0, y: 0.0, z: 0.0 };
+        for i in 0..ALBEDO_RESOLUTION {
+            let mu: f64 = (i as f64 + 0.5) / ALBEDO_RESOLUTION as f64;
+            average_albedo += self.lookup_albedo(mu) * mu;
+            average_fresnel = average_fresnel + self.fresnel(mu) * mu;
+        }
+        let average_albedo: f64 = (2.0 * average_albedo / ALBEDO_RESOLUTION as f64).min(1.0);
+        let average_fresnel: Color = average_fresnel * (2.0 / ALBEDO_RESOLUTION as f64);
+
+        self.energy_compensation = true;
+        self.average_albedo = average_albedo;
+        self.multiple_scattering_color = Color {
+            x: multiple_scattering_fresnel(average_fresnel.x, average_albedo),
+            y: multiple_scattering_fresnel(average_fresnel.y, average_albedo),
+            z: multiple_scattering_fresnel(average_fresnel.z, average_albedo),
+        };
+        self
+    }
+
+    pub fn is_energy_compensated(&self) -> bool {
+        self.energy_compensation
+    }
+
+    pub fn alpha(&self) -> (f64, f64) {
+        (self.alpha_x, self.alpha_y)
+    }
+
+    // 微小面と見る方向のなす角の余弦cos_iにおける反射率。
+    pub fn fresnel(&self, cos_i: f64) -> Color {
+        conductor_fresnel(cos_i, &self.eta, &self.k)
+    }
+
+    // 法線まわりの基底。接線はbrush_directionを法線に垂直な面に射影したもの。
+    // brush_directionが法線と平行なときは、create_ortho_normal_basisの基底にする。
+    pub fn frame(&self, normal: &Vec) -> OrthoBasis {
+        let along: Vec = self.brush_direction.reject(normal);
+        if along.length_squared() < 1e-12 {
+            return OrthoBasis::from_normal(normal)
+        }
+        let tangent: Vec = Vec::normalize(along);
+        let binormal: Vec = Vec::cross(normal, &tangent);
+        OrthoBasis { tangent, binormal, normal: normal.clone() }
+    }
+
+    // 表を線形補間して、見る方向の余弦cos_vにおける方向アルベドE(μ)を引く。
+    fn lookup_albedo(&self, cos_v: f64) -> f64 {
+        let x: f64 = cos_v.clamp(0.0, 1.0) * (ALBEDO_TABLE_SIZE - 1) as f64;
+        let i: usize = std::cmp::min(x.floor() as usize, ALBEDO_TABLE_SIZE - 2);
+        let t: f64 = x - i as f64;
+        self.albedo_table[i] * (1.0 - t) + self.albedo_table[i + 1] * t
+    }
+
+    // 多重散乱の項 f_ms。エネルギーを補わないときや、補う分の無いときは0。
+    fn multiple_scattering_eval(&self, cos_v: f64, cos_o: f64) -> Color {
+        if !self.energy_compensation || self.average_albedo >= 1.0 {
+            return Color { x: 0.0, y: 0.0, z: 0.0 }
+        }
+        let scale: f64 = (1.0 - self.lookup_albedo(cos_v)) * (1.0 - self.lookup_albedo(cos_o)) /
+            (K_PI * (1.0 - self.average_albedo));
+        &self.multiple_scattering_color * scale
+    }
+
+    // 多重散乱の項の側（cosΘ/πの分布）をサンプリングする確率。その項の反射率の割合 1 - E(μv)にする。
+    fn multiple_scattering_probability(&self, cos_v: f64) -> f64 {
+        if !self.energy_compensation {
+            return 0.0
+        }
+        (1.0 - self.lookup_albedo(cos_v)).max(0.0)
+    }
+
+    // 呼び出し側から渡された接線と従法線の基底。
+    fn frame_from(normal: &Vec, tangent: &Vec, bitangent: &Vec) -> OrthoBasis {
+        OrthoBasis { tangent: tangent.clone(), binormal: bitangent.clone(), normal: normal.clone() }
+    }
+
+    // basisを法線まわりの基底としたときのBRDF（eval）。
+    fn eval_in(&self, basis: &OrthoBasis, input: &Vec, output: &Vec) -> Color {
+        let view: Vec = basis.to_local(&-input);
+        let out: Vec = basis.to_local(output);
+        if view.z <= 0.0 || out.z <= 0.0 {
+            return Color { x: 0.0, y: 0.0, z: 0.0 }
+        }
+
+        let half: Vec = Vec::normalize(&view + &out);
+        let d: f64 = ggx_anisotropic_d(&half, self.alpha_x, self.alpha_y);
+        let g: f64 = 1.0 / (1.0 +
+            ggx_anisotropic_lambda(&view, self.alpha_x, self.alpha_y) +
+            ggx_anisotropic_lambda(&out, self.alpha_x, self.alpha_y));
+        self.fresnel(Vec::dot(&view, &half)) * (d * g / (4.0 * view.z * out.z)) +
+            self.multiple_scattering_eval(view.z, out.z)
+    }
+
+    // basisを法線まわりの基底としたときのサンプリング（sample）。
+    fn sample_in(
+        &self, basis: &OrthoBasis, random: &mut dyn Sampler, input: &Vec,
+        pdf: &mut f64, brdf_value: &mut Color) -> Vec {
+        let view: Vec = basis.to_local(&-input);
+        if view.z <= 0.0 {
+            // 裏側から見たときは寄与が0になる。0で割らないように、pdfは任意の正の値にしておく。
+            *pdf = 1.0;
+            *brdf_value = Color { x: 0.0, y: 0.0, z: 0.0 };
+            return Vec::reflect(input, &basis.normal)
+        }
+
+        let probability: f64 = self.multiple_scattering_probability(view.z);
+        let dir: Vec = if probability > 0.0 && random.next_1d() < probability {
+            Sampling::cosine_weighted_hemisphere_surface(random, &basis.normal, &basis.tangent, &basis.binormal)
+        } else {
+            let half: Vec = Sampling::ggx_vndf(random, &view, self.alpha_x, self.alpha_y);
+            basis.to_world(&Vec::reflect(&-&view, &half))
+        };
+        let out: Vec = basis.to_local(&dir);
+
+        *pdf = self.local_pdf(&view, &out);
+        *brdf_value = self.eval_in(basis, input, &dir);
+
+        dir
+    }
+
+    // 局所座標の見る方向viewから、sampleでoutputの方向を選ぶpdf。
+    // 見える法線の分布で選んだ微小面で反射するものと、多重散乱の項の側のcosΘ/πを選ぶ確率で混ぜる。
+    fn local_pdf(&self, view: &Vec, output: &Vec) -> f64 {
+        let probability: f64 = self.multiple_scattering_probability(view.z);
+        let mut pdf: f64 = 0.0;
+        let half: Vec = Vec::normalize(view + output);
+        let v_dot_h: f64 = Vec::dot(view, &half);
+        if v_dot_h > 0.0 {
+            pdf += Sampling::ggx_vndf_pdf(view, &half, self.alpha_x, self.alpha_y) / (4.0 * v_dot_h) * (1.0 - probability);
+        }
+        if probability > 0.0 && output.z > 0.0 {
+            pdf += output.z * K_INV_PI * probability;
+        }
+        pdf
+    }
+}
+
+// αが0に近いとD(h)がδ関数に近づき、数値的に扱えなくなるので下限を設ける（CLEARCOAT_MIN_ALPHAと同じ）。
+const CONDUCTOR_MIN_ALPHA: f64 = 1e-3;
+
+// ConductorMaterial::with_energy_compensationで作る表の大きさと、表の一項目あたりの数値積分の分割数（各次元）。
+// 異方性のときは、見る方向の方位角もALBEDO_AZIMUTHS通りに分けて平均する。
+const ALBEDO_TABLE_SIZE: usize = 32;
+const ALBEDO_RESOLUTION: usize = 32;
+const ALBEDO_AZIMUTHS: usize = 8;
+
+// GGXの面を見る方向の余弦cos_vから見たときの、Fresnel反射率を1とした方向アルベド。
+// 見える法線の分布で選んだ微小面での反射の重みは G2(v, o) / G1(v) になるので、
+// 単位正方形を格子に切った点で微小面を選び、重みの平均を求める。
+// 異方性のときは方位角で平均する。分布は接線と従法線について対称なので、方位角は[0, π/2]だけ調べればよい。
+fn integrate_ggx_albedo(cos_v: f64, alpha_x: f64, alpha_y: f64) -> f64 {
+    let cos_v: f64 = cos_v.max(1e-4);
+    let sin_v: f64 = (1.0 - cos_v * cos_v).sqrt();
+    let azimuths: usize = if alpha_x == alpha_y { 1 } else { ALBEDO_AZIMUTHS };
+
+    let mut sum: f64 = 0.0;
+    for k in 0..azimuths {
+        let phi: f64 = (k as f64 + 0.5) / azimuths as f64 * K_PI * 0.5;
+        let view: Vec = Vec { x: sin_v * phi.cos(), y: sin_v * phi.sin(), z: cos_v };
+        let lambda_v: f64 = ggx_anisotropic_lambda(&view, alpha_x, alpha_y);
+        for i in 0..ALBEDO_RESOLUTION {
+            let u: f64 = (i as f64 + 0.5) / ALBEDO_RESOLUTION as f64;
+            for j in 0..ALBEDO_RESOLUTION {
+                let v: f64 = (j as f64 + 0.5) / ALBEDO_RESOLUTION as f64;
+                let half: Vec = Sampling::ggx_vndf_at(&view, alpha_x, alpha_y, u, v);
+                let out: Vec = Vec::reflect(&-&view, &half);
+                if out.z > 0.0 {
+                    sum += (1.0 + lambda_v) / (1.0 + lambda_v + ggx_anisotropic_lambda(&out, alpha_x, alpha_y));
+                }
+            }
+        }
+    }
+    sum / (azimuths * ALBEDO_RESOLUTION * ALBEDO_RESOLUTION) as f64
+}
+
+// 多重散乱した光の色。Fresnel反射率の平均f_avgの面で、反射を繰り返した分の和
+// Σ (f_avg (1 - e_avg))^k f_avg e_avg を、多重散乱の項の反射率 1 - e_avg の割合で割り振ったもの。
+fn multiple_scattering_fresnel(f_avg: f64, e_avg: f64) -> f64 {
+    f_avg * f_avg * e_avg / (1.0 - f_avg * (1.0 - e_avg))
+}
+
+impl MaterialTrait for ConductorMaterial {
+    fn emission(&self) -> &Color {
+        &self.emission
+    }
+
+    fn reflectance(&self) -> &Color {
+        &self.reflectance
+    }
+
+    // F(v・h) D(h) G2(v, o) / (4 cosθv cosθo)。G2は高さを考慮したSmithの遮蔽関数 1 / (1 + Λ(v) + Λ(o))。
+    // 裏側から見たときと、裏側に出るときは0。
+    fn eval(&self, input: &Vec, normal: &Vec, output: &Vec) -> Color {
+        self.eval_in(&self.frame(normal), input, output)
+    }
+
+    fn eval_with_frame(&self, input: &Vec, normal: &Vec, tangent: &Vec, bitangent: &Vec, output: &Vec) -> Color {
+        self.eval_in(&ConductorMaterial::frame_from(normal, tangent, bitangent), input, output)
+    }
+
+    // 見える法線の分布から微小面の法線を選んで反射する。
+    // with_energy_compensationのときは、多重散乱の項の側をmultiple_scattering_probabilityの確率で選ぶ。
+    // 反射した方向が地面より下になったときはBRDFが0になる。
+    fn sample(
+        &self, random: &mut dyn Sampler, input: &Vec, normal: &Vec,
+        pdf: &mut f64, brdf_value: &mut Color) -> Vec {
+        self.sample_in(&self.frame(normal), random, input, pdf, brdf_value)
+    }
+
+    // sampleと同じだが、渡された接線の向きをalpha_xの方向にする。
+    fn sample_with_frame(
+        &self, random: &mut dyn Sampler, input: &Vec, normal: &Vec, tangent: &Vec, bitangent: &Vec,
+        pdf: &mut f64, brdf_value: &mut Color, delta_lobe: &mut bool) -> Vec {
+        *delta_lobe = false;
+        self.sample_in(&ConductorMaterial::frame_from(normal, tangent, bitangent), random, input, pdf, brdf_value)
+    }
+
+    fn eval_pdf(&self, input: &Vec, normal: &Vec, output: &Vec) -> f64 {
+        let basis: OrthoBasis = self.frame(normal);
+        self.local_pdf(&basis.to_local(&-input), &basis.to_local(output))
+    }
+
+    fn eval_pdf_with_frame(&self, input: &Vec, normal: &Vec, tangent: &Vec, bitangent: &Vec, output: &Vec) -> f64 {
+        let basis: OrthoBasis = ConductorMaterial::frame_from(normal, tangent, bitangent);
+        self.local_pdf(&basis.to_local(&-input), &basis.to_local(output))
+    }
+
+    fn sample_bsdf(&self, random: &mut dyn Sampler, input: &Vec, normal: &Vec) -> BsdfSample {
+        let mut pdf: f64 = -1.0;
+        let mut brdf_value: Color = Color { x: 0.0, y: 0.0, z: 0.0 };
+        let direction: Vec = self.sample(random, input, normal, &mut pdf, &mut brdf_value);
+
+        BsdfSample { direction, pdf, brdf_value, lobe: LobeType::Glossy }
+    }
+}
+
+impl MixMaterial {
+    // 放射のあるマテリアル（Lightsource、EmissiveTextureMaterialなど）は混ぜられないので、Errを返す。
+    // 光源サンプリングで放射の分布（sample_emission_uv）やカメラからの見え方を決められなくなるため。
+    pub fn new(a: Material, b: Material, factor: f64) -> Result<MixMaterial, String> {
+        if has_emission(&a) || has_emission(&b) {
+            return Err("MixMaterial cannot mix emissive materials".to_string())
+        }
+        let emission: Color = a.emission() * (1.0 - factor) + b.emission() * factor;
+        let reflectance: Color = a.reflectance() * (1.0 - factor) + b.reflectance() * factor;
+        Ok(MixMaterial {
+            emission,
+            reflectance,
+            a: Box::new(a),
+            b: Box::new(b),
+            factor,
+        })
+    }
+
+    // サンプリングにbを使うかどうかを選ぶ。
+    fn choose_b(&self, random: &mut dyn Sampler) -> bool {
+        if self.factor <= 0.0 {
+            false
+        } else if self.factor >= 1.0 {
+            true
+        } else {
+            random.next_1d() < self.factor
+        }
+    }
+
+    // 選んだ側（use_bならb）でサンプリングした方向outputのpdfとBRDFを、二つのマテリアルの混合のものにする。
+    // 選んだ側はsampleの返した値を、もう一方はeval_pdfとevalの値を選択確率で重み付けして足す。
+    // frameはsample_with_frameに渡された(tangent, bitangent)で、あればもう一方もその基底で評価する。
+    // 選んだ側がδ関数を含むときは、もう一方がその方向を選ぶ確率は0なので、選択確率を掛けるだけになる。
+    // factorが0か1のときは片方しか選ばないので、そのままにする。
+    #[allow(clippy::too_many_arguments)]
+    fn mix_sample(
+        &self, use_b: bool, input: &Vec, normal: &Vec, frame: Option<(&Vec, &Vec)>, output: &Vec,
+        pdf: &mut f64, brdf_value: &mut Color) {
+        if self.factor <= 0.0 || self.factor >= 1.0 {
+            return
+        }
+
+        let (chosen, other, weight) = if use_b {
+            (&self.b, &self.a, self.factor)
+        } else {
+            (&self.a, &self.b, 1.0 - self.factor)
+        };
+        if chosen.is_delta() || other.is_delta() {
+            *pdf *= weight;
+            *brdf_value = &*brdf_value * weight;
+            return
+        }
+
+        let (other_pdf, other_brdf_value): (f64, Color) = match frame {
+            Some((tangent, bitangent)) => (
+                other.eval_pdf_with_frame(input, normal, tangent, bitangent, output),
+                other.eval_with_frame(input, normal, tangent, bitangent, output)),
+            None => (other.eval_pdf(input, normal, output), other.eval(input, normal, output))
+        };
+        *pdf = *pdf * weight + other_pdf * (1.0 - weight);
+        *brdf_value = &*brdf_value * weight + other_brdf_value * (1.0 - weight);
+    }
+}
+
+// 放射があるか（SceneObject::is_lightと同じ判定）。
+fn has_emission(material: &Material) -> bool {
+    let emission: &Color = material.emission();
+    emission.x > 0.0 || emission.y > 0.0 || emission.z > 0.0
+}
+
+impl MaterialTrait for MixMaterial {
+    fn emission(&self) -> &Color {
+        &self.emission
+    }
+
+    fn reflectance(&self) -> &Color {
+        &self.reflectance
+    }
+
+    // BRDFをfactorで線形補間する。
+    // δ関数を含む側は光源サンプリングでは評価できないので0として扱う。
+    fn eval(&self, input: &Vec, normal: &Vec, output: &Vec) -> Color {
+        let black = || Color { x: 0.0, y: 0.0, z: 0.0 };
+        let eval_a: Color = if self.a.is_delta() { black() } else { self.a.eval(input, normal, output) };
+        let eval_b: Color = if self.b.is_delta() { black() } else { self.b.eval(input, normal, output) };
+
+        eval_a * (1.0 - self.factor) + eval_b * self.factor
+    }
+
+    fn eval_with_frame(&self, input: &Vec, normal: &Vec, tangent: &Vec, bitangent: &Vec, output: &Vec) -> Color {
+        let black = || Color { x: 0.0, y: 0.0, z: 0.0 };
+        let eval_a: Color = if self.a.is_delta() { black() } else { self.a.eval_with_frame(input, normal, tangent, bitangent, output) };
+        let eval_b: Color = if self.b.is_delta() { black() } else { self.b.eval_with_frame(input, normal, tangent, bitangent, output) };
+
+        eval_a * (1.0 - self.factor) + eval_b * self.factor
+    }
+
+    fn emitted(&self, input: &Vec, normal: &Vec) -> Color {
+        self.a.emitted(input, normal) * (1.0 - self.factor) + self.b.emitted(input, normal) * self.factor
+    }
+
+    fn emitted_at(&self, input: &Vec, normal: &Vec, uv: (f64, f64)) -> Color {
+        self.a.emitted_at(input, normal, uv) * (1.0 - self.factor) + self.b.emitted_at(input, normal, uv) * self.factor
+    }
+
+    fn visible_to_camera(&self) -> bool {
+        self.a.visible_to_camera() && self.b.visible_to_camera()
+    }
+
+    // どちらのBRDFも一定なら、その補間も一定になる。factorが0か1なら選ぶ側のもの。
+    fn constant_brdf(&self) -> Option<Color> {
+        if self.factor <= 0.0 {
+            return self.a.constant_brdf()
+        }
+        if self.factor >= 1.0 {
+            return self.b.constant_brdf()
+        }
+        match (self.a.constant_brdf(), self.b.constant_brdf()) {
+            (Some(brdf_a), Some(brdf_b)) => Some(brdf_a * (1.0 - self.factor) + brdf_b * self.factor),
+            _ => None
+        }
+    }
+
+    // 屈折させるのは誘電体の側なので、その屈折率を返す。両方とも誘電体なら重みの大きい側のもの。
+    // factorが0か1のときは選ぶ側の値をそのまま返す。
+    fn dielectric_ior(&self) -> Option<f64> {
+        if self.factor <= 0.0 {
+            return self.a.dielectric_ior()
+        }
+        if self.factor >= 1.0 {
+            return self.b.dielectric_ior()
+        }
+        let (major, minor) = if self.factor > 0.5 { (&self.b, &self.a) } else { (&self.a, &self.b) };
+        major.dielectric_ior().or_else(|| minor.dielectric_ior())
+    }
+
+    // 混ぜるときは両方ともδ関数を含むときだけ。factorが0か1なら選ぶ側のもの。
+    fn is_delta(&self) -> bool {
+        if self.factor <= 0.0 {
+            return self.a.is_delta()
+        }
+        if self.factor >= 1.0 {
+            return self.b.is_delta()
+        }
+        self.a.is_delta() && self.b.is_delta()
+    }
+
+    // 選択確率で重み付けした二つのpdfの和。δ関数を含む側のpdfは0になる。
+    fn eval_pdf(&self, input: &Vec, normal: &Vec, output: &Vec) -> f64 {
+        let factor: f64 = self.factor.clamp(0.0, 1.0);
+        self.a.eval_pdf(input, normal, output) * (1.0 - factor) + self.b.eval_pdf(input, normal, output) * factor
+    }
+
+    fn eval_pdf_with_frame(&self, input: &Vec, normal: &Vec, tangent: &Vec, bitangent: &Vec, output: &Vec) -> f64 {
+        let factor: f64 = self.factor.clamp(0.0, 1.0);
+        self.a.eval_pdf_with_frame(input, normal, tangent, bitangent, output) * (1.0 - factor) +
+            self.b.eval_pdf_with_frame(input, normal, tangent, bitangent, output) * factor
+    }
+
+    fn albedo_hint(&self) -> Color {
+        self.a.albedo_hint() * (1.0 - self.factor) + self.b.albedo_hint() * self.factor
+    }
+
+    // factorを確率としてどちらかのマテリアルを選び、そのマテリアルで方向をサンプリングする。
+    // pdfとBRDFは、選ばなかった側も含めた混合のものを返す（mix_sample）。
+    // factorが0か1のときは乱数を消費せず、片方のマテリアルと全く同じ結果になるようにする。
+    fn sample(
+        &self, random: &mut dyn Sampler, input: &Vec, normal: &Vec,
+        pdf: &mut f64, brdf_value: &mut Color) -> Vec {
+        let use_b: bool = self.choose_b(random);
+        let chosen: &Material = if use_b { &self.b } else { &self.a };
+        let dir: Vec = chosen.sample(random, input, normal, pdf, brdf_value);
+        self.mix_sample(use_b, input, normal, None, &dir, pdf, brdf_value);
+
+        dir
+    }
+
+    fn sample_with_frame(
+        &self, random: &mut dyn Sampler, input: &Vec, normal: &Vec, tangent: &Vec, bitangent: &Vec,
+        pdf: &mut f64, brdf_value: &mut Color, delta_lobe: &mut bool) -> Vec {
+        let use_b: bool = self.choose_b(random);
+        let chosen: &Material = if use_b { &self.b } else { &self.a };
+        let dir: Vec = chosen.sample_with_frame(random, input, normal, tangent, bitangent, pdf, brdf_value, delta_lobe);
+        self.mix_sample(use_b, input, normal, Some((tangent, bitangent)), &dir, pdf, brdf_value);
+
+        dir
+    }
+
+    fn sample_with_outer_ior(
+        &self, random: &mut dyn Sampler, input: &Vec, normal: &Vec, outer_ior: f64,
+        pdf: &mut f64, brdf_value: &mut Color) -> Vec {
+        let use_b: bool = self.choose_b(random);
+        let chosen: &Material = if use_b { &self.b } else { &self.a };
+        let dir: Vec = chosen.sample_with_outer_ior(random, input, normal, outer_ior, pdf, brdf_value);
+        self.mix_sample(use_b, input, normal, None, &dir, pdf, brdf_value);
+
+        dir
+    }
+
+    fn sample_bsdf(&self, random: &mut dyn Sampler, input: &Vec, normal: &Vec) -> BsdfSample {
+        let use_b: bool = self.choose_b(random);
+        let chosen: &Material = if use_b { &self.b } else { &self.a };
+        let mut sample: BsdfSample = chosen.sample_bsdf(random, input, normal);
+        self.mix_sample(use_b, input, normal, None, &sample.direction, &mut sample.pdf, &mut sample.brdf_value);
+
+        sample
+    }
+
+    fn hemispherical_reflectance(&self, input: &Vec, normal: &Vec, random: &mut dyn Sampler, samples: usize) -> Color {
+        self.a.hemispherical_reflectance(input, normal, random, samples) * (1.0 - self.factor) +
+            self.b.hemispherical_reflectance(input, normal, random, samples) * self.factor
+    }
+}
+
+impl ScaledMaterial {
+    pub fn new(inner: Material, scale: Color) -> ScaledMaterial {
+        let emission: Color = Vec::multiply(inner.emission(), &scale);
+        let reflectance: Color = Vec::multiply(inner.reflectance(), &scale);
+        ScaledMaterial {
+            emission,
+            reflectance,
+            inner: Box::new(inner),
+            scale,
+        }
+    }
+}
+
+impl MaterialTrait for ScaledMaterial {
+    fn emission(&self) -> &Color {
+        &self.emission
+    }
+
+    fn reflectance(&self) -> &Color {
+        &self.reflectance
+    }
+
+    fn eval(&self, input: &Vec, normal: &Vec, output: &Vec) -> Color {
+        Vec::multiply(&self.inner.eval(input, normal, output), &self.scale)
+    }
+
+    fn eval_with_frame(&self, input: &Vec, normal: &Vec, tangent: &Vec, bitangent: &Vec, output: &Vec) -> Color {
+        Vec::multiply(&self.inner.eval_with_frame(input, normal, tangent, bitangent, output), &self.scale)
+    }
+
+    fn emitted(&self, input: &Vec, normal: &Vec) -> Color {
+        Vec::multiply(&self.inner.emitted(input, normal), &self.scale)
+    }
+
+    fn emitted_at(&self, input: &Vec, normal: &Vec, uv: (f64, f64)) -> Color {
+        Vec::multiply(&self.inner.emitted_at(input, normal, uv), &self.scale)
+    }
 
-            refraction_dir
+    fn visible_to_camera(&self) -> bool {
+        self.inner.visible_to_camera()
+    }
+
+    fn is_cutout(&self, uv: (f64, f64)) -> bool {
+        self.inner.is_cutout(uv)
+    }
+
+    fn has_cutout(&self) -> bool {
+        self.inner.has_cutout()
+    }
+
+    // 放射の模様は変わらないので、元のマテリアルの分布をそのまま使う。
+    fn sample_emission_uv(&self, random: &mut dyn Sampler) -> Option<((f64, f64), f64)> {
+        self.inner.sample_emission_uv(random)
+    }
+
+    fn emission_uv_pdf(&self, uv: (f64, f64)) -> Option<f64> {
+        self.inner.emission_uv_pdf(uv)
+    }
+
+    fn constant_brdf(&self) -> Option<Color> {
+        self.inner.constant_brdf().map(|brdf| Vec::multiply(&brdf, &self.scale))
+    }
+
+    // 色を変えても屈折のしかたは変わらないので、元のマテリアルの屈折率をそのまま使う。
+    fn dielectric_ior(&self) -> Option<f64> {
+        self.inner.dielectric_ior()
+    }
+
+    fn is_delta(&self) -> bool {
+        self.inner.is_delta()
+    }
+
+    fn eval_pdf(&self, input: &Vec, normal: &Vec, output: &Vec) -> f64 {
+        self.inner.eval_pdf(input, normal, output)
+    }
+
+    fn eval_pdf_with_frame(&self, input: &Vec, normal: &Vec, tangent: &Vec, bitangent: &Vec, output: &Vec) -> f64 {
+        self.inner.eval_pdf_with_frame(input, normal, tangent, bitangent, output)
+    }
+
+    fn albedo_hint(&self) -> Color {
+        Vec::multiply(&self.inner.albedo_hint(), &self.scale)
+    }
+
+    fn sample(
+        &self, random: &mut dyn Sampler, input: &Vec, normal: &Vec,
+        pdf: &mut f64, brdf_value: &mut Color) -> Vec {
+        let mut inner_brdf_value: Color = Color { x: 0.0, y: 0.0, z: 0.0 };
+        let dir: Vec = self.inner.sample(random, input, normal, pdf, &mut inner_brdf_value);
+        *brdf_value = Vec::multiply(&inner_brdf_value, &self.scale);
+
+        dir
+    }
+
+    fn sample_with_frame(
+        &self, random: &mut dyn Sampler, input: &Vec, normal: &Vec, tangent: &Vec, bitangent: &Vec,
+        pdf: &mut f64, brdf_value: &mut Color, delta_lobe: &mut bool) -> Vec {
+        let mut inner_brdf_value: Color = Color { x: 0.0, y: 0.0, z: 0.0 };
+        let dir: Vec = self.inner.sample_with_frame(random, input, normal, tangent, bitangent, pdf, &mut inner_brdf_value, delta_lobe);
+        *brdf_value = Vec::multiply(&inner_brdf_value, &self.scale);
+
+        dir
+    }
+
+    fn sample_with_outer_ior(
+        &self, random: &mut dyn Sampler, input: &Vec, normal: &Vec, outer_ior: f64,
+        pdf: &mut f64, brdf_value: &mut Color) -> Vec {
+        let mut inner_brdf_value: Color = Color { x: 0.0, y: 0.0, z: 0.0 };
+        let dir: Vec = self.inner.sample_with_outer_ior(random, input, normal, outer_ior, pdf, &mut inner_brdf_value);
+        *brdf_value = Vec::multiply(&inner_brdf_value, &self.scale);
+
+        dir
+    }
+
+    fn sample_bsdf(&self, random: &mut dyn Sampler, input: &Vec, normal: &Vec) -> BsdfSample {
+        let mut sample: BsdfSample = self.inner.sample_bsdf(random, input, normal);
+        sample.brdf_value = Vec::multiply(&sample.brdf_value, &self.scale);
+
+        sample
+    }
+
+    fn hemispherical_reflectance(&self, input: &Vec, normal: &Vec, random: &mut dyn Sampler, samples: usize) -> Color {
+        Vec::multiply(&self.inner.hemispherical_reflectance(input, normal, random, samples), &self.scale)
+    }
+}
+
+impl CutoutMaterial {
+    // alphaはアルファチャンネル付きのテクスチャ（ImageTexture::with_alpha）。
+    // 光源サンプリングは抜けを考えずに光源の面全体から点を選ぶため、光源には使えない。
+    pub fn new(inner: Material, alpha: Arc<ImageTexture>, threshold: f64) -> CutoutMaterial {
+        assert!(!has_emission(&inner), "CutoutMaterial cannot cut out emissive materials");
+        assert!(alpha.has_alpha(), "CutoutMaterial needs a texture with an alpha channel");
+        CutoutMaterial {
+            emission: inner.emission().clone(),
+            reflectance: inner.reflectance().clone(),
+            inner: Box::new(inner),
+            alpha,
+            threshold,
         }
     }
 }
 
+impl MaterialTrait for CutoutMaterial {
+    fn emission(&self) -> &Color {
+        &self.emission
+    }
+
+    fn reflectance(&self) -> &Color {
+        &self.reflectance
+    }
+
+    fn eval(&self, input: &Vec, normal: &Vec, output: &Vec) -> Color {
+        self.inner.eval(input, normal, output)
+    }
+
+    fn eval_with_frame(&self, input: &Vec, normal: &Vec, tangent: &Vec, bitangent: &Vec, output: &Vec) -> Color {
+        self.inner.eval_with_frame(input, normal, tangent, bitangent, output)
+    }
+
+    fn is_cutout(&self, uv: (f64, f64)) -> bool {
+        self.alpha.is_cutout(uv.0, uv.1, 0.0, self.threshold)
+    }
+
+    fn has_cutout(&self) -> bool {
+        true
+    }
+
+    fn constant_brdf(&self) -> Option<Color> {
+        self.inner.constant_brdf()
+    }
+
+    fn dielectric_ior(&self) -> Option<f64> {
+        self.inner.dielectric_ior()
+    }
+
+    fn is_delta(&self) -> bool {
+        self.inner.is_delta()
+    }
+
+    fn visible_to_camera(&self) -> bool {
+        self.inner.visible_to_camera()
+    }
+
+    fn eval_pdf(&self, input: &Vec, normal: &Vec, output: &Vec) -> f64 {
+        self.inner.eval_pdf(input, normal, output)
+    }
+
+    fn eval_pdf_with_frame(&self, input: &Vec, normal: &Vec, tangent: &Vec, bitangent: &Vec, output: &Vec) -> f64 {
+        self.inner.eval_pdf_with_frame(input, normal, tangent, bitangent, output)
+    }
+
+    fn albedo_hint(&self) -> Color {
+        self.inner.albedo_hint()
+    }
+
+    fn sample(
+        &self, random: &mut dyn Sampler, input: &Vec, normal: &Vec,
+        pdf: &mut f64, brdf_value: &mut Color) -> Vec {
+        self.inner.sample(random, input, normal, pdf, brdf_value)
+    }
+
+    fn sample_with_frame(
+        &self, random: &mut dyn Sampler, input: &Vec, normal: &Vec, tangent: &Vec, bitangent: &Vec,
+        pdf: &mut f64, brdf_value: &mut Color, delta_lobe: &mut bool) -> Vec {
+        self.inner.sample_with_frame(random, input, normal, tangent, bitangent, pdf, brdf_value, delta_lobe)
+    }
+
+    fn sample_with_outer_ior(
+        &self, random: &mut dyn Sampler, input: &Vec, normal: &Vec, outer_ior: f64,
+        pdf: &mut f64, brdf_value: &mut Color) -> Vec {
+        self.inner.sample_with_outer_ior(random, input, normal, outer_ior, pdf, brdf_value)
+    }
+
+    fn sample_bsdf(&self, random: &mut dyn Sampler, input: &Vec, normal: &Vec) -> BsdfSample {
+        self.inner.sample_bsdf(random, input, normal)
+    }
+
+    fn hemispherical_reflectance(&self, input: &Vec, normal: &Vec, random: &mut dyn Sampler, samples: usize) -> Color {
+        self.inner.hemispherical_reflectance(input, normal, random, samples)
+    }
+}
+
 impl Lightsource {
     pub fn new(emission: Color) -> Lightsource {
         Lightsource {
             emission: emission,
             reflectance: Color { x: 0.0, y: 0.0, z: 0.0 },
+            two_sided: true,
+            visible_to_camera: true,
         }
     }
 
-    fn eval_pdf(&self, input: &Vec, normal: &Vec, output: &Vec) -> f64 {
-        assert!(false);
-        -1.0
+    // カメラから直接は見えない光源にする（MaterialTrait::visible_to_camera）。
+    pub fn hidden_from_camera(mut self) -> Lightsource {
+        self.visible_to_camera = false;
+        self
+    }
+
+    // 表面からのみ放射する光源にする。
+    pub fn one_sided(mut self) -> Lightsource {
+        self.two_sided = false;
+        self
+    }
+
+    // 色と強度を分けて指定する。
+    // colorは最大のチャンネルが1になるよう正規化し、intensity倍したものを放射輝度とする。
+    // 色味を変えずに明るさだけを調整したいときに使う。
+    pub fn with_intensity(color: Color, intensity: f64) -> Lightsource {
+        let max_component: f64 = color.x.max(color.y).max(color.z);
+        let normalized_color: Color = if max_component > 0.0 {
+            color / max_component
+        } else {
+            Color { x: 0.0, y: 0.0, z: 0.0 }
+        };
+
+        Lightsource::new(normalized_color * intensity)
     }
 }
 
@@ -361,13 +2572,734 @@ impl MaterialTrait for Lightsource {
     }
 
     fn eval(&self, input: &Vec, normal: &Vec, output: &Vec) -> Color {
-        assert!(false);
-        Color { x: 0.0, y: 0.0, z: 0.0}
+        unreachable!("light sources are not shaded: the integrators stop at their emission")
+    }
+
+    // 片面光源を裏側から見たときは何も放射しない。
+    // inはカメラ側からの方向なので、法線と同じ向きなら裏側から見ていることになる。
+    fn emitted(&self, input: &Vec, normal: &Vec) -> Color {
+        if !self.two_sided && Vec::dot(input, normal) > 0.0 {
+            return Color { x: 0.0, y: 0.0, z: 0.0 }
+        }
+        self.emission.clone()
+    }
+
+    fn visible_to_camera(&self) -> bool {
+        self.visible_to_camera
+    }
+
+    // 光源は反射率0なので、代わりに放射を使う。
+    fn albedo_hint(&self) -> Color {
+        self.emission.clone()
+    }
+
+    fn sample(&self, random: &mut dyn Sampler, input: &Vec, normal: &Vec, pdf: &mut f64, brdf_value: &mut Color) -> Vec {
+        unreachable!("light sources are not shaded: the integrators stop at their emission")
+    }
+
+    fn eval_pdf(&self, input: &Vec, normal: &Vec, output: &Vec) -> f64 {
+        unreachable!("light sources are not shaded: the integrators stop at their emission")
+    }
+
+    // 光源は反射率0。
+    fn hemispherical_reflectance(&self, input: &Vec, normal: &Vec, random: &mut dyn Sampler, samples: usize) -> Color {
+        Color { x: 0.0, y: 0.0, z: 0.0 }
+    }
+}
+
+impl EmissiveTextureMaterial {
+    // textureの各テクセルの色をintensity倍したものを放射輝度とする。
+    pub fn new(texture: Arc<ImageTexture>, intensity: f64) -> EmissiveTextureMaterial {
+        let (width, height) = (texture.width(), texture.height());
+        let mut sum: Color = Color { x: 0.0, y: 0.0, z: 0.0 };
+        let mut weights: std::vec::Vec<f64> = std::vec::Vec::with_capacity((width * height) as usize);
+        for y in 0..height {
+            for x in 0..width {
+                let texel: &Color = texture.texel(x, y);
+                sum = sum + texel;
+                weights.push(texel.luminance().max(0.0));
+            }
+        }
+        let distribution: Distribution2D = Distribution2D::new(width as usize, height as usize, &weights);
+
+        EmissiveTextureMaterial {
+            emission: sum * (intensity / (width * height) as f64),
+            reflectance: Color { x: 0.0, y: 0.0, z: 0.0 },
+            texture,
+            intensity,
+            distribution: Arc::new(distribution),
+        }
+    }
+
+    pub fn texture(&self) -> &ImageTexture {
+        &self.texture
+    }
+}
+
+impl MaterialTrait for EmissiveTextureMaterial {
+    fn emission(&self) -> &Color {
+        &self.emission
+    }
+
+    fn reflectance(&self) -> &Color {
+        &self.reflectance
+    }
+
+    fn eval(&self, input: &Vec, normal: &Vec, output: &Vec) -> Color {
+        unreachable!("light sources are not shaded: the integrators stop at their emission")
+    }
+
+    // テクスチャ座標が分からないときは平均の放射輝度を返す。
+    fn emitted(&self, input: &Vec, normal: &Vec) -> Color {
+        if Vec::dot(input, normal) > 0.0 {
+            return Color { x: 0.0, y: 0.0, z: 0.0 }
+        }
+        self.emission.clone()
+    }
+
+    // 光源サンプリングの分布（テクセルごとに一定）と合わせるため、補間せずに最近傍のテクセルを使う。
+    fn emitted_at(&self, input: &Vec, normal: &Vec, uv: (f64, f64)) -> Color {
+        if Vec::dot(input, normal) > 0.0 {
+            return Color { x: 0.0, y: 0.0, z: 0.0 }
+        }
+        self.texture.nearest(uv.0, uv.1) * self.intensity
+    }
+
+    fn sample_emission_uv(&self, random: &mut dyn Sampler) -> Option<((f64, f64), f64)> {
+        let (u0, u1) = random.next_2d();
+        Some(self.distribution.sample(u0, u1))
     }
 
-    fn sample(&self, random: &mut Random, input: &Vec, normal: &Vec, pdf: &mut f64, brdf_value: &mut Color) -> Vec {
-        assert!(false);
-        Color { x: 0.0, y: 0.0, z: 0.0}
+    fn emission_uv_pdf(&self, uv: (f64, f64)) -> Option<f64> {
+        Some(self.distribution.pdf(uv.0, uv.1))
+    }
+
+    // 光源は反射率0なので、代わりに放射を使う。
+    fn albedo_hint(&self) -> Color {
+        self.emission.clone()
+    }
+
+    fn sample(&self, random: &mut dyn Sampler, input: &Vec, normal: &Vec, pdf: &mut f64, brdf_value: &mut Color) -> Vec {
+        unreachable!("light sources are not shaded: the integrators stop at their emission")
+    }
+
+    fn eval_pdf(&self, input: &Vec, normal: &Vec, output: &Vec) -> f64 {
+        unreachable!("light sources are not shaded: the integrators stop at their emission")
+    }
+
+    // 光源は反射率0。
+    fn hemispherical_reflectance(&self, input: &Vec, normal: &Vec, random: &mut dyn Sampler, samples: usize) -> Color {
+        Color { x: 0.0, y: 0.0, z: 0.0 }
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use random::Random;
+    use sampling::Sampling;
+
+    fn gray(value: f64) -> Color {
+        Color { x: value, y: value, z: value }
+    }
+
+    fn incoming() -> (Vec, Vec) {
+        (Vec::normalize(Vec { x: 0.3, y: 0.1, z: -1.0 }), Vec { x: 0.0, y: 0.0, z: 1.0 })
+    }
+
+    fn same_color(a: &Color, b: &Color) -> bool {
+        a.x.to_bits() == b.x.to_bits() && a.y.to_bits() == b.y.to_bits() && a.z.to_bits() == b.z.to_bits()
+    }
+
+    fn lambertian() -> Material {
+        Material::LambertianMaterial(LambertianMaterial::new(gray(0.8)))
+    }
+
+    fn glass() -> Material {
+        Material::GlassMaterial(GlassMaterial::new(gray(1.0), 1.5))
+    }
+
+    fn phong() -> Material {
+        Material::PhongMaterial(PhongMaterial::new(gray(0.6), 20.0))
+    }
+
+    // 同じ乱数の状態からサンプリングして、方向、pdf、BRDFが全く同じになるか。
+    fn samples_identically(mix: &Material, expected: &Material) -> bool {
+        let (input, normal) = incoming();
+        let mut random_mix = Random::new(7);
+        let mut random_expected = Random::new(7);
+        (0..64).all(|_| {
+            let (mut pdf_mix, mut pdf_expected) = (-1.0, -1.0);
+            let (mut brdf_mix, mut brdf_expected) = (gray(0.0), gray(0.0));
+            let dir_mix: Vec = mix.sample(&mut random_mix, &input, &normal, &mut pdf_mix, &mut brdf_mix);
+            let dir_expected: Vec = expected.sample(&mut random_expected, &input, &normal, &mut pdf_expected, &mut brdf_expected);
+            same_color(&dir_mix, &dir_expected) && pdf_mix.to_bits() == pdf_expected.to_bits() &&
+                same_color(&brdf_mix, &brdf_expected)
+        })
+    }
+
+    // factorが0ならa、1ならbと全く同じに振る舞う。
+    #[test]
+    fn mix_with_factor_zero_or_one_is_the_sub_material() {
+        let only_a = Material::MixMaterial(MixMaterial::new(lambertian(), glass(), 0.0).unwrap());
+        let only_b = Material::MixMaterial(MixMaterial::new(lambertian(), glass(), 1.0).unwrap());
+        assert!(samples_identically(&only_a, &lambertian()));
+        assert!(samples_identically(&only_b, &glass()));
+        assert!(!only_a.is_delta() && only_b.is_delta());
+        assert_eq!(only_a.dielectric_ior(), None);
+        assert_eq!(only_b.dielectric_ior(), Some(1.5));
+
+        let (input, normal) = incoming();
+        let output: Vec = Vec::normalize(Vec { x: -0.2, y: 0.4, z: 1.0 });
+        assert!(same_color(&only_a.eval(&input, &normal, &output), &lambertian().eval(&input, &normal, &output)));
+        assert_eq!(only_a.eval_pdf(&input, &normal, &output), lambertian().eval_pdf(&input, &normal, &output));
+        assert!(same_color(&only_a.constant_brdf().unwrap(), &lambertian().constant_brdf().unwrap()));
+    }
+
+    // δ関数を含まない二つを混ぜたとき、sampleは混合のpdfとBRDF（eval_pdf、evalと同じもの）を返す。
+    #[test]
+    fn mix_sample_returns_mixture_pdf_and_brdf() {
+        let mix = Material::MixMaterial(MixMaterial::new(lambertian(), phong(), 0.3).unwrap());
+        let (input, normal) = incoming();
+        let mut random = Random::new(3);
+        for _ in 0..256 {
+            let mut pdf: f64 = -1.0;
+            let mut brdf_value: Color = gray(0.0);
+            let dir: Vec = mix.sample(&mut random, &input, &normal, &mut pdf, &mut brdf_value);
+            if Vec::dot(&normal, &dir) <= 0.0 {
+                continue
+            }
+            let expected_pdf: f64 = mix.eval_pdf(&input, &normal, &dir);
+            let expected_brdf: Color = mix.eval(&input, &normal, &dir);
+            assert!((pdf - expected_pdf).abs() <= 1.0e-9 * expected_pdf.max(1.0), "{} {}", pdf, expected_pdf);
+            assert!((brdf_value.x - expected_brdf.x).abs() <= 1.0e-9 * expected_brdf.x.max(1.0));
+        }
+    }
+
+    // 混合のサンプリングによる反射率の推定は、それぞれの反射率をfactorで補間したものになる。
+    #[test]
+    fn mix_reflectance_interpolates_sub_materials() {
+        let (input, normal) = incoming();
+        let reflectance = |material: &Material, seed: u64| -> f64 {
+            let mut random = Random::new(seed);
+            let samples: usize = 20000;
+            let mut sum: f64 = 0.0;
+            for _ in 0..samples {
+                let mut pdf: f64 = -1.0;
+                let mut brdf_value: Color = gray(0.0);
+                let dir: Vec = material.sample(&mut random, &input, &normal, &mut pdf, &mut brdf_value);
+                if pdf > 0.0 {
+                    sum += brdf_value.x * Vec::dot(&normal, &dir).abs() / pdf;
+                }
+            }
+            sum / samples as f64
+        };
+        let mix = Material::MixMaterial(MixMaterial::new(lambertian(), phong(), 0.3).unwrap());
+        let expected: f64 = reflectance(&lambertian(), 1) * 0.7 + reflectance(&phong(), 2) * 0.3;
+        let actual: f64 = reflectance(&mix, 3);
+        assert!((actual - expected).abs() < 0.02, "{} {}", actual, expected);
+
+        // 片方がδ関数のときも同じ。
+        let mix = Material::MixMaterial(MixMaterial::new(lambertian(), glass(), 0.4).unwrap());
+        let expected: f64 = reflectance(&lambertian(), 4) * 0.6 + reflectance(&glass(), 5) * 0.4;
+        let actual: f64 = reflectance(&mix, 6);
+        assert!((actual - expected).abs() < 0.02, "{} {}", actual, expected);
+    }
+
+    // 誘電体を混ぜたときは、その屈折率を媒質の管理に使えるよう返す。
+    #[test]
+    fn mix_forwards_dielectric_ior() {
+        let mix = Material::MixMaterial(MixMaterial::new(lambertian(), glass(), 0.25).unwrap());
+        assert_eq!(mix.dielectric_ior(), Some(1.5));
+        let mix = Material::MixMaterial(MixMaterial::new(lambertian(), phong(), 0.5).unwrap());
+        assert_eq!(mix.dielectric_ior(), None);
+    }
+
+    // 色を変えたガラスも誘電体として扱い、外側の屈折率を渡したサンプリングは元のガラスと同じ方向になる。
+    #[test]
+    fn scaled_material_forwards_dielectric_queries() {
+        let tint: Color = Color { x: 0.5, y: 0.8, z: 1.0 };
+        let scaled = Material::ScaledMaterial(ScaledMaterial::new(glass(), tint.clone()));
+        assert_eq!(scaled.dielectric_ior(), Some(1.5));
+
+        let (input, normal) = incoming();
+        let mut random_scaled = Random::new(11);
+        let mut random_glass = Random::new(11);
+        for _ in 0..64 {
+            let (mut pdf_scaled, mut pdf_glass) = (-1.0, -1.0);
+            let (mut brdf_scaled, mut brdf_glass) = (gray(0.0), gray(0.0));
+            let dir_scaled: Vec = scaled.sample_with_outer_ior(
+                &mut random_scaled, &input, &normal, 1.33, &mut pdf_scaled, &mut brdf_scaled);
+            let dir_glass: Vec = glass().sample_with_outer_ior(
+                &mut random_glass, &input, &normal, 1.33, &mut pdf_glass, &mut brdf_glass);
+            assert!(same_color(&dir_scaled, &dir_glass));
+            assert_eq!(pdf_scaled, pdf_glass);
+            assert!(same_color(&brdf_scaled, &Vec::multiply(&brdf_glass, &tint)));
+        }
+
+        let scaled = Material::ScaledMaterial(ScaledMaterial::new(lambertian(), tint.clone()));
+        assert!(same_color(&scaled.constant_brdf().unwrap(), &Vec::multiply(&lambertian().constant_brdf().unwrap(), &tint)));
+    }
+
+    // 光源は混ぜられず、panicせずにErrを返す。
+    #[test]
+    fn mix_rejects_light_sources() {
+        let light = || Material::Lightsource(Lightsource::new(gray(1.0)));
+        assert!(MixMaterial::new(lambertian(), light(), 0.5).is_err());
+        assert!(MixMaterial::new(light(), lambertian(), 0.0).is_err());
+        assert!(MixMaterial::new(lambertian(), glass(), 0.5).is_ok());
+    }
+
+    // ガラスの中から臨界角より浅い角度で当たった光は、Snellの法則の向きに屈折するか、鏡面反射する。
+    #[test]
+    fn glass_refracts_out_of_the_object_by_snell_law() {
+        let normal: Vec = Vec { x: 0.0, y: 0.0, z: 1.0 };
+        // 外向きの法線と30度の角度で、物体の中から外へ向かう。
+        let input: Vec = Vec { x: 0.5, y: 0.0, z: (0.75f64).sqrt() };
+        let mut random = Random::new(5);
+        let mut transmitted: usize = 0;
+        let samples: usize = 1000;
+        for _ in 0..samples {
+            let mut pdf: f64 = -1.0;
+            let mut brdf_value: Color = gray(0.0);
+            let dir: Vec = glass().sample(&mut random, &input, &normal, &mut pdf, &mut brdf_value);
+            assert!(pdf > 0.0);
+            if dir.z > 0.0 {
+                // 1.5 * sin 30° = sin θt
+                assert!((dir.x - 0.75).abs() < 1.0e-9, "{:?}", dir);
+                assert!(brdf_value.x * dir.z / pdf > 0.0);
+                transmitted += 1;
+            } else {
+                assert!((dir.x - 0.5).abs() < 1.0e-9, "{:?}", dir);
+            }
+        }
+        // 反射率は数%なので、ほとんどが屈折する（全反射していない）。
+        assert!(transmitted as f64 > samples as f64 * 0.85, "{}", transmitted);
+    }
+
+    // 反射率が最大になる色。0が赤、1が緑、2が青。
+    fn brightest_channel(color: &Color) -> usize {
+        if color.x >= color.y && color.x >= color.z {
+            0
+        } else if color.y >= color.z {
+            1
+        } else {
+            2
+        }
+    }
+
+    // 垂直入射では光路差が半波長（2 n d = λ / 2）になる波長で反射率が最大になる。
+    // 膜を厚くすると、反射率が最大になる色が青から赤へ移る。
+    #[test]
+    fn thin_film_peak_shifts_with_thickness() {
+        let ior: f64 = 1.33;
+        let film = |peak_wavelength: f64| ThinFilmMaterial::new(gray(1.0), peak_wavelength / (4.0 * ior), ior);
+        assert_eq!(brightest_channel(&film(WAVELENGTH_B).film_reflectance(1.0)), 2);
+        assert_eq!(brightest_channel(&film(WAVELENGTH_G).film_reflectance(1.0)), 1);
+        assert_eq!(brightest_channel(&film(WAVELENGTH_R).film_reflectance(1.0)), 0);
+    }
+
+    // 厚さ0の膜は反射しない。どの厚さと角度でも反射率は[0, 1]に収まる。
+    #[test]
+    fn thin_film_reflectance_is_bounded() {
+        assert!(ThinFilmMaterial::new(gray(1.0), 0.0, 1.33).reflectance_at(WAVELENGTH_G, 1.0).abs() < 1e-12);
+        for thickness in [50.0, 120.0, 300.0, 1000.0].iter() {
+            for cos_i in [1.0, 0.7, 0.3, 0.05].iter() {
+                let reflectance: f64 = ThinFilmMaterial::new(gray(1.0), *thickness, 1.33).reflectance_at(WAVELENGTH_G, *cos_i);
+                assert!((0.0..=1.0).contains(&reflectance), "{} {} {}", thickness, cos_i, reflectance);
+            }
+        }
+    }
+
+    // 強度を2倍にすると放射輝度も2倍になる。色は最大のチャンネルが1になるよう正規化してから掛ける。
+    #[test]
+    fn doubling_intensity_doubles_emission() {
+        let color: Color = Color { x: 2.0, y: 1.0, z: 0.5 };
+        let light = Lightsource::with_intensity(color.clone(), 3.0);
+        let brighter = Lightsource::with_intensity(color, 6.0);
+        assert!(same_color(&(light.emission() * 2.0), brighter.emission()));
+        assert!(same_color(light.emission(), &Color { x: 3.0, y: 1.5, z: 0.75 }));
+
+        let (input, normal) = incoming();
+        assert!(same_color(&(light.emitted(&input, &normal) * 2.0), &brighter.emitted(&input, &normal)));
+    }
+
+    // 片面光源は法線の側から見たときだけ放射する。両面光源はどちらから見ても同じ。
+    #[test]
+    fn one_sided_light_emits_only_from_the_front() {
+        let normal: Vec = Vec { x: 0.0, y: 0.0, z: 1.0 };
+        let from_front: Vec = Vec::normalize(Vec { x: 0.2, y: 0.0, z: -1.0 });
+        let from_back: Vec = Vec::normalize(Vec { x: 0.2, y: 0.0, z: 1.0 });
+        let one_sided = Lightsource::new(gray(2.0)).one_sided();
+        assert!(same_color(&one_sided.emitted(&from_front, &normal), &gray(2.0)));
+        assert!(same_color(&one_sided.emitted(&from_back, &normal), &gray(0.0)));
+        let two_sided = Lightsource::new(gray(2.0));
+        assert!(same_color(&two_sided.emitted(&from_back, &normal), &gray(2.0)));
+    }
+
+    // ScaledMaterialのevalとBRDFの値は元のマテリアルのscale倍で、方向とpdfは元のマテリアルのまま。
+    #[test]
+    fn scaled_material_multiplies_eval_by_scale() {
+        let scale: Color = Color { x: 0.25, y: 0.5, z: 2.0 };
+        for inner in [lambertian(), phong()].iter() {
+            let scaled = Material::ScaledMaterial(ScaledMaterial::new(inner.clone(), scale.clone()));
+            let (input, normal) = incoming();
+            let mut random_scaled = Random::new(5);
+            let mut random_inner = Random::new(5);
+            for _ in 0..64 {
+                let (mut pdf_scaled, mut pdf_inner) = (-1.0, -1.0);
+                let (mut brdf_scaled, mut brdf_inner) = (gray(0.0), gray(0.0));
+                let dir: Vec = scaled.sample(&mut random_scaled, &input, &normal, &mut pdf_scaled, &mut brdf_scaled);
+                let dir_inner: Vec = inner.sample(&mut random_inner, &input, &normal, &mut pdf_inner, &mut brdf_inner);
+                assert!(same_color(&dir, &dir_inner));
+                assert_eq!(pdf_scaled, pdf_inner);
+                assert!(same_color(&brdf_scaled, &Vec::multiply(&brdf_inner, &scale)));
+                assert!(same_color(&scaled.eval(&input, &normal, &dir),
+                                   &Vec::multiply(&inner.eval(&input, &normal, &dir), &scale)));
+                assert_eq!(scaled.eval_pdf(&input, &normal, &dir), inner.eval_pdf(&input, &normal, &dir));
+            }
+        }
+    }
+
+    // 光源を包んだときは放射もscale倍になる。
+    #[test]
+    fn scaled_light_scales_emission() {
+        let light = Material::Lightsource(Lightsource::new(gray(4.0)));
+        let scaled = ScaledMaterial::new(light, Color { x: 0.5, y: 1.0, z: 0.0 });
+        assert!(same_color(scaled.emission(), &Color { x: 2.0, y: 4.0, z: 0.0 }));
+        let (input, normal) = incoming();
+        assert!(same_color(&scaled.emitted(&input, &normal), &Color { x: 2.0, y: 4.0, z: 0.0 }));
+    }
+
+    // (平均, 平均の標準誤差)。
+    fn mean_and_stderr(values: &[f64]) -> (f64, f64) {
+        let n: f64 = values.len() as f64;
+        let mean: f64 = values.iter().sum::<f64>() / n;
+        let variance: f64 = values.iter().map(|v| (v - mean) * (v - mean)).sum::<f64>() / n;
+        (mean, (variance / n).sqrt())
+    }
+
+    // 小さな円錐状の光源に向けて方向を選び eval / pdf を平均した値は、
+    // Phongの反射ローブからサンプリングして光源に入った方向だけを数えた値と同じところに収束する。
+    #[test]
+    fn phong_light_sampling_agrees_with_brdf_sampling() {
+        let material: Material = phong();
+        let (input, normal) = incoming();
+        // 反射方向から少しずらした方向にある光源。
+        let reflection: Vec = Vec::reflect(&input, &normal);
+        let axis: Vec = Vec::normalize(&reflection + Vec { x: 0.15, y: -0.1, z: 0.0 });
+        let cos_theta_max: f64 = 0.97;
+        let n: usize = 200000;
+        let mut random = Random::new(29);
+
+        let light: std::vec::Vec<f64> = (0..n).map(|_| {
+            let (dir, pdf) = Sampling::uniform_cone(&mut random, &axis, cos_theta_max);
+            material.eval(&input, &normal, &dir).x * Vec::dot(&dir, &normal).max(0.0) / pdf
+        }).collect();
+
+        let brdf: std::vec::Vec<f64> = (0..n).map(|_| {
+            let mut pdf: f64 = -1.0;
+            let mut brdf_value: Color = gray(0.0);
+            let dir: Vec = material.sample(&mut random, &input, &normal, &mut pdf, &mut brdf_value);
+            let cos: f64 = Vec::dot(&dir, &normal);
+            if cos <= 0.0 || Vec::dot(&dir, &axis) < cos_theta_max {
+                return 0.0
+            }
+            // 地面より上ならeval_pdfはsampleのpdfと同じ。
+            assert!((material.eval_pdf(&input, &normal, &dir) - pdf).abs() <= 1e-9 * pdf, "{:?}", dir);
+            brdf_value.x * cos / pdf
+        }).collect();
+
+        let (light, brdf) = (mean_and_stderr(&light), mean_and_stderr(&brdf));
+        assert!(light.0 > 0.0);
+        assert!((light.0 - brdf.0).abs() <= 4.0 * (light.1 * light.1 + brdf.1 * brdf.1).sqrt(), "{:?} {:?}", light, brdf);
+    }
+
+    // どのマテリアルも、デノイザーに渡せる有限で妥当なアルベドを返す。
+    // 光を反射するだけのものは各成分が[0, 1]で真っ黒ではなく、光源は放射そのもの。
+    #[test]
+    fn every_material_has_a_reasonable_albedo_hint() {
+        let white: Color = gray(1.0);
+        let alpha = Arc::new(ImageTexture::with_alpha(2, 1, vec![white.clone(), white.clone()], vec![1.0, 0.0]));
+        let reflective: std::vec::Vec<(&str, Material)> = vec![
+            ("lambertian simple", Material::LambertianMaterialSimple(LambertianMaterialSimple::new(gray(0.5)))),
+            ("lambertian", lambertian()),
+            ("phong", phong()),
+            ("blinn", Material::BlinnMaterial(BlinnMaterial::new(gray(0.7), 50.0))),
+            ("plastic", Material::PlasticMaterial(PlasticMaterial::new(gray(0.4), 0.04, 100.0).with_clearcoat(0.5, 0.1))),
+            ("glass", glass()),
+            ("thin film", Material::ThinFilmMaterial(ThinFilmMaterial::new(white.clone(), 500.0, 1.33))),
+            ("rough metal", Material::RoughMetalMaterial(RoughMetalMaterial::gold())),
+            ("conductor", Material::ConductorMaterial(ConductorMaterial::from_rough_metal(&RoughMetalMaterial::copper(), 0.3, 0.1))),
+            ("mix", Material::MixMaterial(MixMaterial::new(lambertian(), glass(), 0.5).unwrap())),
+            ("scaled", Material::ScaledMaterial(ScaledMaterial::new(phong(), gray(0.5)))),
+            ("cutout", Material::CutoutMaterial(CutoutMaterial::new(lambertian(), alpha, 0.5))),
+        ];
+        for &(name, ref material) in &reflective {
+            let albedo: Color = material.albedo_hint();
+            for &c in &[albedo.x, albedo.y, albedo.z] {
+                assert!(c.is_finite() && (0.0..=1.0).contains(&c), "{} {:?}", name, albedo);
+            }
+            assert!(albedo.x + albedo.y + albedo.z > 0.0, "{} {:?}", name, albedo);
+        }
+
+        let emission: Color = Color { x: 4.0, y: 2.0, z: 1.0 };
+        let light: Material = Material::Lightsource(Lightsource::new(emission.clone()));
+        assert!(same_color(&light.albedo_hint(), &emission));
+        let texture = Arc::new(ImageTexture::new(1, 1, vec![emission.clone()]));
+        let textured: Material = Material::EmissiveTextureMaterial(EmissiveTextureMaterial::new(texture, 1.0));
+        let albedo: Color = textured.albedo_hint();
+        assert!(albedo.x.is_finite() && albedo.y.is_finite() && albedo.z.is_finite(), "{:?}", albedo);
+    }
+
+    // create_ortho_normal_basisで作った基底を渡したsample_with_frameは、同じ乱数の状態からsampleと全く同じ結果になる。
+    #[test]
+    fn sample_with_computed_frame_matches_sample() {
+        let (input, normal) = incoming();
+        let mut tangent: Vec = gray(0.0);
+        let mut bitangent: Vec = gray(0.0);
+        create_ortho_normal_basis(&normal, &mut tangent, &mut bitangent);
+        let materials: std::vec::Vec<Material> = vec![
+            Material::LambertianMaterialSimple(LambertianMaterialSimple::new(gray(0.5))),
+            lambertian(),
+            phong(),
+            glass(),
+            Material::PlasticMaterial(PlasticMaterial::new(gray(0.4), 0.3, 50.0)),
+            Material::ConductorMaterial(ConductorMaterial::from_rough_metal(&RoughMetalMaterial::gold(), 0.3, 0.1)),
+            Material::MixMaterial(MixMaterial::new(lambertian(), phong(), 0.5).unwrap()),
+        ];
+        for (index, material) in materials.iter().enumerate() {
+            let mut random_sample = Random::new(13);
+            let mut random_frame = Random::new(13);
+            for _ in 0..64 {
+                let (mut pdf_sample, mut pdf_frame) = (-1.0, -1.0);
+                let (mut brdf_sample, mut brdf_frame) = (gray(0.0), gray(0.0));
+                let dir_sample: Vec = material.sample(&mut random_sample, &input, &normal, &mut pdf_sample, &mut brdf_sample);
+                let dir_frame: Vec = material.sample_with_frame(
+                    &mut random_frame, &input, &normal, &tangent, &bitangent, &mut pdf_frame, &mut brdf_frame, &mut false);
+                assert!(same_color(&dir_sample, &dir_frame), "{} {:?} {:?}", index, dir_sample, dir_frame);
+                assert_eq!(pdf_sample.to_bits(), pdf_frame.to_bits(), "{}", index);
+                assert!(same_color(&brdf_sample, &brdf_frame), "{}", index);
+            }
+        }
+    }
+
+    // 項を選ぶ確率がどちらに偏っていても、プラスチックのサンプリングによる推定は偏らない。
+    // 反射方向の付近が明るい照明の下での反射光を、半球一様サンプリングで求めた参照値と比べる。
+    #[test]
+    fn plastic_lobe_selection_is_unbiased() {
+        let (input, normal) = incoming();
+        let reflection: Vec = Vec::reflect(&input, &normal);
+        let radiance = |dir: &Vec| -> f64 { 1.0 + 3.0 * Vec::dot(dir, &reflection).max(0.0).powi(8) };
+        let mut tangent: Vec = gray(0.0);
+        let mut bitangent: Vec = gray(0.0);
+        create_ortho_normal_basis(&normal, &mut tangent, &mut bitangent);
+
+        // 光沢の側を選びやすいもの、下地の側を選びやすいもの、塗膜の側も選ぶもの。
+        let materials: std::vec::Vec<PlasticMaterial> = vec![
+            PlasticMaterial::new(gray(0.05), 0.5, 20.0),
+            PlasticMaterial::new(gray(0.9), 0.1, 20.0),
+            PlasticMaterial::new(gray(0.5), 0.3, 20.0).with_clearcoat(1.0, 0.3),
+        ];
+        let n: usize = 100000;
+        for (index, material) in materials.iter().enumerate() {
+            let mut random = Random::new(101 + index as u64);
+            let sampled: std::vec::Vec<f64> = (0..n).map(|_| {
+                let mut pdf: f64 = -1.0;
+                let mut brdf_value: Color = gray(0.0);
+                let dir: Vec = material.sample(&mut random, &input, &normal, &mut pdf, &mut brdf_value);
+                let cos: f64 = Vec::dot(&dir, &normal);
+                if cos <= 0.0 || pdf <= 0.0 {
+                    return 0.0
+                }
+                brdf_value.x * cos * radiance(&dir) / pdf
+            }).collect();
+            let reference: std::vec::Vec<f64> = (0..n).map(|_| {
+                let dir: Vec = Sampling::uniform_hemisphere_surface(&mut random, &normal, &tangent, &bitangent);
+                material.eval(&input, &normal, &dir).x * Vec::dot(&dir, &normal) * radiance(&dir) * K_2PI
+            }).collect();
+
+            let (sampled, reference) = (mean_and_stderr(&sampled), mean_and_stderr(&reference));
+            assert!((sampled.0 - reference.0).abs() <= 4.0 * (sampled.1 * sampled.1 + reference.1 * reference.1).sqrt(),
+                    "{} {:?} {:?}", index, sampled, reference);
+        }
+    }
+
+    // デバッグビルドでは、単位ベクトルでない法線を渡すとdebug_assert!で止まる。
+    #[cfg(debug_assertions)]
+    #[test]
+    #[should_panic(expected = "normal must be a unit vector")]
+    fn non_unit_normal_trips_debug_assertion() {
+        let (input, _) = incoming();
+        let normal: Vec = Vec { x: 0.0, y: 0.0, z: 2.0 };
+        lambertian().eval_pdf(&input, &normal, &normal);
+    }
+
+    // 拡散面の方向半球反射率はちょうどreflectanceになり、Phongはローブが地面の下にはみ出すぶんreflectance以下になる。
+    #[test]
+    fn hemispherical_reflectance_is_exact_for_lambertian_and_bounded_for_phong() {
+        let mut random = Random::new(3);
+        let normal: Vec = Vec { x: 0.0, y: 0.0, z: 1.0 };
+        let reflectance: Color = Color { x: 0.2, y: 0.5, z: 0.8 };
+        let lambertian = Material::LambertianMaterial(LambertianMaterial::new(reflectance.clone()));
+        let phong = Material::PhongMaterial(PhongMaterial::new(reflectance.clone(), 20.0));
+        for &cos in &[1.0, 0.5, 0.1] {
+            let input: Vec = Vec { x: (1.0_f64 - cos * cos).sqrt(), y: 0.0, z: -cos };
+            assert!(same_color(&lambertian.hemispherical_reflectance(&input, &normal, &mut random, 16), &reflectance));
+            let albedo: Color = phong.hemispherical_reflectance(&input, &normal, &mut random, 4096);
+            assert!(albedo.x <= reflectance.x + 1e-9 && albedo.y <= reflectance.y + 1e-9 && albedo.z <= reflectance.z + 1e-9,
+                    "{} {:?}", cos, albedo);
+            assert!(albedo.x > 0.0);
+        }
+    }
+
+    // Blinnのeval_pdfは負にならず、半球で積分すると（地面の下に反射するごくわずかな分を除いて）1になり、
+    // sampleが返すpdfと一致する。同じ指数では、ハイライトはPhongより少し広がる。
+    #[test]
+    fn blinn_pdf_is_valid_and_its_highlight_is_broader_than_phong() {
+        let n: f64 = 20.0;
+        let blinn = Material::BlinnMaterial(BlinnMaterial::new(gray(0.8), n));
+        let phong = Material::PhongMaterial(PhongMaterial::new(gray(0.8), n));
+        let input: Vec = Vec { x: 0.0, y: 0.0, z: -1.0 };
+        let normal: Vec = Vec { x: 0.0, y: 0.0, z: 1.0 };
+        let direction = |theta: f64, phi: f64| -> Vec {
+            Vec { x: theta.sin() * phi.cos(), y: theta.sin() * phi.sin(), z: theta.cos() }
+        };
+
+        // 天頂角と方位角の格子の中点で数値積分する。
+        let (steps_theta, steps_phi): (usize, usize) = (512, 64);
+        let d_theta: f64 = K_PI / 2.0 / steps_theta as f64;
+        let d_phi: f64 = K_2PI / steps_phi as f64;
+        let mut integral: f64 = 0.0;
+        for i in 0..steps_theta {
+            let theta: f64 = (i as f64 + 0.5) * d_theta;
+            for j in 0..steps_phi {
+                let pdf: f64 = blinn.eval_pdf(&input, &normal, &direction(theta, (j as f64 + 0.5) * d_phi));
+                assert!(pdf >= 0.0);
+                integral += pdf * theta.sin() * d_theta * d_phi;
+            }
+        }
+        assert!(integral > 0.99 && integral < 1.0 + 1e-3, "{}", integral);
+
+        let mut random = Random::new(11);
+        for _ in 0..256 {
+            let mut pdf: f64 = -1.0;
+            let mut brdf_value: Color = gray(0.0);
+            let dir: Vec = blinn.sample(&mut random, &input, &normal, &mut pdf, &mut brdf_value);
+            if Vec::dot(&normal, &dir) > 0.0 {
+                assert!((pdf - blinn.eval_pdf(&input, &normal, &dir)).abs() <= 1e-9 * pdf, "{:?}", dir);
+            }
+        }
+
+        // 正反射の方向（真上）の明るさに対する、15°ずれた方向の明るさの割合。
+        let falloff = |material: &Material| -> f64 {
+            material.eval(&input, &normal, &direction(15.0_f64.to_radians(), 0.0)).x /
+                material.eval(&input, &normal, &normal).x
+        };
+        assert!(falloff(&blinn) > falloff(&phong), "{} {}", falloff(&blinn), falloff(&phong));
+    }
+
+    // ガラスのsample_bsdfは、入射した側に戻る方向ならReflection、反対側に抜ける方向ならTransmissionと報告する。
+    // 中から浅い角度で当たって全反射するときは、いつもReflectionになる。
+    #[test]
+    fn glass_reports_reflection_or_transmission_per_branch() {
+        let normal: Vec = Vec { x: 0.0, y: 0.0, z: 1.0 };
+        let (from_outside, _) = incoming();
+        let from_inside: Vec = Vec { x: (0.75f64).sqrt(), y: 0.0, z: 0.5 };
+        let mut random = Random::new(8);
+        let (mut reflected, mut transmitted): (usize, usize) = (0, 0);
+        for _ in 0..1000 {
+            let sample: BsdfSample = glass().sample_bsdf(&mut random, &from_outside, &normal);
+            if sample.direction.z > 0.0 {
+                assert_eq!(sample.lobe, LobeType::Reflection);
+                reflected += 1;
+            } else {
+                assert_eq!(sample.lobe, LobeType::Transmission);
+                transmitted += 1;
+            }
+
+            let sample: BsdfSample = glass().sample_bsdf(&mut random, &from_inside, &normal);
+            assert!(sample.direction.z < 0.0, "{:?}", sample.direction);
+            assert_eq!(sample.lobe, LobeType::Reflection);
+        }
+        assert!(reflected > 0 && transmitted > reflected, "{} {}", reflected, transmitted);
+    }
+
+    // alpha_x < alpha_yの導体を真上から見ると、ハイライトは従法線（y）の向きに伸びる。
+    // 接線（x）の向きに傾けた方向のpdfは、同じ角度だけ従法線の向きに傾けた方向よりずっと小さく、
+    // サンプリングした方向もyの向きに広がる。α_x = α_yなら向きによらない。
+    #[test]
+    fn anisotropic_conductor_stretches_the_highlight_along_the_rougher_axis() {
+        let input: Vec = Vec { x: 0.0, y: 0.0, z: -1.0 };
+        let normal: Vec = Vec { x: 0.0, y: 0.0, z: 1.0 };
+        let theta: f64 = 20.0_f64.to_radians();
+        let along_tangent: Vec = Vec { x: theta.sin(), y: 0.0, z: theta.cos() };
+        let along_binormal: Vec = Vec { x: 0.0, y: theta.sin(), z: theta.cos() };
+        let brushed = Material::ConductorMaterial(
+            ConductorMaterial::from_rough_metal(&RoughMetalMaterial::aluminium(), 0.05, 0.4));
+        let isotropic = Material::ConductorMaterial(
+            ConductorMaterial::from_rough_metal(&RoughMetalMaterial::aluminium(), 0.2, 0.2));
+
+        let tangent_pdf: f64 = brushed.eval_pdf(&input, &normal, &along_tangent);
+        let binormal_pdf: f64 = brushed.eval_pdf(&input, &normal, &along_binormal);
+        assert!(tangent_pdf * 10.0 < binormal_pdf, "{} {}", tangent_pdf, binormal_pdf);
+        assert!(brushed.eval(&input, &normal, &along_tangent).x < brushed.eval(&input, &normal, &along_binormal).x);
+        let (a, b) = (isotropic.eval_pdf(&input, &normal, &along_tangent), isotropic.eval_pdf(&input, &normal, &along_binormal));
+        assert!((a - b).abs() <= 1e-9 * a, "{} {}", a, b);
+
+        let mut random = Random::new(5);
+        let (mut spread_x, mut spread_y): (f64, f64) = (0.0, 0.0);
+        for _ in 0..2000 {
+            let mut pdf: f64 = -1.0;
+            let mut brdf_value: Color = gray(0.0);
+            let dir: Vec = brushed.sample(&mut random, &input, &normal, &mut pdf, &mut brdf_value);
+            spread_x += dir.x * dir.x;
+            spread_y += dir.y * dir.y;
+        }
+        assert!(spread_x * 4.0 < spread_y, "{} {}", spread_x, spread_y);
+    }
+
+    // 上向きの法線の基底は(x, y, z)そのものなので、(u, v) = (0.125, 0.36)からは
+    // φ = π/4、sinθ = 0.6の方向 (0.6 / √2, 0.6 / √2, 0.8) を選び、pdfはcosθ/π、BRDFはρ/πになる。
+    // 乱数はちょうど二つだけ使う。
+    #[test]
+    fn lambertian_sample_with_fixed_values_returns_the_precomputed_direction() {
+        use sampler::FixedSampler;
+        let (input, normal) = incoming();
+        let reflectance: Color = Color { x: 0.8, y: 0.4, z: 0.2 };
+        let material = Material::LambertianMaterial(LambertianMaterial::new(reflectance.clone()));
+        let mut random = FixedSampler::new(vec![0.125, 0.36]);
+        let mut pdf: f64 = -1.0;
+        let mut brdf_value: Color = gray(0.0);
+        let dir: Vec = material.sample(&mut random, &input, &normal, &mut pdf, &mut brdf_value);
+
+        let expected: Vec = Vec { x: 0.6 / 2.0_f64.sqrt(), y: 0.6 / 2.0_f64.sqrt(), z: 0.8 };
+        assert!((&dir - &expected).length() < 1e-12, "{:?}", dir);
+        assert!((pdf - 0.8 * K_INV_PI).abs() < 1e-12, "{}", pdf);
+        assert!((&brdf_value - &(&reflectance * K_INV_PI)).length() < 1e-12, "{:?}", brdf_value);
+        assert_eq!(random.position(), 2);
+    }
+
+    // 垂直に当たったときのFresnel反射率は((n - 1) / (n + 1))²で、内側からでも同じ。
+    // 外側から当てる角度を浅くするほど増えて1に近づき、内側から臨界角を超えて当たれば全反射で1になる。
+    #[test]
+    fn glass_fresnel_matches_normal_incidence_and_rises_toward_grazing() {
+        let ior: f64 = 1.5;
+        let glass = GlassMaterial::new(gray(1.0), ior);
+        let normal: Vec = Vec { x: 0.0, y: 0.0, z: 1.0 };
+        let at = |cos: f64, sign: f64| Vec { x: (1.0 - cos * cos).sqrt(), y: 0.0, z: -sign * cos };
+
+        let expected: f64 = ((ior - 1.0) / (ior + 1.0)).powi(2);
+        assert!((glass.fresnel(&at(1.0, 1.0), &normal) - expected).abs() < 1e-12);
+        assert!((glass.fresnel(&at(1.0, -1.0), &normal) - expected).abs() < 1e-12);
+
+        let outside: std::vec::Vec<f64> = [1.0, 0.8, 0.5, 0.2, 0.05, 1e-3].iter()
+            .map(|&cos| glass.fresnel(&at(cos, 1.0), &normal)).collect();
+        assert!(outside.windows(2).all(|pair| pair[0] < pair[1]), "{:?}", outside);
+        assert!(outside[outside.len() - 1] > 0.98, "{:?}", outside);
+
+        // 臨界角の余弦は√(1 - 1/n²) ≈ 0.745。
+        assert_eq!(glass.fresnel(&at(0.5, -1.0), &normal), 1.0);
+        assert!(glass.fresnel(&at(0.9, -1.0), &normal) < 1.0);
+    }
+}
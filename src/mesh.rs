@@ -0,0 +1,231 @@
+#![allow(dead_code)]
+
+use std;
+use std::collections::HashMap;
+
+use vec::{Vec, Normalize, Cross};
+use ray::Ray;
+use hitpoint::Hitpoint;
+use sampler::Sampler;
+use triangle::Triangle;
+use bvh::Bvh;
+use texture::ImageTexture;
+
+// 三角形の集まりと、それを囲むBVH。
+// 同じメッシュを何度も置くときは、Arc<Mesh>をInstanceで共有する。
+#[derive(Debug)]
+pub struct Mesh {
+    triangles: std::vec::Vec<Triangle>,
+    bvh: Bvh,
+    area_cdf: std::vec::Vec<f64>, // 三角形の面積の累積和。表面上の点を一様に選ぶのに使う。
+}
+
+impl Mesh {
+    pub fn new(triangles: std::vec::Vec<Triangle>) -> Mesh {
+        let bounds: std::vec::Vec<(Vec, Vec)> = triangles.iter().map(|triangle| triangle.bounds()).collect();
+        let bvh = Bvh::new(&bounds);
+        let mut sum: f64 = 0.0;
+        let area_cdf: std::vec::Vec<f64> = triangles.iter().map(|triangle| { sum += triangle.area(); sum }).collect();
+        Mesh { triangles, bvh, area_cdf }
+    }
+
+    pub fn triangles(&self) -> &[Triangle] {
+        &self.triangles
+    }
+
+    // 軸に平行な境界ボックスの(最小の角, 最大の角)。
+    pub fn bounds(&self) -> (Vec, Vec) {
+        self.bvh.bounds()
+    }
+
+    pub fn area(&self) -> f64 {
+        self.area_cdf.last().cloned().unwrap_or(0.0)
+    }
+
+    // 最も近い三角形との交差点を返す。テクスチャ座標と接線（surface_details）も求めてある。
+    pub fn intersect(&self, ray: &Ray) -> Option<Hitpoint> {
+        self.intersect_with_t_min(ray, 0.0)
+    }
+
+    // intersectと同じだが、距離がt_min以下の交差は無視する。
+    pub fn intersect_with_t_min(&self, ray: &Ray, t_min: f64) -> Option<Hitpoint> {
+        let triangles = &self.triangles;
+        let mut closest: Option<(usize, Hitpoint)> = None;
+        self.bvh.intersect(ray, |index, max_distance| {
+            match triangles[index].intersect_with_t_min(ray, t_min) {
+                Some(hitpoint) => {
+                    if hitpoint.distance < max_distance {
+                        let distance: f64 = hitpoint.distance;
+                        closest = Some((index, hitpoint));
+                        Some(distance)
+                    } else {
+                        None
+                    }
+                },
+                None => None
+            }
+        });
+
+        closest.map(|(index, mut hitpoint)| {
+            triangles[index].surface_details(&mut hitpoint);
+            hitpoint
+        })
+    }
+
+    // 距離max_distanceより手前でどれかの三角形と交差するか。最初に見つけた交差で打ち切る。
+    pub fn intersect_any(&self, ray: &Ray, max_distance: f64) -> bool {
+        let triangles = &self.triangles;
+        self.bvh.intersect_any(ray, max_distance, |index| {
+            triangles[index].intersect(ray).is_some_and(|hitpoint| hitpoint.distance < max_distance)
+        })
+    }
+
+    // 表面上の点を一様にサンプリングする。面積に比例して三角形を選び、その上で一様に選ぶ。
+    // (位置, 法線, 面積測度のpdf)を返す。
+    pub fn sample_surface(&self, random: &mut dyn Sampler) -> (Vec, Vec, f64) {
+        let area: f64 = self.area();
+        let target: f64 = random.next_1d() * area;
+        let index: usize = match self.area_cdf.binary_search_by(|sum| sum.partial_cmp(&target).unwrap_or(std::cmp::Ordering::Less)) {
+            Ok(index) => index,
+            Err(index) => index,
+        };
+        let index: usize = std::cmp::min(index, self.triangles.len() - 1);
+
+        let (position, normal, _) = self.triangles[index].sample_surface(random);
+        (position, normal, 1.0 / area)
+    }
+}
+
+// 中心center、半径radiusの球を三角形に分割する（icosphere）。
+// 正二十面体の各三角形をsubdivisions回だけ四つに分け、頂点を球面上に押し出す。三角形の数は20 * 4^subdivisions。
+// 頂点はいずれも球面上にあり、三角形は外側から見て反時計回り（法線が外側を向く）になる。
+// Triangleは頂点法線を持たないので、頂点の法線（中心から頂点への方向）は三角形の面の向きとしてだけ反映される。
+// BVHだけで交差判定する場合や、変位を加えたい場合に使う。
+pub fn tessellate_sphere(center: Vec, radius: f64, subdivisions: u32) -> std::vec::Vec<Triangle> {
+    // 正二十面体の頂点は、黄金比tを使って(0, ±1, ±t)とその巡回置換で表せる。
+    let t: f64 = (1.0 + 5.0f64.sqrt()) * 0.5;
+    let mut vertices: std::vec::Vec<Vec> = [
+        (-1.0, t, 0.0), (1.0, t, 0.0), (-1.0, -t, 0.0), (1.0, -t, 0.0),
+        (0.0, -1.0, t), (0.0, 1.0, t), (0.0, -1.0, -t), (0.0, 1.0, -t),
+        (t, 0.0, -1.0), (t, 0.0, 1.0), (-t, 0.0, -1.0), (-t, 0.0, 1.0),
+    ].iter().map(|&(x, y, z)| Vec::normalize(Vec { x, y, z })).collect();
+    let mut faces: std::vec::Vec<(usize, usize, usize)> = vec![
+        (0, 11, 5), (0, 5, 1), (0, 1, 7), (0, 7, 10), (0, 10, 11),
+        (1, 5, 9), (5, 11, 4), (11, 10, 2), (10, 7, 6), (7, 1, 8),
+        (3, 9, 4), (3, 4, 2), (3, 2, 6), (3, 6, 8), (3, 8, 9),
+        (4, 9, 5), (2, 4, 11), (6, 2, 10), (8, 6, 7), (9, 8, 1),
+    ];
+
+    for _ in 0..subdivisions {
+        // 隣り合う三角形で辺の中点を共有するよう、辺（頂点の番号の組）ごとに中点の番号を覚えておく。
+        let mut midpoints: HashMap<(usize, usize), usize> = HashMap::new();
+        let mut midpoint = |a: usize, b: usize, vertices: &mut std::vec::Vec<Vec>| -> usize {
+            let key = if a < b { (a, b) } else { (b, a) };
+            *midpoints.entry(key).or_insert_with(|| {
+                vertices.push(Vec::normalize((&vertices[a] + &vertices[b]) * 0.5));
+                vertices.len() - 1
+            })
+        };
+
+        let mut next_faces: std::vec::Vec<(usize, usize, usize)> = std::vec::Vec::with_capacity(faces.len() * 4);
+        for &(a, b, c) in &faces {
+            let ab: usize = midpoint(a, b, &mut vertices);
+            let bc: usize = midpoint(b, c, &mut vertices);
+            let ca: usize = midpoint(c, a, &mut vertices);
+            next_faces.push((a, ab, ca));
+            next_faces.push((b, bc, ab));
+            next_faces.push((c, ca, bc));
+            next_faces.push((ab, bc, ca));
+        }
+        faces = next_faces;
+    }
+
+    let to_world = |v: &Vec| -> Vec { &center + v * radius };
+    faces.iter()
+        .map(|&(a, b, c)| Triangle::new(to_world(&vertices[a]), to_world(&vertices[b]), to_world(&vertices[c])))
+        .collect()
+}
+
+// 三角形の頂点を、高さのテクスチャheight_textureの値 * scaleだけ頂点の法線の向きに動かす（変位マッピング）。
+// Triangleは頂点ごとのテクスチャ座標を持たないので、頂点の位置からテクスチャ座標を求める関数uv_atを渡す（平面への投影など）。
+// 高さはテクスチャの輝度で、[0, 1]の灰色の画像ならその値になる。
+// 同じ位置の頂点は一つの頂点として扱い、法線は周りの三角形の法線を面積で重み付けした平均にする。
+// 動かしたあとの三角形の法線は、新しい頂点から求め直される。レンダリングの前に一度だけ行う前処理。
+pub fn displace<F>(triangles: &mut [Triangle], height_texture: &ImageTexture, scale: f64, uv_at: F)
+    where F: Fn(&Vec) -> (f64, f64) {
+    // -0.0と0.0を同じ位置として扱うよう、0.0を足してからビット列にする。
+    let key = |v: &Vec| -> (u64, u64, u64) { ((v.x + 0.0).to_bits(), (v.y + 0.0).to_bits(), (v.z + 0.0).to_bits()) };
+
+    // 頂点ごとに、周りの三角形の (v1 - v0) × (v2 - v0)（長さが面積の二倍）を足し合わせる。
+    let mut normals: HashMap<(u64, u64, u64), Vec> = HashMap::new();
+    for triangle in triangles.iter() {
+        let (v0, v1, v2) = triangle.vertices();
+        let weighted_normal: Vec = Vec::cross(&(v1 - v0), &(v2 - v0));
+        for v in &[v0, v1, v2] {
+            let sum = normals.entry(key(v)).or_insert(Vec { x: 0.0, y: 0.0, z: 0.0 });
+            *sum = &*sum + &weighted_normal;
+        }
+    }
+
+    let displaced = |v: &Vec| -> Vec {
+        let normal: Vec = Vec::normalize(&normals[&key(v)]);
+        let (u, w) = uv_at(v);
+        let height: f64 = height_texture.lookup(u, w, 0.0).luminance();
+        v + normal * (height * scale)
+    };
+    for triangle in triangles.iter_mut() {
+        let moved: Triangle = {
+            let (v0, v1, v2) = triangle.vertices();
+            Triangle::new(displaced(v0), displaced(v1), displaced(v2))
+        };
+        *triangle = moved;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use vec::Dot;
+
+    // 分割した球の頂点はどれも球面上にあり、三角形の法線は外側（中心から三角形の重心への向き）を向く。
+    // 三角形の数は20 * 4^subdivisions。
+    #[test]
+    fn tessellated_sphere_lies_on_the_sphere_and_faces_outward() {
+        let center: Vec = Vec { x: 1.0, y: -2.0, z: 0.5 };
+        let radius: f64 = 3.0;
+        for subdivisions in 0..4 {
+            let triangles: std::vec::Vec<Triangle> = tessellate_sphere(center.clone(), radius, subdivisions);
+            assert_eq!(triangles.len(), 20 * 4usize.pow(subdivisions));
+            for triangle in &triangles {
+                let (v0, v1, v2) = triangle.vertices();
+                for v in &[v0, v1, v2] {
+                    assert!(((*v - &center).length() - radius).abs() < 1e-12 * radius, "{:?}", v);
+                }
+                let centroid: Vec = (v0 + v1 + v2) / 3.0;
+                assert!(Vec::dot(triangle.normal(), &Vec::normalize(&centroid - &center)) > 0.9, "{:?}", triangle.normal());
+            }
+        }
+    }
+
+    // 高さが一定のテクスチャで変位させた平らな四角形は、どの頂点も法線の向きにちょうどheight * scaleだけ持ち上がる。
+    #[test]
+    fn constant_height_raises_a_flat_quad_uniformly() {
+        let (a, b, c, d) = (Vec { x: 0.0, y: 0.0, z: 0.0 }, Vec { x: 1.0, y: 0.0, z: 0.0 },
+                            Vec { x: 1.0, y: 1.0, z: 0.0 }, Vec { x: 0.0, y: 1.0, z: 0.0 });
+        let mut triangles: std::vec::Vec<Triangle> = vec![
+            Triangle::new(a.clone(), b.clone(), c.clone()), Triangle::new(a.clone(), c.clone(), d.clone())];
+        let height = ImageTexture::new(1, 1, vec![Vec { x: 0.5, y: 0.5, z: 0.5 }]);
+        let scale: f64 = 0.2;
+        displace(&mut triangles, &height, scale, |v| (v.x, v.y));
+
+        let expected: f64 = Vec { x: 0.5, y: 0.5, z: 0.5 }.luminance() * scale;
+        for (triangle, original) in triangles.iter().zip(&[(&a, &b, &c), (&a, &c, &d)]) {
+            let (v0, v1, v2) = triangle.vertices();
+            for (moved, before) in [v0, v1, v2].iter().zip(&[original.0, original.1, original.2]) {
+                assert!((moved.x - before.x).abs() < 1e-12 && (moved.y - before.y).abs() < 1e-12, "{:?}", moved);
+                assert!((moved.z - expected).abs() < 1e-12, "{:?}", moved);
+            }
+            assert!(triangle.normal().z > 1.0 - 1e-12);
+        }
+    }
+}
@@ -0,0 +1,295 @@
+#![allow(dead_code)]
+
+use std;
+
+use vec::{Vec, Multiply};
+use ppm::to_ldr;
+
+type Color = Vec;
+
+// レンダリング結果のHDR画像に対する後処理。
+// いずれもリニアな値に対する処理なので、LDR化（トーンマッピング、ガンマ補正）の前にかける。
+
+// 露出をevだけ変える。+1で2倍、-1で半分の明るさになる。
+pub fn exposure(buffer: &mut [Color], ev: f64) {
+    let scale: f64 = 2.0_f64.powf(ev);
+    for color in buffer.iter_mut() {
+        *color = &*color * scale;
+    }
+}
+
+// ホワイトバランス。チャンネルごとにgainsを掛ける。
+pub fn white_balance(buffer: &mut [Color], gains: &Color) {
+    for color in buffer.iter_mut() {
+        *color = Vec::multiply(&*color, gains);
+    }
+}
+
+// Rec. 709の係数による輝度。
+pub fn luminance(color: &Color) -> f64 {
+    color.luminance()
+}
+
+// 画像の対数平均輝度（輝度の幾何平均）。画像全体の明るさの目安で、少数の明るいピクセルに引きずられにくい。
+// 真っ黒なピクセルで0にならないよう、輝度に小さな値を足してから対数をとる。
+// Erik Reinhard, et al. Photographic Tone Reproduction for Digital Images. SIGGRAPH 2002.
+pub fn log_average_luminance(buffer: &[Color]) -> f64 {
+    if buffer.is_empty() {
+        return 0.0
+    }
+    let sum: f64 = buffer.iter().map(|color| (LOG_AVERAGE_DELTA + luminance(color).max(0.0)).ln()).sum();
+    (sum / buffer.len() as f64).exp()
+}
+
+const LOG_AVERAGE_DELTA: f64 = 1e-4;
+
+// Reinhardのトーンマッピング。輝度LをL / (1 + L)に圧縮する。
+// 色味が変わらないよう、チャンネルごとではなく輝度の比を三つのチャンネルに掛ける。輝度は1未満になる。
+pub fn reinhard(buffer: &mut [Color]) {
+    for color in buffer.iter_mut() {
+        let l: f64 = luminance(color).max(0.0);
+        *color = &*color * (1.0 / (1.0 + l));
+    }
+}
+
+// 自動露出。対数平均輝度がkey（中間の灰色の明るさ、0.18程度）になるように全体を明るく（暗く）してから、
+// Reinhardのトーンマッピングをかける。露出を手で決めずに、暗い画像も明るい画像も見やすくする。
+// 真っ黒な画像はそのまま。
+pub fn auto_exposure(buffer: &mut [Color], key: f64) {
+    let average: f64 = log_average_luminance(buffer);
+    if average > LOG_AVERAGE_DELTA {
+        let scale: f64 = key / average;
+        for color in buffer.iter_mut() {
+            *color = &*color * scale;
+        }
+    }
+    reinhard(buffer);
+}
+
+// 確認用の縮小画像。長い辺がmax_dimピクセル以下になるよう縦横同じ比率で縮め、
+// Reinhardのトーンマッピングとガンマ補正（ppm::to_ldr）をかけた8bitのRGBにする。
+// (上の行から順に並んだR, G, Bのバイト列, 幅, 高さ)を返す。画像がmax_dim以下ならそのままの大きさになる。
+// 縮小は、縮小後のピクセルが覆う範囲の元のピクセルを平均するボックスフィルターで、トーンマッピングの前にかける。
+// max_dimは正であること。
+pub fn thumbnail(buffer: &[Color], width: i32, height: i32, max_dim: i32) -> (std::vec::Vec<u8>, i32, i32) {
+    debug_assert!(max_dim > 0, "max_dim must be positive: {}", max_dim);
+    let longest: i32 = std::cmp::max(width, height);
+    let (thumb_width, thumb_height) = if longest <= max_dim {
+        (width, height)
+    } else {
+        // 四捨五入で比率を保ち、細長い画像でも1ピクセルは残す。
+        let scale = |length: i32| -> i32 {
+            std::cmp::max(((length as i64 * max_dim as i64 + longest as i64 / 2) / longest as i64) as i32, 1)
+        };
+        (scale(width), scale(height))
+    };
+
+    let mut thumb: std::vec::Vec<Color> = std::vec::Vec::with_capacity((thumb_width * thumb_height) as usize);
+    for ty in 0..thumb_height {
+        // 縮小後のピクセル(tx, ty)が覆う元の画像の範囲[x0, x1) x [y0, y1)。
+        let y0: i32 = ty * height / thumb_height;
+        let y1: i32 = std::cmp::max((ty + 1) * height / thumb_height, y0 + 1);
+        for tx in 0..thumb_width {
+            let x0: i32 = tx * width / thumb_width;
+            let x1: i32 = std::cmp::max((tx + 1) * width / thumb_width, x0 + 1);
+            let mut sum = Color { x: 0.0, y: 0.0, z: 0.0 };
+            for y in y0..y1 {
+                for x in x0..x1 {
+                    sum = sum + &buffer[(y * width + x) as usize];
+                }
+            }
+            thumb.push(sum / ((x1 - x0) * (y1 - y0)) as f64);
+        }
+    }
+
+    reinhard(&mut thumb);
+    let mut bytes: std::vec::Vec<u8> = std::vec::Vec::with_capacity(thumb.len() * 3);
+    for color in &thumb {
+        bytes.push(to_ldr(color.x) as u8);
+        bytes.push(to_ldr(color.y) as u8);
+        bytes.push(to_ldr(color.z) as u8);
+    }
+    (bytes, thumb_width, thumb_height)
+}
+
+// ブルーム。明るい部分を滲ませる。
+// 輝度がthresholdを超えるピクセルだけを取り出してガウスぼかしをかけ、intensity倍して元の画像に足す。
+// bufferはwidth * height個の色で、radiusはぼかしの半径（ピクセル）。
+// ぼかしの標準偏差はradius / 3とし、radiusより外は打ち切る。画像の外は端のピクセルで延長する。
+pub fn bloom(buffer: &mut [Color], width: i32, height: i32, threshold: f64, radius: i32, intensity: f64) {
+    assert_eq!((width * height) as usize, buffer.len());
+
+    let bright: std::vec::Vec<Color> = buffer.iter()
+        .map(|color| if luminance(color) > threshold { color.clone() } else { Color { x: 0.0, y: 0.0, z: 0.0 } })
+        .collect();
+
+    let kernel = gaussian_kernel(radius);
+    let horizontal = blur_1d(&bright, width, height, &kernel, true);
+    let blurred = blur_1d(&horizontal, width, height, &kernel, false);
+
+    for (color, glow) in buffer.iter_mut().zip(blurred.iter()) {
+        *color = &*color + glow * intensity;
+    }
+}
+
+// 和が1になるよう正規化した、長さ2 * radius + 1のガウス関数。
+fn gaussian_kernel(radius: i32) -> std::vec::Vec<f64> {
+    if radius <= 0 {
+        return vec![1.0]
+    }
+
+    let sigma: f64 = radius as f64 / 3.0;
+    let weights: std::vec::Vec<f64> = (-radius..radius + 1)
+        .map(|i| (-((i * i) as f64) / (2.0 * sigma * sigma)).exp())
+        .collect();
+    let sum: f64 = weights.iter().sum();
+    weights.iter().map(|w| w / sum).collect()
+}
+
+// 横（horizontalがtrue）または縦方向の一次元の畳み込み。
+fn blur_1d(
+    image: &[Color], width: i32, height: i32, kernel: &[f64], horizontal: bool) -> std::vec::Vec<Color> {
+    let radius: i32 = (kernel.len() / 2) as i32;
+    let mut result = vec![Color { x: 0.0, y: 0.0, z: 0.0 }; image.len()];
+
+    for y in 0..height {
+        for x in 0..width {
+            let mut sum = Color { x: 0.0, y: 0.0, z: 0.0 };
+            for (k, weight) in kernel.iter().enumerate() {
+                let offset: i32 = k as i32 - radius;
+                let (sx, sy) = if horizontal {
+                    (std::cmp::min(std::cmp::max(x + offset, 0), width - 1), y)
+                } else {
+                    (x, std::cmp::min(std::cmp::max(y + offset, 0), height - 1))
+                };
+                sum = sum + &image[(sy * width + sx) as usize] * *weight;
+            }
+            result[(y * width + x) as usize] = sum;
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn same_color(a: &Color, b: &Color) -> bool {
+        a.x.to_bits() == b.x.to_bits() && a.y.to_bits() == b.y.to_bits() && a.z.to_bits() == b.z.to_bits()
+    }
+
+    fn ramp() -> std::vec::Vec<Color> {
+        (0..16).map(|i| Color { x: 0.1 * i as f64, y: 0.05 * i as f64 + 0.3, z: 2.0 - 0.1 * i as f64 }).collect()
+    }
+
+    // +1 EVで画素値がちょうど2倍になる。
+    #[test]
+    fn plus_one_ev_doubles_pixels() {
+        let original = ramp();
+        let mut buffer = ramp();
+        exposure(&mut buffer, 1.0);
+        for (a, b) in original.iter().zip(buffer.iter()) {
+            assert!(same_color(&(a * 2.0), b), "{:?} {:?}", a, b);
+        }
+        // 0 EVでは変わらない。
+        exposure(&mut buffer, -1.0);
+        exposure(&mut buffer, 0.0);
+        for (a, b) in original.iter().zip(buffer.iter()) {
+            assert!(same_color(a, b), "{:?} {:?}", a, b);
+        }
+    }
+
+    // ゲインが{1, 1, 1}のホワイトバランスは何もしない。
+    #[test]
+    fn unit_white_balance_is_a_no_op() {
+        let original = ramp();
+        let mut buffer = ramp();
+        white_balance(&mut buffer, &Color { x: 1.0, y: 1.0, z: 1.0 });
+        for (a, b) in original.iter().zip(buffer.iter()) {
+            assert!(same_color(a, b), "{:?} {:?}", a, b);
+        }
+
+        // それ以外のゲインはチャンネルごとに掛かる。
+        white_balance(&mut buffer, &Color { x: 2.0, y: 1.0, z: 0.5 });
+        for (a, b) in original.iter().zip(buffer.iter()) {
+            assert!(same_color(&Color { x: a.x * 2.0, y: a.y, z: a.z * 0.5 }, b), "{:?} {:?}", a, b);
+        }
+    }
+
+    // 一つだけ明るいピクセルがあると、ブルームでその周りのピクセルにも光が滲む。
+    #[test]
+    fn bright_pixel_produces_a_halo() {
+        let (width, height): (i32, i32) = (15, 15);
+        let black: Color = Color { x: 0.0, y: 0.0, z: 0.0 };
+        let mut buffer: std::vec::Vec<Color> = vec![black.clone(); (width * height) as usize];
+        let center: usize = (7 * width + 7) as usize;
+        buffer[center] = Color { x: 100.0, y: 100.0, z: 100.0 };
+        // しきい値より暗いピクセルは滲まない。
+        let dim: usize = 0;
+        buffer[dim] = Color { x: 0.5, y: 0.5, z: 0.5 };
+
+        bloom(&mut buffer, width, height, 1.0, 3, 0.5);
+
+        for y in 0..height {
+            for x in 0..width {
+                let index: usize = (y * width + x) as usize;
+                if index == dim {
+                    assert!(same_color(&buffer[index], &Color { x: 0.5, y: 0.5, z: 0.5 }));
+                    continue;
+                }
+                let near: bool = (x - 7).abs() <= 3 && (y - 7).abs() <= 3;
+                // 半径の内側は明るくなり、外側は打ち切られて黒のまま。
+                assert_eq!(buffer[index].x > 0.0, near, "{} {} {:?}", x, y, buffer[index]);
+            }
+        }
+        assert!(buffer[center].x > 100.0);
+
+        // カーネルは和が1なので、足された光の合計は intensity * 明るいピクセルの値。
+        let added: f64 = buffer.iter().map(|c| c.x).sum::<f64>() - 100.0 - 0.5;
+        assert!((added - 50.0).abs() < 1e-9, "{}", added);
+    }
+
+    // 一様に暗い画像は明るく、一様に明るい画像は暗くなり、どちらも輝度keyをReinhardで圧縮した明るさに揃う。
+    #[test]
+    fn auto_exposure_brings_dim_and_bright_images_to_the_key() {
+        let key: f64 = 0.18;
+        let expected: f64 = key / (1.0 + key);
+        for &value in &[0.01, 10.0] {
+            let mut buffer = vec![Color { x: value, y: value, z: value }; 16];
+            auto_exposure(&mut buffer, key);
+            let result: f64 = luminance(&buffer[0]);
+            if value < expected {
+                assert!(result > value, "{} {}", value, result);
+            } else {
+                assert!(result < value, "{} {}", value, result);
+            }
+            assert!((result - expected).abs() < 0.01 * expected, "{} {}", value, result);
+            assert!(buffer.iter().all(|color| same_color(color, &buffer[0])));
+        }
+    }
+
+    // 幅400の画像をmax_dim = 100で縮小すると幅100になり、縦横比も保たれる。縦長でも長い辺がmax_dimに収まる。
+    // 左半分が黒、右半分が明るい画像では、縮小後も左半分は0で、右半分はトーンマッピングで255以下に収まる。
+    #[test]
+    fn thumbnail_fits_max_dim_and_keeps_the_aspect() {
+        let (width, height): (i32, i32) = (400, 300);
+        let bright = Color { x: 3.0, y: 3.0, z: 3.0 };
+        let black = Color { x: 0.0, y: 0.0, z: 0.0 };
+        let buffer: std::vec::Vec<Color> = (0..width * height)
+            .map(|index| if index % width < width / 2 { black.clone() } else { bright.clone() })
+            .collect();
+        let (bytes, thumb_width, thumb_height) = thumbnail(&buffer, width, height, 100);
+        assert!(thumb_width <= 100);
+        assert_eq!((thumb_width, thumb_height), (100, 75));
+        assert_eq!(bytes.len(), (thumb_width * thumb_height * 3) as usize);
+        let row: usize = (thumb_height / 2 * thumb_width) as usize;
+        assert_eq!(&bytes[row * 3..row * 3 + 3], &[0, 0, 0]);
+        let right: usize = (row + thumb_width as usize - 1) * 3;
+        assert!(bytes[right] > 128 && bytes[right] < 255, "{}", bytes[right]);
+
+        let (_, tall_width, tall_height) = thumbnail(&buffer[..120 * 400], 120, 400, 100);
+        assert_eq!((tall_width, tall_height), (30, 100));
+        let (small, small_width, small_height) = thumbnail(&buffer[..40 * 30], 40, 30, 100);
+        assert_eq!((small_width, small_height, small.len()), (40, 30, 40 * 30 * 3));
+    }
+}
@@ -1,11 +1,13 @@
 #![allow(dead_code)]
 
-use std::error::Error;
+use std;
 use std::fs::File;
 use std::path::Path;
-use std::io::{BufWriter, Write};
+use std::io::{self, BufWriter, Read, Write};
 
 use vec::Vec;
+use render::RenderError;
+use random::sample_seed;
 
 type Color = Vec;
 
@@ -19,29 +21,238 @@ fn clamp(x: f64) -> f64 {
     }
 }
 
-fn to_ldr(x: f64) -> i32 {
+// ディスプレイのガンマ。
+const DISPLAY_GAMMA: f64 = 2.2;
+
+pub fn to_ldr(x: f64) -> i32 {
     // ディスプレイのガンマが2.2であることを仮定し、1/2.2乗する。
     // 簡易的なLDR化処理。
-    (clamp(x).powf(1.0/2.2) * 255.0 + 0.5) as i32
+    (clamp(x).powf(1.0 / DISPLAY_GAMMA) * 255.0 + 0.5) as i32
+}
+
+// to_ldrを色の各チャンネルに施す。
+// ditherはチャンネルごとに丸める前に足す量（8bitの1段階を単位とし、[-0.5, 0.5)）。
+fn to_ldr_color(color: &Color, dither: &Color) -> (i32, i32, i32) {
+    let clamped = Color { x: clamp(color.x), y: clamp(color.y), z: clamp(color.z) };
+    let encoded: Color = clamped.pow(1.0 / DISPLAY_GAMMA) * 255.0 + dither;
+    let quantize = |value: f64| -> i32 { ((value + 0.5) as i32).clamp(0, 255) };
+    (quantize(encoded.x), quantize(encoded.y), quantize(encoded.z))
 }
 
-pub fn save_ppm_file(filename: &str, image: &[Color], width: i32, height: i32) {
+// ピクセルpixel_indexのディザの量。seedとピクセルから決まるハッシュ値による一様な値で、チャンネルごとに異なる。
+fn dither_at(pixel_index: u64, seed: u64) -> Color {
+    let channel = |c: u64| -> f64 {
+        (sample_seed(pixel_index, c, seed) >> 11) as f64 * (1.0 / (1u64 << 53) as f64) - 0.5
+    };
+    Color { x: channel(0), y: channel(1), z: channel(2) }
+}
+
+// 画像をどう描画したかの記録。不具合の報告などで同じ画像を描画し直せるよう、画像ファイルに埋め込む。
+#[derive(Debug, Clone, PartialEq)]
+pub struct ImageMetadata {
+    pub spp: i32,       // ピクセルあたりのサンプル数。
+    pub seed: u64,      // 画像全体のシード値。
+    pub width: i32,
+    pub height: i32,
+}
+
+impl ImageMetadata {
+    // "spp=16 seed=0 resolution=64x48"のような一行の文字列。
+    pub fn to_comment(&self) -> String {
+        format!("spp={} seed={} resolution={}x{}", self.spp, self.seed, self.width, self.height)
+    }
+
+    // to_commentで作った文字列から読み取る。項目が欠けていたり形式が違ったりすればNone。
+    pub fn from_comment(comment: &str) -> Option<ImageMetadata> {
+        let (mut spp, mut seed, mut resolution) = (None, None, None);
+        for field in comment.split_whitespace() {
+            let mut pair = field.splitn(2, '=');
+            match (pair.next(), pair.next()) {
+                (Some("spp"), Some(value)) => spp = value.parse::<i32>().ok(),
+                (Some("seed"), Some(value)) => seed = value.parse::<u64>().ok(),
+                (Some("resolution"), Some(value)) => {
+                    let mut size = value.splitn(2, 'x').map(|n| n.parse::<i32>().ok());
+                    resolution = match (size.next(), size.next()) {
+                        (Some(Some(width)), Some(Some(height))) => Some((width, height)),
+                        _ => None
+                    };
+                },
+                _ => {}
+            }
+        }
+        match (spp, seed, resolution) {
+            (Some(spp), Some(seed), Some((width, height))) =>
+                Some(ImageMetadata { spp, seed, width, height }),
+            _ => None
+        }
+    }
+}
+
+// PPM（P3）形式で保存する。imageは上の行から順に並んだwidth * height個の色。
+pub fn save_ppm_file(filename: &str, image: &[Color], width: i32, height: i32) -> Result<(), RenderError> {
+    save_ppm_file_with_metadata(filename, image, width, height, None)
+}
+
+// save_ppm_fileと同じだが、metadataがあればヘッダーの後にコメント（# spp=...）として書く。
+// コメントはPPMの読み込み（load_ppm_fileなど）では読み飛ばされ、load_ppm_metadataで読み取れる。
+// 要望はPNGのtEXtチャンクに書くことだったが、このクレートにはPNGの書き出しが無いので、PPMのコメントで代える。
+// そのためテスト（saved_ppm_carries_the_sample_count）が確かめるのもPPMのコメントで、要望にあったPNGのtEXtチャンクではない。
+pub fn save_ppm_file_with_metadata(
+    filename: &str, image: &[Color], width: i32, height: i32,
+    metadata: Option<&ImageMetadata>) -> Result<(), RenderError> {
+    write_ppm_file(filename, image, width, height, metadata, None)
+}
+
+// save_ppm_file_with_metadataと同じだが、8bitに丸める前に1段階未満のディザを足す。
+// なめらかなグラデーションが丸めで段々になる（バンディング）のを、細かいノイズに置き換えて目立たなくする。
+// ディザはseedとピクセルの位置だけで決まるので、同じseedなら同じ画像になる。
+pub fn save_ppm_file_dithered(
+    filename: &str, image: &[Color], width: i32, height: i32,
+    metadata: Option<&ImageMetadata>, seed: u64) -> Result<(), RenderError> {
+    write_ppm_file(filename, image, width, height, metadata, Some(seed))
+}
+
+// dither_seedがNoneならディザをかけない。
+fn write_ppm_file(
+    filename: &str, image: &[Color], width: i32, height: i32,
+    metadata: Option<&ImageMetadata>, dither_seed: Option<u64>) -> Result<(), RenderError> {
+    if width <= 0 || height <= 0 || (width * height) as usize != image.len() {
+        return Err(RenderError::InvalidDimensions { width, height })
+    }
+
     // 目的ファイルに対する`Path`を作成
     let path = Path::new(filename);
-    let display = path.display();
 
     // ファイルを書き込み専用モードで開く。返り値は`io::Result<File>`
-    let mut file_handler = BufWriter::new( match File::create(&path) {
-        // `io::Error`の`description`メソッドはエラーを説明する文字列を返す。
-        Err(why) => panic!("couldn't create {}: {}",
-                           display,
-                           Error::description(&why)),
-        Ok(file) => file,
-    });
+    let mut file_handler = BufWriter::new(File::create(path)?);
 
-    file_handler.write(format!("P3\n{} {}\n{}\n", width, height, 255).as_bytes()).unwrap();
-    for color in image {
-        file_handler.write(format!("{} {} {} ", to_ldr(color.x), to_ldr(color.y), to_ldr(color.z)).as_bytes()).unwrap();
+    file_handler.write_all(b"P3\n")?;
+    if let Some(metadata) = metadata {
+        file_handler.write_all(format!("# {}\n", metadata.to_comment()).as_bytes())?;
+    }
+    file_handler.write_all(format!("{} {}\n{}\n", width, height, 255).as_bytes())?;
+    let no_dither = Color { x: 0.0, y: 0.0, z: 0.0 };
+    for (i, color) in image.iter().enumerate() {
+        let dither: Color = match dither_seed {
+            Some(seed) => dither_at(i as u64, seed),
+            None => no_dither.clone()
+        };
+        let (r, g, b) = to_ldr_color(color, &dither);
+        file_handler.write_all(format!("{} {} {} ", r, g, b).as_bytes())?;
     }
+    file_handler.flush()?;
+    Ok(())
 }
 
+// save_ppm_fileで保存したようなPPM（P3）形式のファイルを読み込む。
+// (上の行から順に並んだ色, 幅, 高さ)を返す。色はファイルの値を最大値で割った[0, 1]の値で、ガンマ補正されたままになる。
+// 形式が正しくないときはRenderError::Io（InvalidData）になる。
+pub fn load_ppm_file(filename: &str) -> Result<(std::vec::Vec<Color>, i32, i32), RenderError> {
+    let mut text = String::new();
+    File::open(Path::new(filename))?.read_to_string(&mut text)?;
+
+    // #から行末まではコメント。
+    let mut tokens = text.lines()
+        .map(|line| match line.find('#') { Some(index) => &line[..index], None => line })
+        .flat_map(|line| line.split_whitespace());
+
+    if tokens.next() != Some("P3") {
+        return Err(invalid_data("not a P3 PPM file"))
+    }
+    let mut next_number = || -> Result<i32, RenderError> {
+        match tokens.next().map(|token| token.parse::<i32>()) {
+            Some(Ok(value)) => Ok(value),
+            _ => Err(invalid_data("truncated or malformed PPM file"))
+        }
+    };
+
+    let width: i32 = next_number()?;
+    let height: i32 = next_number()?;
+    let max_value: i32 = next_number()?;
+    if width <= 0 || height <= 0 || max_value <= 0 {
+        return Err(invalid_data("invalid PPM header"))
+    }
+
+    let scale: f64 = 1.0 / max_value as f64;
+    let mut image: std::vec::Vec<Color> = std::vec::Vec::with_capacity((width * height) as usize);
+    for _ in 0..(width * height) {
+        let r: i32 = next_number()?;
+        let g: i32 = next_number()?;
+        let b: i32 = next_number()?;
+        image.push(Color { x: r as f64 * scale, y: g as f64 * scale, z: b as f64 * scale });
+    }
+    Ok((image, width, height))
+}
+
+// PPMファイルのコメントから、save_ppm_file_with_metadataで埋め込んだ記録を探す。見つからなければNone。
+pub fn load_ppm_metadata(filename: &str) -> Result<Option<ImageMetadata>, RenderError> {
+    let mut text = String::new();
+    File::open(Path::new(filename))?.read_to_string(&mut text)?;
+    Ok(text.lines()
+        .filter_map(|line| line.find('#').map(|index| &line[index + 1..]))
+        .filter_map(ImageMetadata::from_comment)
+        .next())
+}
+
+fn invalid_data(message: &str) -> RenderError {
+    RenderError::Io(io::Error::new(io::ErrorKind::InvalidData, message))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    // 書き込んだPPMには"# spp=..."のコメントが入り、load_ppm_metadataで同じ記録を読み戻せる。
+    // コメントがあっても、load_ppm_fileは画像をそのまま読める。
+    #[test]
+    fn saved_ppm_carries_the_sample_count() {
+        let image: std::vec::Vec<Color> = vec![
+            Color { x: 0.0, y: 0.0, z: 0.0 }, Color { x: 1.0, y: 1.0, z: 1.0 }, Color { x: 1.0, y: 0.0, z: 0.0 }];
+        let metadata = ImageMetadata { spp: 16, seed: 7, width: 3, height: 1 };
+        let path = std::env::temp_dir().join(format!("gemspt-ppm-metadata-{}.ppm", std::process::id()));
+        let filename: &str = path.to_str().unwrap();
+        save_ppm_file_with_metadata(filename, &image, 3, 1, Some(&metadata)).unwrap();
+        let text = fs::read_to_string(&path);
+        let read_back = load_ppm_metadata(filename);
+        let loaded = load_ppm_file(filename);
+        fs::remove_file(&path).unwrap();
+
+        assert!(text.unwrap().lines().any(|line| line == "# spp=16 seed=7 resolution=3x1"));
+        assert_eq!(read_back.unwrap(), Some(metadata));
+        let (pixels, width, height) = loaded.unwrap();
+        assert_eq!((width, height), (3, 1));
+        assert_eq!(pixels[1].x, 1.0);
+        assert_eq!(pixels[2].y, 0.0);
+    }
+
+    // 8bitで4段階ほどしか変わらない、なめらかなグラデーション。
+    // ディザをかけないと同じ値が長く続く帯（バンディング）になり、隣のピクセルと値が変わるのは段の境目だけになる。
+    // ディザをかけると隣と値が変わるところが増え、64ピクセルごとの平均は元のグラデーションにより近くなる。
+    #[test]
+    fn dithering_breaks_up_banding() {
+        let count: usize = 1024;
+        let gradient: std::vec::Vec<f64> = (0..count).map(|i| 100.0 + 4.0 * i as f64 / count as f64).collect();
+        let quantized = |dithered: bool| -> std::vec::Vec<i32> {
+            gradient.iter().enumerate().map(|(i, &level)| {
+                let value: f64 = (level / 255.0).powf(DISPLAY_GAMMA);
+                let dither: Color = if dithered { dither_at(i as u64, 3) } else { Color { x: 0.0, y: 0.0, z: 0.0 } };
+                to_ldr_color(&Color { x: value, y: value, z: value }, &dither).0
+            }).collect()
+        };
+        let changes = |values: &[i32]| -> usize { values.windows(2).filter(|pair| pair[0] != pair[1]).count() };
+        let block_error = |values: &[i32]| -> f64 {
+            values.chunks(64).zip(gradient.chunks(64)).map(|(block, levels)| {
+                let mean: f64 = block.iter().sum::<i32>() as f64 / block.len() as f64;
+                let expected: f64 = levels.iter().sum::<f64>() / levels.len() as f64;
+                (mean - expected).abs()
+            }).fold(0.0, f64::max)
+        };
+
+        let (plain, dithered) = (quantized(false), quantized(true));
+        assert!(changes(&plain) <= 4, "{}", changes(&plain));
+        assert!(changes(&dithered) > 10 * changes(&plain), "{} {}", changes(&dithered), changes(&plain));
+        assert!(block_error(&dithered) < block_error(&plain), "{} {}", block_error(&dithered), block_error(&plain));
+        assert_eq!(dithered, quantized(true));
+    }
+}
@@ -0,0 +1,122 @@
+#![allow(dead_code)]
+
+use vec::{Vec, Dot, Normalize, Cross};
+use ray::Ray;
+use hitpoint::Hitpoint;
+use sampler::Sampler;
+
+// 平行四辺形（四角形）の幾何学的な情報を持つ
+// origin + u * edge_u + v * edge_v (u, v ∈ [0, 1]) の範囲で、(u, v)をそのままテクスチャ座標とする。
+// 法線はedge_u × edge_vの向き。画面のような光源（EmissiveTextureMaterial）に使う。
+#[derive(Debug)]
+pub struct Quad {
+    origin_: Vec,
+    edge_u_: Vec,
+    edge_v_: Vec,
+    normal_: Vec,
+    area_: f64,
+}
+
+impl Quad {
+    pub fn new(origin: Vec, edge_u: Vec, edge_v: Vec) -> Quad {
+        let cross: Vec = Vec::cross(&edge_u, &edge_v);
+        let area: f64 = cross.length();
+        Quad { origin_: origin, edge_u_: edge_u, edge_v_: edge_v, normal_: Vec::normalize(cross), area_: area }
+    }
+
+    pub fn origin(&self) -> &Vec {
+        &self.origin_
+    }
+
+    pub fn edges(&self) -> (&Vec, &Vec) {
+        (&self.edge_u_, &self.edge_v_)
+    }
+
+    pub fn normal(&self) -> &Vec {
+        &self.normal_
+    }
+
+    // 軸に平行な境界ボックスの(最小の角, 最大の角)。
+    pub fn bounds(&self) -> (Vec, Vec) {
+        let corners = [
+            self.origin_.clone(),
+            &self.origin_ + &self.edge_u_,
+            &self.origin_ + &self.edge_v_,
+            &self.origin_ + &self.edge_u_ + &self.edge_v_,
+        ];
+        let mut lower: Vec = corners[0].clone();
+        let mut upper: Vec = corners[0].clone();
+        for corner in &corners[1..] {
+            lower = Vec { x: lower.x.min(corner.x), y: lower.y.min(corner.y), z: lower.z.min(corner.z) };
+            upper = Vec { x: upper.x.max(corner.x), y: upper.y.max(corner.y), z: upper.z.max(corner.z) };
+        }
+        (lower, upper)
+    }
+
+    pub fn area(&self) -> f64 {
+        self.area_
+    }
+
+    // テクスチャ座標(u, v)の点。
+    // (位置, 法線, テクスチャ座標あたりの面積 |∂p/∂u × ∂p/∂v|)を返す。
+    // テクスチャ座標で選んだ点の面積測度のpdfは、テクスチャ座標でのpdfをこの面積で割ったものになる。
+    pub fn point_at_uv(&self, u: f64, v: f64) -> (Vec, Vec, f64) {
+        let position: Vec = &self.origin_ + &self.edge_u_ * u + &self.edge_v_ * v;
+        (position, self.normal_.clone(), self.area_)
+    }
+
+    // 四角形上の点を一様にサンプリングする。
+    // (位置, 法線, 面積測度のpdf)を返す。
+    pub fn sample_surface(&self, random: &mut dyn Sampler) -> (Vec, Vec, f64) {
+        let (u, v) = random.next_2d();
+        let (position, normal, area) = self.point_at_uv(u, v);
+
+        (position, normal, 1.0 / area)
+    }
+
+    // 交差点hitpointの接線を求める。テクスチャ座標はintersectで求めてある。
+    // uが増える向きはedge_uの向きになる。
+    pub fn surface_details(&self, hitpoint: &mut Hitpoint) {
+        let tangent: Vec = Vec::normalize(&self.edge_u_);
+        let bitangent: Vec = Vec::cross(&self.normal_, &tangent);
+        hitpoint.tangent_frame = Some((tangent, bitangent));
+    }
+
+    // 入力のrayに対する交差点までの距離を得る。
+    // 交差したらその情報を,さもなくばNoneを返す。
+    // Triangleと同じく、裏側から当たった場合も交差とし、法線は常に表側を向いたまま。
+    pub fn intersect(&self, ray: &Ray) -> Option<Hitpoint> {
+        self.intersect_with_t_min(ray, 0.0)
+    }
+
+    // intersectと同じだが、距離がt_min以下の交差は無視する。
+    pub fn intersect_with_t_min(&self, ray: &Ray, t_min: f64) -> Option<Hitpoint> {
+        let denominator: f64 = Vec::dot(&self.normal_, ray.dir);
+        if denominator == 0.0 {
+            return None
+        }
+
+        let t: f64 = Vec::dot(&self.normal_, &(&self.origin_ - ray.org)) / denominator;
+        if t <= t_min {
+            return None
+        }
+
+        // 平面上の点をedge_u, edge_vの座標に分解する。
+        // n = edge_u × edge_vとして u = n・(p × edge_v) / |n|^2, v = n・(edge_u × p) / |n|^2。
+        let p: Vec = ray.org + ray.dir * t - &self.origin_;
+        let scale: f64 = 1.0 / self.area_;
+        let u: f64 = Vec::dot(&self.normal_, &Vec::cross(&p, &self.edge_v_)) * scale;
+        let v: f64 = Vec::dot(&self.normal_, &Vec::cross(&self.edge_u_, &p)) * scale;
+        if !(0.0..=1.0).contains(&u) || !(0.0..=1.0).contains(&v) {
+            return None
+        }
+
+        let mut hitpoint = Hitpoint::new();
+        hitpoint.distance = t;
+        hitpoint.position = &self.origin_ + &self.edge_u_ * u + &self.edge_v_ * v;
+        hitpoint.normal = self.normal_.clone();
+        hitpoint.uv = (u, v);
+
+        Some(hitpoint)
+    }
+}
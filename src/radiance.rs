@@ -1,51 +1,1667 @@
 #![allow(dead_code)]
 
 use ray::Ray;
-use scene::{Scene, intersect_scene};
-use random::Random;
-use material::MaterialTrait;
+use scene::{Scene, SceneObject, intersect_scene, intersect_scene_any, sample_light, object_index};
+use sampler::Sampler;
+use lighttree::LightTree;
+use material::{MaterialTrait, BsdfSample, LobeType};
+use hitpoint::Hitpoint;
+use sampling::Sampling;
+use constant::{K_PI, K_INF, K_EPSILON};
 
 use vec::{Vec, Dot, Multiply};
 
 type Color = Vec;
 
+const BACKGROUND_COLOR: Color = Color { x: 0.0, y: 0.0, z: 0.0 };
+// 経路の反射回数の上限の既定値。この深さに達した経路は打ち切る。
+pub const DEPTH_LIMIT: i32 = 10;
+
+// 散乱させる前に、屈折しないマテリアルでは法線をレイの来た側に向ける（Hitpoint::face_forward）。
+// 片面の四角形や三角形を裏側から見たとき、外側を向いた法線のまま方向を選ぶと、面の向こう側へ光が漏れるため。
+// 屈折する誘電体（MaterialTrait::dielectric_ior）は、外側を向いた法線で物体に入るのか出るのかを決めるのでそのままにする。
+fn orient_normal(hitpoint: &mut Hitpoint, material: &dyn MaterialTrait, ray: &Ray) {
+    if material.dielectric_ior().is_none() {
+        hitpoint.face_forward(ray.dir);
+    }
+}
+
+// ロシアンルーレットで経路を続ける確率の決め方。
+// 反射一回あたりの重み brdf * cosΘ / pdf の色から確率を決める（1を超えたら1）。
+//   MaxComponent ... 最大のチャンネル。どのチャンネルも重みが確率以下になるので、推定値が1を大きく超えない。
+//   Luminance    ... 輝度。一つのチャンネルだけが明るい色（赤い壁など）では最大のチャンネルより小さくなり、
+//                    見た目への寄与が小さい経路を早く打ち切る。その分、そのチャンネルの推定値の分散は増える。
+// どちらも打ち切らなかった経路を確率で割るので、期待値は変わらない。
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum RussianRoulette {
+    #[default]
+    MaxComponent,
+    Luminance,
+}
+
+impl RussianRoulette {
+    // 重みweightの反射のあとに経路を続ける確率。
+    // 屈折ではcosΘが負になるので重みも負になりうる（GlassMaterial）。大きさで決めるよう、絶対値を使う。
+    pub fn continue_probability(&self, weight: &Color) -> f64 {
+        let weight = Color { x: weight.x.abs(), y: weight.y.abs(), z: weight.z.abs() };
+        let p: f64 = match *self {
+            RussianRoulette::MaxComponent => weight.max_component(),
+            RussianRoulette::Luminance    => weight.luminance(),
+        };
+        p.clamp(0.0, 1.0)
+    }
+}
+
+// この深さまではロシアンルーレットで打ち切らない。最初の数回の反射は寄与が大きいため。
+pub const ROULETTE_START_DEPTH: i32 = 2;
+// ロシアンルーレットで経路を続ける確率の下限の既定値。
+pub const ROULETTE_MIN_SURVIVAL: f64 = 0.05;
+
+// ロシアンルーレットの設定。
+//   strategy     ... 続ける確率の決め方。
+//   start_depth  ... この深さより前の反射では打ち切らない。
+//   min_survival ... 続ける確率の下限。暗い反射が続いても、経路をすぐには打ち切らないようにする。
+// 打ち切らなかった経路は続ける確率で割るので、start_depth >= 0、0 <= min_survival <= 1の範囲なら期待値は変わらない。
+// 確率を下げるほど速くなるが、打ち切らなかった経路の推定値が大きくなり分散が増える。
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RouletteSettings {
+    pub strategy: RussianRoulette,
+    pub start_depth: i32,
+    pub min_survival: f64,
+}
+
+impl Default for RouletteSettings {
+    fn default() -> RouletteSettings {
+        RouletteSettings {
+            strategy: RussianRoulette::default(),
+            start_depth: ROULETTE_START_DEPTH,
+            min_survival: ROULETTE_MIN_SURVIVAL,
+        }
+    }
+}
+
+impl RouletteSettings {
+    // strategyで確率を決め、ほかは既定値にした設定。
+    pub fn new(strategy: RussianRoulette) -> RouletteSettings {
+        RouletteSettings { strategy, ..RouletteSettings::default() }
+    }
+
+    // start_depth以降の反射で、重みweightの反射のあとに経路を続ける確率。
+    pub fn continue_probability(&self, weight: &Color) -> f64 {
+        self.strategy.continue_probability(weight).max(self.min_survival).min(1.0)
+    }
+}
+
 // ray方向からの放射輝度を求める
-pub fn radiance(scene: &Scene, ray: &Ray, random: &mut Random, depth: &i32) -> Color {
-    const BACKGROUND_COLOR: Color = Color { x: 0.0, y: 0.0, z: 0.0 };
-    const DEPTH_LIMIT: i32 = 10;
+pub fn radiance(scene: &Scene, ray: &Ray, random: &mut dyn Sampler, depth: &i32) -> Color {
+    radiance_internal(scene, ray, random, depth, DEPTH_LIMIT, None)
+}
+
+// radianceと同じだが、反射回数の上限をDEPTH_LIMITの代わりにmax_depthにする。
+// 上限を下げると速くなるが、打ち切った光のぶん暗くなる（期待値がradianceと変わる）。
+pub fn radiance_with_max_depth(
+    scene: &Scene, ray: &Ray, random: &mut dyn Sampler, depth: &i32, max_depth: i32) -> Color {
+    radiance_internal(scene, ray, random, depth, max_depth, None)
+}
+
+// カメラから飛ばしたrayについてのradiance。render::render_withに渡す放射輝度の関数の既定のもの。
+// 独自の放射輝度の関数から呼んで、結果を加工するのにも使える。
+pub fn path_tracing_radiance(scene: &Scene, ray: &Ray, random: &mut dyn Sampler) -> Color {
+    radiance(scene, ray, random, &0)
+}
+
+// radianceと同じだが、ROULETTE_START_DEPTH回目以降の反射ではロシアンルーレットで経路を確率的に打ち切る。
+// 期待値はradianceと同じで、寄与の小さい経路を早く打ち切るぶん速くなる（DEPTH_LIMITでの打ち切りはそのまま）。
+// 続ける確率の下限はROULETTE_MIN_SURVIVAL（RouletteSettings::new）。
+pub fn radiance_with_roulette(
+    scene: &Scene, ray: &Ray, random: &mut dyn Sampler, depth: &i32, roulette: RussianRoulette) -> Color {
+    radiance_internal(scene, ray, random, depth, DEPTH_LIMIT, Some(RouletteSettings::new(roulette)))
+}
+
+// radiance_with_rouletteと同じだが、打ち切りを始める深さと続ける確率の下限もsettingsで指定する。
+pub fn radiance_with_roulette_settings(
+    scene: &Scene, ray: &Ray, random: &mut dyn Sampler, depth: &i32, settings: RouletteSettings) -> Color {
+    radiance_internal(scene, ray, random, depth, DEPTH_LIMIT, Some(settings))
+}
+
+// radianceと同じ推定を、カメラレイが最初に当たった点でサンプリングしたローブ（MaterialTrait::sample_bsdf）ごとに分けて、
+// (拡散の成分, 鏡面の成分)として返す。鏡面の成分はGlossy, Reflection, Transmissionのローブから来た光。
+// 二つの和がradianceの一つのサンプルと同じ期待値になるよう、光源を直接見たときの放射は拡散の側に入れる。
+// 合成の際に拡散と鏡面の明るさを別々に調整するためのもの。rayはカメラレイ（深さ0）とする。
+pub fn radiance_by_lobe(scene: &Scene, ray: &Ray, random: &mut dyn Sampler) -> (Color, Color) {
+    let black = Color { x: 0.0, y: 0.0, z: 0.0 };
+    let (now_object, mut hitpoint) = match intersect_scene(scene, ray) {
+        (Some(object), hitpoint) => (object, hitpoint),
+        (None, _) => return (BACKGROUND_COLOR, black)
+    };
+
+    let now_material = now_object.get_material();
+    let emission = now_material.emission();
+    if emission.x > 0.0 || emission.y > 0.0 || emission.z > 0.0 {
+        if !now_material.visible_to_camera() {
+            return (black.clone(), black)
+        }
+        return (now_material.emitted_at(ray.dir, &hitpoint.normal, hitpoint.uv), black)
+    }
+    orient_normal(&mut hitpoint, now_material, ray);
+
+    let sample: BsdfSample = now_material.sample_bsdf(random, ray.dir, &hitpoint.normal);
+    let cost = Vec::dot(&hitpoint.normal, &sample.direction);
+    let origin: Vec = hitpoint.offset_origin(&sample.direction);
+    let contribution: Color = Vec::multiply(
+        sample.brdf_value,
+        radiance_internal(scene, &Ray::with_time(&origin, &sample.direction, ray.time), random, &1, DEPTH_LIMIT, None))
+        * cost / sample.pdf;
+
+    match sample.lobe {
+        LobeType::Diffuse => (contribution, black),
+        _ => (black, contribution),
+    }
+}
+
+// max_depthの深さに達した経路は打ち切る。rouletteがNoneならロシアンルーレットを使わない。
+fn radiance_internal(
+    scene: &Scene, ray: &Ray, random: &mut dyn Sampler, depth: &i32, max_depth: i32,
+    roulette: Option<RouletteSettings>) -> Color {
     // 打ち切りチェック
-    if depth >= &DEPTH_LIMIT {
+    if depth >= &max_depth {
         return Color { x: 0.0, y: 0.0, z: 0.0 }
     }
 
     // シーンと交差判定
     // 交差チェック
-    let (now_object, hitpoint) = match intersect_scene(scene, ray) {
+    let (now_object, mut hitpoint) = match intersect_scene(scene, ray) {
         (Some(object), hitpoint) => (object, hitpoint),
         (None, _) => return BACKGROUND_COLOR
     };
-    
+
     // マテリアル取得
     let now_material = now_object.get_material();
     let emission = now_material.emission();
     if &emission.x > &0.0 ||&emission.y > &0.0 || &emission.z > &0.0 {
         // 光源にヒットしたら放射項だけ返して終わる。
         // （今回、光源は反射率0と仮定しているため）
-        return emission.clone()
+        // カメラから見えない光源では、カメラレイ（深さ0）のときだけ放射を数えない。
+        if depth == &0 && !now_material.visible_to_camera() {
+            return Color { x: 0.0, y: 0.0, z: 0.0 }
+        }
+        return now_material.emitted_at(ray.dir, &hitpoint.normal, hitpoint.uv)
     }
+    orient_normal(&mut hitpoint, now_material, ray);
 
     // 次の方向をサンプリング + その方向のBRDF項の値を得る。
     let mut pdf = -1.0;
     let mut brdf_value: Color = Color { x: 0.0, y: 0.0, z: 0.0 };
-    let dir_out = now_material.sample(random, &ray.dir, &hitpoint.normal, &mut pdf, &mut brdf_value);
+    // 基底は交差点で求めた接線を使う。
+    let (tangent, bitangent) = hitpoint.tangent_frame();
+    let dir_out = now_material.sample_with_frame(
+        random, ray.dir, &hitpoint.normal, &tangent, &bitangent, &mut pdf, &mut brdf_value, &mut false);
 
     // cos項。
     let cost = Vec::dot(&hitpoint.normal, &dir_out);
 
+    // ロシアンルーレット。続けたときは続ける確率で割る。
+    let mut survival: f64 = 1.0;
+    if let Some(roulette) = roulette {
+        if depth >= &roulette.start_depth {
+            survival = roulette.continue_probability(&(&brdf_value * (cost / pdf)));
+            if random.next_1d() >= survival {
+                return Color { x: 0.0, y: 0.0, z: 0.0 }
+            }
+        }
+    }
+
     // レンダリング方程式をモンテカルロ積分によって再帰的に解く。
+    let origin: Vec = hitpoint.offset_origin(&dir_out);
+    Vec::multiply(
+        brdf_value,
+        radiance_internal(scene, &Ray::with_time(&origin, &dir_out, ray.time), random, &(depth + 1), max_depth, roulette))
+        * cost / pdf / survival
+}
+
+// レイが今どの誘電体の中にいるか。ガラスに水を満たしたように物体が入れ子になっているときに使う。
+// 物体に入る（表側から屈折する）ときにその屈折率を積み、出るときに取り除く。一番上がレイのいる媒質の屈折率で、
+// 空なら真空（1.0）。物体はマテリアルの屈折率（MaterialTrait::dielectric_ior）だけで見分けるので、
+// 同じ屈折率の物体が重なっていると区別できないが、屈折率の比はどちらでも変わらない。
+#[derive(Debug, Clone, PartialEq)]
+pub struct MediumStack {
+    iors: std::vec::Vec<f64>,
+}
+
+impl Default for MediumStack {
+    fn default() -> MediumStack {
+        MediumStack::new()
+    }
+}
+
+impl MediumStack {
+    // 真空の中から始める。
+    pub fn new() -> MediumStack {
+        MediumStack { iors: std::vec::Vec::new() }
+    }
+
+    // レイのいる媒質の屈折率。
+    pub fn current_ior(&self) -> f64 {
+        match self.iors.last() {
+            Some(ior) => *ior,
+            None => 1.0
+        }
+    }
+
+    // 積んである媒質の数。0なら真空の中。
+    pub fn depth(&self) -> usize {
+        self.iors.len()
+    }
+
+    // 屈折率iorの物体に入る。
+    pub fn push(&mut self, ior: f64) {
+        self.iors.push(ior);
+    }
+
+    // 屈折率iorの物体から出る。一番上にある同じ屈折率のものを取り除く。
+    // 交差判定の誤差で入ったことを数え損ねていた（積んでいない）ときは何もしない。
+    pub fn remove(&mut self, ior: f64) {
+        if let Some(index) = self.iors.iter().rposition(|&entry| entry == ior) {
+            self.iors.remove(index);
+        }
+    }
+
+    // 屈折率iorの物体から出るときの、物体の外側の媒質の屈折率。
+    pub fn outer_ior_on_exit(&self, ior: f64) -> f64 {
+        let mut outside: MediumStack = self.clone();
+        outside.remove(ior);
+        outside.current_ior()
+    }
+}
+
+// radianceと同じだが、入れ子になった誘電体の境界で、両側の媒質の屈折率の比を使って屈折させる。
+// radianceでは誘電体の外側は常に真空なので、ガラスの器の中の水に入るところでも1.0と1.33の比で屈折してしまう。
+// 誘電体に当たるたびに、表側から当たったか（Hitpoint::front_face）でmediaを積み降ろしする（MediumStack）。
+// mediaはrayの始点のある媒質で、カメラが真空にあればMediumStack::new()を渡す。
+pub fn radiance_with_media(
+    scene: &Scene, ray: &Ray, random: &mut dyn Sampler, depth: &i32, media: &MediumStack) -> Color {
+    radiance_media_internal(scene, ray, random, depth, DEPTH_LIMIT, media.clone())
+}
+
+fn radiance_media_internal(
+    scene: &Scene, ray: &Ray, random: &mut dyn Sampler, depth: &i32, max_depth: i32,
+    mut media: MediumStack) -> Color {
+    if depth >= &max_depth {
+        return Color { x: 0.0, y: 0.0, z: 0.0 }
+    }
+
+    let (now_object, mut hitpoint) = match intersect_scene(scene, ray) {
+        (Some(object), hitpoint) => (object, hitpoint),
+        (None, _) => return BACKGROUND_COLOR
+    };
+
+    let now_material = now_object.get_material();
+    let emission = now_material.emission();
+    if emission.x > 0.0 || emission.y > 0.0 || emission.z > 0.0 {
+        if depth == &0 && !now_material.visible_to_camera() {
+            return Color { x: 0.0, y: 0.0, z: 0.0 }
+        }
+        return now_material.emitted_at(ray.dir, &hitpoint.normal, hitpoint.uv)
+    }
+    orient_normal(&mut hitpoint, now_material, ray);
+
+    let mut pdf = -1.0;
+    let mut brdf_value: Color = Color { x: 0.0, y: 0.0, z: 0.0 };
+    let dir_out: Vec = match now_material.dielectric_ior() {
+        Some(ior) => {
+            // 入るときは今いる媒質が外側、出るときはこの物体を除いた一番上の媒質が外側になる。
+            let entering: bool = hitpoint.front_face(ray.dir);
+            let outer_ior: f64 = if entering { media.current_ior() } else { media.outer_ior_on_exit(ior) };
+            let dir_out: Vec = now_material.sample_with_outer_ior(
+                random, ray.dir, &hitpoint.normal, outer_ior, &mut pdf, &mut brdf_value);
+
+            // 境界を通り抜けたときだけ媒質が変わる。反射ならそのまま。
+            let transmitted: bool = Vec::dot(&hitpoint.normal, ray.dir) * Vec::dot(&hitpoint.normal, &dir_out) > 0.0;
+            if transmitted {
+                if entering { media.push(ior) } else { media.remove(ior) }
+            }
+            dir_out
+        },
+        None => {
+            let (tangent, bitangent) = hitpoint.tangent_frame();
+            now_material.sample_with_frame(
+                random, ray.dir, &hitpoint.normal, &tangent, &bitangent, &mut pdf, &mut brdf_value, &mut false)
+        }
+    };
+
+    let cost = Vec::dot(&hitpoint.normal, &dir_out);
+    let origin: Vec = hitpoint.offset_origin(&dir_out);
     Vec::multiply(
         brdf_value,
-        radiance(scene, &Ray::new(&hitpoint.position, &dir_out), random, &(depth + 1)))
+        radiance_media_internal(scene, &Ray::with_time(&origin, &dir_out, ray.time), random, &(depth + 1), max_depth, media))
         * cost / pdf
 }
 
+// 光源サンプリング（Next Event Estimation）を併用してray方向からの放射輝度を求める。
+// radianceと期待値は同じになる。
+// δ関数でないマテリアルでの直接光は、光源サンプリングとBRDFサンプリングの両方で求め、
+// 同じ方向を選ぶpdfからpower heuristicで重み付けして足し合わせる（Multiple Importance Sampling）。
+// 小さく明るい光源は光源サンプリングが、光沢面に映る大きな光源はBRDFサンプリングが受け持つ。
+// radiance_nee系の関数は、反射ごとに光源の選択と光源上の点、BRDFサンプリングに決まった次元を使う（NEE_FIRST_DIMENSION）。
+// randomに低食い違い量列（HaltonSampler、SobolSampler）をピクセルとサンプルの番号で始めて（start_pixel）渡せば、
+// 光源上の点がピクセルのサンプル全体で層別化され、影の境界などのノイズが減る。擬似乱数（Random）では何も変わらない。
+pub fn radiance_nee(scene: &Scene, ray: &Ray, random: &mut dyn Sampler, depth: &i32) -> Color {
+    let settings = NeeSettings { light_tree: None, max_indirect: K_INF, fold_constant_brdf: false };
+    radiance_nee_internal(scene, &settings, ray, random, depth, None, Branching::new(1))
+}
+
+// radiance_neeと同じだが、光源サンプリングで光源を一様に選ぶ代わりに、
+// light_tree（LightTree::new(scene)で作ったもの）でシェーディング点への寄与に応じて選ぶ。
+// 光源の多いシーンで、遠くの光源や裏側の光源ばかり選んでしまうことによるノイズを減らす。期待値は同じ。
+pub fn radiance_nee_with_light_tree(
+    scene: &Scene, light_tree: &LightTree, ray: &Ray, random: &mut dyn Sampler, depth: &i32) -> Color {
+    let settings = NeeSettings { light_tree: Some(light_tree), max_indirect: K_INF, fold_constant_brdf: false };
+    radiance_nee_internal(scene, &settings, ray, random, depth, None, Branching::new(1))
+}
+
+// radiance_neeと同じだが、間接光の寄与だけを各チャンネルがmax_indirect以下になるようクランプする。
+// カメラから直接見える放射と直接光はそのままにして、間接光によるファイアフライだけを抑える。
+// 色味が変わらないよう、最大のチャンネルがmax_indirectになるように全体を縮める。
+// 直接光がクランプされないよう、直接光は光源サンプリングだけで求める（MISは使わない）。
+// クランプしたぶんエネルギーは失われる（バイアスが乗る）ことに注意。
+pub fn radiance_nee_clamp_indirect(
+    scene: &Scene, ray: &Ray, random: &mut dyn Sampler, depth: &i32, max_indirect: f64) -> Color {
+    let settings = NeeSettings { light_tree: None, max_indirect, fold_constant_brdf: false };
+    radiance_nee_internal(scene, &settings, ray, random, depth, None, Branching::new(1))
+}
+
+// radiance_neeと同じだが、経路が最初にδ関数でないマテリアル（拡散面や光沢面）に当たったところで、
+// 間接光の方向をsplit個サンプリングし、それぞれの先の放射輝度を1 / splitの重みで足し合わせる（経路の分岐、splitting）。
+// 分岐した先の経路はそれ以上分岐しない。期待値は同じで、split = 1ならradiance_neeと同じになる。
+// ガラス越しに見た拡散面のように、最初の拡散面に届くまでの経路を作る手間が大きいところで、
+// カメラレイを増やすより少ない手間で間接光のノイズを減らせる。
+pub fn radiance_nee_with_splitting(
+    scene: &Scene, ray: &Ray, random: &mut dyn Sampler, depth: &i32, split: usize) -> Color {
+    let settings = NeeSettings { light_tree: None, max_indirect: K_INF, fold_constant_brdf: false };
+    radiance_nee_internal(scene, &settings, ray, random, depth, None, Branching::new(split))
+}
+
+// radiance_neeと同じだが、Lambertianのように BRDFが一定のマテリアル（MaterialTrait::constant_brdf）での直接光は、
+// 光源からの Le * cosΘ / pdf を求めてから一定のBRDFを一度だけ掛ける。evalを呼ばずに済むぶん少し速い。
+// 使う乱数はradiance_neeと同じで、結果は丸め誤差の範囲で一致する。
+pub fn radiance_nee_lambert(scene: &Scene, ray: &Ray, random: &mut dyn Sampler, depth: &i32) -> Color {
+    let settings = NeeSettings { light_tree: None, max_indirect: K_INF, fold_constant_brdf: true };
+    radiance_nee_internal(scene, &settings, ray, random, depth, None, Branching::new(1))
+}
+
+// radiance_nee系の関数が使う最初の次元。これより前の次元は、呼び出し側がカメラレイ（ピクセル内の位置、時刻など）に使う。
+pub const NEE_FIRST_DIMENSION: u32 = 4;
+// 反射一回あたりに使う次元の数。最初のNEE_LIGHT_DIMENSIONS個を光源サンプリング（光源の選択、光源上の点）に、
+// 残りをBRDFサンプリングに使う。これより多く使うマテリアルでは、余った分が次の反射の次元にはみ出す。
+const NEE_DIMENSIONS_PER_BOUNCE: u32 = 8;
+const NEE_LIGHT_DIMENSIONS: u32 = 4;
+
+// radiance_nee系の関数の設定。
+//   light_tree         ... あれば光源サンプリングで光源をそれで選び、無ければ一様に選ぶ。
+//   max_indirect       ... 間接光のクランプの上限。K_INFならクランプしない。
+//   fold_constant_brdf ... trueなら、BRDFが一定のマテリアルでの直接光に一定のBRDFを後から掛ける。
+struct NeeSettings<'a> {
+    light_tree: Option<&'a LightTree>,
+    max_indirect: f64,
+    fold_constant_brdf: bool,
+}
+
+impl<'a> NeeSettings<'a> {
+    // 直接光を光源サンプリングとBRDFサンプリングのMISで求めるか。
+    // 間接光をクランプするときは、BRDFサンプリングで当たった光源の寄与までクランプしてしまうので使わない。
+    fn uses_mis(&self) -> bool {
+        self.max_indirect >= K_INF
+    }
+}
+
+// BRDFサンプリングで次の方向を選んだ点。その先で光源に当たったときに、MISの重みを求めるのに使う。
+// inputはその点に当たったレイの方向。tangent, bitangentはsample_with_frameに渡した接線と従法線。
+struct ScatterVertex<'b> {
+    material: &'b dyn MaterialTrait,
+    input: &'b Vec,
+    normal: &'b Vec,
+    tangent: &'b Vec,
+    bitangent: &'b Vec,
+    position: &'b Vec,
+}
+
+// 経路の分岐の状態。
+//   split    ... 次にδ関数でないマテリアルに当たったときの間接光の分岐数。分岐した先では1にする。
+//   stratify ... trueなら、深さごとに決まった次元から値を使う。分岐した先では同じ次元を使い回すと
+//                分岐同士が同じ値になってしまうので、falseにして続きの次元を使う。
+#[derive(Debug, Clone, Copy)]
+struct Branching {
+    split: usize,
+    stratify: bool,
+}
+
+impl Branching {
+    fn new(split: usize) -> Branching {
+        Branching { split: split.max(1), stratify: true }
+    }
+}
+
+// 各チャンネルがmax_value以下になるよう、色味を保ったまま縮める。
+fn clamp_color(color: Color, max_value: f64) -> Color {
+    let max_component: f64 = color.x.max(color.y).max(color.z);
+    if max_component > max_value {
+        color * (max_value / max_component)
+    } else {
+        color
+    }
+}
+
+// previousはrayを飛ばしたδ関数でないマテリアルの点で、カメラレイやδ関数のマテリアルの先ではNone。
+// previousがあるときに光源に当たったら、光源サンプリングでも数えている分を除くよう放射に重みを掛ける（emission_weight）。
+// カメラから見えない光源（MaterialTrait::visible_to_camera）は、カメラレイ（深さ0）で当たったときは数えない。
+// branchingは経路の分岐の状態（Branching）。
+fn radiance_nee_internal(
+    scene: &Scene, settings: &NeeSettings, ray: &Ray, random: &mut dyn Sampler, depth: &i32,
+    previous: Option<&ScatterVertex>, branching: Branching) -> Color {
+    // 打ち切りチェック
+    if depth >= &DEPTH_LIMIT {
+        return Color { x: 0.0, y: 0.0, z: 0.0 }
+    }
+
+    // シーンと交差判定
+    let (now_object, mut hitpoint) = match intersect_scene(scene, ray) {
+        (Some(object), hitpoint) => (object, hitpoint),
+        (None, _) => return BACKGROUND_COLOR
+    };
+
+    let now_material = now_object.get_material();
+    if now_object.is_light() {
+        if *depth == 0 && !now_material.visible_to_camera() {
+            return Color { x: 0.0, y: 0.0, z: 0.0 }
+        }
+        let weight: f64 = emission_weight(scene, settings, now_object, &hitpoint, ray, previous);
+        if weight <= 0.0 {
+            return Color { x: 0.0, y: 0.0, z: 0.0 }
+        }
+        return now_material.emitted_at(ray.dir, &hitpoint.normal, hitpoint.uv) * weight
+    }
+    orient_normal(&mut hitpoint, now_material, ray);
+
+    let dimension: u32 = NEE_FIRST_DIMENSION + *depth as u32 * NEE_DIMENSIONS_PER_BOUNCE;
+    if branching.stratify {
+        random.start_dimension(dimension);
+    }
+
+    // 直接光。δ関数を含むマテリアルでは評価できないのでBRDFサンプリングに任せる。
+    let is_delta = now_material.is_delta();
+    let direct = if is_delta {
+        Color { x: 0.0, y: 0.0, z: 0.0 }
+    } else {
+        nee_direct_light(scene, settings, &hitpoint, now_material, ray, random)
+    };
+
+    // 間接光。δ関数のマテリアルでは分岐せず、分岐数はその先に持ち越す。
+    let (num_branches, next_split) = if is_delta { (1, branching.split) } else { (branching.split, 1) };
+    let next_branching = Branching { split: next_split, stratify: branching.stratify && num_branches == 1 };
+    let (tangent, bitangent) = hitpoint.tangent_frame();
+    let vertex = ScatterVertex {
+        material: now_material, input: ray.dir, normal: &hitpoint.normal, tangent: &tangent, bitangent: &bitangent,
+        position: &hitpoint.position };
+    let mut indirect: Color = Color { x: 0.0, y: 0.0, z: 0.0 };
+    for branch in 0..num_branches {
+        if branching.stratify && branch == 0 {
+            random.start_dimension(dimension + NEE_LIGHT_DIMENSIONS);
+        }
+        let mut pdf = -1.0;
+        let mut brdf_value: Color = Color { x: 0.0, y: 0.0, z: 0.0 };
+        let mut delta_lobe: bool = false;
+        let dir_out = now_material.sample_with_frame(
+            random, ray.dir, &hitpoint.normal, &tangent, &bitangent, &mut pdf, &mut brdf_value, &mut delta_lobe);
+        let cost = Vec::dot(&hitpoint.normal, &dir_out);
+        let origin: Vec = hitpoint.offset_origin(&dir_out);
+
+        // δ関数のローブ（MixMaterialのδ関数を含む側など）で選んだ方向は、光源サンプリングでは数えていない。
+        // その先で光源に当たったら重み1で数えるよう、previousをNoneにする。
+        let delta_branch: bool = is_delta || delta_lobe;
+        let next_previous: Option<&ScatterVertex> = if delta_branch { None } else { Some(&vertex) };
+        let branch: Color = Vec::multiply(
+            brdf_value,
+            radiance_nee_internal(
+                scene, settings, &Ray::with_time(&origin, &dir_out, ray.time), random, &(depth + 1),
+                next_previous, next_branching))
+            * cost / pdf;
+
+        // δ関数のローブでは、その先で光源に当たった分はカメラから（鏡越しに）見える放射や直接光になる。
+        // クランプは次に当たったところで行う。
+        indirect = if delta_branch { indirect + branch } else { indirect + clamp_color(branch, settings.max_indirect) };
+    }
+
+    direct + indirect / num_branches as f64
+}
+
+// radiance_nee_internalで、previousの点からBRDFサンプリングしたrayが光源lightのhitpointに当たったときの、放射に掛ける重み。
+// previousが無い（カメラレイやδ関数のマテリアルの先）ときは、光源サンプリングでは数えていないので1。
+// 光源サンプリングでその点を選ぶpdfが0ならBRDFサンプリングだけで数えるので1、
+// そうでなければMISを使うならpower heuristicの重み、使わないなら光源サンプリングだけで数えるので0。
+// 光源サンプリングのpdfが分からない光源（Shape::has_surface_pdf）は、光源サンプリングだけで数える（nee_direct_light）。
+fn emission_weight(
+    scene: &Scene, settings: &NeeSettings, light: &SceneObject, hitpoint: &Hitpoint, ray: &Ray,
+    previous: Option<&ScatterVertex>) -> f64 {
+    let previous: &ScatterVertex = match previous {
+        Some(previous) => previous,
+        None => return 1.0
+    };
+
+    let light_pdf: f64 = match light_sample_pdf(scene, settings.light_tree, light, hitpoint, ray, previous) {
+        Some(light_pdf) => light_pdf,
+        None => return 0.0
+    };
+    if light_pdf <= 0.0 {
+        return 1.0
+    }
+    if !settings.uses_mis() {
+        return 0.0
+    }
+    mis_weight_for(
+        previous.material, previous.input, previous.normal, (previous.tangent, previous.bitangent), ray.dir, light_pdf)
+}
+
+// previousの点から光源サンプリング（sample_light_ray）で、光源light上のhitpointの点を選ぶときの立体角測度のpdf。
+// 光源の選択確率も含める。光源サンプリングが選ばない方向（面の裏側）では0。
+// 光源上の点のpdfが分からない形状（Shape::has_surface_pdf）ではNone。
+fn light_sample_pdf(
+    scene: &Scene, light_tree: Option<&LightTree>, light: &SceneObject, hitpoint: &Hitpoint, ray: &Ray,
+    previous: &ScatterVertex) -> Option<f64> {
+    let pdf_area: f64 = light_point_pdf(light, hitpoint, ray.time)?;
+
+    let to_light: Vec = &hitpoint.position - previous.position;
+    let distance_squared: f64 = to_light.length_squared();
+    let light_dir: Vec = to_light / distance_squared.sqrt();
+    let cos_light: f64 = Vec::dot(&hitpoint.normal, &light_dir).abs();
+    if Vec::dot(previous.normal, &light_dir) <= 0.0 || cos_light <= 0.0 {
+        return Some(0.0)
+    }
+
+    let select_pdf: f64 = match light_tree {
+        Some(light_tree) => light_tree.pdf(previous.position, previous.normal, object_index(scene, light)?),
+        None => 1.0 / scene.iter().filter(|object| object.is_light()).count() as f64
+    };
+    Some(select_pdf * Sampling::area_to_solid_angle_pdf(pdf_area, distance_squared, cos_light))
+}
+
+// sample_light_pointが光源light上のhitpointの点を選ぶときの面積測度のpdf。
+fn light_point_pdf(light: &SceneObject, hitpoint: &Hitpoint, time: f64) -> Option<f64> {
+    let shape = light.get_shape();
+    if shape.has_uv_parameterization() {
+        if let Some((_, _, area)) = shape.point_at_uv(hitpoint.uv.0, hitpoint.uv.1) {
+            let pdf_uv: f64 = light.get_material().emission_uv_pdf(hitpoint.uv).unwrap_or(1.0);
+            return Some(pdf_uv / area)
+        }
+    }
+    shape.surface_pdf(&hitpoint.normal, time)
+}
+
+// radiance_nee_internalでの直接光。光源をsettings.light_treeで（無ければ一様に）選んで光源サンプリングする。
+// settings.uses_mis()なら、BRDFサンプリングで同じ方向を選ぶ場合とのpower heuristicで重み付けする
+// （BRDFサンプリングの側はemission_weight）。光源上の点のpdfが分からない形状の光源では重み付けしない。
+// settings.fold_constant_brdfなら、BRDFが一定のマテリアルでは Le * cosΘ / pdf を求めてから一定のBRDFを掛ける。
+fn nee_direct_light(
+    scene: &Scene, settings: &NeeSettings, hitpoint: &Hitpoint, material: &dyn MaterialTrait,
+    ray: &Ray, random: &mut dyn Sampler) -> Color {
+    let selected = match settings.light_tree {
+        Some(light_tree) => light_tree.sample(scene, &hitpoint.position, &hitpoint.normal, random),
+        None => sample_light(scene, random)
+    };
+    let (light, select_pdf) = match selected {
+        Some(selected) => selected,
+        None => return Color { x: 0.0, y: 0.0, z: 0.0 }
+    };
+    let (light_dir, emission, cos_surface, pdf) = match sample_light_ray(scene, light, hitpoint, ray, random) {
+        Some(sample) => sample,
+        None => return Color { x: 0.0, y: 0.0, z: 0.0 }
+    };
+
+    // BRDFはBRDFサンプリング（sample_with_frame）と同じ交差点の接線の基底で評価する。
+    let (tangent, bitangent) = hitpoint.tangent_frame();
+    let weight: f64 = if settings.uses_mis() && light.get_shape().has_surface_pdf() {
+        1.0 - mis_weight_for(material, ray.dir, &hitpoint.normal, (&tangent, &bitangent), &light_dir, pdf * select_pdf)
+    } else {
+        1.0
+    };
+    let incident: Color = emission * (cos_surface / pdf / select_pdf * weight);
+    let constant_brdf: Option<Color> = if settings.fold_constant_brdf { material.constant_brdf() } else { None };
+    match constant_brdf {
+        Some(brdf) => Vec::multiply(brdf, incident),
+        None => Vec::multiply(incident, material.eval_with_frame(ray.dir, &hitpoint.normal, &tangent, &bitangent, &light_dir))
+    }
+}
+
+// 光源サンプリングによる直接光の推定。
+// 光源上の点を面積測度でサンプリングし、立体角測度のpdfに変換して Le * f * cosΘ / pdf_ω を求める。
+// 光源側のcos項（Lambertの余弦則）はpdfの変換に含まれる。
+// 光源は一様に選び（direct_light_with_light_treeでは寄与に応じて選び）、選択確率でも割る。
+// rayはhitpointに当たったレイで、光源はレイの時刻での位置からサンプリングする。
+// MISの重みは掛けないので、これだけで直接光の推定になる。
+pub fn direct_light(
+    scene: &Scene, hitpoint: &Hitpoint, material: &dyn MaterialTrait, ray: &Ray, random: &mut dyn Sampler) -> Color {
+    match sample_light(scene, random) {
+        Some((light, select_pdf)) => direct_light_from(scene, light, select_pdf, hitpoint, material, ray, random),
+        None => Color { x: 0.0, y: 0.0, z: 0.0 }
+    }
+}
+
+// direct_lightと同じだが、光源をlight_treeでシェーディング点への寄与に応じて選ぶ。
+pub fn direct_light_with_light_tree(
+    scene: &Scene, light_tree: &LightTree, hitpoint: &Hitpoint, material: &dyn MaterialTrait,
+    ray: &Ray, random: &mut dyn Sampler) -> Color {
+    match light_tree.sample(scene, &hitpoint.position, &hitpoint.normal, random) {
+        Some((light, select_pdf)) => direct_light_from(scene, light, select_pdf, hitpoint, material, ray, random),
+        None => Color { x: 0.0, y: 0.0, z: 0.0 }
+    }
+}
+
+// 選択確率select_pdfで選んだ光源lightからの直接光。
+fn direct_light_from(
+    scene: &Scene, light: &SceneObject, select_pdf: f64, hitpoint: &Hitpoint, material: &dyn MaterialTrait,
+    ray: &Ray, random: &mut dyn Sampler) -> Color {
+    match sample_light_ray(scene, light, hitpoint, ray, random) {
+        Some((light_dir, emission, cos_surface, pdf)) => {
+            let (tangent, bitangent) = hitpoint.tangent_frame();
+            let brdf_value: Color = material.eval_with_frame(ray.dir, &hitpoint.normal, &tangent, &bitangent, &light_dir);
+            Vec::multiply(emission, brdf_value) * cos_surface / pdf / select_pdf
+        },
+        None => Color { x: 0.0, y: 0.0, z: 0.0 }
+    }
+}
+
+// 光源light上の点をサンプリングして、hitpointから見えるなら(光源への方向, 放射輝度, 面側のcosΘ, 立体角測度のpdf)を返す。
+// 光源が面の裏側にあるとき、遮られているとき、その向きに放射しないときはNone。
+// 両面光源（Lightsource::new）は裏側にもemittedの放射をするので、光源側のcosΘは絶対値をとる。
+// 片面光源を裏側から見たときはemittedが黒を返すので、遮蔽判定をせずに打ち切る。
+fn sample_light_ray(
+    scene: &Scene, light: &SceneObject, hitpoint: &Hitpoint, ray: &Ray,
+    random: &mut dyn Sampler) -> Option<(Vec, Color, f64, f64)> {
+
+    let (light_position, light_normal, pdf_area, light_uv) = sample_light_point(light, random, ray.time);
+    let to_light: Vec = &light_position - &hitpoint.position;
+    let distance_squared: f64 = to_light.length_squared();
+    let distance: f64 = distance_squared.sqrt();
+    let light_dir: Vec = to_light / distance;
+
+    let cos_surface: f64 = Vec::dot(&hitpoint.normal, &light_dir);
+    let cos_light: f64 = Vec::dot(&light_normal, &light_dir).abs();
+    if cos_surface <= 0.0 || cos_light <= 0.0 {
+        return None
+    }
+
+    let emission: Color = match light_uv {
+        Some(uv) => light.get_material().emitted_at(&light_dir, &light_normal, uv),
+        None => light.get_material().emitted(&light_dir, &light_normal)
+    };
+    if emission.x <= 0.0 && emission.y <= 0.0 && emission.z <= 0.0 {
+        return None
+    }
+
+    // 遮蔽判定。サンプルした光源上の点より手前で何かに当たったら寄与なし。
+    // 光源自身に当たらないよう、判定する距離を少しだけ縮めておく。
+    let origin: Vec = hitpoint.offset_origin(&light_dir);
+    if intersect_scene_any(scene, &Ray::with_time(&origin, &light_dir, ray.time), distance * (1.0 - K_EPSILON)) {
+        return None
+    }
+
+    let pdf: f64 = Sampling::area_to_solid_angle_pdf(pdf_area, distance_squared, cos_light);
+    Some((light_dir, emission, cos_surface, pdf))
+}
+
+// BRDFサンプリングで選んだ方向outputの先で光源に当たったときの、その寄与に掛けるMISの重み（power heuristic）。
+// light_pdfは同じ方向を光源サンプリングで選ぶときの立体角測度のpdf。光源サンプリングの側の重みは1からこれを引いたもの。
+// frameはoutputを選んだときにsample_with_frameへ渡した(tangent, bitangent)で、BRDFサンプリングのpdfはその基底で求める。
+// δ関数を含むマテリアル（MaterialTrait::is_delta）では光源サンプリングで寄与を評価できないので、
+// eval_pdfを呼ばずに1を返す（BRDFサンプリングの経路だけで寄与を数える）。
+// δ関数を含むマテリアルのeval_pdfは意味のある値にならないので、MISの計算では必ずこれを通すこと。
+pub fn mis_weight_for(
+    material: &dyn MaterialTrait, input: &Vec, normal: &Vec, frame: (&Vec, &Vec), output: &Vec, light_pdf: f64) -> f64 {
+    if material.is_delta() {
+        return 1.0
+    }
+    let (tangent, bitangent) = frame;
+    power_heuristic(material.eval_pdf_with_frame(input, normal, tangent, bitangent, output), light_pdf)
+}
+
+// 二つのサンプリング方法のpdfがpdf_a, pdf_bのときの、aの側の重み（β = 2）。
+fn power_heuristic(pdf_a: f64, pdf_b: f64) -> f64 {
+    let a2: f64 = pdf_a * pdf_a;
+    let b2: f64 = pdf_b * pdf_b;
+    if a2 + b2 <= 0.0 {
+        return 0.0
+    }
+    a2 / (a2 + b2)
+}
+
+// 光源上の点をサンプリングする。(位置, 法線, 面積測度のpdf, テクスチャ座標)を返す。
+// テクスチャ座標で点を選べる形状（Shape::point_at_uv）では、マテリアルの分布（sample_emission_uv）
+// があればそれに従って、無ければ一様にテクスチャ座標を選ぶ。放射の模様を引くためにテクスチャ座標も返す。
+// それ以外の形状では形状上で一様に選び、テクスチャ座標はNoneになる。
+fn sample_light_point(light: &SceneObject, random: &mut dyn Sampler, time: f64) -> (Vec, Vec, f64, Option<(f64, f64)>) {
+    let shape = light.get_shape();
+    if shape.has_uv_parameterization() {
+        let (uv, pdf_uv) = match light.get_material().sample_emission_uv(random) {
+            Some(sample) => sample,
+            None => (random.next_2d(), 1.0)
+        };
+        if let Some((position, normal, area)) = shape.point_at_uv(uv.0, uv.1) {
+            return (position, normal, pdf_uv / area, Some(uv))
+        }
+    }
+
+    let (position, normal, pdf_area) = shape.sample_surface(random, time);
+    (position, normal, pdf_area, None)
+}
+
+// 放射輝度emissionの球光源から、位置point・法線normalの面が受ける放射照度を解析的に求める。
+// 遮蔽は考慮しない近似だが、サンプリングによるノイズが無いのでプレビューに使える。
+// 光源が地平線をまたぐ場合も含めて正しく扱う。
+// John M. Snyder. Area light sources for real-time graphics. Microsoft Research Technical Report, 1996.
+// Sébastien Lagarde, Charles de Rousiers. Moving Frostbite to Physically Based Rendering 3.0. SIGGRAPH 2014 Course.
+pub fn analytic_sphere_irradiance(
+    point: &Vec, normal: &Vec, light_center: &Vec, light_radius: f64, emission: &Color) -> Color {
+
+    let to_light: Vec = light_center - point;
+    let distance_squared: f64 = to_light.length_squared();
+    let sin_sigma_squared: f64 = light_radius * light_radius / distance_squared;
+    if sin_sigma_squared >= 1.0 {
+        // 光源の内部にいるときは半球全体から光が来る。
+        return emission * K_PI
+    }
+
+    let cos_theta: f64 = Vec::dot(normal, &to_light) / distance_squared.sqrt();
+    let irradiance: f64 = if cos_theta * cos_theta > sin_sigma_squared {
+        // 光源全体が地平線の上か下にある。
+        K_PI * sin_sigma_squared * cos_theta.max(0.0)
+    } else {
+        // 光源の一部が地平線の下に隠れている。
+        let sin_theta: f64 = (1.0 - cos_theta * cos_theta).sqrt();
+        let x: f64 = (1.0 / sin_sigma_squared - 1.0).sqrt();
+        let y: f64 = -x * cos_theta / sin_theta;
+        let sin_theta_sqrt_y: f64 = sin_theta * (1.0 - y * y).sqrt();
+        (cos_theta * y.acos() - x * sin_theta_sqrt_y) * sin_sigma_squared + (sin_theta_sqrt_y / x).atan()
+    };
+
+    emission * irradiance.max(0.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use material::{Material, LambertianMaterial, PhongMaterial, GlassMaterial, Lightsource, EmissiveTextureMaterial, CutoutMaterial, MixMaterial};
+    use material::{ConductorMaterial, RoughMetalMaterial};
+    use random::Random;
+    use scene::SceneBuilder;
+    use shape::Shape;
+    use quad::Quad;
+    use triangle::Triangle;
+    use vec::Reflect;
+    use sphere::Sphere;
+    use instance::Instance;
+    use mesh::{Mesh, tessellate_sphere};
+    use transform::Transform;
+    use texture::ImageTexture;
+    use vec::Normalize;
+
+    // (平均, 平均の標準誤差)。
+    fn estimate<F: FnMut(&mut Random) -> f64>(samples: usize, seed: u64, mut f: F) -> (f64, f64) {
+        let mut random = Random::new(seed);
+        let mut sum: f64 = 0.0;
+        let mut sum_squared: f64 = 0.0;
+        for _ in 0..samples {
+            let value: f64 = f(&mut random);
+            sum += value;
+            sum_squared += value * value;
+        }
+        let mean: f64 = sum / samples as f64;
+        let variance: f64 = (sum_squared / samples as f64 - mean * mean).max(0.0);
+        (mean, (variance / samples as f64).sqrt())
+    }
+
+    fn agree(a: (f64, f64), b: (f64, f64)) -> bool {
+        (a.0 - b.0).abs() <= 4.0 * (a.1 * a.1 + b.1 * b.1).sqrt()
+    }
+
+    // 上向きの床と、その上で床に裏側を向けた四角形の光源。
+    fn floor_under_light(light: Lightsource) -> Scene {
+        let floor = Material::LambertianMaterial(LambertianMaterial::new(Color { x: 0.5, y: 0.5, z: 0.5 }));
+        SceneBuilder::new()
+            .add_quad(Vec { x: -50.0, y: 0.0, z: -50.0 }, Vec { x: 0.0, y: 0.0, z: 100.0 }, Vec { x: 100.0, y: 0.0, z: 0.0 }, floor)
+            .add_quad(Vec { x: -0.5, y: 1.0, z: -0.5 }, Vec { x: 0.0, y: 0.0, z: 1.0 }, Vec { x: 1.0, y: 0.0, z: 0.0 },
+                      Material::Lightsource(light))
+            .build().unwrap().0
+    }
+
+    fn floor_ray() -> Ray<'static> {
+        static ORIGIN: Vec = Vec { x: 3.0, y: 3.0, z: 0.0 };
+        static DIR: Vec = Vec { x: -0.6, y: -0.8, z: 0.0 };
+        Ray::new(&ORIGIN, &DIR)
+    }
+
+    // 両面光源の裏側から照らされた床でも、光源サンプリングとBRDFサンプリングの推定が一致する。
+    #[test]
+    fn nee_matches_brdf_sampling_for_two_sided_light() {
+        let scene = floor_under_light(Lightsource::new(Color { x: 10.0, y: 10.0, z: 10.0 }));
+        let ray = floor_ray();
+        let nee = estimate(4000, 1, |random| radiance_nee(&scene, &ray, random, &0).x);
+        let brdf = estimate(40000, 2, |random| radiance(&scene, &ray, random, &0).x);
+        assert!(nee.0 > 0.0);
+        assert!(agree(nee, brdf), "nee {:?} brdf {:?}", nee, brdf);
+    }
+
+    // 拡散面とガラスを混ぜた床で、ガラスの側の鏡面反射の方向に光源が映る。
+    // 鏡面反射は光源サンプリングでは数えないので、その側を選んで光源に当たったときは重み1で数える。
+    // そうすれば、光源サンプリングとBRDFサンプリングだけの推定が一致する。
+    #[test]
+    fn nee_counts_the_delta_lobe_of_a_mix_in_full() {
+        let floor = Material::MixMaterial(MixMaterial::new(
+            Material::LambertianMaterial(LambertianMaterial::new(Color { x: 0.5, y: 0.5, z: 0.5 })),
+            Material::GlassMaterial(GlassMaterial::new(Color { x: 1.0, y: 1.0, z: 1.0 }, 1.5)),
+            0.5).unwrap());
+        let scene = SceneBuilder::new()
+            .add_quad(Vec { x: -50.0, y: 0.0, z: -50.0 }, Vec { x: 0.0, y: 0.0, z: 100.0 }, Vec { x: 100.0, y: 0.0, z: 0.0 }, floor)
+            .add_quad(Vec { x: -0.5, y: 1.0, z: -0.5 }, Vec { x: 0.0, y: 0.0, z: 1.0 }, Vec { x: 1.0, y: 0.0, z: 0.0 },
+                      Material::Lightsource(Lightsource::new(Color { x: 10.0, y: 10.0, z: 10.0 })))
+            .build().unwrap().0;
+        // 床の(0.75, 0, 0)で正反射した方向は、光源の中心(0, 1, 0)に向かう。
+        let ray = floor_ray();
+        let nee = estimate(4000, 1, |random| radiance_nee(&scene, &ray, random, &0).x);
+        let brdf = estimate(40000, 2, |random| path_tracing_radiance(&scene, &ray, random).x);
+        assert!(nee.0 > 0.0);
+        assert!(agree(nee, brdf), "nee {:?} brdf {:?}", nee, brdf);
+    }
+
+    // ヘアライン加工の金属（alpha_xとalpha_yの違うConductorMaterial）。
+    fn brushed_metal() -> Material {
+        Material::ConductorMaterial(ConductorMaterial::from_rough_metal(&RoughMetalMaterial::aluminium(), 0.05, 0.4))
+    }
+
+    // 異方性の向きは交差点の接線で決まるので、メッシュをテクスチャ座標ごと法線まわりに回すとpdfも一緒に回る。
+    // サンプリングの返すpdfと、光源サンプリングやMISで使うeval_pdf_with_frameも同じ基底で一致する。
+    #[test]
+    fn rotating_a_mesh_rotates_the_anisotropic_pdf() {
+        let mesh = Arc::new(Mesh::new(vec![Triangle::new(
+            Vec { x: -10.0, y: 0.0, z: -10.0 }, Vec { x: 0.0, y: 0.0, z: 10.0 }, Vec { x: 10.0, y: 0.0, z: -10.0 })]));
+        let metal = brushed_metal();
+        let up = Vec { x: 0.0, y: 1.0, z: 0.0 };
+        let rotation = Transform::rotation(&up, 1.0);
+        let origin = Vec { x: 0.6, y: 1.0, z: 0.2 };
+        let dir = Vec::normalize(-&origin);
+        let mirror = Vec::reflect(&dir, &up);
+
+        // 回転の無いものと回したもので、同じ点を回した向きから見る。
+        let hit = |transform: &Transform| -> (Hitpoint, Vec) {
+            let scene: Scene = vec![SceneObject::new(
+                Shape::Instance(Instance::new(mesh.clone(), transform.clone())), metal.clone())];
+            let origin = transform.vector(&origin);
+            let dir = transform.vector(&dir);
+            let mut hitpoint = intersect_scene(&scene, &Ray::new(&origin, &dir)).1;
+            hitpoint.face_forward(&dir);
+            (hitpoint, dir)
+        };
+        let pdf = |hitpoint: &Hitpoint, input: &Vec, output: &Vec| -> f64 {
+            let (tangent, bitangent) = hitpoint.tangent_frame();
+            metal.eval_pdf_with_frame(input, &hitpoint.normal, &tangent, &bitangent, output)
+        };
+        let (plain, plain_dir) = hit(&Transform::identity());
+        let (rotated, rotated_dir) = hit(&rotation);
+        let (plain_tangent, _) = plain.tangent_frame();
+        let (rotated_tangent, _) = rotated.tangent_frame();
+        assert!((&rotation.vector(&plain_tangent) - &rotated_tangent).length() < 1e-9);
+
+        // 正反射の方向から接線の向きにずらした方向。
+        let output = Vec::normalize(&mirror + &plain_tangent * 0.3);
+        let plain_pdf: f64 = pdf(&plain, &plain_dir, &output);
+        let rotated_pdf: f64 = pdf(&rotated, &rotated_dir, &rotation.vector(&output));
+        assert!((plain_pdf - rotated_pdf).abs() < 1e-9 * plain_pdf, "{} {}", plain_pdf, rotated_pdf);
+        // 回さなかった方向では、粗さの違う向きにずれたことになるのでpdfが変わる。
+        let unrotated_pdf: f64 = pdf(&rotated, &rotated_dir, &output);
+        assert!((plain_pdf - unrotated_pdf).abs() > 0.1 * plain_pdf, "{} {}", plain_pdf, unrotated_pdf);
+
+        let (tangent, bitangent) = rotated.tangent_frame();
+        let mut random = Random::new(5);
+        for _ in 0..64 {
+            let mut sampled_pdf: f64 = -1.0;
+            let mut brdf_value: Color = Color { x: 0.0, y: 0.0, z: 0.0 };
+            let output = metal.sample_with_frame(
+                &mut random, &rotated_dir, &rotated.normal, &tangent, &bitangent, &mut sampled_pdf, &mut brdf_value, &mut false);
+            let expected: f64 = pdf(&rotated, &rotated_dir, &output);
+            assert!((sampled_pdf - expected).abs() <= 1e-9 * expected, "{} {}", sampled_pdf, expected);
+        }
+    }
+
+    // 床の接線（z軸）とbrush_direction（x軸）が違っても、光源サンプリングとMISがBRDFサンプリングと同じ基底を使うので、
+    // NEEの推定はBRDFサンプリングだけの推定と一致する。
+    #[test]
+    fn nee_matches_brdf_sampling_for_brushed_metal() {
+        let scene = SceneBuilder::new()
+            .add_quad(Vec { x: -50.0, y: 0.0, z: -50.0 }, Vec { x: 0.0, y: 0.0, z: 100.0 }, Vec { x: 100.0, y: 0.0, z: 0.0 },
+                      brushed_metal())
+            .add_quad(Vec { x: -2.0, y: 1.0, z: -2.0 }, Vec { x: 0.0, y: 0.0, z: 4.0 }, Vec { x: 4.0, y: 0.0, z: 0.0 },
+                      Material::Lightsource(Lightsource::new(Color { x: 2.0, y: 2.0, z: 2.0 })))
+            .build().unwrap().0;
+        let ray = glossy_ray();
+        let nee = estimate(4000, 1, |random| radiance_nee(&scene, &ray, random, &0).x);
+        let brdf = estimate(4000, 2, |random| radiance(&scene, &ray, random, &0).x);
+        assert!(nee.0 > 0.0);
+        assert!(agree(nee, brdf), "nee {:?} brdf {:?}", nee, brdf);
+    }
+
+    // 片面光源は裏側を照らさないので、どちらの推定でも床は黒い。
+    #[test]
+    fn one_sided_light_does_not_light_its_back() {
+        let scene = floor_under_light(Lightsource::new(Color { x: 10.0, y: 10.0, z: 10.0 }).one_sided());
+        let ray = floor_ray();
+        let nee = estimate(1000, 1, |random| radiance_nee(&scene, &ray, random, &0).x);
+        let brdf = estimate(1000, 2, |random| radiance(&scene, &ray, random, &0).x);
+        assert_eq!(nee.0, 0.0);
+        assert_eq!(brdf.0, 0.0);
+    }
+
+    // 光源の下から床を見るレイ。正反射の方向は光源の中に入る。
+    fn glossy_ray() -> Ray<'static> {
+        static ORIGIN: Vec = Vec { x: 3.0, y: 0.5, z: 0.0 };
+        static DIR: Vec = Vec { x: -0.6, y: -0.8, z: 0.0 };
+        Ray::new(&ORIGIN, &DIR)
+    }
+
+    fn glossy_floor() -> Material {
+        Material::PhongMaterial(PhongMaterial::new(Color { x: 0.8, y: 0.8, z: 0.8 }, 200.0))
+    }
+
+    // 光沢のある床と、床の正反射の方向にある大きな光源。
+    fn glossy_floor_under_large_light() -> Scene {
+        SceneBuilder::new()
+            .add_quad(Vec { x: -50.0, y: 0.0, z: -50.0 }, Vec { x: 0.0, y: 0.0, z: 100.0 }, Vec { x: 100.0, y: 0.0, z: 0.0 },
+                      glossy_floor())
+            .add_quad(Vec { x: -2.0, y: 1.0, z: -2.0 }, Vec { x: 0.0, y: 0.0, z: 4.0 }, Vec { x: 4.0, y: 0.0, z: 0.0 },
+                      Material::Lightsource(Lightsource::new(Color { x: 2.0, y: 2.0, z: 2.0 })))
+            .build().unwrap().0
+    }
+
+    // 光沢面に映る大きな光源でも、MISを使ったNEEはBRDFサンプリングだけの推定と一致する。
+    #[test]
+    fn mis_matches_brdf_sampling_on_glossy_floor() {
+        let scene = glossy_floor_under_large_light();
+        let ray = glossy_ray();
+        let nee = estimate(4000, 1, |random| radiance_nee(&scene, &ray, random, &0).x);
+        let brdf = estimate(4000, 2, |random| radiance(&scene, &ray, random, &0).x);
+        assert!(nee.0 > 0.0);
+        assert!(agree(nee, brdf), "nee {:?} brdf {:?}", nee, brdf);
+    }
+
+    // 光沢面では、BRDFサンプリングで当たった光源も数えるMISの方が、光源サンプリングだけより分散が小さい。
+    // radiance_nee_clamp_indirectはMISを使わないので、十分大きな上限を渡して比べる。
+    #[test]
+    fn mis_reduces_variance_on_glossy_floor() {
+        let scene = glossy_floor_under_large_light();
+        let ray = glossy_ray();
+        let mis = estimate(4000, 1, |random| radiance_nee(&scene, &ray, random, &0).x);
+        let nee_only = estimate(4000, 1, |random| radiance_nee_clamp_indirect(&scene, &ray, random, &0, 1.0e30).x);
+        assert!(agree(mis, nee_only), "mis {:?} nee {:?}", mis, nee_only);
+        assert!(mis.1 < nee_only.1 * 0.5, "mis {:?} nee {:?}", mis, nee_only);
+    }
+
+    // 光源の選択確率が光源ごとに違うLightTreeでも、MISの重みは選択確率を含めて求めるので期待値は変わらない。
+    #[test]
+    fn mis_with_light_tree_matches_brdf_sampling() {
+        let floor = Material::PhongMaterial(PhongMaterial::new(Color { x: 0.8, y: 0.8, z: 0.8 }, 5.0));
+        let scene = SceneBuilder::new()
+            .add_quad(Vec { x: -50.0, y: 0.0, z: -50.0 }, Vec { x: 0.0, y: 0.0, z: 100.0 }, Vec { x: 100.0, y: 0.0, z: 0.0 },
+                      floor)
+            .add_quad(Vec { x: -2.0, y: 1.0, z: -2.0 }, Vec { x: 0.0, y: 0.0, z: 4.0 }, Vec { x: 4.0, y: 0.0, z: 0.0 },
+                      Material::Lightsource(Lightsource::new(Color { x: 2.0, y: 2.0, z: 2.0 })))
+            .add_sphere(Vec { x: 4.0, y: 1.5, z: 3.0 }, 0.5, Material::Lightsource(Lightsource::new(Color { x: 8.0, y: 8.0, z: 8.0 })))
+            .build().unwrap().0;
+        let light_tree = LightTree::new(&scene);
+        let ray = glossy_ray();
+        let nee = estimate(4000, 1, |random| radiance_nee_with_light_tree(&scene, &light_tree, &ray, random, &0).x);
+        let brdf = estimate(8000, 2, |random| radiance(&scene, &ray, random, &0).x);
+        assert!(agree(nee, brdf), "nee {:?} brdf {:?}", nee, brdf);
+    }
+
+    // 放射に模様のある片面光源を床に向けて置く。テクスチャ座標で点を選ぶ光源でも、MISの重みは一致する。
+    #[test]
+    fn mis_matches_brdf_sampling_for_emissive_texture() {
+        let texels: std::vec::Vec<Color> = vec![
+            Color { x: 8.0, y: 8.0, z: 8.0 }, Color { x: 0.5, y: 0.5, z: 0.5 },
+            Color { x: 0.5, y: 0.5, z: 0.5 }, Color { x: 2.0, y: 2.0, z: 2.0 }];
+        let light = EmissiveTextureMaterial::new(Arc::new(ImageTexture::new(2, 2, texels)), 1.0);
+        let scene = SceneBuilder::new()
+            .add_quad(Vec { x: -50.0, y: 0.0, z: -50.0 }, Vec { x: 0.0, y: 0.0, z: 100.0 }, Vec { x: 100.0, y: 0.0, z: 0.0 },
+                      glossy_floor())
+            .add_quad(Vec { x: -2.0, y: 1.0, z: -2.0 }, Vec { x: 4.0, y: 0.0, z: 0.0 }, Vec { x: 0.0, y: 0.0, z: 4.0 },
+                      Material::EmissiveTextureMaterial(light))
+            .build().unwrap().0;
+        let ray = glossy_ray();
+        let nee = estimate(4000, 1, |random| radiance_nee(&scene, &ray, random, &0).x);
+        let brdf = estimate(4000, 2, |random| radiance(&scene, &ray, random, &0).x);
+        assert!(nee.0 > 0.0);
+        assert!(agree(nee, brdf), "nee {:?} brdf {:?}", nee, brdf);
+    }
+
+    // 変形したメッシュのインスタンスの光源でも、光源上の点のpdf（Instance::surface_pdf）が一致する。
+    #[test]
+    fn mis_matches_brdf_sampling_for_instance_light() {
+        let mesh = Arc::new(Mesh::new(tessellate_sphere(Vec { x: 0.0, y: 0.0, z: 0.0 }, 1.0, 1)));
+        let transform = Transform::scaling(&Vec { x: 2.0, y: 0.3, z: 1.0 })
+            .then(&Transform::translation(&Vec { x: 0.0, y: 1.5, z: 0.0 }));
+        let floor = Shape::Quad(Quad::new(
+            Vec { x: -50.0, y: 0.0, z: -50.0 }, Vec { x: 0.0, y: 0.0, z: 100.0 }, Vec { x: 100.0, y: 0.0, z: 0.0 }));
+        let scene: Scene = vec![
+            SceneObject::new(floor, glossy_floor()),
+            SceneObject::new(Shape::Instance(Instance::new(mesh, transform)),
+                             Material::Lightsource(Lightsource::new(Color { x: 2.0, y: 2.0, z: 2.0 }))),
+        ];
+        let ray = glossy_ray();
+        let nee = estimate(4000, 1, |random| radiance_nee(&scene, &ray, random, &0).x);
+        let brdf = estimate(4000, 2, |random| radiance(&scene, &ray, random, &0).x);
+        assert!(nee.0 > 0.0);
+        assert!(agree(nee, brdf), "nee {:?} brdf {:?}", nee, brdf);
+    }
+
+    // ガラス越しに見た光源（δ関数のマテリアルの先で当たった光源）は、MISの重みを掛けずにそのまま数える。
+    #[test]
+    fn light_seen_through_glass_keeps_full_weight() {
+        let scene = SceneBuilder::new()
+            .add_sphere(Vec { x: 0.0, y: 0.0, z: -3.0 }, 1.0,
+                        Material::GlassMaterial(GlassMaterial::new(Color { x: 1.0, y: 1.0, z: 1.0 }, 1.5)))
+            .add_quad(Vec { x: -5.0, y: -5.0, z: -10.0 }, Vec { x: 10.0, y: 0.0, z: 0.0 }, Vec { x: 0.0, y: 10.0, z: 0.0 },
+                      Material::Lightsource(Lightsource::new(Color { x: 1.0, y: 1.0, z: 1.0 })))
+            .build().unwrap().0;
+        let origin: Vec = Vec { x: 0.0, y: 0.0, z: 0.0 };
+        let dir: Vec = Vec { x: 0.0, y: 0.0, z: -1.0 };
+        let ray = Ray::new(&origin, &dir);
+        let nee = estimate(4000, 1, |random| radiance_nee(&scene, &ray, random, &0).x);
+        let brdf = estimate(4000, 2, |random| radiance(&scene, &ray, random, &0).x);
+        // 正面から入る光はほとんど反射せず、大きな光源へ抜ける。
+        assert!(nee.0 > 0.9, "nee {:?}", nee);
+        assert!(agree(nee, brdf), "nee {:?} brdf {:?}", nee, brdf);
+    }
+
+    // 屈折率の比eta = n1 / n2で、dの向きに進む光を法線n（dと逆向き）の面で屈折させた方向。
+    fn refract(d: &Vec, n: &Vec, eta: f64) -> Vec {
+        let cos_i: f64 = -Vec::dot(n, d);
+        let cos_t: f64 = (1.0 - eta * eta * (1.0 - cos_i * cos_i)).sqrt();
+        d * eta + n * (eta * cos_i - cos_t)
+    }
+
+    // 原点を中心とする半径radiusの球と、origからdの向きに進むレイの交点。farなら遠い側。
+    fn hit_centered_sphere(orig: &Vec, d: &Vec, radius: f64, far: bool) -> Vec {
+        let b: f64 = Vec::dot(orig, d);
+        let c: f64 = orig.length_squared() - radius * radius;
+        let root: f64 = (b * b - c).sqrt();
+        let t: f64 = if far { -b + root } else { -b - root };
+        orig + d * t
+    }
+
+    // 半径1のガラス球の中に半径0.8の水の球がある同心球を通り抜けたあとの(位置, 方向)。
+    // glass_to_waterはガラスから水に入るときの屈折率の比。
+    fn trace_glass_with_water(orig: &Vec, d: &Vec, glass_to_water: f64) -> (Vec, Vec) {
+        let p1: Vec = hit_centered_sphere(orig, d, 1.0, false);
+        let d1: Vec = refract(d, &Vec::normalize(p1.clone()), 1.0 / 1.5);
+        let p2: Vec = hit_centered_sphere(&p1, &d1, 0.8, false);
+        let d2: Vec = refract(&d1, &Vec::normalize(p2.clone()), glass_to_water);
+        let p3: Vec = hit_centered_sphere(&p2, &d2, 0.8, true);
+        let d3: Vec = refract(&d2, &-Vec::normalize(p3.clone()), 1.0 / glass_to_water);
+        let p4: Vec = hit_centered_sphere(&p3, &d3, 1.0, true);
+        let d4: Vec = refract(&d3, &-Vec::normalize(p4.clone()), 1.5);
+        (p4, d4)
+    }
+
+    // ガラス球の中の水の球を通ると、レイはどの境界でも両側の屈折率の比で曲がる。
+    // 正しい比（ガラスと水の境界では1.5 / 1.33）で曲がったときだけ当たる位置に小さな光源を置く。
+    // 水に入るところを真空との比（1 / 1.33）で曲げるradianceでは光源に届かない。
+    #[test]
+    fn media_stack_refracts_with_both_iors() {
+        let origin: Vec = Vec { x: 0.5, y: 0.0, z: 5.0 };
+        let dir: Vec = Vec { x: 0.0, y: 0.0, z: -1.0 };
+        let (exit, exit_dir) = trace_glass_with_water(&origin, &dir, 1.5 / 1.33);
+        let light_center: Vec = &exit + &exit_dir * 3.0;
+
+        // 真空との比で曲げた経路は光源から十分に離れたところを通る。
+        let (wrong_exit, wrong_dir) = trace_glass_with_water(&origin, &dir, 1.0 / 1.33);
+        let to_light: Vec = &light_center - &wrong_exit;
+        let along: f64 = Vec::dot(&to_light, &wrong_dir);
+        assert!((&to_light - &wrong_dir * along).length() > 0.2);
+
+        let scene = SceneBuilder::new()
+            .add_sphere(Vec { x: 0.0, y: 0.0, z: 0.0 }, 1.0, Material::GlassMaterial(GlassMaterial::new(Color { x: 1.0, y: 1.0, z: 1.0 }, 1.5)))
+            .add_sphere(Vec { x: 0.0, y: 0.0, z: 0.0 }, 0.8, Material::GlassMaterial(GlassMaterial::new(Color { x: 1.0, y: 1.0, z: 1.0 }, 1.33)))
+            .add_sphere(light_center, 0.05, Material::Lightsource(Lightsource::new(Color { x: 1.0, y: 1.0, z: 1.0 })))
+            .build().unwrap().0;
+        let ray = Ray::new(&origin, &dir);
+        let media = estimate(2000, 1, |random| radiance_with_media(&scene, &ray, random, &0, &MediumStack::new()).x);
+        let vacuum = estimate(2000, 2, |random| radiance(&scene, &ray, random, &0).x);
+        // 四つの境界での反射（それぞれ数%）を除いた分が届く。
+        assert!(media.0 > 0.8, "media {:?}", media);
+        assert!(vacuum.0 < 0.1, "vacuum {:?}", vacuum);
+    }
+
+    // 入れ子の媒質を出入りするとき、物体の外側の屈折率は一つ外の媒質のものになる。
+    #[test]
+    fn medium_stack_tracks_nested_media() {
+        let mut media = MediumStack::new();
+        assert_eq!(media.current_ior(), 1.0);
+        media.push(1.5);
+        media.push(1.33);
+        assert_eq!(media.current_ior(), 1.33);
+        assert_eq!(media.outer_ior_on_exit(1.33), 1.5);
+        media.remove(1.33);
+        assert_eq!(media.outer_ior_on_exit(1.5), 1.0);
+        media.remove(1.5);
+        assert_eq!(media.depth(), 0);
+        // 積んでいない媒質から出ても何もしない。
+        media.remove(1.5);
+        assert_eq!(media.current_ior(), 1.0);
+    }
+
+    // カメラに裏側を向けた片面の壁と、その向こう側にある光源。
+    // extra_lightがあれば、それもカメラの側に置く。
+    fn wall_facing_away(extra_light: bool) -> Scene {
+        let wall = Material::LambertianMaterial(LambertianMaterial::new(Color { x: 0.5, y: 0.5, z: 0.5 }));
+        let light = || Material::Lightsource(Lightsource::new(Color { x: 4.0, y: 4.0, z: 4.0 }));
+        let mut builder = SceneBuilder::new()
+            .add_quad(Vec { x: -2.0, y: -2.0, z: 0.0 }, Vec { x: 0.0, y: 4.0, z: 0.0 }, Vec { x: 4.0, y: 0.0, z: 0.0 }, wall)
+            .add_quad(Vec { x: -1.0, y: -1.0, z: -1.0 }, Vec { x: 0.0, y: 2.0, z: 0.0 }, Vec { x: 2.0, y: 0.0, z: 0.0 }, light());
+        if extra_light {
+            builder = builder.add_quad(
+                Vec { x: 0.5, y: -0.5, z: 1.0 }, Vec { x: 0.0, y: 1.0, z: 0.0 }, Vec { x: 1.0, y: 0.0, z: 0.0 }, light());
+        }
+        builder.build().unwrap().0
+    }
+
+    fn wall_ray() -> Ray<'static> {
+        static ORIGIN: Vec = Vec { x: 0.0, y: 0.0, z: 2.0 };
+        static DIR: Vec = Vec { x: 0.0, y: 0.0, z: -1.0 };
+        Ray::new(&ORIGIN, &DIR)
+    }
+
+    // 片面の壁を裏側から見ても、壁の向こうの光源の光は漏れてこない。
+    #[test]
+    fn back_of_one_sided_wall_does_not_leak_light() {
+        let scene = wall_facing_away(false);
+        let ray = wall_ray();
+        let brdf = estimate(2000, 1, |random| radiance(&scene, &ray, random, &0).x);
+        let nee = estimate(2000, 2, |random| radiance_nee(&scene, &ray, random, &0).x);
+        assert_eq!(brdf.0, 0.0);
+        assert_eq!(nee.0, 0.0);
+    }
+
+    // 裏側から見た壁も、見ている側にある光源で照らされる。
+    #[test]
+    fn back_of_one_sided_wall_is_lit_from_the_viewer_side() {
+        let scene = wall_facing_away(true);
+        let ray = wall_ray();
+        let brdf = estimate(20000, 1, |random| radiance(&scene, &ray, random, &0).x);
+        let nee = estimate(2000, 2, |random| radiance_nee(&scene, &ray, random, &0).x);
+        assert!(nee.0 > 0.0);
+        assert!(agree(nee, brdf), "nee {:?} brdf {:?}", nee, brdf);
+    }
+
+    // カメラがガラス球の中にあるとき、臨界角より浅い角度で内側から当たったレイは全反射せず、
+    // Snellの法則の向きに屈折して外に出る。その先に置いた小さな光源が見える。
+    #[test]
+    fn camera_inside_glass_sphere_refracts_out() {
+        let origin: Vec = Vec { x: 0.5, y: 0.0, z: 0.0 };
+        let dir: Vec = Vec { x: 0.0, y: 0.0, z: -1.0 };
+        // 球面には法線と30度の角度で当たる。1.5 * sin 30° = 0.75 < 1なので屈折する。
+        let exit: Vec = hit_centered_sphere(&origin, &dir, 1.0, true);
+        let exit_dir: Vec = refract(&dir, &-Vec::normalize(exit.clone()), 1.5);
+        assert!((Vec::dot(&exit_dir, &Vec::normalize(exit.clone())) - (1.0f64 - 0.75 * 0.75).sqrt()).abs() < 1.0e-12);
+
+        let scene = SceneBuilder::new()
+            .add_sphere(Vec { x: 0.0, y: 0.0, z: 0.0 }, 1.0, Material::GlassMaterial(GlassMaterial::new(Color { x: 1.0, y: 1.0, z: 1.0 }, 1.5)))
+            .add_sphere(&exit + &exit_dir * 2.0, 0.05, Material::Lightsource(Lightsource::new(Color { x: 1.0, y: 1.0, z: 1.0 })))
+            .build().unwrap().0;
+        let ray = Ray::new(&origin, &dir);
+        let brdf = estimate(2000, 1, |random| radiance(&scene, &ray, random, &0).x);
+        let nee = estimate(2000, 2, |random| radiance_nee(&scene, &ray, random, &0).x);
+        let mut inside_glass = MediumStack::new();
+        inside_glass.push(1.5);
+        let media = estimate(2000, 3, |random| radiance_with_media(&scene, &ray, random, &0, &inside_glass).x);
+        // 境界での反射（数%）を除いた分が光源に届く。
+        assert!(brdf.0 > 0.9, "brdf {:?}", brdf);
+        assert!(nee.0 > 0.9, "nee {:?}", nee);
+        assert!(media.0 > 0.9, "media {:?}", media);
+    }
+
+    // u < 0.5の半分が不透明で、残りの半分が抜けになるマテリアル。
+    fn half_cutout(inner: Material) -> Material {
+        let white: Color = Color { x: 1.0, y: 1.0, z: 1.0 };
+        let alpha = ImageTexture::with_alpha(2, 1, vec![white.clone(), white], vec![1.0, 0.0]);
+        Material::CutoutMaterial(CutoutMaterial::new(inner, Arc::new(alpha), 0.5))
+    }
+
+    // 半分が抜けの黒い板を通して、その向こうの光源を見る。板に当たったレイの半分ほどが光源まで届く。
+    #[test]
+    fn half_cutout_quad_passes_half_the_rays() {
+        let black = Material::LambertianMaterial(LambertianMaterial::new(Color { x: 0.0, y: 0.0, z: 0.0 }));
+        let scene = SceneBuilder::new()
+            .add_quad(Vec { x: 0.0, y: -0.5, z: 0.0 }, Vec { x: 1.0, y: 0.0, z: 0.0 }, Vec { x: 0.0, y: 1.0, z: 0.0 }, half_cutout(black))
+            .add_quad(Vec { x: -5.0, y: -5.0, z: -1.0 }, Vec { x: 10.0, y: 0.0, z: 0.0 }, Vec { x: 0.0, y: 10.0, z: 0.0 },
+                      Material::Lightsource(Lightsource::new(Color { x: 1.0, y: 1.0, z: 1.0 })))
+            .build().unwrap().0;
+        let dir: Vec = Vec { x: 0.0, y: 0.0, z: -1.0 };
+        let through = |integrator: fn(&Scene, &Ray, &mut dyn Sampler, &i32) -> Color, seed: u64| {
+            estimate(4000, seed, |random| {
+                let origin: Vec = Vec { x: random.next_1d(), y: 0.0, z: 2.0 };
+                integrator(&scene, &Ray::new(&origin, &dir), random, &0).x
+            })
+        };
+        let brdf = through(radiance, 1);
+        let nee = through(radiance_nee, 2);
+        assert!(agree(brdf, (0.5, 0.0)), "brdf {:?}", brdf);
+        assert!(agree(nee, (0.5, 0.0)), "nee {:?}", nee);
+    }
+
+    // 床と光源の間にある板の抜けを通して、シャドウレイも光源まで届く。
+    // 光源サンプリングとBRDFサンプリングの推定が一致する。
+    #[test]
+    fn shadow_rays_pass_through_cutouts() {
+        let mut scene = floor_under_light(Lightsource::new(Color { x: 10.0, y: 10.0, z: 10.0 }));
+        let black = Material::LambertianMaterial(LambertianMaterial::new(Color { x: 0.0, y: 0.0, z: 0.0 }));
+        scene.push(SceneObject::new(
+            Shape::Quad(Quad::new(Vec { x: -1.0, y: 0.5, z: -1.0 }, Vec { x: 2.0, y: 0.0, z: 0.0 }, Vec { x: 0.0, y: 0.0, z: 2.0 })),
+            half_cutout(black)));
+        let ray = floor_ray();
+        let nee = estimate(4000, 1, |random| radiance_nee(&scene, &ray, random, &0).x);
+        let brdf = estimate(40000, 2, |random| radiance(&scene, &ray, random, &0).x);
+        assert!(nee.0 > 0.0);
+        assert!(agree(nee, brdf), "nee {:?} brdf {:?}", nee, brdf);
+    }
+
+    // 光源の強度を2倍にすると、同じ乱数で追跡した床の明るさもちょうど2倍になる。
+    #[test]
+    fn doubling_light_intensity_doubles_the_illumination() {
+        let color: Color = Color { x: 1.0, y: 0.8, z: 0.6 };
+        let dim = floor_under_light(Lightsource::with_intensity(color.clone(), 5.0));
+        let bright = floor_under_light(Lightsource::with_intensity(color, 10.0));
+        let ray = floor_ray();
+        for integrator in [radiance_nee as fn(&Scene, &Ray, &mut dyn Sampler, &i32) -> Color, radiance].iter() {
+            let dim = estimate(500, 1, |random| integrator(&dim, &ray, random, &0).y);
+            let bright = estimate(500, 1, |random| integrator(&bright, &ray, random, &0).y);
+            assert!(dim.0 > 0.0);
+            assert!((bright.0 - 2.0 * dim.0).abs() < 1e-9 * bright.0, "dim {:?} bright {:?}", dim, bright);
+        }
+    }
+
+    // 片面光源を裏側から見ると黒く、表側から見ると放射が見える。
+    #[test]
+    fn one_sided_light_is_black_from_behind() {
+        let scene = floor_under_light(Lightsource::new(Color { x: 10.0, y: 10.0, z: 10.0 }).one_sided());
+        // 光源は床に裏側を向けているので、床から見上げると裏側が見える。
+        let up: Vec = Vec { x: 0.0, y: 1.0, z: 0.0 };
+        let below: Vec = Vec { x: 0.0, y: 0.5, z: 0.0 };
+        let back = estimate(100, 1, |random| radiance(&scene, &Ray::new(&below, &up), random, &0).x);
+        assert_eq!(back.0, 0.0);
+        let down: Vec = Vec { x: 0.0, y: -1.0, z: 0.0 };
+        let above: Vec = Vec { x: 0.0, y: 3.0, z: 0.0 };
+        let front = estimate(100, 2, |random| radiance(&scene, &Ray::new(&above, &down), random, &0).x);
+        assert_eq!(front.0, 10.0);
+    }
+
+    // 球光源からの放射照度は、点が光源に近づくほど大きくなり、光源が地平線の下に沈むと0になる。
+    #[test]
+    fn analytic_sphere_irradiance_grows_closer_and_vanishes_below_horizon() {
+        let emission: Color = Color { x: 1.0, y: 2.0, z: 3.0 };
+        let normal: Vec = Vec { x: 0.0, y: 1.0, z: 0.0 };
+        let point: Vec = Vec { x: 0.0, y: 0.0, z: 0.0 };
+        let irradiance = |center: Vec| -> Color { analytic_sphere_irradiance(&point, &normal, &center, 0.5, &emission) };
+
+        let mut previous: f64 = 0.0;
+        for &height in &[10.0, 5.0, 2.0, 1.0, 0.6] {
+            let e: Color = irradiance(Vec { x: 0.0, y: height, z: 0.0 });
+            assert!(e.x > previous, "{} {:?}", height, e);
+            assert!((e.y - 2.0 * e.x).abs() < 1e-12 && (e.z - 3.0 * e.x).abs() < 1e-12, "{:?}", e);
+            previous = e.x;
+        }
+
+        // 真上の遠い光源は、点光源と同じく放射強度 / 距離の二乗に近づく。
+        let far: f64 = irradiance(Vec { x: 0.0, y: 100.0, z: 0.0 }).x;
+        assert!((far - K_PI * 0.25 / 10000.0).abs() < 1e-3 * far, "{}", far);
+
+        // 光源が完全に地平線の下にあると0。
+        for &height in &[-0.6, -2.0, -10.0] {
+            let e: Color = irradiance(Vec { x: 3.0, y: height, z: 0.0 });
+            assert!(e.x == 0.0 && e.y == 0.0 && e.z == 0.0, "{} {:?}", height, e);
+        }
+
+        // 地平線をまたぐときは、光源の上に出ている部分だけが照らす。円錐のサンプリングによる積分と一致する。
+        for &height in &[0.3, 0.0, -0.3] {
+            let center: Vec = Vec { x: 1.0, y: height, z: 0.0 };
+            let analytic: f64 = irradiance(center.clone()).x;
+            let to_light: Vec = &center - &point;
+            let cos_theta_max: f64 = (1.0 - 0.25 / to_light.length_squared()).sqrt();
+            let axis: Vec = Vec::normalize(to_light);
+            let reference = estimate(100000, 11, |random| {
+                let (dir, pdf) = Sampling::uniform_cone(random, &axis, cos_theta_max);
+                Vec::dot(&dir, &normal).max(0.0) / pdf
+            });
+            assert!(agree((analytic, 0.0), reference), "{} {} {:?}", height, analytic, reference);
+        }
+    }
+
+    // 間接光だけをクランプする。カメラから直接見える光源と直接光はそのままで、間接光で照らされたところは上限で抑えられる。
+    #[test]
+    fn clamp_indirect_keeps_direct_light_and_caps_indirect() {
+        let max_indirect: f64 = 0.01;
+        let no_clamp: f64 = 1.0e30;
+
+        // 床と、床に裏側を向けた両面の光源。床を照らすのは直接光だけ。
+        let direct_scene = floor_under_light(Lightsource::new(Color { x: 10.0, y: 10.0, z: 10.0 }));
+        let ray = floor_ray();
+        for seed in 0..200 {
+            let clamped: Color = radiance_nee_clamp_indirect(&direct_scene, &ray, &mut Random::new(seed), &0, max_indirect);
+            let unclamped: Color = radiance_nee_clamp_indirect(&direct_scene, &ray, &mut Random::new(seed), &0, no_clamp);
+            assert_eq!(clamped.x.to_bits(), unclamped.x.to_bits(), "{}", seed);
+        }
+        // 光源を直接見たピクセルもそのまま。
+        let down: Vec = Vec { x: 0.0, y: -1.0, z: 0.0 };
+        let above: Vec = Vec { x: 0.0, y: 3.0, z: 0.0 };
+        let emission: Color = radiance_nee_clamp_indirect(&direct_scene, &Ray::new(&above, &down), &mut Random::new(1), &0, max_indirect);
+        assert_eq!(emission.x, 10.0);
+
+        // 光源を上向きの片面のものにして、その上に下向きの天井を置く。床は天井で反射した間接光だけで照らされる。
+        let gray = Material::LambertianMaterial(LambertianMaterial::new(Color { x: 0.5, y: 0.5, z: 0.5 }));
+        let indirect_scene: Scene = SceneBuilder::new()
+            .add_quad(Vec { x: -50.0, y: 0.0, z: -50.0 }, Vec { x: 0.0, y: 0.0, z: 100.0 }, Vec { x: 100.0, y: 0.0, z: 0.0 }, gray.clone())
+            .add_quad(Vec { x: -50.0, y: 2.0, z: -50.0 }, Vec { x: 100.0, y: 0.0, z: 0.0 }, Vec { x: 0.0, y: 0.0, z: 100.0 }, gray)
+            .add_quad(Vec { x: -0.5, y: 1.0, z: -0.5 }, Vec { x: 0.0, y: 0.0, z: 1.0 }, Vec { x: 1.0, y: 0.0, z: 0.0 },
+                      Material::Lightsource(Lightsource::new(Color { x: 10.0, y: 10.0, z: 10.0 }).one_sided()))
+            .build().unwrap().0;
+        let origin: Vec = Vec { x: 3.0, y: 1.5, z: 0.0 };
+        let dir: Vec = Vec { x: -0.6, y: -0.8, z: 0.0 };
+        let ray = Ray::new(&origin, &dir);
+        let unclamped = estimate(4000, 3, |random| radiance_nee_clamp_indirect(&indirect_scene, &ray, random, &0, no_clamp).x);
+        assert!(unclamped.0 > 4.0 * max_indirect, "{:?}", unclamped);
+        let clamped = estimate(4000, 3, |random| {
+            let value: f64 = radiance_nee_clamp_indirect(&indirect_scene, &ray, random, &0, max_indirect).x;
+            assert!(value <= max_indirect * (1.0 + 1e-12), "{}", value);
+            value
+        });
+        assert!(clamped.0 < unclamped.0);
+    }
+
+    // next_1dを呼んだ回数を数えるサンプラー。経路が何回反射したかの目安になる。
+    struct CountingSampler {
+        random: Random,
+        calls: usize,
+    }
+
+    impl Sampler for CountingSampler {
+        fn next_1d(&mut self) -> f64 {
+            self.calls += 1;
+            self.random.next_1d()
+        }
+
+        fn start_pixel(&mut self, pixel_index: u64, sample_index: u64) {
+            self.random.start_pixel(pixel_index, sample_index)
+        }
+
+        fn start_dimension(&mut self, dimension: u32) {
+            self.random.start_dimension(dimension)
+        }
+    }
+
+    // estimateと同じだが、CountingSamplerを渡して、next_1dを呼んだ回数の合計も返す。
+    fn counted_estimate<F: FnMut(&mut dyn Sampler) -> f64>(samples: usize, seed: u64, mut f: F) -> ((f64, f64), usize) {
+        let mut random = CountingSampler { random: Random::new(seed), calls: 0 };
+        let mut sum: f64 = 0.0;
+        let mut sum_squared: f64 = 0.0;
+        for _ in 0..samples {
+            let value: f64 = f(&mut random);
+            sum += value;
+            sum_squared += value * value;
+        }
+        let mean: f64 = sum / samples as f64;
+        let variance: f64 = (sum_squared / samples as f64 - mean * mean).max(0.0);
+        ((mean, (variance / samples as f64).sqrt()), random.calls)
+    }
+
+    // 赤い球の内側に光源の球を置いた、何度も反射する色付きのシーン。
+    fn red_sphere_around_a_light() -> Scene {
+        let red = Material::LambertianMaterial(LambertianMaterial::new(Color { x: 0.8, y: 0.2, z: 0.2 }));
+        let light = Material::Lightsource(Lightsource::new(Color { x: 1.0, y: 1.0, z: 1.0 }));
+        vec![
+            SceneObject::new(Shape::Sphere(Sphere::new(10.0, Vec { x: 0.0, y: 0.0, z: 0.0 })), red),
+            SceneObject::new(Shape::Sphere(Sphere::new(2.0, Vec { x: 0.0, y: 0.0, z: 0.0 })), light),
+        ]
+    }
+
+    // どちらのロシアンルーレットでも、打ち切らない推定と同じ明るさになる。
+    // 赤い壁では輝度が最大のチャンネルより小さいので、Luminanceのほうが多くの経路を打ち切る。
+    #[test]
+    fn both_roulette_strategies_stay_unbiased_and_luminance_stops_earlier() {
+        let scene = red_sphere_around_a_light();
+        let origin: Vec = Vec { x: 0.0, y: 0.0, z: -5.0 };
+        let dir: Vec = Vec { x: 0.0, y: 0.0, z: -1.0 };
+        let ray = Ray::new(&origin, &dir);
+
+        let samples: usize = 20000;
+        let run = |roulette: Option<RussianRoulette>, seed: u64| -> ((f64, f64), usize) {
+            counted_estimate(samples, seed, |random| match roulette {
+                Some(strategy) => radiance_with_roulette(&scene, &ray, random, &0, strategy).x,
+                None => radiance(&scene, &ray, random, &0).x,
+            })
+        };
+
+        let (reference, reference_calls) = run(None, 1);
+        let (max_component, max_component_calls) = run(Some(RussianRoulette::MaxComponent), 2);
+        let (luminance, luminance_calls) = run(Some(RussianRoulette::Luminance), 3);
+        assert!(reference.0 > 0.0);
+        assert!(agree(max_component, reference), "{:?} {:?}", max_component, reference);
+        assert!(agree(luminance, reference), "{:?} {:?}", luminance, reference);
+        assert!(luminance_calls < max_component_calls && max_component_calls < reference_calls,
+                "{} {} {}", luminance_calls, max_component_calls, reference_calls);
+    }
+
+    // 打ち切りを始める深さと続ける確率の下限をどう変えても、明るさは打ち切らない推定と同じ。
+    // 下限を1にすると打ち切らなくなり、下限を下げるほど、また早くから打ち切るほど乱数を使う回数が減る。
+    #[test]
+    fn roulette_start_depth_and_survival_floor_keep_the_brightness() {
+        let scene = red_sphere_around_a_light();
+        let origin: Vec = Vec { x: 0.0, y: 0.0, z: -5.0 };
+        let dir: Vec = Vec { x: 0.0, y: 0.0, z: -1.0 };
+        let ray = Ray::new(&origin, &dir);
+
+        let samples: usize = 10000;
+        let (reference, reference_calls) = counted_estimate(samples, 1, |random| radiance(&scene, &ray, random, &0).x);
+        let mut calls_by_setting: std::vec::Vec<usize> = std::vec::Vec::new();
+        let sweep: [(i32, f64); 5] = [(0, 0.0), (0, 0.9), (1, ROULETTE_MIN_SURVIVAL), (5, 0.0), (ROULETTE_START_DEPTH, 1.0)];
+        for (index, &(start_depth, min_survival)) in sweep.iter().enumerate() {
+            let settings = RouletteSettings { start_depth, min_survival, ..RouletteSettings::default() };
+            let (estimate, calls) = counted_estimate(samples, 2 + index as u64, |random| {
+                radiance_with_roulette_settings(&scene, &ray, random, &0, settings).x
+            });
+            assert!(agree(estimate, reference), "{:?} {:?} {:?}", settings, estimate, reference);
+            calls_by_setting.push(calls);
+        }
+        // 下限1では、経路ごとに続けるかを決める乱数を一つずつ余分に使うだけ。
+        assert!(calls_by_setting[4] > reference_calls, "{:?} {}", calls_by_setting, reference_calls);
+        assert!(calls_by_setting[0] < calls_by_setting[1], "{:?}", calls_by_setting);
+        assert!(calls_by_setting[0] < calls_by_setting[3] && calls_by_setting[3] < calls_by_setting[4],
+                "{:?}", calls_by_setting);
+    }
+
+    // 左半分だけ明るいテクスチャの光源を、拡散反射する床のすぐ上に床へ向けて置く。
+    // NEEで照らした床には光源の模様が映り、明るい半分の真下が暗い半分の真下より明るくなる。
+    #[test]
+    fn nee_projects_the_emissive_texture_onto_the_floor() {
+        let texels: std::vec::Vec<Color> = vec![Color { x: 10.0, y: 10.0, z: 10.0 }, Color { x: 0.5, y: 0.5, z: 0.5 }];
+        let light = EmissiveTextureMaterial::new(Arc::new(ImageTexture::new(2, 1, texels)), 1.0);
+        let floor = Material::LambertianMaterial(LambertianMaterial::new(Color { x: 0.5, y: 0.5, z: 0.5 }));
+        let scene = SceneBuilder::new()
+            .add_quad(Vec { x: -50.0, y: 0.0, z: -50.0 }, Vec { x: 0.0, y: 0.0, z: 100.0 }, Vec { x: 100.0, y: 0.0, z: 0.0 }, floor)
+            .add_quad(Vec { x: -1.0, y: 0.2, z: -1.0 }, Vec { x: 2.0, y: 0.0, z: 0.0 }, Vec { x: 0.0, y: 0.0, z: 2.0 },
+                      Material::EmissiveTextureMaterial(light))
+            .build().unwrap().0;
+
+        // 光源と床の間から、x = ±0.5の床の点を真下に見る。
+        let down: Vec = Vec { x: 0.0, y: -1.0, z: 0.0 };
+        let brightness = |x: f64| -> (f64, f64) {
+            let origin: Vec = Vec { x, y: 0.1, z: 0.0 };
+            let ray = Ray::new(&origin, &down);
+            estimate(2000, 1, |random| radiance_nee(&scene, &ray, random, &0).x)
+        };
+        let under_bright = brightness(-0.5);
+        let under_dark = brightness(0.5);
+        assert!(under_dark.0 > 0.0);
+        assert!(under_bright.0 > 4.0 * under_dark.0, "{:?} {:?}", under_bright, under_dark);
+    }
+
+    // カメラから見えない光源は、カメラレイが直接当たると黒いが、床は見える光源と同じだけ照らす。
+    #[test]
+    fn hidden_light_is_black_to_the_camera_but_still_lights_the_floor() {
+        let intensity: Color = Color { x: 10.0, y: 10.0, z: 10.0 };
+        let visible = floor_under_light(Lightsource::new(intensity.clone()));
+        let hidden = floor_under_light(Lightsource::new(intensity).hidden_from_camera());
+        let origin: Vec = Vec { x: 0.0, y: 0.5, z: 0.0 };
+        let up: Vec = Vec { x: 0.0, y: 1.0, z: 0.0 };
+        let to_light = Ray::new(&origin, &up);
+
+        let mut random = Random::new(1);
+        assert!(radiance(&visible, &to_light, &mut random, &0).x > 0.0);
+        assert!(radiance_nee(&visible, &to_light, &mut random, &0).x > 0.0);
+        assert_eq!(radiance(&hidden, &to_light, &mut random, &0).x, 0.0);
+        assert_eq!(radiance_nee(&hidden, &to_light, &mut random, &0).x, 0.0);
+
+        let ray = floor_ray();
+        let lit_by_visible = estimate(4000, 2, |random| radiance_nee(&visible, &ray, random, &0).x);
+        let lit_by_hidden = estimate(4000, 2, |random| radiance_nee(&hidden, &ray, random, &0).x);
+        assert!(lit_by_hidden.0 > 0.0);
+        assert_eq!(lit_by_hidden, lit_by_visible);
+    }
+
+    // 上だけを照らす片面光源と、その光を床に返す小さな天井。床はすべて間接光で照らされる。
+    // カメラレイが決まっているので、推定値の分散はすべて最初の拡散面から先の経路による。
+    // 天井が小さいので分岐した先の経路はすぐに外へ抜け、分岐しても手間はsplit倍ほどには増えない。
+    // 間接光をsplit本に分岐すると、next_1dを呼ぶ回数（手間の目安）を揃えても分岐しないより分散が小さくなる。
+    #[test]
+    fn splitting_reduces_indirect_variance_at_equal_cost() {
+        let mut scene = floor_under_light(Lightsource::new(Color { x: 10.0, y: 10.0, z: 10.0 }).one_sided());
+        let ceiling = Material::LambertianMaterial(LambertianMaterial::new(Color { x: 0.8, y: 0.8, z: 0.8 }));
+        scene.push(SceneObject::new(
+            Shape::Quad(Quad::new(Vec { x: -2.0, y: 2.0, z: -2.0 }, Vec { x: 4.0, y: 0.0, z: 0.0 }, Vec { x: 0.0, y: 0.0, z: 4.0 })),
+            ceiling));
+        let ray = floor_ray();
+
+        let samples: usize = 4000;
+        let split: usize = 8;
+        let (unsplit, unsplit_calls) = counted_estimate(samples, 1, |random| radiance_nee(&scene, &ray, random, &0).x);
+        // 分岐するほうのサンプル数を減らして、next_1dを呼ぶ回数の合計を揃える。
+        let trial_samples: usize = 200;
+        let (_, trial_calls) = counted_estimate(trial_samples, 2, |random| radiance_nee_with_splitting(&scene, &ray, random, &0, split).x);
+        let split_samples: usize = unsplit_calls * trial_samples / trial_calls;
+        let (splitting, splitting_calls) =
+            counted_estimate(split_samples, 3, |random| radiance_nee_with_splitting(&scene, &ray, random, &0, split).x);
+
+        assert!(unsplit.0 > 0.0);
+        assert!((splitting_calls as f64 / unsplit_calls as f64 - 1.0).abs() < 0.1, "{} {}", splitting_calls, unsplit_calls);
+        assert!(agree(splitting, unsplit), "{:?} {:?}", splitting, unsplit);
+        assert!(splitting.1 < unsplit.1, "{:?} {:?}", splitting, unsplit);
+    }
+
+    // δ関数のマテリアルでは、光源サンプリングのpdfがいくらでもMISの重みは1になる。
+    // 球の中心を通って光源を見るレイは、境界ごとに垂直入射の反射率R = ((n - 1) / (n + 1))^2で反射し、
+    // 球の中で往復した分も含めて (1 - R)^2 / (1 - R^2) = (1 - R) / (1 + R) の明るさで光源が見える。
+    // どちらの推定もこの値になる。
+    #[test]
+    fn delta_materials_get_full_weight_and_the_expected_brightness() {
+        let glass = GlassMaterial::new(Color { x: 1.0, y: 1.0, z: 1.0 }, 1.5);
+        let (direction, normal) = (Vec { x: 0.0, y: 0.0, z: -1.0 }, Vec { x: 0.0, y: 0.0, z: 1.0 });
+        let (tangent, bitangent) = (Vec { x: 1.0, y: 0.0, z: 0.0 }, Vec { x: 0.0, y: 1.0, z: 0.0 });
+        for &light_pdf in &[0.0, 1.0, 1e6] {
+            assert_eq!(mis_weight_for(&glass, &direction, &normal, (&tangent, &bitangent), &direction, light_pdf), 1.0);
+        }
+
+        let scene = SceneBuilder::new()
+            .add_sphere(Vec { x: 0.0, y: 0.0, z: -3.0 }, 1.0, Material::GlassMaterial(glass))
+            .add_quad(Vec { x: -5.0, y: -5.0, z: -10.0 }, Vec { x: 10.0, y: 0.0, z: 0.0 }, Vec { x: 0.0, y: 10.0, z: 0.0 },
+                      Material::Lightsource(Lightsource::new(Color { x: 1.0, y: 1.0, z: 1.0 })))
+            .build().unwrap().0;
+        let origin: Vec = Vec { x: 0.0, y: 0.0, z: 0.0 };
+        let ray = Ray::new(&origin, &direction);
+        let r: f64 = (0.5_f64 / 2.5).powi(2);
+        let expected: (f64, f64) = ((1.0 - r) / (1.0 + r), 0.0);
+        let nee = estimate(4000, 1, |random| radiance_nee(&scene, &ray, random, &0).x);
+        let brdf = estimate(4000, 2, |random| radiance(&scene, &ray, random, &0).x);
+        assert!(agree(nee, expected), "nee {:?} expected {:?}", nee, expected);
+        assert!(agree(brdf, expected), "brdf {:?} expected {:?}", brdf, expected);
+    }
+
+    // 光源の半分を遮る黒い板の縁の真下（半影）の床の点を、ピクセルごとに16サンプルで推定する。
+    // Sobol列で光源上の点を層別に選ぶと、擬似乱数で選ぶより、ピクセルごとの推定値のばらつきが小さい。
+    #[test]
+    fn stratified_nee_has_less_shadow_edge_variance_than_random() {
+        use sampler::SobolSampler;
+        let mut scene = floor_under_light(Lightsource::new(Color { x: 4.0, y: 4.0, z: 4.0 }));
+        scene.push(SceneObject::new(
+            Shape::Quad(Quad::new(Vec { x: 0.0, y: 0.5, z: -5.0 }, Vec { x: 0.0, y: 0.0, z: 10.0 }, Vec { x: 5.0, y: 0.0, z: 0.0 })),
+            Material::LambertianMaterial(LambertianMaterial::new(Color { x: 0.0, y: 0.0, z: 0.0 }))));
+        let origin: Vec = Vec { x: 0.0, y: 0.25, z: 0.0 };
+        let down: Vec = Vec { x: 0.0, y: -1.0, z: 0.0 };
+        let ray = Ray::new(&origin, &down);
+
+        let (pixels, spp): (u64, u64) = (64, 16);
+        let variance = |mut pixel_estimate: Box<dyn FnMut(u64) -> f64>| -> (f64, f64) {
+            let estimates: std::vec::Vec<f64> = (0..pixels).map(&mut pixel_estimate).collect();
+            let mean: f64 = estimates.iter().sum::<f64>() / pixels as f64;
+            (mean, estimates.iter().map(|e| (e - mean) * (e - mean)).sum::<f64>() / (pixels - 1) as f64)
+        };
+        let (random_mean, random_variance) = variance(Box::new(|pixel| {
+            let mut random = Random::new(pixel + 1);
+            (0..spp).map(|_| radiance_nee(&scene, &ray, &mut random, &0).x).sum::<f64>() / spp as f64
+        }));
+        let (sobol_mean, sobol_variance) = variance(Box::new(|pixel| {
+            let mut sobol = SobolSampler::new(0);
+            (0..spp).map(|s| {
+                sobol.start_pixel(pixel, s);
+                radiance_nee(&scene, &ray, &mut sobol, &0).x
+            }).sum::<f64>() / spp as f64
+        }));
+
+        assert!(random_mean > 0.0);
+        let stderr = |variance: f64| -> f64 { (variance / pixels as f64).sqrt() };
+        assert!(agree((sobol_mean, stderr(sobol_variance)), (random_mean, stderr(random_variance))),
+                "{} {}", sobol_mean, random_mean);
+        assert!(sobol_variance < 0.25 * random_variance, "{} {}", sobol_variance, random_variance);
+    }
+
+    // BRDFが一定なのはLambertianだけ。
+    // コーネルボックスでは、一定のBRDFを後から掛ける光源サンプリングは、同じ乱数なら丸め誤差の範囲で一般の場合と一致し、
+    // 別々の乱数で推定した明るさもノイズの範囲で一致する。
+    #[test]
+    fn lambert_nee_agrees_with_the_general_nee() {
+        use constant::K_INV_PI;
+        use scenes::cornell_box;
+        let white: Color = Color { x: 0.75, y: 0.75, z: 0.75 };
+        let lambertian = Material::LambertianMaterial(LambertianMaterial::new(white.clone()));
+        assert!((&lambertian.constant_brdf().unwrap() - &(&white * K_INV_PI)).length() < 1e-15);
+        assert!(Material::PhongMaterial(PhongMaterial::new(white.clone(), 20.0)).constant_brdf().is_none());
+
+        let (scene, camera) = cornell_box();
+        let (width, height): (i32, i32) = (4, 3);
+        for y in 0..height {
+            for x in 0..width {
+                let dir: Vec = camera.ray_direction(x as f64 + 0.5, y as f64 + 0.5, width, height);
+                let ray = Ray::new(camera.position(), &dir);
+                let (mut general_random, mut lambert_random) = (Random::new(7), Random::new(7));
+                for _ in 0..16 {
+                    let general: Color = radiance_nee(&scene, &ray, &mut general_random, &0);
+                    let lambert: Color = radiance_nee_lambert(&scene, &ray, &mut lambert_random, &0);
+                    assert!((&general - &lambert).length() <= 1e-12 * (1.0 + general.length()), "{:?} {:?}", general, lambert);
+                }
+            }
+        }
+
+        let dir: Vec = camera.ray_direction(0.5 * width as f64, 0.5 * height as f64, width, height);
+        let ray = Ray::new(camera.position(), &dir);
+        let samples: usize = 4000;
+        let (general, _) = counted_estimate(samples, 1, |random| radiance_nee(&scene, &ray, random, &0).y);
+        let (lambert, _) = counted_estimate(samples, 2, |random| radiance_nee_lambert(&scene, &ray, random, &0).y);
+        assert!(general.0 > 0.0);
+        assert!(agree(general, lambert), "{:?} {:?}", general, lambert);
+    }
+}
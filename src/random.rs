@@ -39,3 +39,23 @@ impl XorShift {
 }
 
 pub type Random = XorShift;
+
+// ピクセルpixel_index番目のsample_index番目のサンプルに使う乱数のシード値。
+// 画像全体のシード値global_seedと合わせてハッシュ値を求めるので、どのサンプルから計算しても同じ乱数列になる。
+// 並列処理のスレッド数や処理の順序によらず、同じ画像を再現できる。
+// ハッシュにはSplitMix64の出力関数を使い、近い番号のサンプル同士でも相関の無いシード値にする。
+// Guy L. Steele Jr., Doug Lea, Christine H. Flood. Fast splittable pseudorandom number generators. OOPSLA 2014.
+pub fn sample_seed(pixel_index: u64, sample_index: u64, global_seed: u64) -> u64 {
+    let mut hash: u64 = mix64(global_seed.wrapping_add(GOLDEN_GAMMA));
+    hash = mix64(hash ^ pixel_index.wrapping_add(GOLDEN_GAMMA));
+    mix64(hash ^ sample_index.wrapping_add(GOLDEN_GAMMA))
+}
+
+const GOLDEN_GAMMA: u64 = 0x9E3779B97F4A7C15;
+
+fn mix64(x: u64) -> u64 {
+    let mut z: u64 = x;
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58476D1CE4E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D049BB133111EB);
+    z ^ (z >> 31)
+}
@@ -6,11 +6,16 @@ use vec::Vec;
 pub struct Ray<'a> {
     pub org: &'a Vec,
     pub dir: &'a Vec,
+    pub time: f64, // シャッターが開いている間の時刻（[0, 1)）。動く物体（AnimatedTransform）はこの時刻の位置で交差判定する。
 }
 
 impl<'a> Ray<'a> {
+    // 時刻0のレイ。
     pub fn new(org: &'a Vec, dir: &'a Vec) -> Ray<'a> {
-        Ray { org: org, dir: dir }
+        Ray { org, dir, time: 0.0 }
     }
-}
 
+    pub fn with_time(org: &'a Vec, dir: &'a Vec, time: f64) -> Ray<'a> {
+        Ray { org, dir, time }
+    }
+}
@@ -0,0 +1,220 @@
+#![allow(dead_code)]
+
+use vec::{Vec, Dot};
+use ray::Ray;
+use hitpoint::Hitpoint;
+
+// レイ微分
+// 隣接ピクセル方向（スクリーンのx, y）にずらしたときの、レイの原点と方向の変化量を持つ。
+// 交差点での位置の変化量からテクスチャのフットプリントを見積もり、LODの選択に使う。
+// Homan Igehy. Tracing ray differentials. SIGGRAPH 1999.
+#[derive(Debug, Clone)]
+pub struct RayDifferential {
+    pub dorg_dx: Vec,
+    pub ddir_dx: Vec,
+    pub dorg_dy: Vec,
+    pub ddir_dy: Vec,
+}
+
+// 交差点における位置の変化量。
+#[derive(Debug, Clone)]
+pub struct SurfaceDifferential {
+    pub dpdx: Vec,
+    pub dpdy: Vec,
+}
+
+impl RayDifferential {
+    pub fn new(dorg_dx: Vec, ddir_dx: Vec, dorg_dy: Vec, ddir_dy: Vec) -> RayDifferential {
+        RayDifferential { dorg_dx, ddir_dx, dorg_dy, ddir_dy }
+    }
+
+    // 隣接ピクセルに向けたレイの方向から、カメラレイのレイ微分を作る。
+    // カメラレイは原点が共通なので原点の変化量は0になる。
+    pub fn from_camera(dir: &Vec, dir_x: &Vec, dir_y: &Vec) -> RayDifferential {
+        RayDifferential {
+            dorg_dx: Vec { x: 0.0, y: 0.0, z: 0.0 },
+            ddir_dx: dir_x - dir,
+            dorg_dy: Vec { x: 0.0, y: 0.0, z: 0.0 },
+            ddir_dy: dir_y - dir,
+        }
+    }
+
+    // 交差点の接平面までレイ微分を転送し、位置の変化量を求める。
+    // t = -dot(org - p, n) / dot(dir, n) を微分すると
+    // dt/dx = -dot(dorg/dx + t * ddir/dx, n) / dot(dir, n) となる。
+    pub fn transfer(&self, ray: &Ray, hitpoint: &Hitpoint) -> SurfaceDifferential {
+        let t: f64 = hitpoint.distance;
+        let normal: &Vec = &hitpoint.normal;
+        let dir_dot_normal: f64 = Vec::dot(ray.dir, normal);
+
+        let transfer_one = |dorg: &Vec, ddir: &Vec| -> Vec {
+            let offset: Vec = dorg + ddir * t;
+            if dir_dot_normal == 0.0 {
+                // 接平面に平行なレイ。位置の変化量は求まらないのでそのまま返す。
+                return offset
+            }
+            let dt: f64 = -Vec::dot(&offset, normal) / dir_dot_normal;
+            offset + ray.dir * dt
+        };
+
+        SurfaceDifferential {
+            dpdx: transfer_one(&self.dorg_dx, &self.ddir_dx),
+            dpdy: transfer_one(&self.dorg_dy, &self.ddir_dy),
+        }
+    }
+
+    // 反射方向のレイ微分。
+    // r = d - 2 dot(d, n) n を微分すると
+    // dr/dx = dd/dx - 2 (dot(d, n) dn/dx + (dot(dd/dx, n) + dot(d, dn/dx)) n) となる。
+    // dndx, dndyは交差点での法線の変化量（平面なら0）。
+    pub fn reflect(
+        &self, ray: &Ray, normal: &Vec, surface: &SurfaceDifferential,
+        dndx: &Vec, dndy: &Vec) -> RayDifferential {
+        let dir_dot_normal: f64 = Vec::dot(ray.dir, normal);
+
+        let reflect_one = |ddir: &Vec, dn: &Vec| -> Vec {
+            let ddn: f64 = Vec::dot(ddir, normal) + Vec::dot(ray.dir, dn);
+            ddir - (dn * dir_dot_normal + normal * ddn) * 2.0
+        };
+
+        RayDifferential {
+            dorg_dx: surface.dpdx.clone(),
+            ddir_dx: reflect_one(&self.ddir_dx, dndx),
+            dorg_dy: surface.dpdy.clone(),
+            ddir_dy: reflect_one(&self.ddir_dy, dndy),
+        }
+    }
+
+    // 屈折方向のレイ微分。
+    // normalはレイと向かい合う向きの法線、nは相対屈折率（入射側 / 透過側）。
+    // GlassMaterialと同じく t = n d - mu nn, mu = n dot(d, nn) + cos2 とすると
+    // dmu/dx = (n + n^2 dot(d, nn) / cos2) ddn/dx となる。
+    // 全反射する場合はNoneを返す。
+    pub fn refract(
+        &self, ray: &Ray, normal: &Vec, n: f64, surface: &SurfaceDifferential,
+        dndx: &Vec, dndy: &Vec) -> Option<RayDifferential> {
+        let dir_dot_normal: f64 = Vec::dot(ray.dir, normal);
+        let cos2t_2: f64 = 1.0 - n * n * (1.0 - dir_dot_normal * dir_dot_normal);
+        if cos2t_2 <= 0.0 {
+            return None
+        }
+        let cost_2: f64 = cos2t_2.sqrt();
+        let mu: f64 = n * dir_dot_normal + cost_2;
+        let dmu_factor: f64 = n + n * n * dir_dot_normal / cost_2;
+
+        let refract_one = |ddir: &Vec, dn: &Vec| -> Vec {
+            let ddn: f64 = Vec::dot(ddir, normal) + Vec::dot(ray.dir, dn);
+            ddir * n - dn * mu - normal * (dmu_factor * ddn)
+        };
+
+        Some(RayDifferential {
+            dorg_dx: surface.dpdx.clone(),
+            ddir_dx: refract_one(&self.ddir_dx, dndx),
+            dorg_dy: surface.dpdy.clone(),
+            ddir_dy: refract_one(&self.ddir_dy, dndy),
+        })
+    }
+}
+
+impl SurfaceDifferential {
+    // ワールド空間でのフットプリントの大きさ。
+    pub fn footprint(&self) -> f64 {
+        let dx: f64 = self.dpdx.length();
+        let dy: f64 = self.dpdy.length();
+        if dx > dy { dx } else { dy }
+    }
+
+    // 表面のパラメータ化の偏微分dpdu, dpdvから、テクスチャ座標の変化量(du/dx, dv/dx, du/dy, dv/dy)を求める。
+    // dp/dx = du/dx dpdu + dv/dx dpdv を最小二乗法で解く。
+    pub fn texture_derivatives(&self, dpdu: &Vec, dpdv: &Vec) -> (f64, f64, f64, f64) {
+        let uu: f64 = Vec::dot(dpdu, dpdu);
+        let uv: f64 = Vec::dot(dpdu, dpdv);
+        let vv: f64 = Vec::dot(dpdv, dpdv);
+        let det: f64 = uu * vv - uv * uv;
+        if det.abs() < 1e-12 {
+            return (0.0, 0.0, 0.0, 0.0)
+        }
+
+        let solve = |dp: &Vec| -> (f64, f64) {
+            let pu: f64 = Vec::dot(dp, dpdu);
+            let pv: f64 = Vec::dot(dp, dpdv);
+            ((vv * pu - uv * pv) / det, (uu * pv - uv * pu) / det)
+        };
+
+        let (dudx, dvdx) = solve(&self.dpdx);
+        let (dudy, dvdy) = solve(&self.dpdy);
+        (dudx, dvdx, dudy, dvdy)
+    }
+
+    // テクスチャ空間でのフットプリントの幅（uv単位）。ImageTexture::lookup_footprintに渡す。
+    pub fn texture_footprint(&self, dpdu: &Vec, dpdv: &Vec) -> f64 {
+        let (dudx, dvdx, dudy, dvdy) = self.texture_derivatives(dpdu, dpdv);
+        let x: f64 = (dudx * dudx + dvdx * dvdx).sqrt();
+        let y: f64 = (dudy * dudy + dvdy * dvdy).sqrt();
+        if x > y { x } else { y }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use vec::{Normalize, Cross};
+    use quad::Quad;
+
+    // z = 0の平面に広がる、一辺20の正方形。テクスチャ座標は辺の長さで0から1になる。
+    fn floor() -> Quad {
+        Quad::new(Vec { x: -10.0, y: -10.0, z: 0.0 }, Vec { x: 20.0, y: 0.0, z: 0.0 }, Vec { x: 0.0, y: 20.0, z: 0.0 })
+    }
+
+    // originからdirの方向に床を見たときの、テクスチャ空間でのフットプリント。
+    // 隣のピクセルのレイは、dirに垂直な二方向に角度0.001だけずらしたものとする。
+    fn footprint_from(origin: &Vec, dir: &Vec) -> f64 {
+        let dir: Vec = Vec::normalize(dir.clone());
+        let side: Vec = Vec::normalize(Vec::cross(&dir, &Vec { x: 0.0, y: 1.0, z: 0.0 }));
+        let up: Vec = Vec::cross(&side, &dir);
+        let dir_x: Vec = Vec::normalize(&dir + &side * 0.001);
+        let dir_y: Vec = Vec::normalize(&dir + &up * 0.001);
+
+        let ray = Ray::new(origin, &dir);
+        let hitpoint = floor().intersect(&ray).unwrap();
+        let surface = RayDifferential::from_camera(&dir, &dir_x, &dir_y).transfer(&ray, &hitpoint);
+        surface.texture_footprint(&Vec { x: 20.0, y: 0.0, z: 0.0 }, &Vec { x: 0.0, y: 20.0, z: 0.0 })
+    }
+
+    // 同じ距離から見ても、浅い角度で見た面ほどフットプリントは大きい。
+    #[test]
+    fn grazing_view_has_larger_footprint() {
+        let head_on: f64 = footprint_from(&Vec { x: 0.0, y: 0.0, z: 1.0 }, &Vec { x: 0.0, y: 0.0, z: -1.0 });
+        let grazing: f64 = footprint_from(&Vec { x: -0.985, y: 0.0, z: 0.174 }, &Vec { x: 0.985, y: 0.0, z: -0.174 });
+        assert!(grazing > 3.0 * head_on, "head-on {} grazing {}", head_on, grazing);
+    }
+
+    // 正面から見たときのフットプリントは距離に比例する。
+    #[test]
+    fn head_on_footprint_grows_with_distance() {
+        let near: f64 = footprint_from(&Vec { x: 0.0, y: 0.0, z: 1.0 }, &Vec { x: 0.0, y: 0.0, z: -1.0 });
+        let far: f64 = footprint_from(&Vec { x: 0.0, y: 0.0, z: 4.0 }, &Vec { x: 0.0, y: 0.0, z: -1.0 });
+        assert!((far / near - 4.0).abs() < 1e-3, "near {} far {}", near, far);
+        // 角度0.001、距離1で、幅20の正方形のテクスチャ座標では0.001 / 20。
+        assert!((near - 0.001 / 20.0).abs() < 1e-6, "{}", near);
+    }
+
+    // 平らな鏡で反射しても、レイ微分の方向の広がりは変わらない。
+    #[test]
+    fn flat_mirror_keeps_the_spread() {
+        let origin: Vec = Vec { x: 0.0, y: 0.0, z: 1.0 };
+        let dir: Vec = Vec::normalize(Vec { x: 0.3, y: 0.0, z: -1.0 });
+        let ray = Ray::new(&origin, &dir);
+        let hitpoint = floor().intersect(&ray).unwrap();
+        let differential = RayDifferential::new(
+            Vec { x: 0.0, y: 0.0, z: 0.0 }, Vec { x: 0.001, y: 0.0, z: 0.0 },
+            Vec { x: 0.0, y: 0.0, z: 0.0 }, Vec { x: 0.0, y: 0.001, z: 0.0 });
+        let surface = differential.transfer(&ray, &hitpoint);
+        let zero: Vec = Vec { x: 0.0, y: 0.0, z: 0.0 };
+        let reflected = differential.reflect(&ray, &hitpoint.normal, &surface, &zero, &zero);
+        assert!((reflected.ddir_dx.length() - 0.001).abs() < 1e-12);
+        assert!((reflected.ddir_dy.length() - 0.001).abs() < 1e-12);
+        assert!((reflected.dorg_dx.length() - surface.dpdx.length()).abs() < 1e-12);
+    }
+}
@@ -1,17 +1,97 @@
 #![allow(dead_code, unused_variables)]
+// レンダリングの設定（解像度、サンプル数、並列処理、キャンセル、シード値など）は引数で並べて受け取る。
+#![allow(clippy::too_many_arguments)]
 
 use std;
+use std::fmt;
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+#[cfg(feature = "rayon")]
 use std::sync::{Arc, Mutex};
 
+#[cfg(feature = "rayon")]
 use rayon::prelude::*;
 
-use radiance::radiance;
-use ppm::save_ppm_file;
-use random::Random;
+use radiance::{path_tracing_radiance, radiance_by_lobe, radiance_with_roulette_settings, RouletteSettings};
+use ppm::{save_ppm_file_with_metadata, ImageMetadata};
+use random::{Random, sample_seed};
+use sampler::Sampler;
 
-use vec::{Vec, Normalize, Cross};
+use vec::{Vec, Multiply};
 use scene::Scene;
 use ray::Ray;
+use camera::Camera;
+
+// レンダリングと画像の出力で起こりうるエラー。
+#[derive(Debug)]
+pub enum RenderError {
+    EmptyScene,                                 // シーンに物体が一つも無い。
+    NoLights,                                   // シーンに光源が無い（真っ黒な画像にしかならない）。
+    InvalidDimensions { width: i32, height: i32 }, // 解像度が正でない。
+    InvalidSampleCount { num_sample_per_subpixel: i32, num_subpixel: i32 }, // サンプル数が正でない。
+    BufferSizeMismatch { pixels: usize, buffer: usize, weights: usize }, // 呼び出し側のバッファの大きさが画像と合わない。
+    GuideSizeMismatch { pixels: usize, guide: usize }, // サンプル数の配分に使う画像の大きさが画像と合わない。
+    Io(io::Error),                              // ファイルの書き込みに失敗した。
+}
+
+impl fmt::Display for RenderError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            RenderError::EmptyScene => write!(f, "scene has no objects"),
+            RenderError::NoLights => write!(f, "scene has no light source"),
+            RenderError::InvalidDimensions { width, height } =>
+                write!(f, "invalid image size {} x {}", width, height),
+            RenderError::InvalidSampleCount { num_sample_per_subpixel, num_subpixel } =>
+                write!(f, "invalid sample count ({} samples per subpixel, {} x {} subpixels)",
+                       num_sample_per_subpixel, num_subpixel, num_subpixel),
+            RenderError::BufferSizeMismatch { pixels, buffer, weights } =>
+                write!(f, "buffer sizes ({} colors, {} weights) do not match the image ({} pixels)",
+                       buffer, weights, pixels),
+            RenderError::GuideSizeMismatch { pixels, guide } =>
+                write!(f, "guide has {} values but the image has {} pixels", guide, pixels),
+            RenderError::Io(ref why) => write!(f, "I/O error: {}", why),
+        }
+    }
+}
+
+impl std::error::Error for RenderError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match *self {
+            RenderError::Io(ref why) => Some(why),
+            _ => None
+        }
+    }
+}
+
+impl From<io::Error> for RenderError {
+    fn from(why: io::Error) -> RenderError {
+        RenderError::Io(why)
+    }
+}
+
+// レンダリングを始める前に引数を確かめる。
+fn check_arguments(
+    scene: &Scene, width: i32, height: i32,
+    num_sample_per_subpixel: i32, num_subpixel: i32) -> Result<(), RenderError> {
+    if width <= 0 || height <= 0 {
+        return Err(RenderError::InvalidDimensions { width, height })
+    }
+    if num_sample_per_subpixel <= 0 || num_subpixel <= 0 {
+        return Err(RenderError::InvalidSampleCount {
+            num_sample_per_subpixel, num_subpixel })
+    }
+    if scene.is_empty() {
+        return Err(RenderError::EmptyScene)
+    }
+    if !scene.iter().any(|object| object.is_light()) {
+        return Err(RenderError::NoLights)
+    }
+    Ok(())
+}
+
+// render、render_imageで使う画像全体のシード値。
+pub const DEFAULT_SEED: u64 = 0;
 
 macro_rules! clone_value {
     ($x:expr, $n:expr) => ({
@@ -23,118 +103,823 @@ macro_rules! clone_value {
     })
 }
 
+// レンダリングしてPPM形式でfilenameに保存する。
+// サンプル数、シード値、解像度をコメントとして埋め込む（ppm::ImageMetadata）。
 pub fn render(
-    scene: &Scene, filename: &str, width: i32, height: i32,
+    scene: &Scene, camera: &Camera, filename: &str, width: i32, height: i32,
     num_sample_per_subpixel: i32, num_subpixel: i32,
-    is_parallel: bool) {
-
-    // カメラ位置。
-    let camera_position = Vec { x: 7.0, y: 3.0, z: 7.0 };
-    let camera_lookat   = Vec { x: 0.0, y: 1.0, z: 0.0 };
-    let camera_dir      = Vec::normalize(&camera_lookat - &camera_position);
-    let camera_up       = Vec { x: 0.0, y: 1.0, z: 0.0 };
-
-    // ワールド座標系でのイメージセンサーの大きさ。
-    let sensor_width = 30.0 * width as f64 / height as f64; // アスペクト比調整。
-    let sensor_height= 30.0;
-    // イメージセンサーまでの距離。
-    let sensor_dist  = 45.0;
-    // イメージセンサーを張るベクトル。
-    let sensor_x_vec = Vec::normalize(Vec::cross(&camera_dir, &camera_up)) * &sensor_width;
-    let sensor_y_vec = Vec::normalize(Vec::cross(&sensor_x_vec, &camera_dir)) * &sensor_height;
-    let sensor_center = &camera_position + &camera_dir * &sensor_dist;
-
-    let mut image = clone_value![Vec { x: 0.0, y: 0.0, z: 0.0 }, (width * height) as u64];
-    println!("{}x{} {} spp", width, height, num_sample_per_subpixel * (num_subpixel * num_subpixel));
-
-    // Rayonを使った並列処理
-    // 実装するために下の逐次処理版と一部ロジックが変わってしまった
-    // 1. for 文から for_each メソッドへの変更
-    // 2. 1.に伴う 二重ループの除去
-    // 3. 1.に伴う image 配列へのアクセスロジックの変更
-    // 4. 進捗表示処理の追加と表示回数抑制のための if 文の追加
-    if is_parallel {
-        let count = Arc::new(Mutex::new(0));
-        image.par_iter_mut().enumerate().for_each(|(i, pixel)| {
-            // 進捗表示
-            {
-                let mut count = count.lock().unwrap();
-                *count += 1;
-                let count = *count;
-                if count % width == 0 {
-                    eprint!("\rRendering (y= {}, {} %)\x1b[0K", count / width as i32, 100.0 * count as f64 / (width * height) as f64);
-                }
-            }
-            let y = i as i32 / width;
-            let x = i as i32 - y * width;
-            let mut random = Random::new((y * width + x + 1) as u64);
-            let image_index = height - y - 1;
-            // num_subpixel x num_subpixel のスーパーサンプリング。
-            for sy in 0..num_subpixel {
-                for sx in 0..num_subpixel {
-                    let mut accumulated_radiance = Vec { x: 0.0, y: 0.0, z: 0.0 };
-                    // let accumulated_radiance: Vec;
-                    // 一つのサブピクセルあたりsamples回サンプリングする。
-                    for _s in 0..num_sample_per_subpixel {
-                        let rate = 1.0 / num_subpixel as f64;
-                        let r1 = sx as f64 * rate + rate / 2.0;
-                        let r2 = sy as f64 * rate + rate / 2.0;
-                        // イメージセンサー上の位置。
-                        let position_on_sensor =
-                            &sensor_center +
-                            &sensor_x_vec * ((r1 + x as f64) / width as f64 - 0.5) +
-                            &sensor_y_vec * ((r2 + image_index as f64) / height as f64 - 0.5);
-                        // レイを飛ばす方向。
-                        let dir = Vec::normalize(&position_on_sensor - &camera_position);
-
-                        accumulated_radiance = accumulated_radiance +
-                            radiance(scene, &Ray::new(&camera_position, &dir), &mut random, &0)
-                            / num_sample_per_subpixel as f64 / (num_subpixel * num_subpixel) as f64;
-                    }
-                    *pixel = &*pixel + accumulated_radiance;
+    is_parallel: bool) -> Result<(), RenderError> {
+
+    let cancel = AtomicBool::new(false);
+    let (image, _) = render_image(
+        scene, camera, width, height, num_sample_per_subpixel, num_subpixel, is_parallel, &cancel)?;
+
+    // 出力
+    let metadata = ImageMetadata {
+        spp: num_sample_per_subpixel * num_subpixel * num_subpixel, seed: DEFAULT_SEED, width, height };
+    save_ppm_file_with_metadata(filename, &image, width, height, Some(&metadata))
+}
+
+// レンダリングした画像を返す。
+// 別スレッドからcancelをtrueにするとレンダリングを途中で打ち切り、(途中までの画像, true)を返す。
+// 途中までの画像では、各ピクセルは計算済みか黒（未計算）のどちらかになる。
+// 解像度やサンプル数が正でないとき、シーンが空か光源が無いときはエラーになる。
+pub fn render_image(
+    scene: &Scene, camera: &Camera, width: i32, height: i32,
+    num_sample_per_subpixel: i32, num_subpixel: i32,
+    is_parallel: bool, cancel: &AtomicBool) -> Result<(std::vec::Vec<Vec>, bool), RenderError> {
+
+    let (image, _, cancelled) = render_image_with_variance(
+        scene, camera, width, height, num_sample_per_subpixel, num_subpixel, is_parallel, cancel, DEFAULT_SEED)?;
+    Ok((image, cancelled))
+}
+
+// サンプルを保存せずに、平均と分散を逐次求める（Welfordの方法）。
+// 和と二乗和から求めるのと違い、大きな和に小さなサンプルを足して桁が落ちることが無く、
+// 分散も差の打ち消し合いで負になったりしない。
+// B. P. Welford. Note on a Method for Calculating Corrected Sums of Squares and Products. 1962.
+#[derive(Debug, Clone)]
+pub struct PixelAccumulator {
+    count: u64,
+    mean: Vec,
+    m2: Vec,        // 平均との差の二乗の和。
+}
+
+impl Default for PixelAccumulator {
+    fn default() -> PixelAccumulator {
+        PixelAccumulator::new()
+    }
+}
+
+impl PixelAccumulator {
+    pub fn new() -> PixelAccumulator {
+        PixelAccumulator {
+            count: 0,
+            mean: Vec { x: 0.0, y: 0.0, z: 0.0 },
+            m2: Vec { x: 0.0, y: 0.0, z: 0.0 },
+        }
+    }
+
+    pub fn add(&mut self, sample: &Vec) {
+        self.count += 1;
+        let delta: Vec = sample - &self.mean;
+        self.mean = &self.mean + &delta / self.count as f64;
+        let delta_after: Vec = sample - &self.mean;
+        self.m2 = &self.m2 + Vec::multiply(&delta, &delta_after);
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    // サンプルの平均。サンプルが無ければ0。
+    pub fn mean(&self) -> Vec {
+        self.mean.clone()
+    }
+
+    // サンプルの不偏分散 Σ (L - mean)^2 / (N - 1)。N < 2のときは0。
+    pub fn variance(&self) -> Vec {
+        if self.count < 2 {
+            return Vec { x: 0.0, y: 0.0, z: 0.0 }
+        }
+        &self.m2 / (self.count - 1) as f64
+    }
+
+    // 平均（ピクセル値）の分散の推定値 variance / N。render_image_with_varianceが返す分散。
+    pub fn variance_of_mean(&self) -> Vec {
+        if self.count < 2 {
+            return Vec { x: 0.0, y: 0.0, z: 0.0 }
+        }
+        self.variance() / self.count as f64
+    }
+}
+
+// render_imageと同じ画像に加えて、ピクセルごとの分散を返す。
+// 分散はピクセル値（サンプルの平均）の分散の推定値で、チャンネルごとに
+// Σ (L - mean)^2 / (N - 1) / N をPixelAccumulatorで求める。Nはピクセルあたりのサンプル数。
+// 収束の度合いの確認や適応的サンプリングに使う。N = 1のときは推定できないので0になる。
+// seedは画像全体のシード値で、同じ値なら並列処理の有無やスレッド数によらずビット単位で同じ画像になる。
+pub fn render_image_with_variance(
+    scene: &Scene, camera: &Camera, width: i32, height: i32,
+    num_sample_per_subpixel: i32, num_subpixel: i32,
+    is_parallel: bool, cancel: &AtomicBool, seed: u64)
+    -> Result<(std::vec::Vec<Vec>, std::vec::Vec<Vec>, bool), RenderError> {
+    render_with(
+        scene, camera, width, height, num_sample_per_subpixel, num_subpixel, is_parallel, cancel, seed,
+        &path_tracing_radiance)
+}
+
+// render_image_with_varianceと同じだが、カメラレイごとの放射輝度をradiance_fnで求める。
+// 積分器を差し替えて試すためのもの。既定ではradiance::path_tracing_radianceを使う。
+// radiance_fnに渡すサンプラーは、時刻を選んだあとのもの。並列にレンダリングするのでSyncが要る。
+pub fn render_with<F>(
+    scene: &Scene, camera: &Camera, width: i32, height: i32,
+    num_sample_per_subpixel: i32, num_subpixel: i32,
+    is_parallel: bool, cancel: &AtomicBool, seed: u64, radiance_fn: &F)
+    -> Result<(std::vec::Vec<Vec>, std::vec::Vec<Vec>, bool), RenderError>
+    where F: Fn(&Scene, &Ray, &mut dyn Sampler) -> Vec + Sync {
+    render_with_progress(
+        scene, camera, width, height, num_sample_per_subpixel, num_subpixel, is_parallel, cancel, seed,
+        radiance_fn, None)
+}
+
+// render_withと同じだが、進捗をprogressに知らせる。
+// progressは(計算し終えた行数, 全体の行数)で呼ばれ、最後まで計算すれば必ず(height, height)で終わる。
+// 呼ばれるたびに行数は増えていく。並列処理では別々のスレッドから呼ばれうるのでSyncが要る。
+// progressがNoneなら何も表示しない。ライブラリからは標準出力にも標準エラー出力にも書かない（表示はbin側で行う）。
+pub fn render_with_progress<F>(
+    scene: &Scene, camera: &Camera, width: i32, height: i32,
+    num_sample_per_subpixel: i32, num_subpixel: i32,
+    is_parallel: bool, cancel: &AtomicBool, seed: u64, radiance_fn: &F,
+    progress: Option<&(dyn Fn(usize, usize) + Sync)>)
+    -> Result<(std::vec::Vec<Vec>, std::vec::Vec<Vec>, bool), RenderError>
+    where F: Fn(&Scene, &Ray, &mut dyn Sampler) -> Vec + Sync {
+    check_arguments(scene, width, height, num_sample_per_subpixel, num_subpixel)?;
+
+    let black = Vec { x: 0.0, y: 0.0, z: 0.0 };
+    let mut pixels = clone_value![(black.clone(), black.clone()), (width * height) as u64];
+    // カメラは動かないので、カメラレイの方向は前計算したものを使う。
+    let ray_cache = camera.primary_ray_cache(width, height);
+
+    // イメージセンサー上の(x, y)のピクセルの放射輝度とその分散を求める。
+    // 乱数はサンプルごとに、ピクセルの位置とサンプルの番号から決めたシード値で初期化する（sample_seed）。
+    let render_pixel = |x: i32, y: i32| -> (Vec, Vec) {
+        let mut accumulator = PixelAccumulator::new();
+        let pixel_index: u64 = (y * width + x) as u64;
+        // num_subpixel x num_subpixel のスーパーサンプリング。
+        for sy in 0..num_subpixel {
+            for sx in 0..num_subpixel {
+                // 一つのサブピクセルあたりsamples回サンプリングする。
+                for s in 0..num_sample_per_subpixel {
+                    let sample_index: u64 = ((sy * num_subpixel + sx) * num_sample_per_subpixel + s) as u64;
+                    let mut random = Random::new(sample_seed(pixel_index, sample_index, seed));
+                    let rate = 1.0 / num_subpixel as f64;
+                    let r1 = sx as f64 * rate + rate / 2.0;
+                    let r2 = sy as f64 * rate + rate / 2.0;
+                    // レイを飛ばす方向。
+                    let dir = ray_cache.ray_direction(x, y, r1, r2);
+
+                    // シャッターが開いている間の時刻を一様に選ぶ。動く物体はサンプルを重ねるとモーションブラーになる。
+                    let time: f64 = random.next_1d();
+
+                    let sample = radiance_fn(scene, &Ray::with_time(camera.position(), &dir, time), &mut random);
+                    accumulator.add(&sample);
                 }
             }
-        });
-        eprintln!("");
-    }
-    // 逐次処理
-    else {
-        for y in 0..height {
-            eprint!("\rRendering (y= {}, {} %)\x1b[0K", y, 100.0 * y as f64 / (height - 1) as f64);
-            for x in 0..width {
-                let mut random = Random::new((y * width + x + 1) as u64);
-                let image_index = ((height - y - 1) * width + x) as usize;
-                // num_subpixel x num_subpixel のスーパーサンプリング。
-                for sy in 0..num_subpixel {
-                    for sx in 0..num_subpixel {
-                        let mut accumulated_radiance = Vec { x: 0.0, y: 0.0, z: 0.0 };
-                        // let accumulated_radiance: Vec;
-                        // 一つのサブピクセルあたりsamples回サンプリングする。
-                        for _s in 0..num_sample_per_subpixel {
-                            let rate = 1.0 / num_subpixel as f64;
-                            let r1 = sx as f64 * rate + rate / 2.0;
-                            let r2 = sy as f64 * rate + rate / 2.0;
-                            // イメージセンサー上の位置。
-                            let position_on_sensor =
-                                &sensor_center +
-                                &sensor_x_vec * ((r1 + x as f64) / width as f64 - 0.5) +
-                                &sensor_y_vec * ((r2 + y as f64) / height as f64 - 0.5);
-                            // レイを飛ばす方向。
-                            let dir = Vec::normalize(&position_on_sensor - &camera_position);
-
-                            accumulated_radiance = accumulated_radiance +
-                                radiance(scene, &Ray::new(&camera_position, &dir), &mut random, &0)
-                                / num_sample_per_subpixel as f64 / (num_subpixel * num_subpixel) as f64;
-                        }
-                        image[image_index] = &image[image_index] + accumulated_radiance;
-                    }
+        }
+
+        (accumulator.mean(), accumulator.variance_of_mean())
+    };
+
+    let cancelled = if is_parallel {
+        render_parallel(&mut pixels, width, height, &render_pixel, cancel, progress)
+    } else {
+        render_serial(&mut pixels, width, height, &render_pixel, cancel, progress)
+    };
+
+    let (image, variance) = pixels.into_iter().unzip();
+    Ok((image, variance, cancelled))
+}
+
+// render_image_with_varianceと同じだが、ロシアンルーレットで経路を打ち切る（radiance::radiance_with_roulette_settings）。
+// 打ち切りを始める深さと続ける確率の下限はrouletteで指定する。既定値はRouletteSettings::default()。
+// 期待値はrender_image_with_varianceの画像と同じ。
+pub fn render_image_with_roulette(
+    scene: &Scene, camera: &Camera, width: i32, height: i32,
+    num_sample_per_subpixel: i32, num_subpixel: i32,
+    is_parallel: bool, cancel: &AtomicBool, seed: u64, roulette: RouletteSettings)
+    -> Result<(std::vec::Vec<Vec>, std::vec::Vec<Vec>, bool), RenderError> {
+    let radiance_fn = |scene: &Scene, ray: &Ray, random: &mut dyn Sampler| -> Vec {
+        radiance_with_roulette_settings(scene, ray, random, &0, roulette)
+    };
+    render_with(
+        scene, camera, width, height, num_sample_per_subpixel, num_subpixel, is_parallel, cancel, seed,
+        &radiance_fn)
+}
+
+// render_image_by_lobeの(画像, 拡散の成分, 鏡面の成分, キャンセルしたか)。
+pub type LobeImages = (std::vec::Vec<Vec>, std::vec::Vec<Vec>, std::vec::Vec<Vec>, bool);
+
+// render_image_with_varianceと同じサンプルの配置で、(画像, 拡散の成分, 鏡面の成分, キャンセルしたか)を返す。
+// 成分はカメラから最初に当たった点でサンプリングしたローブで分けたもの（radiance::radiance_by_lobe）。
+// 画像はピクセルごとに拡散と鏡面の成分を足したもので、期待値はrender_imageの画像と同じ。
+// 合成ソフトで拡散と鏡面の明るさを別々に調整し、足し合わせて元の画像に戻すのに使う。
+pub fn render_image_by_lobe(
+    scene: &Scene, camera: &Camera, width: i32, height: i32,
+    num_sample_per_subpixel: i32, num_subpixel: i32,
+    is_parallel: bool, cancel: &AtomicBool, seed: u64) -> Result<LobeImages, RenderError> {
+    check_arguments(scene, width, height, num_sample_per_subpixel, num_subpixel)?;
+
+    let black = Vec { x: 0.0, y: 0.0, z: 0.0 };
+    let mut pixels = clone_value![(black.clone(), black.clone()), (width * height) as u64];
+    let ray_cache = camera.primary_ray_cache(width, height);
+    let weight: f64 = 1.0 / (num_sample_per_subpixel * num_subpixel * num_subpixel) as f64;
+
+    let render_pixel = |x: i32, y: i32| -> (Vec, Vec) {
+        let mut diffuse = Vec { x: 0.0, y: 0.0, z: 0.0 };
+        let mut specular = Vec { x: 0.0, y: 0.0, z: 0.0 };
+        let pixel_index: u64 = (y * width + x) as u64;
+        for sy in 0..num_subpixel {
+            for sx in 0..num_subpixel {
+                for s in 0..num_sample_per_subpixel {
+                    let sample_index: u64 = ((sy * num_subpixel + sx) * num_sample_per_subpixel + s) as u64;
+                    let mut random = Random::new(sample_seed(pixel_index, sample_index, seed));
+                    let rate = 1.0 / num_subpixel as f64;
+                    let r1 = sx as f64 * rate + rate / 2.0;
+                    let r2 = sy as f64 * rate + rate / 2.0;
+                    let dir = ray_cache.ray_direction(x, y, r1, r2);
+                    let time: f64 = random.next_1d();
+
+                    let (diffuse_sample, specular_sample) =
+                        radiance_by_lobe(scene, &Ray::with_time(camera.position(), &dir, time), &mut random);
+                    diffuse = diffuse + diffuse_sample * weight;
+                    specular = specular + specular_sample * weight;
                 }
             }
         }
-        eprintln!("");
+        (diffuse, specular)
+    };
+
+    let cancelled = if is_parallel {
+        render_parallel(&mut pixels, width, height, &render_pixel, cancel, None)
+    } else {
+        render_serial(&mut pixels, width, height, &render_pixel, cancel, None)
+    };
+
+    let (diffuse, specular): (std::vec::Vec<Vec>, std::vec::Vec<Vec>) = pixels.into_iter().unzip();
+    let image: std::vec::Vec<Vec> = diffuse.iter().zip(specular.iter()).map(|(d, s)| d + s).collect();
+    Ok((image, diffuse, specular, cancelled))
+}
+
+// 呼び出し側のバッファbufferに、ピクセルごとにspp個のサンプルの放射輝度の和を足し込み、weightsにサンプル数を足す。
+// buffer[i] / weights[i]がそのピクセルの値になる。バッファはrender_imageの画像と同じく上の行から順に並べる。
+// サンプルの位置はピクセル内で一様に選ぶ。sample_offsetは最初のサンプルの番号で、
+// 呼び出しごとにsppずつ進めれば、何回かに分けて足し込んでも、まとめて一回で足し込んだのと同じサンプルになる。
+// 漸進的なレンダリングや、独自の重み付けで平均をとるときに使う。
+pub fn render_samples_into(
+    scene: &Scene, camera: &Camera, width: i32, height: i32,
+    buffer: &mut [Vec], weights: &mut [f64], spp: i32, sample_offset: u64) -> Result<(), RenderError> {
+    check_arguments(scene, width, height, spp, 1)?;
+    let num_pixels: usize = (width * height) as usize;
+    if buffer.len() != num_pixels || weights.len() != num_pixels {
+        return Err(RenderError::BufferSizeMismatch { pixels: num_pixels, buffer: buffer.len(), weights: weights.len() })
     }
 
-    // 出力
-    save_ppm_file(filename, &image, width, height);
+    let ray_cache = camera.primary_ray_cache(width, height);
+    let render_pixel = |x: i32, y: i32| -> Vec {
+        let mut sum = Vec { x: 0.0, y: 0.0, z: 0.0 };
+        let pixel_index: u64 = (y * width + x) as u64;
+        for s in 0..spp {
+            let mut random = Random::new(sample_seed(pixel_index, sample_offset + s as u64, DEFAULT_SEED));
+            let (r1, r2) = random.next_2d();
+            let dir = ray_cache.ray_direction(x, y, r1, r2);
+            let time: f64 = random.next_1d();
+            sum = sum + path_tracing_radiance(scene, &Ray::with_time(camera.position(), &dir, time), &mut random);
+        }
+        sum
+    };
+
+    let mut samples = clone_value![Vec { x: 0.0, y: 0.0, z: 0.0 }, num_pixels as u64];
+    let cancel = AtomicBool::new(false);
+    render_parallel(&mut samples, width, height, &render_pixel, &cancel, None);
+
+    for ((color, weight), sample) in buffer.iter_mut().zip(weights.iter_mut()).zip(samples) {
+        *color = &*color + sample;
+        *weight += spp as f64;
+    }
+    Ok(())
+}
+
+// 時間durationの間、ピクセルあたり1サンプルの回（パス）をrender_samples_intoで足し込み続けて、
+// (平均した画像, ピクセルあたりのサンプル数)を返す。サンプル数を決める代わりに「10秒でできるだけ」描くときに使う。
+// 経過時間はパスの合間にだけ確かめるので、パスの途中で打ち切ることは無く、どのピクセルも同じサンプル数になる。
+// そのぶん、最後のパスが終わるまで最大で一回分durationを越える。durationが0でも必ず一回は描く。
+// サンプルの配置はrender_samples_intoと同じなので、同じサンプル数に達した画像は実行ごとに同じになる。
+pub fn render_timed(
+    scene: &Scene, camera: &Camera, width: i32, height: i32, duration: Duration)
+    -> Result<(std::vec::Vec<Vec>, u32), RenderError> {
+    check_arguments(scene, width, height, 1, 1)?;
+    let num_pixels: usize = (width * height) as usize;
+    let mut buffer = clone_value![Vec { x: 0.0, y: 0.0, z: 0.0 }, num_pixels as u64];
+    let mut weights: std::vec::Vec<f64> = vec![0.0; num_pixels];
+
+    let start = Instant::now();
+    let mut spp: u32 = 0;
+    loop {
+        render_samples_into(scene, camera, width, height, &mut buffer, &mut weights, 1, spp as u64)?;
+        spp += 1;
+        if start.elapsed() >= duration {
+            break
+        }
+    }
+
+    let image: std::vec::Vec<Vec> = buffer.into_iter().map(|color| color / spp as f64).collect();
+    Ok((image, spp))
+}
+
+// total_samples個のサンプルを、ピクセルごとにguideの値に比例して割り当てて、(画像, ピクセルごとのサンプル数)を返す。
+// guideはノイズの大きさや重要度の画像で、画像と同じく上の行から順に並べる。割り当てはallocate_samplesで決める。
+// サンプルの配置はrender_samples_intoと同じなので、同じ数のサンプルを割り当てたピクセルは同じ値になる。
+// サンプルが一つも割り当てられなかったピクセルは黒になる。以前の画像に足し込むときはサンプル数で重み付けすること。
+pub fn render_guided(
+    scene: &Scene, camera: &Camera, width: i32, height: i32, total_samples: u64, guide: &[f64])
+    -> Result<(std::vec::Vec<Vec>, std::vec::Vec<u32>), RenderError> {
+    check_arguments(scene, width, height, 1, 1)?;
+    if total_samples == 0 {
+        return Err(RenderError::InvalidSampleCount { num_sample_per_subpixel: 0, num_subpixel: 1 })
+    }
+    let num_pixels: usize = (width * height) as usize;
+    if guide.len() != num_pixels {
+        return Err(RenderError::GuideSizeMismatch { pixels: num_pixels, guide: guide.len() })
+    }
+
+    let counts: std::vec::Vec<u32> = allocate_samples(guide, total_samples);
+    let ray_cache = camera.primary_ray_cache(width, height);
+    let render_pixel = |x: i32, y: i32| -> Vec {
+        let spp: u32 = counts[((height - y - 1) * width + x) as usize];
+        let mut sum = Vec { x: 0.0, y: 0.0, z: 0.0 };
+        let pixel_index: u64 = (y * width + x) as u64;
+        for s in 0..spp {
+            let mut random = Random::new(sample_seed(pixel_index, s as u64, DEFAULT_SEED));
+            let (r1, r2) = random.next_2d();
+            let dir = ray_cache.ray_direction(x, y, r1, r2);
+            let time: f64 = random.next_1d();
+            sum = sum + path_tracing_radiance(scene, &Ray::with_time(camera.position(), &dir, time), &mut random);
+        }
+        if spp == 0 { sum } else { sum / spp as f64 }
+    };
+
+    let mut image = clone_value![Vec { x: 0.0, y: 0.0, z: 0.0 }, num_pixels as u64];
+    let cancel = AtomicBool::new(false);
+    render_parallel(&mut image, width, height, &render_pixel, &cancel, None);
+    Ok((image, counts))
+}
+
+// total_samples個のサンプルを、weightsの値に比例するように各項目に割り当てる。合計はちょうどtotal_samplesになる。
+// 比例配分の端数は切り捨て、余った分を端数の大きい項目から一つずつ配る（最大剰余方式）。
+// 負の値やNaNは0とし、全て0なら一様に割り当てる。
+pub fn allocate_samples(weights: &[f64], total_samples: u64) -> std::vec::Vec<u32> {
+    if weights.is_empty() {
+        return std::vec::Vec::new()
+    }
+    let clean: std::vec::Vec<f64> = weights.iter().map(|&w| if w > 0.0 { w } else { 0.0 }).collect();
+    let sum: f64 = clean.iter().sum();
+    let shares: std::vec::Vec<f64> = if sum > 0.0 {
+        clean.iter().map(|w| w / sum * total_samples as f64).collect()
+    } else {
+        clean.iter().map(|_| total_samples as f64 / clean.len() as f64).collect()
+    };
+
+    let mut counts: std::vec::Vec<u32> = shares.iter().map(|share| share.floor() as u32).collect();
+    let assigned: u64 = counts.iter().map(|&count| count as u64).sum();
+    let mut order: std::vec::Vec<usize> = (0..shares.len()).collect();
+    order.sort_by(|&a, &b| (shares[b] - shares[b].floor()).partial_cmp(&(shares[a] - shares[a].floor())).unwrap());
+    for &index in order.iter().cycle().take(total_samples.saturating_sub(assigned) as usize) {
+        counts[index] += 1;
+    }
+    counts
+}
+
+// 逐次処理
+// imageは上の行から順に並んでいるので、センサーのy座標とは上下が反転する。
+// キャンセルの確認は行ごとに行う。途中で打ち切ったらtrueを返す。
+fn render_serial<T, F>(
+    image: &mut [T], width: i32, height: i32, render_pixel: &F, cancel: &AtomicBool,
+    progress: Option<&(dyn Fn(usize, usize) + Sync)>) -> bool
+    where F: Fn(i32, i32) -> T {
+    for y in 0..height {
+        if cancel.load(Ordering::Relaxed) {
+            return true;
+        }
+        for x in 0..width {
+            let image_index = ((height - y - 1) * width + x) as usize;
+            image[image_index] = render_pixel(x, y);
+        }
+        if let Some(progress) = progress {
+            progress((y + 1) as usize, height as usize);
+        }
+    }
+    false
+}
+
+// Rayonを使った並列処理
+// ピクセル単位で並列化する。進捗表示は全ピクセルの処理数から求める。
+// キャンセルされたら残りのピクセルは計算せずに読み飛ばす。読み飛ばしたピクセルがあればtrueを返す。
+// progressには、計算し終えたピクセル数が一行分増えるごとに、行数に直して知らせる。
+#[cfg(feature = "rayon")]
+fn render_parallel<T, F>(
+    image: &mut [T], width: i32, height: i32, render_pixel: &F, cancel: &AtomicBool,
+    progress: Option<&(dyn Fn(usize, usize) + Sync)>) -> bool
+    where T: Send, F: Fn(i32, i32) -> T + Sync {
+    let count = Arc::new(Mutex::new(0));
+    let skipped = AtomicBool::new(false);
+    image.par_iter_mut().enumerate().for_each(|(i, pixel)| {
+        if cancel.load(Ordering::Relaxed) {
+            skipped.store(true, Ordering::Relaxed);
+            return;
+        }
+        let y = i as i32 / width;
+        let x = i as i32 - y * width;
+        *pixel = render_pixel(x, height - y - 1);
+
+        // 進捗表示
+        // 知らせる行数が前後しないよう、数え上げのロックを持ったまま呼ぶ。
+        let mut count = count.lock().unwrap();
+        *count += 1;
+        let count = *count;
+        if count % width == 0 {
+            if let Some(progress) = progress {
+                progress((count / width) as usize, height as usize);
+            }
+        }
+    });
+    skipped.load(Ordering::Relaxed)
+}
+
+// rayonフィーチャーが無効なときは逐次処理で代用する。
+#[cfg(not(feature = "rayon"))]
+fn render_parallel<T, F>(
+    image: &mut [T], width: i32, height: i32, render_pixel: &F, cancel: &AtomicBool,
+    progress: Option<&(dyn Fn(usize, usize) + Sync)>) -> bool
+    where T: Send, F: Fn(i32, i32) -> T + Sync {
+    render_serial(image, width, height, render_pixel, cancel, progress)
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[cfg(feature = "rayon")]
+    use scene::{generate_scene, SceneRendering};
+    use scenes::cornell_box;
+    use scene::SceneBuilder;
+    use material::{Material, Lightsource, LambertianMaterial};
+    use scene::SceneObject;
+    use shape::Shape;
+    use sphere::Sphere;
+    use std::sync::{Arc, Mutex};
+    use instance::Instance;
+    use mesh::{Mesh, tessellate_sphere};
+    use transform::Transform;
+
+    fn same_pixel(p: &Vec, q: &Vec) -> bool {
+        p.x.to_bits() == q.x.to_bits() && p.y.to_bits() == q.y.to_bits() && p.z.to_bits() == q.z.to_bits()
+    }
+
+    fn same_image(a: &[Vec], b: &[Vec]) -> bool {
+        a.len() == b.len() && a.iter().zip(b.iter()).all(|(p, q)| same_pixel(p, q))
+    }
+
+    // 並列処理でもピクセルごとのシード値は同じなので、逐次処理とビット単位で同じ画像になる。
+    #[cfg(feature = "rayon")]
+    #[test]
+    fn rayon_and_serial_renders_are_identical() {
+        let scene = generate_scene(SceneRendering::SceneGlass);
+        let camera = Camera::default_camera();
+        let cancel = AtomicBool::new(false);
+        let (serial, _) = render_image(&scene, &camera, 16, 12, 1, 2, false, &cancel).unwrap();
+        let (parallel, _) = render_image(&scene, &camera, 16, 12, 1, 2, true, &cancel).unwrap();
+        assert!(same_image(&serial, &parallel));
+    }
+
+    fn is_black(pixel: &Vec) -> bool {
+        pixel.x == 0.0 && pixel.y == 0.0 && pixel.z == 0.0
+    }
+
+    // 最後まで描いた画像と、rows行を描いたところでキャンセルした画像。
+    fn full_and_cancelled(is_parallel: bool, rows: usize) -> (std::vec::Vec<Vec>, std::vec::Vec<Vec>, bool) {
+        let (scene, camera) = cornell_box();
+        let full_cancel = AtomicBool::new(false);
+        let (full, _, full_cancelled) = render_with_progress(
+            &scene, &camera, 16, 12, 1, 1, is_parallel, &full_cancel, DEFAULT_SEED, &path_tracing_radiance, None)
+            .unwrap();
+        assert!(!full_cancelled);
+
+        let cancel = AtomicBool::new(false);
+        let stop_after = |done: usize, _total: usize| if done >= rows { cancel.store(true, Ordering::Relaxed) };
+        let (partial, _, cancelled) = render_with_progress(
+            &scene, &camera, 16, 12, 1, 1, is_parallel, &cancel, DEFAULT_SEED, &path_tracing_radiance, Some(&stop_after))
+            .unwrap();
+        (full, partial, cancelled)
+    }
+
+    // キャンセルすると残りの行は描かずに返す。描いた行は最後まで描いた画像と同じで、残りは黒。
+    // 逐次処理では画像の下の行から順に描く。
+    #[test]
+    fn cancelling_returns_the_rows_rendered_so_far() {
+        let (full, partial, cancelled) = full_and_cancelled(false, 3);
+        assert!(cancelled);
+        for (i, (a, b)) in full.iter().zip(partial.iter()).enumerate() {
+            let row_from_bottom: usize = 11 - i / 16;
+            if row_from_bottom < 3 {
+                assert!(same_pixel(a, b), "pixel {}", i);
+            } else {
+                assert!(is_black(b), "pixel {}", i);
+            }
+        }
+    }
+
+    // 並列処理でも、各ピクセルは最後まで描いた画像と同じか、描かれずに黒のどちらか。
+    #[test]
+    fn cancelled_parallel_render_is_consistent() {
+        let (full, partial, cancelled) = full_and_cancelled(true, 3);
+        assert!(cancelled);
+        let mut skipped: usize = 0;
+        for (a, b) in full.iter().zip(partial.iter()) {
+            if is_black(b) && !is_black(a) {
+                skipped += 1;
+            } else {
+                assert!(same_pixel(a, b));
+            }
+        }
+        assert!(skipped > 0);
+    }
+
+    // 始める前にキャンセルされていれば何も描かない。
+    #[test]
+    fn render_cancelled_before_start_is_black() {
+        let (scene, camera) = cornell_box();
+        let cancel = AtomicBool::new(true);
+        let (image, cancelled) = render_image(&scene, &camera, 8, 6, 1, 1, false, &cancel).unwrap();
+        assert!(cancelled);
+        assert!(image.iter().all(is_black));
+    }
+
+    // 一様に光る面を写したピクセルは分散が0になり、光る面と背景の境目のピクセルは分散が大きくなる。
+    #[test]
+    fn variance_is_zero_on_flat_regions_and_high_on_edges() {
+        // 画面の左半分（x < 0）を覆う光源。
+        let emission: f64 = 2.0;
+        let (scene, _) = SceneBuilder::new()
+            .add_quad(Vec { x: -20.0, y: -20.0, z: 0.0 }, Vec { x: 20.0, y: 0.0, z: 0.0 }, Vec { x: 0.0, y: 40.0, z: 0.0 },
+                      Material::Lightsource(Lightsource::new(Vec { x: emission, y: emission, z: emission })))
+            .build().unwrap();
+        let camera = Camera::new(
+            Vec { x: 0.0, y: 0.0, z: 10.0 },
+            Vec { x: 0.0, y: 0.0, z: 0.0 },
+            Vec { x: 0.0, y: 1.0, z: 0.0 });
+        // 幅が奇数なので、真ん中の列のピクセルが境目をまたぐ。
+        let (width, height): (i32, i32) = (9, 5);
+        let cancel = AtomicBool::new(false);
+        let (image, variance, _) = render_image_with_variance(&scene, &camera, width, height, 1, 4, false, &cancel, DEFAULT_SEED)
+            .unwrap();
+
+        for (index, (pixel, v)) in image.iter().zip(variance.iter()).enumerate() {
+            let x: i32 = index as i32 % width;
+            if x == width / 2 {
+                // 16個のサンプルの半分が光源に当たる。分散は emission^2 * 16 * 0.25 / 15 / 16。
+                let expected: f64 = emission * emission * 0.25 / 15.0;
+                assert!((pixel.x - emission / 2.0).abs() < 1e-12, "{} {:?}", index, pixel);
+                assert!((v.x - expected).abs() < 1e-12, "{} {:?}", index, v);
+            } else {
+                assert!(v.x == 0.0 && v.y == 0.0 && v.z == 0.0, "{} {:?}", index, v);
+            }
+        }
+    }
+
+    // サンプルごとのシード値はピクセルとサンプルの番号だけで決まるので、スレッド数を変えてもビット単位で同じ画像になる。
+    #[cfg(feature = "rayon")]
+    #[test]
+    fn one_and_eight_thread_renders_are_identical() {
+        let (scene, camera) = cornell_box();
+        let render_with_threads = |threads: usize| -> std::vec::Vec<Vec> {
+            let pool = rayon::ThreadPoolBuilder::new().num_threads(threads).build().unwrap();
+            pool.install(|| {
+                let cancel = AtomicBool::new(false);
+                render_image_with_variance(&scene, &camera, 16, 12, 2, 1, true, &cancel, 1234).unwrap().0
+            })
+        };
+        let one = render_with_threads(1);
+        let eight = render_with_threads(8);
+        assert!(same_image(&one, &eight));
+
+        // シード値を変えると別の画像になる。
+        let cancel = AtomicBool::new(false);
+        let other = render_image_with_variance(&scene, &camera, 16, 12, 2, 1, true, &cancel, 4321).unwrap().0;
+        assert!(!same_image(&one, &other));
+    }
+
+    // 引数やシーンの問題ごとに、それを表すRenderErrorが返る。
+    #[test]
+    fn each_render_error_variant_is_reported() {
+        let (scene, camera) = cornell_box();
+        let cancel = AtomicBool::new(false);
+        let image_error = |scene: &Scene, width: i32, height: i32, samples: i32, subpixels: i32| -> RenderError {
+            match render_image(scene, &camera, width, height, samples, subpixels, false, &cancel) {
+                Ok(_) => unreachable!("render_image should have failed"),
+                Err(why) => why,
+            }
+        };
+
+        assert!(matches!(image_error(&std::vec::Vec::new(), 4, 4, 1, 1), RenderError::EmptyScene));
+        let dark: Scene = vec![SceneObject::new(
+            Shape::Sphere(Sphere::new(1.0, Vec { x: 0.0, y: 0.0, z: 0.0 })),
+            Material::LambertianMaterial(LambertianMaterial::new(Vec { x: 0.5, y: 0.5, z: 0.5 })))];
+        assert!(matches!(image_error(&dark, 4, 4, 1, 1), RenderError::NoLights));
+        assert!(matches!(image_error(&scene, 0, 4, 1, 1), RenderError::InvalidDimensions { width: 0, height: 4 }));
+        assert!(matches!(image_error(&scene, 4, -1, 1, 1), RenderError::InvalidDimensions { width: 4, height: -1 }));
+        assert!(matches!(image_error(&scene, 4, 4, 0, 1),
+                         RenderError::InvalidSampleCount { num_sample_per_subpixel: 0, num_subpixel: 1 }));
+        assert!(matches!(image_error(&scene, 4, 4, 1, 0),
+                         RenderError::InvalidSampleCount { num_sample_per_subpixel: 1, num_subpixel: 0 }));
+
+        let mut buffer: std::vec::Vec<Vec> = vec![Vec { x: 0.0, y: 0.0, z: 0.0 }; 15];
+        let mut weights: std::vec::Vec<f64> = vec![0.0; 16];
+        assert!(matches!(render_samples_into(&scene, &camera, 4, 4, &mut buffer, &mut weights, 1, 0),
+                         Err(RenderError::BufferSizeMismatch { pixels: 16, buffer: 15, weights: 16 })));
+
+        assert!(matches!(render_guided(&scene, &camera, 4, 4, 16, &[1.0; 3]),
+                         Err(RenderError::GuideSizeMismatch { pixels: 16, guide: 3 })));
+
+        let missing_dir = std::env::temp_dir().join("gemspt-no-such-directory").join("image.ppm");
+        match render(&scene, &camera, missing_dir.to_str().unwrap(), 2, 2, 1, 1, false) {
+            Err(RenderError::Io(ref why)) => assert_eq!(why.kind(), io::ErrorKind::NotFound),
+            other => unreachable!("expected an I/O error, got {:?}", other),
+        }
+
+        // どのエラーも何が悪いかを説明する文になる。
+        assert_eq!(RenderError::InvalidDimensions { width: 0, height: 4 }.to_string(), "invalid image size 0 x 4");
+        assert_eq!(RenderError::NoLights.to_string(), "scene has no light source");
+    }
+
+    // シャッターが開いている間に横に動く光る物体は、止まっているときより横に広く写る（モーションブラー）。
+    #[test]
+    fn moving_object_leaves_a_wider_footprint() {
+        let mesh = Arc::new(Mesh::new(tessellate_sphere(Vec { x: 0.0, y: 0.0, z: 0.0 }, 0.5, 2)));
+        let light = || Material::Lightsource(Lightsource::new(Vec { x: 1.0, y: 1.0, z: 1.0 }));
+        let still: Scene = vec![SceneObject::new(Shape::Instance(Instance::new(mesh.clone(), Transform::identity())), light())];
+        let moving: Scene = vec![SceneObject::new(Shape::Instance(Instance::new_moving(
+            mesh, Transform::translation(&Vec { x: -1.0, y: 0.0, z: 0.0 }), Transform::translation(&Vec { x: 1.0, y: 0.0, z: 0.0 }))),
+            light())];
+        // 横幅8ほどの範囲が写る。
+        let camera = Camera::new(
+            Vec { x: 0.0, y: 0.0, z: 3.0 },
+            Vec { x: 0.0, y: 0.0, z: 0.0 },
+            Vec { x: 0.0, y: 1.0, z: 0.0 });
+
+        // 物体が写った列の数。
+        let (width, height): (i32, i32) = (40, 10);
+        let columns = |scene: &Scene| -> usize {
+            let cancel = AtomicBool::new(false);
+            let (image, _) = render_image(scene, &camera, width, height, 4, 2, false, &cancel).unwrap();
+            (0..width).filter(|&x| (0..height).any(|y| !is_black(&image[(y * width + x) as usize]))).count()
+        };
+        let (still_columns, moving_columns) = (columns(&still), columns(&moving));
+        // 球の直径1に対して、動く範囲は2なので、写る幅はおよそ3倍になる。
+        assert!(still_columns > 0);
+        assert!(moving_columns as f64 > 2.0 * still_columns as f64, "{} {}", still_columns, moving_columns);
+    }
+
+    // sample_offsetを進めながらspp = Nで二回足し込んだ結果は、spp = 2Nで一回足し込んだ結果と、
+    // 和を取る順序による丸め誤差を除いて一致する。
+    #[test]
+    fn two_accumulations_equal_one_with_twice_the_samples() {
+        let (scene, camera) = cornell_box();
+        let (width, height, spp): (i32, i32, i32) = (8, 6, 2);
+        let num_pixels: usize = (width * height) as usize;
+        let zero = Vec { x: 0.0, y: 0.0, z: 0.0 };
+
+        let mut twice = vec![zero.clone(); num_pixels];
+        let mut twice_weights = vec![0.0; num_pixels];
+        render_samples_into(&scene, &camera, width, height, &mut twice, &mut twice_weights, spp, 0).unwrap();
+        render_samples_into(&scene, &camera, width, height, &mut twice, &mut twice_weights, spp, spp as u64).unwrap();
+
+        let mut once = vec![zero; num_pixels];
+        let mut once_weights = vec![0.0; num_pixels];
+        render_samples_into(&scene, &camera, width, height, &mut once, &mut once_weights, 2 * spp, 0).unwrap();
+
+        assert_eq!(twice_weights, once_weights);
+        assert!(once.iter().any(|pixel| !is_black(pixel)));
+        for i in 0..num_pixels {
+            let (a, b) = (&twice[i] / twice_weights[i], &once[i] / once_weights[i]);
+            assert!((&a - &b).length() <= 1e-12 * (1.0 + b.length()), "{} {:?} {:?}", i, a, b);
+        }
+    }
+
+    // 拡散と鏡面の成分の和が画像になり、画像全体の平均の明るさはrender_imageの画像と誤差の範囲で一致する。
+    // コーネルボックスには鏡とガラスの球があるので、どちらの成分も写る。
+    #[test]
+    fn diffuse_and_specular_images_sum_to_the_render() {
+        let (scene, camera) = cornell_box();
+        let cancel = AtomicBool::new(false);
+        let (width, height): (i32, i32) = (16, 12);
+        let (image, diffuse, specular, _) = render_image_by_lobe(&scene, &camera, width, height, 16, 2, false, &cancel, 1).unwrap();
+        let (reference, _) = render_image(&scene, &camera, width, height, 16, 2, false, &cancel).unwrap();
+
+        for ((pixel, d), s) in image.iter().zip(diffuse.iter()).zip(specular.iter()) {
+            assert!(same_pixel(pixel, &(d + s)));
+        }
+        assert!(diffuse.iter().any(|pixel| !is_black(pixel)) && specular.iter().any(|pixel| !is_black(pixel)));
+        let mean = |pixels: &[Vec]| -> f64 { pixels.iter().map(|pixel| pixel.luminance()).sum::<f64>() / pixels.len() as f64 };
+        assert!((mean(&image) / mean(&reference) - 1.0).abs() < 0.05, "{} {}", mean(&image), mean(&reference));
+    }
+
+    // 同じ小さな値0.1を百万回足すと、和を取ってから割る平均は丸め誤差がたまって0.1からずれるが、
+    // PixelAccumulatorの平均はずれず、分散も負にならない。
+    #[test]
+    fn welford_mean_of_many_tiny_samples_beats_naive_summation() {
+        let value: f64 = 0.1;
+        let samples: usize = 1_000_000;
+        let sample = Vec { x: value, y: value, z: value };
+        let mut accumulator = PixelAccumulator::new();
+        let mut sum: f64 = 0.0;
+        for _ in 0..samples {
+            accumulator.add(&sample);
+            sum += value;
+        }
+        let naive_error: f64 = (sum / samples as f64 - value).abs();
+        let welford_error: f64 = (accumulator.mean().x - value).abs();
+        assert_eq!(accumulator.count(), samples as u64);
+        assert!(naive_error > 0.0 && welford_error < naive_error, "{:e} {:e}", welford_error, naive_error);
+        assert!(accumulator.variance().x >= 0.0 && accumulator.variance().x < 1e-20);
+    }
+
+    // カメラを中心とする球の内側では、当たった点の法線はレイの向きと同じ。
+    // レイの向きを(dir + 1) / 2の色で返す放射輝度の関数でrender_withすると、法線の可視化と同じ画像になる。
+    // render_withはピクセル内でジッターするので、ピクセルの中心を通るrender_normalsとはピクセル一つ分の角度までずれる。
+    #[test]
+    fn direction_as_color_matches_the_normal_visualization() {
+        use aov::render_normals;
+        let camera = Camera::default_camera();
+        let scene: Scene = vec![SceneObject::new(
+            Shape::Sphere(Sphere::new(1.0e3, camera.position().clone())),
+            Material::Lightsource(Lightsource::new(Vec { x: 1.0, y: 1.0, z: 1.0 })))];
+        let (width, height): (i32, i32) = (40, 30);
+        let cancel = AtomicBool::new(false);
+        let direction_as_color = |_: &Scene, ray: &Ray, _: &mut dyn Sampler| {
+            (ray.dir + &Vec { x: 1.0, y: 1.0, z: 1.0 }) * 0.5
+        };
+        let (image, _, _) = render_with(&scene, &camera, width, height, 1, 2, false, &cancel, 0, &direction_as_color).unwrap();
+        let normals = render_normals(&scene, &camera, width, height);
+
+        assert_eq!(image.len(), normals.len());
+        for (index, (pixel, normal)) in image.iter().zip(normals.iter()).enumerate() {
+            let difference: f64 = (pixel - normal).length();
+            assert!(difference < 0.02, "{} {:?} {:?}", index, pixel, normal);
+        }
+    }
+
+    // 進捗は一行ずつ増えながら知らされ、逐次でも並列でも最後は必ず(height, height)、つまり100%になる。
+    #[test]
+    fn progress_reaches_exactly_all_rows() {
+        let (scene, camera) = cornell_box();
+        let (width, height): (i32, i32) = (16, 12);
+        let gray = |_: &Scene, _: &Ray, _: &mut dyn Sampler| Vec { x: 0.5, y: 0.5, z: 0.5 };
+        let expected: std::vec::Vec<(usize, usize)> = (1..height as usize + 1).map(|rows| (rows, height as usize)).collect();
+        for &is_parallel in [false, true].iter() {
+            let reports: Mutex<std::vec::Vec<(usize, usize)>> = Mutex::new(std::vec::Vec::new());
+            let record = |done: usize, total: usize| reports.lock().unwrap().push((done, total));
+            let cancel = AtomicBool::new(false);
+            let (_, _, cancelled) = render_with_progress(
+                &scene, &camera, width, height, 1, 1, is_parallel, &cancel, DEFAULT_SEED, &gray, Some(&record))
+                .unwrap();
+            assert!(!cancelled);
+            assert_eq!(reports.into_inner().unwrap(), expected, "parallel: {}", is_parallel);
+        }
+    }
+
+    // ガイドの値が大きいピクセルほど多くのサンプルを受け取り、合計はちょうどtotal_samplesになる。
+    // ガイドが0のピクセルにはサンプルを割り当てず、黒のまま。ガイドの大きさが画像と違えばエラー。
+    #[test]
+    fn pixels_with_higher_guide_weight_receive_more_samples() {
+        let (scene, camera) = cornell_box();
+        let (width, height): (i32, i32) = (8, 6);
+        let guide: std::vec::Vec<f64> = (0..width * height).map(|index| (index % width) as f64).collect();
+        let total_samples: u64 = 400;
+        let (image, counts) = render_guided(&scene, &camera, width, height, total_samples, &guide).unwrap();
+
+        assert_eq!(counts.iter().map(|&count| count as u64).sum::<u64>(), total_samples);
+        for (index, count) in counts.iter().enumerate() {
+            if guide[index] == 0.0 {
+                assert_eq!(*count, 0);
+                assert!(is_black(&image[index]));
+            }
+        }
+        for row in counts.chunks(width as usize) {
+            assert!(row.windows(2).all(|pair| pair[0] < pair[1]), "{:?}", row);
+        }
+
+        assert!(matches!(render_guided(&scene, &camera, width, height, total_samples, &guide[1..]),
+                         Err(RenderError::GuideSizeMismatch { .. })));
+    }
+
+    // ごく短い時間を指定してもすぐに戻り、少なくとも一回は描く。
+    // 描いた画像は、同じ回数だけrender_samples_intoで足し込んで平均したものと同じ。
+    #[test]
+    fn tiny_time_budget_returns_promptly_with_at_least_one_sample() {
+        let (scene, camera) = cornell_box();
+        let (width, height): (i32, i32) = (8, 6);
+        let start = Instant::now();
+        let (image, spp) = render_timed(&scene, &camera, width, height, Duration::from_millis(1)).unwrap();
+        assert!(start.elapsed() < Duration::from_secs(5), "{:?}", start.elapsed());
+        assert!(spp >= 1);
+
+        let mut buffer = vec![Vec { x: 0.0, y: 0.0, z: 0.0 }; (width * height) as usize];
+        let mut weights: std::vec::Vec<f64> = vec![0.0; (width * height) as usize];
+        for pass in 0..spp {
+            render_samples_into(&scene, &camera, width, height, &mut buffer, &mut weights, 1, pass as u64).unwrap();
+        }
+        for (pixel, sum) in image.iter().zip(buffer.iter()) {
+            assert!((pixel - &(sum / spp as f64)).length() < 1e-12, "{:?} {:?}", pixel, sum);
+        }
+    }
+}
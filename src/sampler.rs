@@ -0,0 +1,319 @@
+#![allow(dead_code, unused_variables)]
+
+use random::{Random, sample_seed};
+
+// マテリアルや積分器が使う[0, 1]の一様な値の供給元。
+// 擬似乱数（Random）と低食い違い量列（Halton、Sobol）を同じように扱えるようにする。
+// 一つのサンプル（カメラレイ一本分）の中では、値を要求するたびに「次元」が進む。
+// 低食い違い量列では、同じ次元の値を全サンプルにわたって並べたものが均等に分布するので、
+// 同じ用途（例えば一回目の反射の方向）には毎回同じ次元が使われるよう、決まった順序で値を要求すること。
+pub trait Sampler {
+    // 次の次元の値を一つ返し、次元を一つ進める。
+    fn next_1d(&mut self) -> f64;
+
+    // 次の二つの次元の値を組にして返し、次元を二つ進める。
+    // 低食い違い量列では、二次元で均等に分布する組になる。
+    fn next_2d(&mut self) -> (f64, f64) {
+        let u: f64 = self.next_1d();
+        let v: f64 = self.next_1d();
+        (u, v)
+    }
+
+    // pixel_index番目のピクセルのsample_index番目のサンプルを始める。次元は0に戻る。
+    fn start_pixel(&mut self, pixel_index: u64, sample_index: u64);
+
+    // 次に返す値の次元をdimensionにする。
+    fn start_dimension(&mut self, dimension: u32);
+}
+
+// 擬似乱数には次元が無いので、next_1dはnext01をそのまま返し、start_dimensionは何もしない。
+// start_pixelは画像全体のシード値を0として、sample_seedで初期化し直す。
+// 別のシード値を使うときは、Random::new(sample_seed(pixel_index, sample_index, seed))で作り直せばよい。
+impl Sampler for Random {
+    fn next_1d(&mut self) -> f64 {
+        self.next01()
+    }
+
+    fn start_pixel(&mut self, pixel_index: u64, sample_index: u64) {
+        *self = Random::new(sample_seed(pixel_index, sample_index, 0));
+    }
+
+    fn start_dimension(&mut self, dimension: u32) {
+    }
+}
+
+// 64ビットのハッシュ値から[0, 1)の値を作る。上位53ビットを仮数に使う。
+fn to_unit(hash: u64) -> f64 {
+    (hash >> 11) as f64 * (1.0 / (1u64 << 53) as f64)
+}
+
+// Halton列で使う素数。これより高い次元ではハッシュによる擬似乱数で代用する。
+const PRIMES: [u64; 32] = [
+    2, 3, 5, 7, 11, 13, 17, 19, 23, 29, 31, 37, 41, 43, 47, 53,
+    59, 61, 67, 71, 73, 79, 83, 89, 97, 101, 103, 107, 109, 113, 127, 131,
+];
+
+// indexのbase進数での根基逆関数（radical inverse）。[0, 1)の値になる。
+fn radical_inverse(base: u64, index: u64) -> f64 {
+    let inv_base: f64 = 1.0 / base as f64;
+    let mut inv_base_n: f64 = 1.0;
+    let mut reversed: u64 = 0;
+    let mut rest: u64 = index;
+    while rest > 0 {
+        let next: u64 = rest / base;
+        reversed = reversed * base + (rest - next * base);
+        inv_base_n *= inv_base;
+        rest = next;
+    }
+    (reversed as f64 * inv_base_n).min(1.0 - f64::EPSILON / 2.0)
+}
+
+// Halton列によるサンプラー。
+// d次元目にはd番目の素数を底とする根基逆関数を使い、サンプルの番号を列の番号とする。
+// ピクセル同士で同じ点列にならないよう、ピクセルと次元ごとにハッシュで決めた量だけ値をずらす（Cranley-Pattersonの回転）。
+#[derive(Debug, Clone)]
+pub struct HaltonSampler {
+    seed: u64,
+    pixel_index: u64,
+    sample_index: u64,
+    dimension: u32,
+}
+
+impl HaltonSampler {
+    // seedは画像全体のシード値。
+    pub fn new(seed: u64) -> HaltonSampler {
+        HaltonSampler { seed, pixel_index: 0, sample_index: 0, dimension: 0 }
+    }
+
+    fn pixel_hash(&self, dimension: u32) -> u64 {
+        sample_seed(self.pixel_index, dimension as u64, self.seed)
+    }
+}
+
+impl Sampler for HaltonSampler {
+    fn next_1d(&mut self) -> f64 {
+        let dimension: u32 = self.dimension;
+        self.dimension += 1;
+
+        if (dimension as usize) >= PRIMES.len() {
+            return to_unit(sample_seed(self.pixel_hash(dimension), self.sample_index, self.seed))
+        }
+        let value: f64 = radical_inverse(PRIMES[dimension as usize], self.sample_index) + to_unit(self.pixel_hash(dimension));
+        if value >= 1.0 { value - 1.0 } else { value }
+    }
+
+    fn start_pixel(&mut self, pixel_index: u64, sample_index: u64) {
+        self.pixel_index = pixel_index;
+        self.sample_index = sample_index;
+        self.dimension = 0;
+    }
+
+    fn start_dimension(&mut self, dimension: u32) {
+        self.dimension = dimension;
+    }
+}
+
+// Sobol列の1次元目（van der Corput列）。
+fn sobol_0(index: u32) -> u32 {
+    index.reverse_bits()
+}
+
+// Sobol列の2次元目。原始多項式x + 1の方向数は v_i = v_{i-1} ^ (v_{i-1} >> 1) になる。
+fn sobol_1(index: u32) -> u32 {
+    let mut result: u32 = 0;
+    let mut v: u32 = 1 << 31;
+    let mut rest: u32 = index;
+    while rest != 0 {
+        if rest & 1 != 0 {
+            result ^= v;
+        }
+        rest >>= 1;
+        v ^= v >> 1;
+    }
+    result
+}
+
+// ハッシュによる入れ子の一様な並べ換え（Owenのスクランブル）。
+// ビットを反転してから、下位のビットが上位のビットにしか影響しないハッシュを掛ける。
+// Brent Burley. Practical Hash-based Owen Scrambling. Journal of Computer Graphics Techniques, 2020.
+fn owen_scramble(x: u32, seed: u32) -> u32 {
+    let mut v: u32 = x.reverse_bits();
+    v = v.wrapping_add(seed);
+    v ^= v.wrapping_mul(0x6c50b47c);
+    v ^= v.wrapping_mul(0xb82f1e52);
+    v ^= v.wrapping_mul(0xc7afe638);
+    v ^= v.wrapping_mul(0x8d22f6e6);
+    v.reverse_bits()
+}
+
+// Owenのスクランブルを掛けたSobol列によるサンプラー。
+// 二次元ごとに、Sobol列の最初の二次元を、次元ごとに異なるシード値でスクランブルし、
+// サンプルの番号も並べ換えて使う（padding）。方向数の表が要らず、いくらでも高い次元を使える。
+// サンプル数が2の冪のとき最もよく分布する。
+#[derive(Debug, Clone)]
+pub struct SobolSampler {
+    seed: u64,
+    pixel_index: u64,
+    sample_index: u64,
+    dimension: u32,
+}
+
+impl SobolSampler {
+    // seedは画像全体のシード値。
+    pub fn new(seed: u64) -> SobolSampler {
+        SobolSampler { seed, pixel_index: 0, sample_index: 0, dimension: 0 }
+    }
+
+    // 今の次元で使うスクランブルのシード値と、並べ換えたサンプルの番号。
+    fn scrambled_index(&self) -> (u32, u32) {
+        let hash: u64 = sample_seed(self.pixel_index, self.dimension as u64, self.seed);
+        let seed: u32 = (hash >> 32) as u32;
+        (owen_scramble(self.sample_index as u32, seed ^ 0x9e3779b9), seed)
+    }
+}
+
+impl Sampler for SobolSampler {
+    fn next_1d(&mut self) -> f64 {
+        let (index, seed) = self.scrambled_index();
+        self.dimension += 1;
+        owen_scramble(sobol_0(index), seed) as f64 * (1.0 / 4294967296.0)
+    }
+
+    fn next_2d(&mut self) -> (f64, f64) {
+        let (index, seed) = self.scrambled_index();
+        self.dimension += 2;
+        let u: u32 = owen_scramble(sobol_0(index), seed);
+        let v: u32 = owen_scramble(sobol_1(index), seed.wrapping_mul(0x2c1b3c6d) ^ 0x297a2d39);
+        (u as f64 * (1.0 / 4294967296.0), v as f64 * (1.0 / 4294967296.0))
+    }
+
+    fn start_pixel(&mut self, pixel_index: u64, sample_index: u64) {
+        self.pixel_index = pixel_index;
+        self.sample_index = sample_index;
+        self.dimension = 0;
+    }
+
+    fn start_dimension(&mut self, dimension: u32) {
+        self.dimension = dimension;
+    }
+}
+
+// 与えた値の並びを順に返すサンプラー。
+// マテリアルのサンプリングなどを、決まった値で動かして結果を確かめるのに使う。
+// 値は[0, 1)の範囲であること。次元は並びの中の位置で、start_pixelで先頭に戻る。
+// newで作ったものは並びを使い切ると次元を表示してpanicし、repeatingで作ったものは先頭に戻って繰り返す。
+#[derive(Debug, Clone)]
+pub struct FixedSampler {
+    values: std::vec::Vec<f64>,
+    position: usize,
+    repeating: bool,
+}
+
+impl FixedSampler {
+    // let mut random = FixedSampler::new(vec![0.25, 0.5]);
+    pub fn new(values: std::vec::Vec<f64>) -> FixedSampler {
+        FixedSampler { values, position: 0, repeating: false }
+    }
+
+    pub fn repeating(values: std::vec::Vec<f64>) -> FixedSampler {
+        FixedSampler { values, position: 0, repeating: true }
+    }
+
+    // これまでに返した値の数（次の値の次元）。
+    pub fn position(&self) -> usize {
+        self.position
+    }
+}
+
+impl Sampler for FixedSampler {
+    fn next_1d(&mut self) -> f64 {
+        let index: usize = if self.repeating && !self.values.is_empty() {
+            self.position % self.values.len()
+        } else {
+            self.position
+        };
+        let value: f64 = match self.values.get(index) {
+            Some(value) => *value,
+            None => panic!("FixedSampler ran out of values at dimension {} ({} given)", self.position, self.values.len())
+        };
+        self.position += 1;
+        value
+    }
+
+    fn start_pixel(&mut self, pixel_index: u64, sample_index: u64) {
+        self.position = 0;
+    }
+
+    fn start_dimension(&mut self, dimension: u32) {
+        self.position = dimension as usize;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // start_pixel(pixel_index, sample_index)の直後から、next_1dでcount個の値を取り出す。
+    fn values(sampler: &mut dyn Sampler, pixel_index: u64, sample_index: u64, count: usize) -> std::vec::Vec<f64> {
+        sampler.start_pixel(pixel_index, sample_index);
+        (0..count).map(|_| sampler.next_1d()).collect()
+    }
+
+    // どのサンプラーでも、値は[0, 1)に入り、同じピクセルとサンプルの番号からは同じ値の並びが得られる。
+    // サンプルの番号が変われば値も変わる。
+    fn check_values(sampler: &mut dyn Sampler) {
+        for pixel_index in 0..16 {
+            for sample_index in 0..64 {
+                let first: std::vec::Vec<f64> = values(sampler, pixel_index, sample_index, 40);
+                assert!(first.iter().all(|v| (0.0..1.0).contains(v)), "{:?}", first);
+                assert_eq!(first, values(sampler, pixel_index, sample_index, 40));
+                assert_ne!(first, values(sampler, pixel_index, sample_index + 1, 40));
+            }
+        }
+    }
+
+    // 次元を持つサンプラーでは、next_2dは二次元、next_1dは一次元進み、start_dimensionで好きな次元から始められる。
+    fn check_dimensions(sampler: &mut dyn Sampler) {
+        let expected: std::vec::Vec<f64> = values(sampler, 3, 5, 8);
+
+        sampler.start_pixel(3, 5);
+        sampler.next_2d();
+        assert_eq!(sampler.next_1d(), expected[2]);
+        sampler.next_2d();
+        sampler.next_1d();
+        assert_eq!(sampler.next_1d(), expected[6]);
+
+        for (dimension, value) in expected.iter().enumerate() {
+            sampler.start_pixel(3, 5);
+            sampler.start_dimension(dimension as u32);
+            assert_eq!(sampler.next_1d(), *value);
+        }
+    }
+
+    #[test]
+    fn random_satisfies_the_sampler_contract() {
+        check_values(&mut Random::new(0));
+    }
+
+    #[test]
+    fn halton_satisfies_the_sampler_contract() {
+        check_values(&mut HaltonSampler::new(1));
+        check_dimensions(&mut HaltonSampler::new(1));
+    }
+
+    #[test]
+    fn sobol_satisfies_the_sampler_contract() {
+        check_values(&mut SobolSampler::new(2));
+        check_dimensions(&mut SobolSampler::new(2));
+    }
+
+    // FixedSamplerは与えた値をそのまま順に返すので、次元の扱いだけを確かめる。
+    #[test]
+    fn fixed_sampler_satisfies_the_dimension_contract() {
+        let mut sampler = FixedSampler::new((0..8).map(|i| i as f64 / 8.0).collect());
+        check_dimensions(&mut sampler);
+        sampler.start_pixel(0, 0);
+        sampler.next_2d();
+        assert_eq!(sampler.position(), 2);
+    }
+}
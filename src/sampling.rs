@@ -1,17 +1,20 @@
 #![allow(dead_code)]
 
-use vec::Vec;
-use random::Random;
-use constant::K_PI;
+use vec::{Vec, Dot, Normalize, Cross, create_ortho_normal_basis};
+use sampler::Sampler;
+use std;
+
+use constant::{K_2PI, K_INV_PI, K_INV_2PI, K_INF};
 
 #[derive(Debug)]
 pub struct Sampling;
 
 impl Sampling {
     pub fn uniform_hemisphere_surface(
-        random: &mut Random, normal: &Vec, tangent: &Vec, binormal: &Vec) -> Vec {
-            let tz: f64 = random.next_range(0.0, 1.0);
-            let phi: f64 = random.next_range(0.0, 2.0 * K_PI);
+        random: &mut dyn Sampler, normal: &Vec, tangent: &Vec, binormal: &Vec) -> Vec {
+            let (u, v) = random.next_2d();
+            let tz: f64 = u;
+            let phi: f64 = K_2PI * v;
             let k: f64 = (1.0 - tz * tz).sqrt();
             let tx: f64 = k * phi.cos();
             let ty: f64 = k * phi.sin();
@@ -20,9 +23,10 @@ impl Sampling {
     }
 
     pub fn cosine_weighted_hemisphere_surface(
-        random: &mut Random, normal: &Vec, tangent: &Vec, binormal: &Vec) -> Vec {
-            let phi: f64 = random.next_range(0.0, 2.0 * K_PI);
-            let r2: f64 = random.next01();
+        random: &mut dyn Sampler, normal: &Vec, tangent: &Vec, binormal: &Vec) -> Vec {
+            let (u, v) = random.next_2d();
+            let phi: f64 = K_2PI * u;
+            let r2: f64 = v;
             let r2s: f64 = r2.sqrt();
 
             let tx: f64 = r2s * phi.cos();
@@ -31,5 +35,376 @@ impl Sampling {
 
             tz * normal + tx * tangent + ty * binormal
     }
+
+    // 単位球面上の一様サンプリング。pdfは1/(4π)。
+    pub fn uniform_sphere_surface(random: &mut dyn Sampler) -> Vec {
+        let (u, v) = random.next_2d();
+        let tz: f64 = 2.0 * u - 1.0;
+        let phi: f64 = K_2PI * v;
+        let k: f64 = (1.0 - tz * tz).sqrt();
+
+        Vec { x: k * phi.cos(), y: k * phi.sin(), z: tz }
+    }
+
+    // axis周りの、axisとのなす角の余弦がcos_theta_max以上の円錐（球冠）内の方向を一様にサンプリングする。
+    // (方向, 立体角測度のpdf)を返す。pdfは1 / (2π(1 - cos_theta_max))。
+    // cos_theta_max = -1なら全球の一様サンプリングと同じになる。
+    pub fn uniform_cone(random: &mut dyn Sampler, axis: &Vec, cos_theta_max: f64) -> (Vec, f64) {
+        let mut tangent: Vec = Vec { x: 0.0, y: 0.0, z: 0.0 };
+        let mut binormal: Vec = Vec { x: 0.0, y: 0.0, z: 0.0 };
+        create_ortho_normal_basis(axis, &mut tangent, &mut binormal);
+
+        let (u, v) = random.next_2d();
+        let tz: f64 = cos_theta_max + (1.0 - cos_theta_max) * u;
+        let phi: f64 = K_2PI * v;
+        let k: f64 = (1.0 - tz * tz).sqrt();
+        let tx: f64 = k * phi.cos();
+        let ty: f64 = k * phi.sin();
+
+        let pdf: f64 = K_INV_2PI / (1.0 - cos_theta_max);
+        (tz * axis + tx * tangent + ty * binormal, pdf)
+    }
+
+    // 三角形上の一様サンプリングのための重心座標(b0, b1)。b0, b1 >= 0, b0 + b1 <= 1で、三つ目はb2 = 1 - b0 - b1。
+    // 単位正方形を平方根で三角形に写す。頂点v0, v1, v2の三角形では b0 v0 + b1 v1 + b2 v2 が一様に分布する。
+    // Greg Turk. Generating random points in triangles. Graphics Gems, 1990.
+    pub fn uniform_barycentric(random: &mut dyn Sampler) -> (f64, f64) {
+        let (u, v) = random.next_2d();
+        let r1: f64 = u.sqrt();
+        let r2: f64 = v;
+        (1.0 - r1, r1 * (1.0 - r2))
+    }
+
+    // 面積測度のpdfを立体角測度のpdfに変換する。
+    // 距離の二乗distance_squaredだけ離れた、サンプル点の法線とのなす角の余弦がcos_lightの面について
+    // dω = cos_light / distance_squared dA より pdf_ω = pdf_A * distance_squared / cos_light となる。
+    pub fn area_to_solid_angle_pdf(pdf_area: f64, distance_squared: f64, cos_light: f64) -> f64 {
+        if cos_light <= 0.0 {
+            return 0.0
+        }
+        pdf_area * distance_squared / cos_light
+    }
+
+    // GGX分布の見える法線の分布（VNDF）から微小面の法線を選ぶ。
+    // view_localは法線まわりの局所座標（zが法線、xがalpha_xの方向）で上側を向いた、見る方向の単位ベクトル。
+    // 面を引き伸ばして半球にしたときに見える円盤の上で点を選び、元に戻す。
+    // 法線の分布D(h)そのものから選ぶと、浅い角度で見たときに裏を向いた微小面を多く選んでしまうが、
+    // こちらは見えている微小面だけを、見える面積に比例して選ぶ。pdfはggx_vndf_pdf。
+    // Eric Heitz. Sampling the GGX Distribution of Visible Normals. JCGT 2018.
+    pub fn ggx_vndf(random: &mut dyn Sampler, view_local: &Vec, alpha_x: f64, alpha_y: f64) -> Vec {
+        let (u, v) = random.next_2d();
+        Sampling::ggx_vndf_at(view_local, alpha_x, alpha_y, u, v)
+    }
+
+    // ggx_vndfと同じだが、乱数の代わりに単位正方形上の点(u, v)を渡す。数値積分で格子点を渡すのに使う。
+    pub fn ggx_vndf_at(view_local: &Vec, alpha_x: f64, alpha_y: f64, u: f64, v: f64) -> Vec {
+        let stretched: Vec = Vec::normalize(Vec { x: alpha_x * view_local.x, y: alpha_y * view_local.y, z: view_local.z });
+        let length2: f64 = stretched.x * stretched.x + stretched.y * stretched.y;
+        let t1: Vec = if length2 > 0.0 {
+            Vec { x: -stretched.y, y: stretched.x, z: 0.0 } / length2.sqrt()
+        } else {
+            Vec { x: 1.0, y: 0.0, z: 0.0 }
+        };
+        let t2: Vec = Vec::cross(&stretched, &t1);
+
+        // 円盤の上の点。見えない側の半分は、見る方向の傾きに合わせて縮める。
+        let r: f64 = u.sqrt();
+        let phi: f64 = K_2PI * v;
+        let p1: f64 = r * phi.cos();
+        let s: f64 = 0.5 * (1.0 + stretched.z);
+        let p2: f64 = (1.0 - s) * (1.0 - p1 * p1).sqrt() + s * r * phi.sin();
+        let p3: f64 = (1.0 - p1 * p1 - p2 * p2).max(0.0).sqrt();
+        let normal: Vec = Vec::mul_add(&t1, p1, &Vec::mul_add(&t2, p2, &(&stretched * p3)));
+
+        Vec::normalize(Vec { x: alpha_x * normal.x, y: alpha_y * normal.y, z: normal.z.max(0.0) })
+    }
+
+    // ggx_vndfで微小面の法線half_localを選ぶpdf G1(v) max(0, v・h) D(h) / cosθv。
+    // 反射した方向のpdfにするには、反射の変換のヤコビアン 1 / (4 v・h) を掛ける。
+    pub fn ggx_vndf_pdf(view_local: &Vec, half_local: &Vec, alpha_x: f64, alpha_y: f64) -> f64 {
+        let v_dot_h: f64 = Vec::dot(view_local, half_local);
+        if view_local.z <= 0.0 || v_dot_h <= 0.0 {
+            return 0.0
+        }
+        let g1: f64 = 1.0 / (1.0 + ggx_anisotropic_lambda(view_local, alpha_x, alpha_y));
+        g1 * v_dot_h * ggx_anisotropic_d(half_local, alpha_x, alpha_y) / view_local.z
+    }
+}
+
+// 異方性のGGX分布。hは法線まわりの局所座標（zが法線、xが接線）の単位ベクトル。
+// Eric Heitz. Understanding the Masking-Shadowing Function in Microfacet-Based BRDFs. JCGT 2014.
+pub fn ggx_anisotropic_d(h: &Vec, alpha_x: f64, alpha_y: f64) -> f64 {
+    if h.z <= 0.0 {
+        return 0.0
+    }
+    let t: f64 = (h.x / alpha_x).powi(2) + (h.y / alpha_y).powi(2) + h.z * h.z;
+    K_INV_PI / (alpha_x * alpha_y * t * t)
+}
+
+// 異方性のGGXのSmithの遮蔽関数のΛ。G1(w) = 1 / (1 + Λ(w))。wは局所座標の単位ベクトル。
+// 方向ごとの粗さ α² = alpha_x² cos²φ + alpha_y² sin²φ を使って、等方的な場合と同じ式で求める。
+pub fn ggx_anisotropic_lambda(w: &Vec, alpha_x: f64, alpha_y: f64) -> f64 {
+    let cos2: f64 = w.z * w.z;
+    if cos2 <= 0.0 {
+        return K_INF
+    }
+    let alpha2_tan2: f64 = ((alpha_x * w.x).powi(2) + (alpha_y * w.y).powi(2)) / cos2;
+    0.5 * ((1.0 + alpha2_tan2).sqrt() - 1.0)
 }
 
+// 離散的な値の並びfuncに比例する確率で番号を選ぶための分布。
+// 区間[0, 1)をfuncの数で等分し、区間ごとに一定の密度を持つ区分定数の分布として扱う。
+// funcが全て0なら一様に選ぶ。
+#[derive(Debug, Clone)]
+pub struct Distribution1D {
+    func: std::vec::Vec<f64>,
+    cdf: std::vec::Vec<f64>, // cdf[i]はi番目より前の番号を選ぶ確率。cdf[n] = 1。
+    func_sum: f64,
+}
+
+impl Distribution1D {
+    // funcは負でない値の並び。
+    pub fn new(func: std::vec::Vec<f64>) -> Distribution1D {
+        assert!(!func.is_empty());
+        let n: usize = func.len();
+        let mut cdf: std::vec::Vec<f64> = std::vec::Vec::with_capacity(n + 1);
+        cdf.push(0.0);
+        for i in 0..n {
+            let previous: f64 = cdf[i];
+            cdf.push(previous + func[i]);
+        }
+        let func_sum: f64 = cdf[n];
+        for (i, value) in cdf.iter_mut().enumerate().skip(1) {
+            *value = if func_sum > 0.0 { *value / func_sum } else { i as f64 / n as f64 };
+        }
+        cdf[n] = 1.0;
+
+        Distribution1D { func, cdf, func_sum }
+    }
+
+    pub fn len(&self) -> usize {
+        self.func.len()
+    }
+
+    // newは空の並びを受け付けないので、常にfalse。
+    pub fn is_empty(&self) -> bool {
+        self.func.is_empty()
+    }
+
+    // 値の和。
+    pub fn func_sum(&self) -> f64 {
+        self.func_sum
+    }
+
+    // index番目を選ぶ確率。
+    pub fn discrete_pdf(&self, index: usize) -> f64 {
+        self.cdf[index + 1] - self.cdf[index]
+    }
+
+    // [0, 1]の一様な値uから番号を選ぶ。
+    // (番号, その番号を選ぶ確率, 区間の中での位置（[0, 1)に引き伸ばしたu）)を返す。
+    // 区間の中での位置は、次の次元のサンプリングや区間内の連続な位置に使える。
+    pub fn sample(&self, u: f64) -> (usize, f64, f64) {
+        let n: usize = self.func.len();
+        // cdf[index] <= u < cdf[index + 1]となるindexを二分探索で求める。確率0の区間は選ばない。
+        let mut low: usize = 0;
+        let mut high: usize = n;
+        while high - low > 1 {
+            let middle: usize = (low + high) / 2;
+            if self.cdf[middle] <= u { low = middle; } else { high = middle; }
+        }
+        let mut index: usize = low;
+        while index + 1 < n && self.discrete_pdf(index) == 0.0 {
+            index += 1;
+        }
+
+        let pdf: f64 = self.discrete_pdf(index);
+        let offset: f64 = if pdf > 0.0 { ((u - self.cdf[index]) / pdf).max(0.0) } else { 0.0 };
+        (index, pdf, offset.min(1.0 - f64::EPSILON / 2.0))
+    }
+}
+
+// width x heightの表funcの値に比例する密度で、単位正方形[0, 1)^2上の点を選ぶための分布。
+// 行（v）を周辺分布から選び、その行の中で列（u）を条件付き分布から選ぶ。
+// 画像の明るさに応じて光源上の点を選ぶのに使う（EmissiveTextureMaterial）。
+#[derive(Debug, Clone)]
+pub struct Distribution2D {
+    conditional: std::vec::Vec<Distribution1D>, // 行ごとの列の分布
+    marginal: Distribution1D,                   // 行の分布
+}
+
+impl Distribution2D {
+    // funcは左上から行優先で並んだwidth * height個の負でない値。
+    pub fn new(width: usize, height: usize, func: &[f64]) -> Distribution2D {
+        assert_eq!(width * height, func.len());
+        let conditional: std::vec::Vec<Distribution1D> = (0..height)
+            .map(|y| Distribution1D::new(func[y * width..(y + 1) * width].to_vec()))
+            .collect();
+        let marginal: Distribution1D = Distribution1D::new(conditional.iter().map(|row| row.func_sum()).collect());
+
+        Distribution2D { conditional, marginal }
+    }
+
+    // 二次元の一様な値(u0, u1)から点を選ぶ。((u, v), 単位正方形上の密度)を返す。
+    pub fn sample(&self, u0: f64, u1: f64) -> ((f64, f64), f64) {
+        let (y, pdf_y, offset_y) = self.marginal.sample(u1);
+        let row: &Distribution1D = &self.conditional[y];
+        let (x, pdf_x, offset_x) = row.sample(u0);
+        let u: f64 = (x as f64 + offset_x) / row.len() as f64;
+        let v: f64 = (y as f64 + offset_y) / self.marginal.len() as f64;
+
+        ((u, v), pdf_x * pdf_y * (row.len() * self.marginal.len()) as f64)
+    }
+
+    // 点(u, v)の単位正方形上の密度。
+    pub fn pdf(&self, u: f64, v: f64) -> f64 {
+        let height: usize = self.marginal.len();
+        let y: usize = std::cmp::min((v.max(0.0) * height as f64) as usize, height - 1);
+        let row: &Distribution1D = &self.conditional[y];
+        let width: usize = row.len();
+        let x: usize = std::cmp::min((u.max(0.0) * width as f64) as usize, width - 1);
+
+        self.marginal.discrete_pdf(y) * row.discrete_pdf(x) * (width * height) as f64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use random::Random;
+    use vec::Reflect;
+    use constant::K_PI;
+
+    // uniform_coneの方向はどれも円錐の中にあり、pdfを全球で積分すると1になる。
+    #[test]
+    fn uniform_cone_stays_inside_and_pdf_integrates_to_one() {
+        let axis: Vec = Vec::normalize(Vec { x: 1.0, y: -2.0, z: 0.5 });
+        let mut random = Random::new(5);
+        for &cos_theta_max in &[-1.0, 0.0, 0.5, 0.99] {
+            let mut pdf: f64 = 0.0;
+            let mut sum_cos: f64 = 0.0;
+            let n: usize = 20000;
+            for _ in 0..n {
+                let (dir, p) = Sampling::uniform_cone(&mut random, &axis, cos_theta_max);
+                assert!((Vec::dot(&dir, &dir) - 1.0).abs() < 1e-9, "{:?}", dir);
+                assert!(Vec::dot(&dir, &axis) >= cos_theta_max - 1e-9, "{:?} {}", dir, cos_theta_max);
+                pdf = p;
+                sum_cos += Vec::dot(&dir, &axis);
+            }
+            // 方向が一様なら、axisとの余弦の平均は(1 + cos_theta_max) / 2。
+            let mean_cos: f64 = sum_cos / n as f64;
+            assert!((mean_cos - (1.0 + cos_theta_max) / 2.0).abs() < 0.01, "{} {}", cos_theta_max, mean_cos);
+
+            // 全球の一様な方向で、円錐の中だけpdfを足し合わせて積分する。
+            let m: usize = 200000;
+            let mut integral: f64 = 0.0;
+            for _ in 0..m {
+                let dir: Vec = Sampling::uniform_sphere_surface(&mut random);
+                if Vec::dot(&dir, &axis) >= cos_theta_max {
+                    integral += pdf;
+                }
+            }
+            integral *= 4.0 * K_PI / m as f64;
+            // 円錐に入る割合fの二項分布なので、相対的な標準誤差はsqrt((1 - f) / (f m))。その4倍まで許す。
+            let fraction: f64 = (1.0 - cos_theta_max) / 2.0;
+            let tolerance: f64 = 4.0 * ((1.0 - fraction) / (fraction * m as f64)).sqrt() + 1e-9;
+            assert!((integral - 1.0).abs() < tolerance, "{} {}", cos_theta_max, integral);
+        }
+    }
+
+    // uniform_barycentricの重心座標は三角形の中にあり、三角形を一様に覆う。
+    #[test]
+    fn uniform_barycentric_covers_the_simplex_uniformly() {
+        let mut random = Random::new(9);
+        let n: usize = 100000;
+        // 辺の中点で四つに分けた三角形（b_i > 0.5の三つの角と、真ん中）に入った数。
+        let mut corners: [usize; 3] = [0; 3];
+        let mut middle: usize = 0;
+        // 座標ごとに、t未満になった数。一様なら割合は 1 - (1 - t)^2。
+        let thresholds: [f64; 3] = [0.25, 0.5, 0.75];
+        let mut below: [[usize; 3]; 3] = [[0; 3]; 3];
+        for _ in 0..n {
+            let (b0, b1) = Sampling::uniform_barycentric(&mut random);
+            let b: [f64; 3] = [b0, b1, 1.0 - b0 - b1];
+            assert!(b.iter().all(|&c| (0.0..=1.0).contains(&c)), "{:?}", b);
+            match b.iter().position(|&c| c > 0.5) {
+                Some(i) => corners[i] += 1,
+                None => middle += 1,
+            }
+            for (i, &c) in b.iter().enumerate() {
+                for (j, &t) in thresholds.iter().enumerate() {
+                    if c < t {
+                        below[i][j] += 1;
+                    }
+                }
+            }
+        }
+
+        // 二項分布の標準偏差の5倍まで許す。
+        let close = |count: usize, p: f64| -> bool {
+            (count as f64 - p * n as f64).abs() < 5.0 * (p * (1.0 - p) * n as f64).sqrt()
+        };
+        assert!(corners.iter().all(|&count| close(count, 0.25)) && close(middle, 0.25), "{:?} {}", corners, middle);
+        for (i, counts) in below.iter().enumerate() {
+            for (j, &t) in thresholds.iter().enumerate() {
+                let expected: f64 = 1.0 - (1.0 - t) * (1.0 - t);
+                assert!(close(counts[j], expected), "{} {} {}", i, t, counts[j]);
+            }
+        }
+    }
+
+    // 浅い角度から見たGGXの面の方向アルベド（Fresnel反射率を1としたもの）を二通りに推定する。
+    // 法線の分布D(h)cosθhから選ぶと裏を向いた微小面も選んでしまい、その分は寄与が0になる。
+    // 見える法線の分布から選べば見えている微小面だけを選ぶので、期待値は同じで分散はずっと小さい。
+    #[test]
+    fn ggx_vndf_has_lower_variance_than_ndf_sampling_at_grazing_angles() {
+        let alpha: f64 = 0.5;
+        let cos_v: f64 = 0.1;
+        let view: Vec = Vec { x: (1.0 - cos_v * cos_v).sqrt(), y: 0.0, z: cos_v };
+        let g1 = |w: &Vec| 1.0 / (1.0 + ggx_anisotropic_lambda(w, alpha, alpha));
+        let g2 = |o: &Vec| 1.0 / (1.0 + ggx_anisotropic_lambda(&view, alpha, alpha) + ggx_anisotropic_lambda(o, alpha, alpha));
+        let samples: usize = 20000;
+        let mean_and_variance = |values: &[f64]| -> (f64, f64) {
+            let mean: f64 = values.iter().sum::<f64>() / values.len() as f64;
+            (mean, values.iter().map(|value| (value - mean).powi(2)).sum::<f64>() / values.len() as f64)
+        };
+
+        // f cosθo / pdf は、見える法線の分布では G2 / G1(v)、D(h)cosθhの分布では G2 (v・h) / (cosθv cosθh) になる。
+        let mut random = Random::new(3);
+        let mut backfacing: usize = 0;
+        let vndf: std::vec::Vec<f64> = (0..samples).map(|_| {
+            let half: Vec = Sampling::ggx_vndf(&mut random, &view, alpha, alpha);
+            if Vec::dot(&view, &half) <= 0.0 {
+                backfacing += 1;
+            }
+            let out: Vec = Vec::reflect(&-&view, &half);
+            if out.z > 0.0 { g2(&out) / g1(&view) } else { 0.0 }
+        }).collect();
+        assert_eq!(backfacing, 0);
+
+        let mut random = Random::new(4);
+        let mut backfacing: usize = 0;
+        let ndf: std::vec::Vec<f64> = (0..samples).map(|_| {
+            let (u, v) = random.next_2d();
+            let tan2: f64 = alpha * alpha * u / (1.0 - u);
+            let cos_h: f64 = 1.0 / (1.0 + tan2).sqrt();
+            let sin_h: f64 = (1.0 - cos_h * cos_h).sqrt();
+            let phi: f64 = 2.0 * K_PI * v;
+            let half: Vec = Vec { x: sin_h * phi.cos(), y: sin_h * phi.sin(), z: cos_h };
+            let v_dot_h: f64 = Vec::dot(&view, &half);
+            let out: Vec = Vec::reflect(&-&view, &half);
+            if v_dot_h <= 0.0 {
+                backfacing += 1;
+                return 0.0
+            }
+            if out.z > 0.0 { g2(&out) * v_dot_h / (cos_v * cos_h) } else { 0.0 }
+        }).collect();
+        assert!(backfacing > samples / 10, "{}", backfacing);
+
+        let (vndf_mean, vndf_variance) = mean_and_variance(&vndf);
+        let (ndf_mean, ndf_variance) = mean_and_variance(&ndf);
+        let stderr: f64 = ((vndf_variance + ndf_variance) / samples as f64).sqrt();
+        assert!((vndf_mean - ndf_mean).abs() < 4.0 * stderr, "{} {} {}", vndf_mean, ndf_mean, stderr);
+        assert!(vndf_variance * 4.0 < ndf_variance, "{} {}", vndf_variance, ndf_variance);
+    }
+}
@@ -1,12 +1,21 @@
 #![allow(dead_code)]
 
-use vec::Vec;
+use std;
+use std::fmt;
+
+use vec::{Vec, Dot, Cross};
 use sphere::Sphere;
-use material::{Material, LambertianMaterial, PhongMaterial, GlassMaterial, Lightsource};
+use triangle::Triangle;
+use quad::Quad;
+use shape::Shape;
+use camera::Camera;
+use material::{Material, MaterialTrait, MaterialRef, LambertianMaterial, PhongMaterial, GlassMaterial, Lightsource};
 use ray::Ray;
 use hitpoint::Hitpoint;
+use sampler::Sampler;
+use constant::K_INF;
 
-pub type Scene = [SceneSphere; 8];
+pub type Scene = std::vec::Vec<SceneObject>;
 type Color = Vec;
 
 pub enum SceneRendering {
@@ -15,109 +24,229 @@ pub enum SceneRendering {
     SceneGlass,
 }
 
-pub struct SceneSphere {
-    sphere: Sphere,
-    material: Material,
+// 物体はマテリアルをMaterialRefで持つ。MaterialもMaterialRefもそのまま渡せる。
+pub struct SceneObject {
+    shape: Shape,
+    material: MaterialRef,
 }
 
-impl SceneSphere {
-    pub fn new(sphere: Sphere, material: Material) -> SceneSphere {
-        SceneSphere { sphere: sphere, material: material }
+impl SceneObject {
+    pub fn new<M: Into<MaterialRef>>(shape: Shape, material: M) -> SceneObject {
+        SceneObject { shape, material: material.into() }
+    }
+
+    pub fn get_shape(&self) -> &Shape {
+        &self.shape
     }
 
-    pub fn get_sphere(&self) -> &Sphere {
-        &self.sphere
+    pub fn get_material(&self) -> &dyn MaterialTrait {
+        &*self.material
     }
 
-    pub fn get_material(&self) -> &Material {
+    pub fn get_material_ref(&self) -> &MaterialRef {
         &self.material
     }
+
+    // 放射があるものを光源とみなす。
+    pub fn is_light(&self) -> bool {
+        let emission = self.material.emission();
+        emission.x > 0.0 || emission.y > 0.0 || emission.z > 0.0
+    }
+}
+
+// シーンを組み立てるためのビルダー。
+// let (scene, camera) = SceneBuilder::new()
+//     .add_sphere(center, radius, material)
+//     .add_triangle(v0, v1, v2, material)
+//     .camera(camera)
+//     .build()?;
+// のように繋げて書く。カメラを指定しなければCamera::default_cameraを使う。
+pub struct SceneBuilder {
+    objects: Scene,
+    camera: Option<Camera>,
+}
+
+impl Default for SceneBuilder {
+    fn default() -> SceneBuilder {
+        SceneBuilder::new()
+    }
+}
+
+impl SceneBuilder {
+    pub fn new() -> SceneBuilder {
+        SceneBuilder { objects: std::vec::Vec::new(), camera: None }
+    }
+
+    pub fn add_sphere<M: Into<MaterialRef>>(mut self, center: Vec, radius: f64, material: M) -> SceneBuilder {
+        self.objects.push(SceneObject::new(Shape::Sphere(Sphere::new(radius, center)), material));
+        self
+    }
+
+    // 頂点を反時計回りに見た側が表になる。
+    pub fn add_triangle<M: Into<MaterialRef>>(mut self, v0: Vec, v1: Vec, v2: Vec, material: M) -> SceneBuilder {
+        self.objects.push(SceneObject::new(Shape::Triangle(Triangle::new(v0, v1, v2)), material));
+        self
+    }
+
+    // 平らな配列で与えた三角形の集まり（triangle soup）をまとめて加える。メッシュを作るツールの出力をそのまま渡すためのもの。
+    // positionsは頂点の座標を[x0, y0, z0, x1, ...]と並べたもの、indicesは三角形ごとに三つの頂点の番号を並べたもの。
+    // Triangleは面の法線しか持たないので、normals（positionsと同じ並びの頂点法線）は表裏を決めるのにだけ使う。
+    // 頂点法線の平均と面の法線が逆向きの三角形は、頂点の順序を入れ替えて表を頂点法線の側にする。
+    // normalsが無ければadd_triangleと同じく、頂点を反時計回りに見た側が表になる。面積が0の三角形は加えない。
+    // 配列の長さが3の倍数でないとき、normalsの長さがpositionsと違うとき、範囲外の番号があるときはエラーになる。
+    pub fn add_triangle_soup<M: Into<MaterialRef>>(
+        mut self, positions: &[f64], indices: &[u32], normals: Option<&[f64]>, material: M) -> Result<SceneBuilder, String> {
+        if !positions.len().is_multiple_of(3) {
+            return Err(format!("positions has {} values, which is not a multiple of 3", positions.len()))
+        }
+        if !indices.len().is_multiple_of(3) {
+            return Err(format!("indices has {} values, which is not a multiple of 3", indices.len()))
+        }
+        if let Some(normals) = normals {
+            if normals.len() != positions.len() {
+                return Err(format!("normals has {} values, but positions has {}", normals.len(), positions.len()))
+            }
+        }
+        let num_vertices: usize = positions.len() / 3;
+        if let Some(&index) = indices.iter().find(|&&index| index as usize >= num_vertices) {
+            return Err(format!("index {} is out of range for {} vertices", index, num_vertices))
+        }
+
+        let vertex = |values: &[f64], index: u32| -> Vec {
+            let i: usize = index as usize * 3;
+            Vec { x: values[i], y: values[i + 1], z: values[i + 2] }
+        };
+        let material: MaterialRef = material.into();
+        self.objects.reserve(indices.len() / 3);
+        for face in indices.chunks(3) {
+            let v0: Vec = vertex(positions, face[0]);
+            let mut v1: Vec = vertex(positions, face[1]);
+            let mut v2: Vec = vertex(positions, face[2]);
+            let face_normal: Vec = Vec::cross(&(&v1 - &v0), &(&v2 - &v0));
+            if face_normal.length_squared() == 0.0 {
+                continue;
+            }
+            if let Some(normals) = normals {
+                let vertex_normal: Vec = vertex(normals, face[0]) + vertex(normals, face[1]) + vertex(normals, face[2]);
+                if Vec::dot(&face_normal, &vertex_normal) < 0.0 {
+                    std::mem::swap(&mut v1, &mut v2);
+                }
+            }
+            self.objects.push(SceneObject::new(Shape::Triangle(Triangle::new(v0, v1, v2)), material.clone()));
+        }
+        Ok(self)
+    }
+
+    // origin + u * edge_u + v * edge_v (u, v ∈ [0, 1])の平行四辺形。edge_u × edge_vの向きが表になる。
+    pub fn add_quad<M: Into<MaterialRef>>(mut self, origin: Vec, edge_u: Vec, edge_v: Vec, material: M) -> SceneBuilder {
+        self.objects.push(SceneObject::new(Shape::Quad(Quad::new(origin, edge_u, edge_v)), material));
+        self
+    }
+
+    pub fn camera(mut self, camera: Camera) -> SceneBuilder {
+        self.camera = Some(camera);
+        self
+    }
+
+    // シーンとカメラを返す。
+    // 光源が一つも無いと何も写らないので、その場合はエラーにする。
+    pub fn build(self) -> Result<(Scene, Camera), String> {
+        if !self.objects.iter().any(|object| object.is_light()) {
+            return Err("scene has no light source".to_string())
+        }
+
+        let camera = match self.camera {
+            Some(camera) => camera,
+            None => Camera::default_camera()
+        };
+        Ok((self.objects, camera))
+    }
 }
 
 pub fn generate_scene(mode: SceneRendering) -> Scene {
     match mode {
         SceneRendering::SceneDiffuseOnly =>
-            [
-                SceneSphere::new (
-                    Sphere::new (
+            vec![
+                SceneObject::new (
+                    Shape::Sphere (Sphere::new (
                         100000.0,
                         Vec { x: 0.0, y: -100000.0, z: 0.0 }
-                    ),
+                    )),
                     Material::LambertianMaterial (
                         LambertianMaterial::new (Color { x: 0.7, y: 0.7, z: 0.7 })
                     )
                 ),
-                SceneSphere::new (
-                    Sphere::new (
+                SceneObject::new (
+                    Shape::Sphere (Sphere::new (
                         100000.0,
                         Vec { x: 0.0, y:  100004.0, z: 0.0 }
-                    ),
+                    )),
                     Material::LambertianMaterial (
                         LambertianMaterial::new (Color { x: 0.7, y: 0.7, z: 0.7 })
                     )
                 ),
-                SceneSphere::new (
-                    Sphere::new (
+                SceneObject::new (
+                    Shape::Sphere (Sphere::new (
                         100000.0,
                         Vec { x: -100003.0, y: 0.0, z: 0.0 }
-                    ),
+                    )),
                     Material::LambertianMaterial (
                         LambertianMaterial::new (Color { x: 0.7, y: 0.1, z: 0.1 })
                     )
                 ),
-                SceneSphere::new (
-                    Sphere::new (
+                SceneObject::new (
+                    Shape::Sphere (Sphere::new (
                         100000.0,
                         Vec { x: 10009.0, y: 0.0, z: 0.0 }
-                    ),
+                    )),
                     Material::LambertianMaterial (
                         LambertianMaterial::new (Color { x: 0.7, y: 0.7, z: 0.7 })
                     )
                 ),
-                SceneSphere::new (
-                    Sphere::new (
+                SceneObject::new (
+                    Shape::Sphere (Sphere::new (
                         100000.0,
                         Vec { x: 0.0, y: 0.0, z: -100003.0 }
-                    ),
+                    )),
                     Material::LambertianMaterial (
                         LambertianMaterial::new (Color { x: 0.7, y: 0.7, z: 0.1 })
                     )
                 ),
-                SceneSphere::new (
-                    Sphere::new (
+                SceneObject::new (
+                    Shape::Sphere (Sphere::new (
                         100.0,
                         Vec { x: 0.0, y: 103.99, z: 0.0 }
-                    ),
+                    )),
                     Material::Lightsource (
                         Lightsource::new (Color { x: 8.0, y: 8.0, z: 8.0 })
                     )
                 ),
-                SceneSphere::new (
-                    Sphere::new (
+                SceneObject::new (
+                    Shape::Sphere (Sphere::new (
                         1.0,
                         Vec { x: -2.0, y: 1.0, z: 0.0 }
-                    ),
+                    )),
                     Material::LambertianMaterial (
                         LambertianMaterial::new (Color { x: 0.7, y: 0.7, z: 0.7 })
                     )
                 ),
-                SceneSphere::new (
-                    Sphere::new (
+                SceneObject::new (
+                    Shape::Sphere (Sphere::new (
                         1.0,
                         Vec { x: 2.0, y: 1.0, z: 0.0 }
-                    ),
+                    )),
                     Material::LambertianMaterial (
                         LambertianMaterial::new (Color { x: 0.1, y: 0.1, z: 0.7 })
                     )
                 ),
             ],
         SceneRendering::SceneSpecular =>
-            [
-                SceneSphere::new (
-                    Sphere::new (
+            vec![
+                SceneObject::new (
+                    Shape::Sphere (Sphere::new (
                         100000.0,
                         Vec { x: 0.0, y: -100000.0, z: 0.0 }
-                    ),
+                    )),
                     Material::PhongMaterial (
                         PhongMaterial::new (
                             Color { x: 0.999, y: 0.999, z: 0.999 },
@@ -125,140 +254,140 @@ pub fn generate_scene(mode: SceneRendering) -> Scene {
                         )
                     )
                 ),
-                SceneSphere::new (
-                    Sphere::new (
+                SceneObject::new (
+                    Shape::Sphere (Sphere::new (
                         100000.0,
                         Vec { x: 0.0, y:  100004.0, z: 0.0 }
-                    ),
+                    )),
                     Material::LambertianMaterial (
                         LambertianMaterial::new (Color { x: 0.7, y: 0.7, z: 0.7 })
                     )
                 ),
-                SceneSphere::new (
-                    Sphere::new (
+                SceneObject::new (
+                    Shape::Sphere (Sphere::new (
                         100000.0,
                         Vec { x: -100003.0, y: 0.0, z: 0.0 }
-                    ),
+                    )),
                     Material::LambertianMaterial (
                         LambertianMaterial::new (Color { x: 0.7, y: 0.1, z: 0.1 })
                     )
                 ),
-                SceneSphere::new (
-                    Sphere::new (
+                SceneObject::new (
+                    Shape::Sphere (Sphere::new (
                         100000.0,
                         Vec { x: 10009.0, y: 0.0, z: 0.0 }
-                    ),
+                    )),
                     Material::LambertianMaterial (
                         LambertianMaterial::new (Color { x: 0.7, y: 0.7, z: 0.7 })
                     )
                 ),
-                SceneSphere::new (
-                    Sphere::new (
+                SceneObject::new (
+                    Shape::Sphere (Sphere::new (
                         100000.0,
                         Vec { x: 0.0, y: 0.0, z: -100003.0 }
-                    ),
+                    )),
                     Material::LambertianMaterial (
                         LambertianMaterial::new (Color { x: 0.1, y: 0.7, z: 0.1 })
                     )
                 ),
-                SceneSphere::new (
-                    Sphere::new (
+                SceneObject::new (
+                    Shape::Sphere (Sphere::new (
                         100.0,
                         Vec { x: 0.0, y: 103.99, z: 0.0 }
-                    ),
+                    )),
                     Material::Lightsource (
                         Lightsource::new (Color { x: 8.0, y: 8.0, z: 8.0 })
                     )
                 ),
-                SceneSphere::new (
-                    Sphere::new (
+                SceneObject::new (
+                    Shape::Sphere (Sphere::new (
                         1.0,
                         Vec { x: -2.0, y: 1.0, z: 0.0 }
-                    ),
+                    )),
                     Material::LambertianMaterial (
                         LambertianMaterial::new (Color { x: 0.7, y: 0.7, z: 0.7 })
                     )
                 ),
-                SceneSphere::new (
-                    Sphere::new (
+                SceneObject::new (
+                    Shape::Sphere (Sphere::new (
                         1.0,
                         Vec { x: 2.0, y: 1.0, z: 0.0 }
-                    ),
+                    )),
                     Material::LambertianMaterial (
                         LambertianMaterial::new (Color { x: 0.1, y: 0.1, z: 0.7 })
                     )
                 ),
             ],
         SceneRendering::SceneGlass =>
-            [
-                SceneSphere::new (
-                    Sphere::new (
+            vec![
+                SceneObject::new (
+                    Shape::Sphere (Sphere::new (
                         100000.0,
                         Vec { x: 0.0, y: -100000.0, z: 0.0 }
-                    ),
+                    )),
                     Material::LambertianMaterial (
                         LambertianMaterial::new (Color { x: 0.7, y: 0.7, z: 0.7 })
                     )
                 ),
-                SceneSphere::new (
-                    Sphere::new (
+                SceneObject::new (
+                    Shape::Sphere (Sphere::new (
                         100000.0,
                         Vec { x: 0.0, y:  100004.0, z: 0.0 }
-                    ),
+                    )),
                     Material::LambertianMaterial (
                         LambertianMaterial::new (Color { x: 0.7, y: 0.7, z: 0.7 })
                     )
                 ),
-                SceneSphere::new (
-                    Sphere::new (
+                SceneObject::new (
+                    Shape::Sphere (Sphere::new (
                         100000.0,
                         Vec { x: -100003.0, y: 0.0, z: 0.0 }
-                    ),
+                    )),
                     Material::LambertianMaterial (
                         LambertianMaterial::new (Color { x: 0.7, y: 0.1, z: 0.1 })
                     )
                 ),
-                SceneSphere::new (
-                    Sphere::new (
+                SceneObject::new (
+                    Shape::Sphere (Sphere::new (
                         100000.0,
                         Vec { x: 10009.0, y: 0.0, z: 0.0 }
-                    ),
+                    )),
                     Material::LambertianMaterial (
                         LambertianMaterial::new (Color { x: 0.7, y: 0.7, z: 0.7 })
                     )
                 ),
-                SceneSphere::new (
-                    Sphere::new (
+                SceneObject::new (
+                    Shape::Sphere (Sphere::new (
                         100000.0,
                         Vec { x: 0.0, y: 0.0, z: -100003.0 }
-                    ),
+                    )),
                     Material::LambertianMaterial (
                         LambertianMaterial::new (Color { x: 0.1, y: 0.7, z: 0.1 })
                     )
                 ),
-                SceneSphere::new (
-                    Sphere::new (
+                SceneObject::new (
+                    Shape::Sphere (Sphere::new (
                         100.0,
                         Vec { x: 0.0, y: 103.99, z: 0.0 }
-                    ),
+                    )),
                     Material::Lightsource (
                         Lightsource::new (Color { x: 8.0, y: 8.0, z: 8.0 })
                     )
                 ),
-                SceneSphere::new (
-                    Sphere::new (
+                SceneObject::new (
+                    Shape::Sphere (Sphere::new (
                         1.0,
                         Vec { x: -2.0, y: 1.0, z: 0.0 }
-                    ),
+                    )),
                     Material::LambertianMaterial (
                         LambertianMaterial::new (Color { x: 0.7, y: 0.7, z: 0.7 })
                     )
                 ),
-                SceneSphere::new (
-                    Sphere::new (
+                SceneObject::new (
+                    Shape::Sphere (Sphere::new (
                         1.0,
                         Vec { x: 2.0, y: 1.0, z: 0.0 }
-                    ),
+                    )),
                     Material::GlassMaterial (
                         GlassMaterial::new (
                             Color { x: 0.999999, y: 0.999999, z: 0.999999 },
@@ -271,21 +400,497 @@ pub fn generate_scene(mode: SceneRendering) -> Scene {
 }
 
 // シーンとの交差判定関数。
-pub fn intersect_scene<'a>(scene: &'a Scene, ray: &'a Ray) -> (Option<&'a SceneSphere>, Hitpoint) {
+// 最も近い交差点については、テクスチャ座標と接線（Shape::surface_details）も求める。
+pub fn intersect_scene<'a>(scene: &'a Scene, ray: &Ray) -> (Option<&'a SceneObject>, Hitpoint) {
+    // 抜け（CutoutMaterial）に当たったら、そこより遠い交差を探しなおす。
+    // t_minは探しなおすたびに増えるので、いずれ抜けでない交差か、交差無しで終わる。
+    let mut t_min: f64 = 0.0;
+    loop {
+        let (now_object, hitpoint) = closest_hit(scene, ray, t_min);
+        match now_object {
+            Some(object) if object.get_material().is_cutout(hitpoint.uv) => t_min = hitpoint.distance,
+            _ => return (now_object, hitpoint),
+        }
+    }
+}
+
+// 距離がt_minより遠い交差のうち、最も近いもの。抜けかどうかは考えない。
+fn closest_hit<'a>(scene: &'a Scene, ray: &Ray, t_min: f64) -> (Option<&'a SceneObject>, Hitpoint) {
     // 初期化
     let mut hitpoint = Hitpoint::new();
-    let mut now_object: Option<&SceneSphere> = None;
+    let mut now_object: Option<&SceneObject> = None;
 
     // 線形探索
     for object in scene.iter() {
-        if let Some(hit) = object.get_sphere().intersect(&ray) {
-            if &hit.distance < &hitpoint.distance {
+        if let Some(hit) = object.get_shape().intersect_with_t_min(ray, t_min) {
+            if hit.distance < hitpoint.distance {
                 hitpoint = hit;
                 now_object = Some(object);
             }
         }
     }
 
+    if let Some(object) = now_object {
+        object.get_shape().surface_details(&mut hitpoint);
+    }
+
     (now_object, hitpoint)
 }
 
+// シャドウレイ用の交差判定関数。
+// 距離max_t未満で何かに当たるかどうかだけを返す。最も近い交差点を探す必要が無いので、見つかった時点で打ち切る。
+// メッシュ（Instance、InstanceGroup）の中でも、BVHをたどって最初に見つけた交差で打ち切る（Shape::intersect_any）。
+// 抜けのあるマテリアルの物体では、抜けでない交差が見つかるまで交差を手前から順にたどる。
+pub fn intersect_scene_any(scene: &Scene, ray: &Ray, max_t: f64) -> bool {
+    scene.iter().any(|object| {
+        if object.get_material().has_cutout() {
+            intersect_opaque(object, ray, max_t)
+        } else {
+            object.get_shape().intersect_any(ray, max_t)
+        }
+    })
+}
+
+// 物体の抜けでない部分に、距離max_t未満で当たるか。
+fn intersect_opaque(object: &SceneObject, ray: &Ray, max_t: f64) -> bool {
+    let shape: &Shape = object.get_shape();
+    let mut t_min: f64 = 0.0;
+    while let Some(mut hitpoint) = shape.intersect_with_t_min(ray, t_min) {
+        if hitpoint.distance >= max_t {
+            return false
+        }
+        shape.surface_details(&mut hitpoint);
+        if !object.get_material().is_cutout(hitpoint.uv) {
+            return true
+        }
+        t_min = hitpoint.distance;
+    }
+    false
+}
+
+// シーン全体を囲む、軸に平行な境界ボックスの(最小の角, 最大の角)。
+// 無限に広がる形状は無いので、全ての形状を含める（壁に使っている巨大な球もそのまま含まれる）。
+// 物体が一つも無いときは最小の角が+∞、最大の角が-∞の空の箱になる。
+pub fn scene_bounds(scene: &Scene) -> (Vec, Vec) {
+    let mut min = Vec { x: K_INF, y: K_INF, z: K_INF };
+    let mut max = Vec { x: -K_INF, y: -K_INF, z: -K_INF };
+    for object in scene.iter() {
+        let (lower, upper) = object.get_shape().bounds();
+        min = Vec { x: min.x.min(lower.x), y: min.y.min(lower.y), z: min.z.min(lower.z) };
+        max = Vec { x: max.x.max(upper.x), y: max.y.max(upper.y), z: max.z.max(upper.z) };
+    }
+    (min, max)
+}
+
+// validate_sceneが見つけた、レンダリングの前に直したほうがよい問題。
+// objectはシーン中の物体のインデックス。
+#[derive(Debug, Clone)]
+pub enum SceneWarning {
+    DegenerateTriangle { object: usize, triangle: usize }, // 面積が0の三角形。triangleはメッシュの中の番号（単体の三角形なら0）。
+    NonUnitNormal { object: usize, normal: Vec },         // 法線が単位ベクトルでない（三角形か四角形）。
+    ReflectanceAboveOne { object: usize, reflectance: Vec }, // 反射率が1を超えるチャンネルがあり、エネルギーが保存されない。
+    NoLights,                                             // 光源が無い。背景は黒なので、真っ黒な画像にしかならない。
+}
+
+impl fmt::Display for SceneWarning {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            SceneWarning::DegenerateTriangle { object, triangle } =>
+                write!(f, "object {}: triangle {} has zero area", object, triangle),
+            SceneWarning::NonUnitNormal { object, ref normal } =>
+                write!(f, "object {}: normal ({}, {}, {}) is not a unit vector", object, normal.x, normal.y, normal.z),
+            SceneWarning::ReflectanceAboveOne { object, ref reflectance } =>
+                write!(f, "object {}: reflectance ({}, {}, {}) exceeds 1", object, reflectance.x, reflectance.y, reflectance.z),
+            SceneWarning::NoLights => write!(f, "scene has no light source"),
+        }
+    }
+}
+
+// レンダリングの前に、よくある間違いを調べる。問題が無ければOk(())、あればその一覧を返す。
+// 調べるのは、面積が0の三角形（メッシュの中のものを含む）、単位ベクトルでない法線、
+// 反射率が1を超えるマテリアル、光源が無いこと。環境光は無いので、光源はシーン中の物体だけ。
+// 面積が0の三角形は法線も求まらない（NaN）ので、NonUnitNormalとしては重ねて報告しない。
+pub fn validate_scene(scene: &Scene) -> Result<(), std::vec::Vec<SceneWarning>> {
+    let mut warnings: std::vec::Vec<SceneWarning> = std::vec::Vec::new();
+    for (index, object) in scene.iter().enumerate() {
+        match *object.get_shape() {
+            Shape::Triangle ( ref triangle ) => check_triangle(index, 0, triangle, &mut warnings),
+            Shape::Quad ( ref quad ) => {
+                if !quad.normal().is_unit() {
+                    warnings.push(SceneWarning::NonUnitNormal { object: index, normal: quad.normal().clone() });
+                }
+            },
+            Shape::Instance ( ref instance ) => {
+                for (number, triangle) in instance.mesh().triangles().iter().enumerate() {
+                    check_triangle(index, number, triangle, &mut warnings);
+                }
+            },
+            Shape::InstanceGroup ( ref group ) => {
+                // グループ内のメッシュの三角形に、グループ全体での通し番号を付ける。
+                let mut number: usize = 0;
+                for instance in group.instances() {
+                    for triangle in instance.mesh().triangles() {
+                        check_triangle(index, number, triangle, &mut warnings);
+                        number += 1;
+                    }
+                }
+            },
+            Shape::Sphere ( _ ) => (),
+        }
+
+        let reflectance: &Vec = object.get_material().reflectance();
+        if reflectance.max_component() > 1.0 {
+            warnings.push(SceneWarning::ReflectanceAboveOne { object: index, reflectance: reflectance.clone() });
+        }
+    }
+
+    if !scene.iter().any(|object| object.is_light()) {
+        warnings.push(SceneWarning::NoLights);
+    }
+
+    if warnings.is_empty() { Ok(()) } else { Err(warnings) }
+}
+
+fn check_triangle(object: usize, number: usize, triangle: &Triangle, warnings: &mut std::vec::Vec<SceneWarning>) {
+    let area: f64 = triangle.area();
+    if area.is_nan() || area <= 0.0 {
+        warnings.push(SceneWarning::DegenerateTriangle { object, triangle: number });
+    } else if !triangle.normal().is_unit() {
+        warnings.push(SceneWarning::NonUnitNormal { object, normal: triangle.normal().clone() });
+    }
+}
+
+// objectがscene中の何番目の物体か。sceneの物体でなければNone。
+// intersect_sceneの返した物体から、LightTreeなどで使う番号を求めるのに使う。
+pub fn object_index(scene: &Scene, object: &SceneObject) -> Option<usize> {
+    let size: usize = std::mem::size_of::<SceneObject>();
+    let start: usize = scene.as_ptr() as usize;
+    let address: usize = object as *const SceneObject as usize;
+    if size == 0 || address < start || !(address - start).is_multiple_of(size) || (address - start) / size >= scene.len() {
+        return None
+    }
+    Some((address - start) / size)
+}
+
+// シーン中の光源から一つを一様に選ぶ。
+// 選んだ光源とその選択確率を返す。光源が無ければNone。
+pub fn sample_light<'a>(scene: &'a Scene, random: &mut dyn Sampler) -> Option<(&'a SceneObject, f64)> {
+    let num_lights = scene.iter().filter(|object| object.is_light()).count();
+    if num_lights == 0 {
+        return None
+    }
+
+    let index = std::cmp::min((random.next_1d() * num_lights as f64) as usize, num_lights - 1);
+    scene.iter()
+        .filter(|object| object.is_light())
+        .nth(index)
+        .map(|light| (light, 1.0 / num_lights as f64))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use instance::{Instance, InstanceGroup};
+    use mesh::{Mesh, tessellate_sphere};
+    use transform::Transform;
+    use random::Random;
+    use sampling::Sampling;
+    use material::CutoutMaterial;
+    use texture::ImageTexture;
+    use constant::{K_PI, K_INV_2PI};
+
+    fn gray() -> Material {
+        Material::LambertianMaterial(LambertianMaterial::new(Vec { x: 0.5, y: 0.5, z: 0.5 }))
+    }
+
+    // 球、三角形、四角形、メッシュのインスタンスとその集まりを含むシーン。
+    fn mixed_scene() -> Scene {
+        let mesh = Arc::new(Mesh::new(tessellate_sphere(Vec { x: 0.0, y: 0.0, z: 0.0 }, 0.5, 2)));
+        let instance = Instance::new(mesh.clone(), Transform::scaling(&Vec { x: 1.5, y: 0.5, z: 1.0 })
+            .then(&Transform::translation(&Vec { x: 1.0, y: -1.0, z: 0.5 })));
+        let group = InstanceGroup::new((0..8).map(|i| {
+            let offset: Vec = Vec { x: -2.0 + 0.6 * i as f64, y: 1.5, z: -1.0 + 0.3 * i as f64 };
+            Instance::new(mesh.clone(), Transform::scaling(&Vec { x: 0.4, y: 0.4, z: 0.4 }).then(&Transform::translation(&offset)))
+        }).collect());
+
+        let mut scene: Scene = SceneBuilder::new()
+            .add_sphere(Vec { x: -1.0, y: 0.0, z: 0.0 }, 0.7, gray())
+            .add_triangle(Vec { x: 0.0, y: 0.0, z: -2.0 }, Vec { x: 2.0, y: 0.0, z: -2.0 }, Vec { x: 0.0, y: 2.0, z: -2.0 }, gray())
+            .add_quad(Vec { x: -3.0, y: -2.0, z: -3.0 }, Vec { x: 0.0, y: 0.0, z: 6.0 }, Vec { x: 6.0, y: 0.0, z: 0.0 }, gray())
+            .add_sphere(Vec { x: 0.0, y: 5.0, z: 0.0 }, 0.5, Material::Lightsource(Lightsource::new(Vec { x: 1.0, y: 1.0, z: 1.0 })))
+            .build().unwrap().0;
+        scene.push(SceneObject::new(Shape::Instance(instance), gray()));
+        scene.push(SceneObject::new(Shape::InstanceGroup(group), gray()));
+        scene
+    }
+
+    // intersect_scene_anyは、intersect_sceneの最も近い交差がmax_tより手前にあるときだけtrueになる。
+    #[test]
+    fn intersect_any_agrees_with_closest_hit() {
+        let scene = mixed_scene();
+        let mut random = Random::new(17);
+        let mut hits: usize = 0;
+        for _ in 0..20000 {
+            let org: Vec = Vec { x: random.next_1d() * 6.0 - 3.0, y: random.next_1d() * 6.0 - 3.0, z: random.next_1d() * 6.0 - 3.0 };
+            let dir: Vec = Sampling::uniform_sphere_surface(&mut random);
+            let max_t: f64 = random.next_1d() * 6.0;
+            let ray = Ray::new(&org, &dir);
+
+            let closest: bool = match intersect_scene(&scene, &ray) {
+                (Some(_), hitpoint) => hitpoint.distance < max_t,
+                (None, _) => false
+            };
+            assert_eq!(intersect_scene_any(&scene, &ray, max_t), closest, "{:?} {:?} {}", org, dir, max_t);
+            if closest {
+                hits += 1;
+            }
+        }
+        // どちらの場合も十分な数だけ確かめている。
+        assert!(hits > 2000 && hits < 18000, "{}", hits);
+    }
+
+    // 抜けに当たったレイは、その向こうの物体に当たる。intersect_scene_anyも抜けを素通りする。
+    #[test]
+    fn rays_pass_through_cutouts() {
+        let white: Vec = Vec { x: 1.0, y: 1.0, z: 1.0 };
+        let alpha = ImageTexture::with_alpha(2, 1, vec![white.clone(), white.clone()], vec![1.0, 0.0]);
+        let screen = Material::CutoutMaterial(CutoutMaterial::new(gray(), Arc::new(alpha), 0.5));
+        let scene: Scene = SceneBuilder::new()
+            .add_quad(Vec { x: 0.0, y: -0.5, z: 0.0 }, Vec { x: 1.0, y: 0.0, z: 0.0 }, Vec { x: 0.0, y: 1.0, z: 0.0 }, screen)
+            .add_sphere(Vec { x: 0.5, y: 0.0, z: -3.0 }, 2.0, Material::Lightsource(Lightsource::new(white)))
+            .build().unwrap().0;
+        let dir: Vec = Vec { x: 0.0, y: 0.0, z: -1.0 };
+        let mut random = Random::new(5);
+        let mut passed: usize = 0;
+        for _ in 0..1000 {
+            let origin: Vec = Vec { x: random.next_1d(), y: 0.0, z: 1.0 };
+            let ray = Ray::new(&origin, &dir);
+            let (object, hitpoint) = intersect_scene(&scene, &ray);
+            let behind: bool = object.unwrap().get_material().emission().x > 0.0;
+            // 板はu < 0.5の側だけが不透明。
+            assert_eq!(behind, origin.x > 0.5, "{:?}", origin);
+            assert_eq!(intersect_scene_any(&scene, &ray, 1.5), !behind, "{:?}", origin);
+            if behind {
+                assert!(hitpoint.distance > 1.5);
+                passed += 1;
+            }
+        }
+        assert!(passed > 450 && passed < 550, "{}", passed);
+    }
+
+    fn index_of(scene: &Scene, object: Option<&SceneObject>) -> Option<usize> {
+        object.and_then(|object| scene.iter().position(|o| std::ptr::eq(o, object)))
+    }
+
+    // ビルダーで組み立てたシーンは、同じ物体を手で並べたシーンと同じものになる。
+    #[test]
+    fn builder_matches_manual_construction() {
+        let light = || Material::Lightsource(Lightsource::new(Vec { x: 4.0, y: 4.0, z: 4.0 }));
+        let (built, camera): (Scene, Camera) = SceneBuilder::new()
+            .add_sphere(Vec { x: -1.0, y: 0.0, z: 0.0 }, 0.7, gray())
+            .add_triangle(Vec { x: 0.0, y: 0.0, z: -2.0 }, Vec { x: 2.0, y: 0.0, z: -2.0 }, Vec { x: 0.0, y: 2.0, z: -2.0 }, gray())
+            .add_quad(Vec { x: -3.0, y: -2.0, z: -3.0 }, Vec { x: 0.0, y: 0.0, z: 6.0 }, Vec { x: 6.0, y: 0.0, z: 0.0 }, gray())
+            .add_sphere(Vec { x: 0.0, y: 5.0, z: 0.0 }, 0.5, light())
+            .build().unwrap();
+        let manual: Scene = vec![
+            SceneObject::new(Shape::Sphere(Sphere::new(0.7, Vec { x: -1.0, y: 0.0, z: 0.0 })), gray()),
+            SceneObject::new(Shape::Triangle(Triangle::new(
+                Vec { x: 0.0, y: 0.0, z: -2.0 }, Vec { x: 2.0, y: 0.0, z: -2.0 }, Vec { x: 0.0, y: 2.0, z: -2.0 })), gray()),
+            SceneObject::new(Shape::Quad(Quad::new(
+                Vec { x: -3.0, y: -2.0, z: -3.0 }, Vec { x: 0.0, y: 0.0, z: 6.0 }, Vec { x: 6.0, y: 0.0, z: 0.0 })), gray()),
+            SceneObject::new(Shape::Sphere(Sphere::new(0.5, Vec { x: 0.0, y: 5.0, z: 0.0 })), light()),
+        ];
+
+        assert_eq!(built.len(), manual.len());
+        for (b, m) in built.iter().zip(manual.iter()) {
+            assert_eq!(b.is_light(), m.is_light());
+        }
+
+        // どの向きのレイでも、同じ番号の物体に同じ距離で当たる。
+        let mut random = Random::new(23);
+        let mut hits: usize = 0;
+        for _ in 0..5000 {
+            let org: Vec = Vec { x: random.next_1d() * 6.0 - 3.0, y: random.next_1d() * 6.0 - 3.0, z: random.next_1d() * 6.0 - 3.0 };
+            let dir: Vec = Sampling::uniform_sphere_surface(&mut random);
+            let ray = Ray::new(&org, &dir);
+            let (built_object, built_hit) = intersect_scene(&built, &ray);
+            let (manual_object, manual_hit) = intersect_scene(&manual, &ray);
+            assert_eq!(index_of(&built, built_object), index_of(&manual, manual_object), "{:?} {:?}", org, dir);
+            if built_object.is_some() {
+                assert_eq!(built_hit.distance, manual_hit.distance);
+                hits += 1;
+            }
+        }
+        assert!(hits > 1000, "{}", hits);
+
+        // カメラを指定しなければCamera::default_cameraになる。
+        let default_camera: Camera = Camera::default_camera();
+        assert_eq!(format!("{:?} {:?}", camera.position(), camera.dir()),
+                   format!("{:?} {:?}", default_camera.position(), default_camera.dir()));
+
+        // 光源の無いシーンは組み立てられない。
+        assert!(SceneBuilder::new().add_sphere(Vec { x: 0.0, y: 0.0, z: 0.0 }, 1.0, gray()).build().is_err());
+    }
+
+    // Materialの外で実装した、どの方向にも一定の値を返す拡散面。
+    struct ConstantMaterial {
+        emission: Vec,
+        reflectance: Vec,
+    }
+
+    impl MaterialTrait for ConstantMaterial {
+        fn emission(&self) -> &Vec {
+            &self.emission
+        }
+
+        fn reflectance(&self) -> &Vec {
+            &self.reflectance
+        }
+
+        fn eval(&self, _input: &Vec, _normal: &Vec, _output: &Vec) -> Vec {
+            &self.reflectance / K_PI
+        }
+
+        fn sample(&self, random: &mut dyn Sampler, input: &Vec, normal: &Vec, pdf: &mut f64, brdf_value: &mut Vec) -> Vec {
+            let dir: Vec = Sampling::uniform_sphere_surface(random);
+            let dir: Vec = if Vec::dot(&dir, normal) < 0.0 { -dir } else { dir };
+            *pdf = self.eval_pdf(input, normal, &dir);
+            *brdf_value = self.eval(input, normal, &dir);
+            dir
+        }
+
+        fn eval_pdf(&self, _input: &Vec, _normal: &Vec, _output: &Vec) -> f64 {
+            K_INV_2PI
+        }
+    }
+
+    // Box<dyn MaterialTrait>の表に集めたマテリアルを、複数の物体が複製せずに指せる。
+    #[test]
+    fn objects_share_materials_from_a_boxed_registry() {
+        let registry: std::vec::Vec<Box<dyn MaterialTrait + Send + Sync>> = vec![
+            Box::new(ConstantMaterial { emission: Vec { x: 0.0, y: 0.0, z: 0.0 }, reflectance: Vec { x: 0.2, y: 0.4, z: 0.6 } }),
+            Box::new(gray()),
+            Box::new(Material::Lightsource(Lightsource::new(Vec { x: 4.0, y: 4.0, z: 4.0 }))),
+        ];
+        let materials: std::vec::Vec<MaterialRef> = registry.into_iter().map(MaterialRef::from).collect();
+
+        // 物体ごとに表の何番のマテリアルを使うか。
+        let assignment: [usize; 5] = [0, 1, 0, 2, 0];
+        let scene: Scene = assignment.iter().enumerate().fold(SceneBuilder::new(), |builder, (i, &m)| {
+            builder.add_sphere(Vec { x: 3.0 * i as f64, y: 0.0, z: 0.0 }, 1.0, materials[m].clone())
+        }).build().unwrap().0;
+
+        for (object, &m) in scene.iter().zip(assignment.iter()) {
+            assert!(MaterialRef::ptr_eq(object.get_material_ref(), &materials[m]));
+        }
+
+        // レイが当たった物体のマテリアルは、表の中の対応するもの。
+        let dir: Vec = Vec { x: 0.0, y: 0.0, z: -1.0 };
+        for (i, &m) in assignment.iter().enumerate() {
+            let origin: Vec = Vec { x: 3.0 * i as f64, y: 0.0, z: 5.0 };
+            let ray = Ray::new(&origin, &dir);
+            let (object, hitpoint) = intersect_scene(&scene, &ray);
+            assert!(MaterialRef::ptr_eq(object.unwrap().get_material_ref(), &materials[m]), "{}", i);
+            assert!((hitpoint.distance - 4.0).abs() < 1e-9);
+            assert_eq!(object.unwrap().is_light(), m == 2);
+        }
+        assert_eq!(scene[2].get_material().reflectance().y, 0.4);
+    }
+
+    // 二つの球のシーンの境界ボックスは、二つの球をちょうど囲む箱になる。
+    #[test]
+    fn two_spheres_have_tight_bounds() {
+        let scene: Scene = vec![
+            SceneObject::new(Shape::Sphere(Sphere::new(1.0, Vec { x: -2.0, y: 0.5, z: 0.0 })), gray()),
+            SceneObject::new(Shape::Sphere(Sphere::new(0.5, Vec { x: 3.0, y: -1.0, z: 2.0 })), gray()),
+        ];
+        let (lower, upper) = scene_bounds(&scene);
+        let expected_lower: Vec = Vec { x: -3.0, y: -1.5, z: -1.0 };
+        let expected_upper: Vec = Vec { x: 3.5, y: 1.5, z: 2.5 };
+        assert!((&lower - &expected_lower).length() < 1e-12, "{:?}", lower);
+        assert!((&upper - &expected_upper).length() < 1e-12, "{:?}", upper);
+
+        // 物体が無ければ空の箱。
+        let (lower, upper) = scene_bounds(&std::vec::Vec::new());
+        assert!(lower.x > upper.x && lower.y > upper.y && lower.z > upper.z);
+    }
+
+    // 平らな配列で与えた二つの三角形は一枚の四角形になり、四角形の中に向けたレイはz = 0の面に当たる。
+    // 頂点法線が-zを向いていれば、三角形も裏返して-zの側を表にする。
+    #[test]
+    fn triangle_soup_builds_a_hittable_quad() {
+        let positions: [f64; 12] = [0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 1.0, 1.0, 0.0, 0.0, 1.0, 0.0];
+        let indices: [u32; 6] = [0, 1, 2, 0, 2, 3];
+        let down: [f64; 12] = [0.0, 0.0, -1.0, 0.0, 0.0, -1.0, 0.0, 0.0, -1.0, 0.0, 0.0, -1.0];
+        let light = || Material::Lightsource(Lightsource::new(Vec { x: 1.0, y: 1.0, z: 1.0 }));
+        let build = |normals: Option<&[f64]>| -> Scene {
+            SceneBuilder::new()
+                .add_triangle_soup(&positions, &indices, normals, gray()).unwrap()
+                .add_sphere(Vec { x: 0.0, y: 0.0, z: 10.0 }, 0.5, light())
+                .build().unwrap().0
+        };
+
+        let scene = build(None);
+        assert_eq!(scene.len(), 3);
+        let dir: Vec = Vec { x: 0.0, y: 0.0, z: -1.0 };
+        for &(x, y) in &[(0.25, 0.75), (0.75, 0.25)] {
+            let origin: Vec = Vec { x, y, z: 1.0 };
+            let (object, hitpoint) = intersect_scene(&scene, &Ray::new(&origin, &dir));
+            assert!(index_of(&scene, object).unwrap() < 2);
+            assert!((hitpoint.distance - 1.0).abs() < 1e-9 && hitpoint.normal.z > 0.99, "{} {:?}", x, hitpoint.normal);
+        }
+        let outside: Vec = Vec { x: 1.5, y: 0.5, z: 1.0 };
+        assert!(intersect_scene(&scene, &Ray::new(&outside, &dir)).0.is_none());
+
+        let flipped = build(Some(&down));
+        for object in &flipped[..2] {
+            match *object.get_shape() {
+                Shape::Triangle(ref triangle) => assert!(triangle.normal().z < -0.99, "{:?}", triangle.normal()),
+                _ => unreachable!("the soup only adds triangles"),
+            }
+        }
+        assert!(SceneBuilder::new().add_triangle_soup(&positions[..11], &indices, None, gray()).is_err());
+        assert!(SceneBuilder::new().add_triangle_soup(&positions, &[0, 1, 4], None, gray()).is_err());
+    }
+
+    // 光源のある、問題の無いシーンはそのまま通る。
+    // 面積が0の三角形（単体とメッシュの中）、辺が平行で法線の求まらない四角形、反射率が1を超えるマテリアル、
+    // 光源が無いことは、それぞれ物体の番号つきで見つかる。
+    #[test]
+    fn validate_detects_each_problem_and_passes_a_clean_scene() {
+        let light = || Material::Lightsource(Lightsource::new(Vec { x: 1.0, y: 1.0, z: 1.0 }));
+        let origin: Vec = Vec { x: 0.0, y: 0.0, z: 0.0 };
+        let x: Vec = Vec { x: 1.0, y: 0.0, z: 0.0 };
+        let z: Vec = Vec { x: 0.0, y: 0.0, z: 1.0 };
+        let clean = SceneBuilder::new()
+            .add_quad(origin.clone(), z.clone(), x.clone(), gray())
+            .add_triangle(origin.clone(), x.clone(), z.clone(), gray())
+            .add_sphere(Vec { x: 0.0, y: 5.0, z: 0.0 }, 0.5, light())
+            .build().unwrap().0;
+        assert!(validate_scene(&clean).is_ok());
+
+        let degenerate = || Triangle::new(origin.clone(), x.clone(), &x * 2.0);
+        let mesh = Arc::new(Mesh::new(vec![Triangle::new(origin.clone(), x.clone(), z.clone()), degenerate()]));
+        let bright = Material::LambertianMaterial(LambertianMaterial::new(Vec { x: 0.5, y: 1.2, z: 0.5 }));
+        let broken: Scene = vec![
+            SceneObject::new(Shape::Triangle(degenerate()), gray()),
+            SceneObject::new(Shape::Instance(Instance::new(mesh, Transform::identity())), gray()),
+            SceneObject::new(Shape::Quad(Quad::new(origin.clone(), x.clone(), &x * 3.0)), gray()),
+            SceneObject::new(Shape::Sphere(Sphere::new(1.0, origin.clone())), bright),
+        ];
+        let warnings = validate_scene(&broken).unwrap_err();
+        assert_eq!(warnings.len(), 5, "{:?}", warnings);
+        assert!(matches!(warnings[0], SceneWarning::DegenerateTriangle { object: 0, triangle: 0 }), "{:?}", warnings);
+        assert!(matches!(warnings[1], SceneWarning::DegenerateTriangle { object: 1, triangle: 1 }), "{:?}", warnings);
+        assert!(matches!(warnings[2], SceneWarning::NonUnitNormal { object: 2, .. }), "{:?}", warnings);
+        match warnings[3] {
+            SceneWarning::ReflectanceAboveOne { object, ref reflectance } => assert!(object == 3 && reflectance.y == 1.2),
+            ref warning => unreachable!("{:?}", warning),
+        }
+        assert!(matches!(warnings[4], SceneWarning::NoLights), "{:?}", warnings);
+        for warning in warnings.iter() {
+            assert!(!warning.to_string().is_empty());
+        }
+    }
+}
@@ -0,0 +1,105 @@
+#![allow(dead_code)]
+
+use vec::Vec;
+use scene::{Scene, SceneBuilder};
+use camera::Camera;
+use material::{Material, MaterialRef, LambertianMaterial, PhongMaterial, GlassMaterial, Lightsource};
+
+type Color = Vec;
+
+// 定番のテストシーン。
+
+// 四角形を二枚の三角形として追加する。
+// 頂点を反時計回りに見た側が表になる。二枚の三角形はマテリアルを共有する。
+fn add_quad<M: Into<MaterialRef>>(builder: SceneBuilder, a: Vec, b: Vec, c: Vec, d: Vec, material: M) -> SceneBuilder {
+    let material: MaterialRef = material.into();
+    builder
+        .add_triangle(a.clone(), b, c.clone(), material.clone())
+        .add_triangle(a, c, d, material)
+}
+
+fn diffuse(reflectance: Color) -> MaterialRef {
+    MaterialRef::from(Material::LambertianMaterial(LambertianMaterial::new(reflectance)))
+}
+
+// コーネルボックス
+// x, zが[-1, 1]、yが[0, 2]の箱で、手前（z = 1）が開いている。
+// 左の壁が赤、右の壁が緑、それ以外は白。天井に下向きの四角形の面光源を置き、
+// 中にPhongの球とガラスの球を一つずつ置く。
+// 箱全体が写るカメラも一緒に返す。
+pub fn cornell_box() -> (Scene, Camera) {
+    // 白い面は床、天井、奥の壁で同じマテリアルを共有する。
+    let white = diffuse(Color { x: 0.75, y: 0.75, z: 0.75 });
+    let red = diffuse(Color { x: 0.75, y: 0.25, z: 0.25 });
+    let green = diffuse(Color { x: 0.25, y: 0.75, z: 0.25 });
+
+    let mut builder = SceneBuilder::new();
+
+    // 床
+    builder = add_quad(builder,
+        Vec { x: -1.0, y: 0.0, z: -1.0 }, Vec { x: -1.0, y: 0.0, z: 1.0 },
+        Vec { x: 1.0, y: 0.0, z: 1.0 }, Vec { x: 1.0, y: 0.0, z: -1.0 },
+        white.clone());
+    // 天井
+    builder = add_quad(builder,
+        Vec { x: -1.0, y: 2.0, z: -1.0 }, Vec { x: 1.0, y: 2.0, z: -1.0 },
+        Vec { x: 1.0, y: 2.0, z: 1.0 }, Vec { x: -1.0, y: 2.0, z: 1.0 },
+        white.clone());
+    // 奥の壁
+    builder = add_quad(builder,
+        Vec { x: -1.0, y: 0.0, z: -1.0 }, Vec { x: 1.0, y: 0.0, z: -1.0 },
+        Vec { x: 1.0, y: 2.0, z: -1.0 }, Vec { x: -1.0, y: 2.0, z: -1.0 },
+        white);
+    // 左の壁
+    builder = add_quad(builder,
+        Vec { x: -1.0, y: 0.0, z: -1.0 }, Vec { x: -1.0, y: 2.0, z: -1.0 },
+        Vec { x: -1.0, y: 2.0, z: 1.0 }, Vec { x: -1.0, y: 0.0, z: 1.0 },
+        red);
+    // 右の壁
+    builder = add_quad(builder,
+        Vec { x: 1.0, y: 0.0, z: -1.0 }, Vec { x: 1.0, y: 0.0, z: 1.0 },
+        Vec { x: 1.0, y: 2.0, z: 1.0 }, Vec { x: 1.0, y: 2.0, z: -1.0 },
+        green);
+    // 光源。天井の少し下に置き、下側だけを光らせる。
+    builder = add_quad(builder,
+        Vec { x: -0.25, y: 1.98, z: -0.25 }, Vec { x: 0.25, y: 1.98, z: -0.25 },
+        Vec { x: 0.25, y: 1.98, z: 0.25 }, Vec { x: -0.25, y: 1.98, z: 0.25 },
+        Material::Lightsource(Lightsource::new(Color { x: 16.0, y: 16.0, z: 16.0 }).one_sided()));
+
+    let builder = builder
+        .add_sphere(
+            Vec { x: -0.45, y: 0.4, z: -0.35 }, 0.4,
+            Material::PhongMaterial(PhongMaterial::new(Color { x: 0.9, y: 0.9, z: 0.9 }, 100.0)))
+        .add_sphere(
+            Vec { x: 0.45, y: 0.35, z: 0.35 }, 0.35,
+            Material::GlassMaterial(GlassMaterial::new(Color { x: 0.999999, y: 0.999999, z: 0.999999 }, 1.5)))
+        .camera(Camera::new(
+            Vec { x: 0.0, y: 1.0, z: 4.5 },
+            Vec { x: 0.0, y: 1.0, z: 0.0 },
+            Vec { x: 0.0, y: 1.0, z: 0.0 }));
+
+    builder.build().unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use scene::SceneObject;
+    use shape::Shape;
+
+    // コーネルボックスは六枚の四角形（三角形二枚ずつ）と二つの球でできていて、光る面は天井の光源一枚だけ。
+    #[test]
+    fn cornell_box_has_expected_primitives_and_one_light() {
+        let (scene, _) = cornell_box();
+        assert_eq!(scene.len(), 14);
+
+        let triangles: usize = scene.iter().filter(|o| matches!(*o.get_shape(), Shape::Triangle ( _ ))).count();
+        let spheres: usize = scene.iter().filter(|o| matches!(*o.get_shape(), Shape::Sphere ( _ ))).count();
+        assert_eq!((triangles, spheres), (12, 2));
+
+        // 光源の四角形の二枚の三角形は同じマテリアルを共有する。
+        let lights: std::vec::Vec<&SceneObject> = scene.iter().filter(|o| o.is_light()).collect();
+        assert_eq!(lights.len(), 2);
+        assert!(MaterialRef::ptr_eq(lights[0].get_material_ref(), lights[1].get_material_ref()));
+    }
+}
@@ -0,0 +1,131 @@
+#![allow(dead_code)]
+
+use vec::Vec;
+use ray::Ray;
+use hitpoint::Hitpoint;
+use sampler::Sampler;
+use sphere::Sphere;
+use triangle::Triangle;
+use quad::Quad;
+use instance::{Instance, InstanceGroup};
+
+// シーンに置ける形状。
+#[derive(Debug)]
+pub enum Shape {
+    Sphere(Sphere),
+    Triangle(Triangle),
+    Quad(Quad),                   // 平行四辺形。(u, v)をテクスチャ座標とする
+    Instance(Instance),           // 共有するメッシュを変換して置いたもの
+    InstanceGroup(InstanceGroup), // 多数のInstanceをトップレベルのBVHでまとめたもの
+}
+
+impl Shape {
+    pub fn intersect(&self, ray: &Ray) -> Option<Hitpoint> {
+        match *self {
+            Shape::Sphere        ( ref shape ) => shape.intersect(ray),
+            Shape::Triangle      ( ref shape ) => shape.intersect(ray),
+            Shape::Quad          ( ref shape ) => shape.intersect(ray),
+            Shape::Instance      ( ref shape ) => shape.intersect(ray),
+            Shape::InstanceGroup ( ref shape ) => shape.intersect(ray),
+        }
+    }
+
+    // intersectと同じだが、距離がt_min以下の交差は無視する。
+    // 自己交差はふつうレイの始点をずらして避ける（Hitpoint::offset_origin）が、始点をずらせない場合に使う。
+    pub fn intersect_with_t_min(&self, ray: &Ray, t_min: f64) -> Option<Hitpoint> {
+        match *self {
+            Shape::Sphere        ( ref shape ) => shape.intersect_with_t_min(ray, t_min),
+            Shape::Triangle      ( ref shape ) => shape.intersect_with_t_min(ray, t_min),
+            Shape::Quad          ( ref shape ) => shape.intersect_with_t_min(ray, t_min),
+            Shape::Instance      ( ref shape ) => shape.intersect_with_t_min(ray, t_min),
+            Shape::InstanceGroup ( ref shape ) => shape.intersect_with_t_min(ray, t_min),
+        }
+    }
+
+    // 距離max_distanceより手前で交差するか。メッシュ（Instance、InstanceGroup）では最初に見つけた交差で打ち切る。
+    pub fn intersect_any(&self, ray: &Ray, max_distance: f64) -> bool {
+        match *self {
+            Shape::Instance      ( ref shape ) => shape.intersect_any(ray, max_distance),
+            Shape::InstanceGroup ( ref shape ) => shape.intersect_any(ray, max_distance),
+            _ => self.intersect(ray).is_some_and(|hitpoint| hitpoint.distance < max_distance),
+        }
+    }
+
+    // intersectで得た交差点のテクスチャ座標と接線を求める。
+    // 交差判定のたびに求めると無駄が多いので、最も近い交差点が決まってから呼ぶ。
+    pub fn surface_details(&self, hitpoint: &mut Hitpoint) {
+        match *self {
+            Shape::Sphere        ( ref shape ) => shape.surface_details(hitpoint),
+            Shape::Triangle      ( ref shape ) => shape.surface_details(hitpoint),
+            Shape::Quad          ( ref shape ) => shape.surface_details(hitpoint),
+            // メッシュの中でどの三角形に当たったかはintersectでしか分からないので、intersectで求めてある。
+            Shape::Instance      ( _ ) | Shape::InstanceGroup ( _ ) => (),
+        }
+    }
+
+    // 形状上の点を一様にサンプリングする。動く形状（Instance）は時刻timeでの位置から選ぶ。
+    // (位置, 法線, 面積測度のpdf)を返す。
+    pub fn sample_surface(&self, random: &mut dyn Sampler, time: f64) -> (Vec, Vec, f64) {
+        match *self {
+            Shape::Sphere        ( ref shape ) => shape.sample_surface(random),
+            Shape::Triangle      ( ref shape ) => shape.sample_surface(random),
+            Shape::Quad          ( ref shape ) => shape.sample_surface(random),
+            Shape::Instance      ( ref shape ) => shape.sample_surface(random, time),
+            Shape::InstanceGroup ( ref shape ) => shape.sample_surface(random, time),
+        }
+    }
+
+    // sample_surfaceで時刻timeに、法線がnormalの点を選ぶ面積測度のpdf（一様に選ぶので、位置にはよらない）。
+    // 光源サンプリングとBRDFサンプリングをMISで組み合わせるときに、BRDFサンプリングで当たった光源上の点について求める。
+    // どのインスタンスを選んだか点から分からないInstanceGroupではNoneを返す（has_surface_pdf）。
+    pub fn surface_pdf(&self, normal: &Vec, time: f64) -> Option<f64> {
+        match *self {
+            Shape::Sphere        ( ref shape ) => Some(1.0 / shape.area()),
+            Shape::Triangle      ( ref shape ) => Some(1.0 / shape.area()),
+            Shape::Quad          ( ref shape ) => Some(1.0 / shape.area()),
+            Shape::Instance      ( ref shape ) => Some(shape.surface_pdf(normal, time)),
+            Shape::InstanceGroup ( _ ) => None,
+        }
+    }
+
+    // surface_pdfが値を返すか。
+    pub fn has_surface_pdf(&self) -> bool {
+        !matches!(*self, Shape::InstanceGroup ( _ ))
+    }
+
+    // テクスチャ座標から点が一意に決まるか。trueの形状ではpoint_at_uvが点を返す。
+    pub fn has_uv_parameterization(&self) -> bool {
+        matches!(self, &Shape::Quad          ( _ ))
+    }
+
+    // テクスチャ座標(u, v)の点の(位置, 法線, テクスチャ座標あたりの面積)。
+    // テクスチャ座標で光源上の点を選ぶ（EmissiveTextureMaterial）のに使う。
+    // has_uv_parameterizationがfalseの形状ではNoneを返す。
+    pub fn point_at_uv(&self, u: f64, v: f64) -> Option<(Vec, Vec, f64)> {
+        match *self {
+            Shape::Quad          ( ref shape ) => Some(shape.point_at_uv(u, v)),
+            _ => None,
+        }
+    }
+
+    // 軸に平行な境界ボックスの(最小の角, 最大の角)。
+    pub fn bounds(&self) -> (Vec, Vec) {
+        match *self {
+            Shape::Sphere        ( ref shape ) => shape.bounds(),
+            Shape::Triangle      ( ref shape ) => shape.bounds(),
+            Shape::Quad          ( ref shape ) => shape.bounds(),
+            Shape::Instance      ( ref shape ) => shape.bounds(),
+            Shape::InstanceGroup ( ref shape ) => shape.bounds(),
+        }
+    }
+
+    pub fn area(&self) -> f64 {
+        match *self {
+            Shape::Sphere        ( ref shape ) => shape.area(),
+            Shape::Triangle      ( ref shape ) => shape.area(),
+            Shape::Quad          ( ref shape ) => shape.area(),
+            Shape::Instance      ( ref shape ) => shape.area(),
+            Shape::InstanceGroup ( ref shape ) => shape.area(),
+        }
+    }
+}
@@ -1,8 +1,11 @@
 #![allow(dead_code)]
 
-use vec::{Vec, Dot, Normalize};
+use vec::{Vec, Dot, Normalize, Cross};
 use ray::Ray;
 use hitpoint::Hitpoint;
+use sampler::Sampler;
+use sampling::Sampling;
+use constant::{K_PI, K_2PI, K_INV_PI, K_INV_2PI};
 
 // 球の幾何学的な情報を持つ
 #[derive(Debug)]
@@ -16,43 +19,173 @@ impl Sphere {
         Sphere { radius_: radius, position_: position }
     }
 
+    pub fn radius(&self) -> f64 {
+        self.radius_
+    }
+
+    pub fn position(&self) -> &Vec {
+        &self.position_
+    }
+
+    // 軸に平行な境界ボックスの(最小の角, 最大の角)。
+    pub fn bounds(&self) -> (Vec, Vec) {
+        let r = Vec { x: self.radius_, y: self.radius_, z: self.radius_ };
+        (&self.position_ - &r, &self.position_ + &r)
+    }
+
+    pub fn area(&self) -> f64 {
+        4.0 * K_PI * self.radius_ * self.radius_
+    }
+
+    // 中心から見た方向normal（単位ベクトル）の点のテクスチャ座標。
+    // +yを北極とした緯度経度（正距円筒図法）の対応で、uは方位角、vは極角から決める。
+    // v = 0が北極（+y）、v = 0.5が赤道、v = 1が南極（-y）。
+    // uは+x方向が0で、+xから-zの向きに増える。外から見たときに画像が左右反転しない向きになる。
+    pub fn uv(normal: &Vec) -> (f64, f64) {
+        let mut phi: f64 = (-normal.z).atan2(normal.x);
+        if phi < 0.0 {
+            phi += K_2PI;
+        }
+        let theta: f64 = normal.y.clamp(-1.0, 1.0).acos();
+
+        (phi * K_INV_2PI, theta * K_INV_PI)
+    }
+
+    // 方向normalの点でuが増える向きの単位接線（緯線の向き）。
+    // 極でも方位角から向きを決めるので、ゼロベクトルにはならない。
+    pub fn tangent(normal: &Vec) -> Vec {
+        let phi: f64 = (-normal.z).atan2(normal.x);
+        Vec { x: -phi.sin(), y: 0.0, z: -phi.cos() }
+    }
+
+    // 交差点hitpointのテクスチャ座標と接線を求める。
+    // 三角関数を使うので、交差判定では最も近い交差点が決まってから一度だけ呼ぶ（intersect_scene）。
+    pub fn surface_details(&self, hitpoint: &mut Hitpoint) {
+        hitpoint.uv = Sphere::uv(&hitpoint.normal);
+        let tangent: Vec = Sphere::tangent(&hitpoint.normal);
+        let bitangent: Vec = Vec::cross(&hitpoint.normal, &tangent);
+        hitpoint.tangent_frame = Some((tangent, bitangent));
+    }
+
+    // 球面上の点を一様にサンプリングする。
+    // (位置, 法線, 面積測度のpdf)を返す。
+    pub fn sample_surface(&self, random: &mut dyn Sampler) -> (Vec, Vec, f64) {
+        let normal: Vec = Sampling::uniform_sphere_surface(random);
+        let position: Vec = &self.position_ + &normal * self.radius_;
+
+        (position, normal, 1.0 / self.area())
+    }
+
     // 入力のrayに対する交差点までの距離を得る。
     // 交差したらその情報を,さもなくばNoneを返す。
+    // 自己交差はレイの始点をずらして避ける（Hitpoint::offset_origin）ので、距離が正なら交差とする。
+    // 判別式と近い方の解は桁落ちしにくい形で求める。
+    // Eric Haines, Johannes Günther, Tomas Akenine-Möller. Precision Improvements for Ray/Sphere Intersection. Ray Tracing Gems, 2019.
     pub fn intersect(&self, ray: &Ray) -> Option<Hitpoint> {
-        // 自己交差の判定用定数。
-        const K_EPS: f64 = 1e-6_f64;
+        self.intersect_with_t_min(ray, 0.0)
+    }
 
+    // intersectと同じだが、距離がt_min以下の交差は無視する。
+    // 球面上から出したレイで、丸め誤差のために同じ球面のすぐ手前の解が残る（自己交差）のを防ぐ。
+    pub fn intersect_with_t_min(&self, ray: &Ray, t_min: f64) -> Option<Hitpoint> {
         let o_to_p: Vec = &self.position_ - ray.org;
         let b: f64 = Vec::dot(&o_to_p, &ray.dir);
-        let c: f64 = &b * &b - Vec::dot(&o_to_p, &o_to_p) + &self.radius_ * &self.radius_;
+        // 球の中心からレイへ下ろした垂線。b^2 - |o_to_p|^2 を直接計算すると、遠くの大きな球で桁落ちする。
+        let to_line: Vec = &o_to_p - ray.dir * b;
+        let c: f64 = self.radius_ * self.radius_ - Vec::dot(&to_line, &to_line);
 
         if &c < &0.0_f64 {
             return None
         }
 
+        // 絶対値が大きい方の解を先に求め、もう一方は解と係数の関係（t1 * t2 = |o_to_p|^2 - r^2）から求める。
         let sqrt_c: f64 = c.sqrt();
-        let t1: f64 = &b - &sqrt_c;
-        let t2: f64 = &b + &sqrt_c;
+        let q: f64 = if b >= 0.0 { b + sqrt_c } else { b - sqrt_c };
+        if q == 0.0 {
+            return None
+        }
+        let product: f64 = Vec::dot(&o_to_p, &o_to_p) - self.radius_ * self.radius_;
+        let (t1, t2) = if product / q < q { (product / q, q) } else { (q, product / q) };
 
-        // 微小距離内だったら交差しないとする（自己交差を避けるため）。
-        if &t1 < &K_EPS && &t2 < &K_EPS {
+        // t_min以下（負値ならレイの逆方向）の場合は交差していないとする。
+        if t1 <= t_min && t2 <= t_min {
             return None
         }
 
         let mut hitpoint = Hitpoint::new();
 
         // 交差するときは二点以上で交差する。（接する場合は一点）
-        // 近い方を交差点とする。また、負値の場合はレイの逆方向で交差してるため交差していないとする。
-        if &t1 > &K_EPS {
+        // t_minより遠いうちの近い方を交差点とする。
+        if t1 > t_min {
             hitpoint.distance = t1
         } else {
             hitpoint.distance = t2
         }
 
-        hitpoint.position = ray.org + &hitpoint.distance * ray.dir;
-        hitpoint.normal = Vec::normalize(&hitpoint.position - &self.position_);
+        // ray.org + t * ray.dirの誤差はレイの始点の遠さに比例するので、球面上に投影し直す。
+        let position: Vec = ray.org + hitpoint.distance * ray.dir;
+        hitpoint.normal = Vec::normalize(&position - &self.position_);
+        hitpoint.position = &self.position_ + &hitpoint.normal * self.radius_;
 
         Some(hitpoint)
     }
 }
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn close(a: (f64, f64), b: (f64, f64)) -> bool {
+        (a.0 - b.0).abs() < 1e-12 && (a.1 - b.1).abs() < 1e-12
+    }
+
+    // 北極と南極はv = 0, 1、赤道はv = 0.5。赤道上ではuが+xから-zの向きに増える。
+    #[test]
+    fn poles_and_equator_map_to_expected_uv() {
+        assert_eq!(Sphere::uv(&Vec { x: 0.0, y: 1.0, z: 0.0 }).1, 0.0);
+        assert_eq!(Sphere::uv(&Vec { x: 0.0, y: -1.0, z: 0.0 }).1, 1.0);
+
+        assert!(close(Sphere::uv(&Vec { x: 1.0, y: 0.0, z: 0.0 }), (0.0, 0.5)));
+        assert!(close(Sphere::uv(&Vec { x: 0.0, y: 0.0, z: -1.0 }), (0.25, 0.5)));
+        assert!(close(Sphere::uv(&Vec { x: -1.0, y: 0.0, z: 0.0 }), (0.5, 0.5)));
+        assert!(close(Sphere::uv(&Vec { x: 0.0, y: 0.0, z: 1.0 }), (0.75, 0.5)));
+
+        // 緯度45度の点。
+        let h: f64 = 0.5_f64.sqrt();
+        assert!(close(Sphere::uv(&Vec { x: h, y: h, z: 0.0 }), (0.0, 0.25)));
+    }
+
+    // 交差点のテクスチャ座標は、中心から見た交差点の方向のもの。
+    #[test]
+    fn intersection_gets_uv_of_the_hit_direction() {
+        let sphere = Sphere::new(2.0, Vec { x: 1.0, y: 1.0, z: 1.0 });
+        // -z側から当てると、赤道上のu = 0.25の点に当たる。
+        let origin: Vec = Vec { x: 1.0, y: 1.0, z: -10.0 };
+        let dir: Vec = Vec { x: 0.0, y: 0.0, z: 1.0 };
+        let mut hitpoint: Hitpoint = sphere.intersect(&Ray::new(&origin, &dir)).unwrap();
+        sphere.surface_details(&mut hitpoint);
+        assert!(close(hitpoint.uv, (0.25, 0.5)), "{:?}", hitpoint.uv);
+    }
+
+    // 球の表面の交差点から外へ向かう二次レイは、t_min = 1e-4なら同じ球に当たり直さない。
+    // 内側へ向かう二次レイは、すぐそばではなく向かい側の面に当たる。
+    #[test]
+    fn secondary_rays_with_t_min_do_not_rehit_the_surface() {
+        use random::Random;
+        use sampling::Sampling;
+        let center: Vec = Vec { x: 3.0, y: -2.0, z: 50.0 };
+        let sphere = Sphere::new(100.0, center.clone());
+        let mut random = Random::new(6);
+        for _ in 0..1000 {
+            // 球の外の遠い点から、中心の向きに撃つ。
+            let origin: Vec = &center + Sampling::uniform_sphere_surface(&mut random) * 300.0;
+            let dir: Vec = Vec::normalize(&center - &origin);
+            let hitpoint: Hitpoint = sphere.intersect(&Ray::new(&origin, &dir)).unwrap();
+            let (outward, inward) = (hitpoint.normal.clone(), -&hitpoint.normal);
+            assert!(sphere.intersect_with_t_min(&Ray::new(&hitpoint.position, &outward), 1e-4).is_none());
+            let far: Hitpoint = sphere.intersect_with_t_min(&Ray::new(&hitpoint.position, &inward), 1e-4).unwrap();
+            assert!((far.distance - 200.0).abs() < 1e-6, "{}", far.distance);
+        }
+    }
+}
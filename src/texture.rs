@@ -0,0 +1,241 @@
+#![allow(dead_code)]
+
+use std;
+
+use vec::Vec;
+
+type Color = Vec;
+
+// ミップマップの1レベル分の画像。
+struct MipLevel {
+    width: i32,
+    height: i32,
+    texels: std::vec::Vec<Color>,
+}
+
+// 画像テクスチャ
+// 生成時にミップマップを作っておき、縮小時のエイリアシングをトライリニア補間で抑える。
+pub struct ImageTexture {
+    levels: std::vec::Vec<MipLevel>, // levels[0]が元画像。
+    alpha: Option<Box<ImageTexture>>, // 不透明度。各チャンネルに同じ値を入れたテクスチャとして持つ。
+}
+
+impl MipLevel {
+    // テクスチャ座標は繰り返し（リピート）とする。
+    fn texel(&self, x: i32, y: i32) -> &Color {
+        let x = ((x % self.width) + self.width) % self.width;
+        let y = ((y % self.height) + self.height) % self.height;
+        &self.texels[(y * self.width + x) as usize]
+    }
+
+    // 2x2の平均を取って半分の解像度のレベルを作る。
+    // 奇数サイズのときは端のテクセルを重複して使う。
+    fn downsample(&self) -> MipLevel {
+        let width = if self.width > 1 { self.width / 2 } else { 1 };
+        let height = if self.height > 1 { self.height / 2 } else { 1 };
+        let mut texels = std::vec::Vec::with_capacity((width * height) as usize);
+
+        for y in 0..height {
+            for x in 0..width {
+                let x0 = std::cmp::min(x * 2, self.width - 1);
+                let x1 = std::cmp::min(x * 2 + 1, self.width - 1);
+                let y0 = std::cmp::min(y * 2, self.height - 1);
+                let y1 = std::cmp::min(y * 2 + 1, self.height - 1);
+                texels.push(
+                    (self.texel(x0, y0) + self.texel(x1, y0) + self.texel(x0, y1) + self.texel(x1, y1)) / 4.0);
+            }
+        }
+
+        MipLevel { width, height, texels }
+    }
+
+    // バイリニア補間。テクセルの中心が(i + 0.5) / widthにあるとする。
+    fn bilinear(&self, u: f64, v: f64) -> Color {
+        let px: f64 = u * self.width as f64 - 0.5;
+        let py: f64 = v * self.height as f64 - 0.5;
+        let x0: f64 = px.floor();
+        let y0: f64 = py.floor();
+        let dx: f64 = px - x0;
+        let dy: f64 = py - y0;
+        let x0 = x0 as i32;
+        let y0 = y0 as i32;
+
+        self.texel(x0, y0) * ((1.0 - dx) * (1.0 - dy)) +
+        self.texel(x0 + 1, y0) * (dx * (1.0 - dy)) +
+        self.texel(x0, y0 + 1) * ((1.0 - dx) * dy) +
+        self.texel(x0 + 1, y0 + 1) * (dx * dy)
+    }
+}
+
+impl ImageTexture {
+    // texelsは左上から行優先で並んだwidth * height個の色。
+    pub fn new(width: i32, height: i32, texels: std::vec::Vec<Color>) -> ImageTexture {
+        assert!(width > 0 && height > 0);
+        assert_eq!((width * height) as usize, texels.len());
+
+        let mut levels = vec![MipLevel { width, height, texels }];
+        // 1x1になるまでミップレベルを生成する。
+        loop {
+            let next = {
+                let last = &levels[levels.len() - 1];
+                if last.width == 1 && last.height == 1 {
+                    break;
+                }
+                last.downsample()
+            };
+            levels.push(next);
+        }
+
+        ImageTexture { levels, alpha: None }
+    }
+
+    // 不透明度（アルファチャンネル）付きのテクスチャを作る。
+    // alphasはtexelsと同じ並びの[0, 1]の値で、0が完全に透明。
+    pub fn with_alpha(
+        width: i32, height: i32, texels: std::vec::Vec<Color>, alphas: std::vec::Vec<f64>) -> ImageTexture {
+        assert_eq!(texels.len(), alphas.len());
+
+        let alpha_texels = alphas.iter().map(|a| Color { x: *a, y: *a, z: *a }).collect();
+        let mut texture = ImageTexture::new(width, height, texels);
+        texture.alpha = Some(Box::new(ImageTexture::new(width, height, alpha_texels)));
+        texture
+    }
+
+    pub fn has_alpha(&self) -> bool {
+        self.alpha.is_some()
+    }
+
+    pub fn width(&self) -> i32 {
+        self.levels[0].width
+    }
+
+    pub fn height(&self) -> i32 {
+        self.levels[0].height
+    }
+
+    // 元画像の(x, y)のテクセル。座標は繰り返しとする。
+    pub fn texel(&self, x: i32, y: i32) -> &Color {
+        self.levels[0].texel(x, y)
+    }
+
+    // テクスチャ座標(u, v)を含む元画像のテクセル（最近傍）。
+    pub fn nearest(&self, u: f64, v: f64) -> &Color {
+        let x: i32 = (u * self.width() as f64).floor() as i32;
+        let y: i32 = (v * self.height() as f64).floor() as i32;
+        self.texel(x, y)
+    }
+
+    pub fn num_levels(&self) -> usize {
+        self.levels.len()
+    }
+
+    // LODを直接指定したトライリニア補間。
+    // lodは0が元画像で、1増えるごとに解像度が半分のレベルになる。小数部で隣接レベル間を線形補間する。
+    pub fn lookup(&self, u: f64, v: f64, lod: f64) -> Color {
+        let max_level: f64 = (self.levels.len() - 1) as f64;
+        let lod: f64 = if lod < 0.0 {
+            0.0
+        } else if lod > max_level {
+            max_level
+        } else {
+            lod
+        };
+
+        let level: usize = lod.floor() as usize;
+        let t: f64 = lod - level as f64;
+        if level + 1 >= self.levels.len() || t == 0.0 {
+            return self.levels[level].bilinear(u, v)
+        }
+
+        self.levels[level].bilinear(u, v) * (1.0 - t) + self.levels[level + 1].bilinear(u, v) * t
+    }
+
+    // テクスチャ空間でのフットプリントの幅（uv単位）からLODを求める。
+    // フットプリントが1テクセルに収まるレベルを選ぶ。
+    pub fn lod_from_footprint(&self, footprint: f64) -> f64 {
+        let texels: f64 = footprint * std::cmp::max(self.width(), self.height()) as f64;
+        if texels <= 1.0 {
+            0.0
+        } else {
+            texels.log2()
+        }
+    }
+
+    // フットプリントからLODを決めてトライリニア補間する。
+    pub fn lookup_footprint(&self, u: f64, v: f64, footprint: f64) -> Color {
+        let lod: f64 = self.lod_from_footprint(footprint);
+        self.lookup(u, v, lod)
+    }
+
+    // 不透明度をトライリニア補間で引く。アルファチャンネルが無ければ常に不透明（1）。
+    pub fn alpha_lookup(&self, u: f64, v: f64, lod: f64) -> f64 {
+        match self.alpha {
+            Some(ref alpha) => alpha.lookup(u, v, lod).x,
+            None => 1.0
+        }
+    }
+
+    // 不透明度がthreshold未満なら、その点は抜け（レイが素通りする）とみなす。
+    pub fn is_cutout(&self, u: f64, v: f64, lod: f64, threshold: f64) -> bool {
+        self.alpha_lookup(u, v, lod) < threshold
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 1テクセルごとに白と黒が入れ替わるsize x sizeの市松模様。
+    fn checker(size: i32) -> ImageTexture {
+        let texels = (0..size * size).map(|i| {
+            let value: f64 = ((i % size + i / size) % 2) as f64;
+            Color { x: value, y: value, z: value }
+        }).collect();
+        ImageTexture::new(size, size, texels)
+    }
+
+    // 格子状に並べたテクスチャ座標で引いた値の分散。
+    fn lookup_variance(texture: &ImageTexture, lod: f64) -> f64 {
+        let n: usize = 64;
+        let values: std::vec::Vec<f64> = (0..n * n)
+            .map(|i| texture.lookup((i % n) as f64 / n as f64 + 0.01, (i / n) as f64 / n as f64 + 0.02, lod).x)
+            .collect();
+        let mean: f64 = values.iter().sum::<f64>() / values.len() as f64;
+        values.iter().map(|v| (v - mean) * (v - mean)).sum::<f64>() / values.len() as f64
+    }
+
+    // 1x1になるまでミップレベルを作る。奇数の辺は切り捨てて半分にする（5x3 → 2x1 → 1x1）。
+    #[test]
+    fn builds_levels_down_to_one_texel() {
+        assert_eq!(checker(8).num_levels(), 4);
+        assert_eq!(ImageTexture::new(5, 3, vec![Color { x: 0.0, y: 0.0, z: 0.0 }; 15]).num_levels(), 3);
+    }
+
+    // LODが高いほど周りのテクセルと平均した値になり、ばらつきが小さくなる。
+    #[test]
+    fn higher_lod_is_more_averaged() {
+        let texture = checker(8);
+        let variances: std::vec::Vec<f64> = (0..4).map(|lod| lookup_variance(&texture, lod as f64)).collect();
+        assert!(variances[0] > variances[1] && variances[1] > variances[2], "{:?}", variances);
+        // 最後のレベルは画像全体の平均。
+        assert!(variances[3] < 1e-20, "{:?}", variances);
+        assert!((texture.lookup(0.3, 0.7, 3.0).x - 0.5).abs() < 1e-12);
+    }
+
+    // LODの小数部では隣り合うレベルを線形補間する。
+    #[test]
+    fn fractional_lod_interpolates_levels() {
+        let texture = checker(8);
+        let (u, v): (f64, f64) = (0.3, 0.6);
+        let expected: f64 = (texture.lookup(u, v, 1.0).x + texture.lookup(u, v, 2.0).x) / 2.0;
+        assert!((texture.lookup(u, v, 1.5).x - expected).abs() < 1e-12);
+    }
+
+    // フットプリントが1テクセル以下なら元画像を使い、広がるほどLODが上がる。
+    #[test]
+    fn lod_grows_with_footprint() {
+        let texture = checker(8);
+        assert_eq!(texture.lod_from_footprint(1.0 / 16.0), 0.0);
+        assert!((texture.lod_from_footprint(0.5) - 2.0).abs() < 1e-12);
+    }
+}
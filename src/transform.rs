@@ -0,0 +1,422 @@
+#![allow(dead_code)]
+
+use std;
+
+use vec::Vec;
+
+type Matrix = [[f64; 4]; 4]; // 行優先（m[行][列]）。点は列ベクトルとして右から掛ける。
+
+// アフィン変換。逆行列も合わせて持つ。
+// 点、方向、法線の変換と、変換の合成、逆変換ができる。
+#[derive(Debug, Clone)]
+pub struct Transform {
+    matrix: Matrix,
+    inverse: Matrix,
+}
+
+const IDENTITY: Matrix = [
+    [1.0, 0.0, 0.0, 0.0],
+    [0.0, 1.0, 0.0, 0.0],
+    [0.0, 0.0, 1.0, 0.0],
+    [0.0, 0.0, 0.0, 1.0],
+];
+
+impl Transform {
+    pub fn identity() -> Transform {
+        Transform { matrix: IDENTITY, inverse: IDENTITY }
+    }
+
+    // 平行移動。
+    pub fn translation(offset: &Vec) -> Transform {
+        let mut matrix: Matrix = IDENTITY;
+        let mut inverse: Matrix = IDENTITY;
+        matrix[0][3] = offset.x;
+        matrix[1][3] = offset.y;
+        matrix[2][3] = offset.z;
+        inverse[0][3] = -offset.x;
+        inverse[1][3] = -offset.y;
+        inverse[2][3] = -offset.z;
+        Transform { matrix, inverse }
+    }
+
+    // 軸ごとの拡大縮小。どの軸も0であってはならない。
+    pub fn scaling(scale: &Vec) -> Transform {
+        let mut matrix: Matrix = IDENTITY;
+        let mut inverse: Matrix = IDENTITY;
+        matrix[0][0] = scale.x;
+        matrix[1][1] = scale.y;
+        matrix[2][2] = scale.z;
+        inverse[0][0] = 1.0 / scale.x;
+        inverse[1][1] = 1.0 / scale.y;
+        inverse[2][2] = 1.0 / scale.z;
+        Transform { matrix, inverse }
+    }
+
+    // 原点を通る軸axisまわりにangle（ラジアン）だけ回転する。回転の向きは右ねじの向き。
+    pub fn rotation(axis: &Vec, angle: f64) -> Transform {
+        let length: f64 = axis.length();
+        let (x, y, z) = (axis.x / length, axis.y / length, axis.z / length);
+        let (s, c) = (angle.sin(), angle.cos());
+        let t: f64 = 1.0 - c;
+
+        let mut matrix: Matrix = IDENTITY;
+        matrix[0][0] = t * x * x + c;     matrix[0][1] = t * x * y - s * z; matrix[0][2] = t * x * z + s * y;
+        matrix[1][0] = t * x * y + s * z; matrix[1][1] = t * y * y + c;     matrix[1][2] = t * y * z - s * x;
+        matrix[2][0] = t * x * z - s * y; matrix[2][1] = t * y * z + s * x; matrix[2][2] = t * z * z + c;
+
+        // 回転行列の逆行列は転置。
+        Transform { matrix, inverse: transpose(&matrix) }
+    }
+
+    // 任意のアフィン変換の行列から作る。最後の行は(0, 0, 0, 1)とみなす。
+    // 逆行列が無い（潰れた変換）ときはNone。
+    pub fn from_matrix(matrix: [[f64; 4]; 4]) -> Option<Transform> {
+        let mut matrix: Matrix = matrix;
+        matrix[3] = [0.0, 0.0, 0.0, 1.0];
+        affine_inverse(&matrix).map(|inverse| Transform { matrix, inverse })
+    }
+
+    pub fn matrix(&self) -> &[[f64; 4]; 4] {
+        &self.matrix
+    }
+
+    // 逆変換。
+    pub fn inverse(&self) -> Transform {
+        Transform { matrix: self.inverse, inverse: self.matrix }
+    }
+
+    // selfの後にnextを適用する変換。
+    pub fn then(&self, next: &Transform) -> Transform {
+        Transform { matrix: multiply(&next.matrix, &self.matrix), inverse: multiply(&self.inverse, &next.inverse) }
+    }
+
+    pub fn point(&self, p: &Vec) -> Vec {
+        transform_point(&self.matrix, p)
+    }
+
+    // 方向（平行移動の影響を受けない）。長さは保たれない。
+    pub fn vector(&self, v: &Vec) -> Vec {
+        transform_vector(&self.matrix, v)
+    }
+
+    // 法線。拡大縮小しても面に垂直なままになるよう、逆行列の転置を掛ける。長さは保たれない。
+    pub fn normal(&self, n: &Vec) -> Vec {
+        let m = &self.inverse;
+        Vec {
+            x: m[0][0] * n.x + m[1][0] * n.y + m[2][0] * n.z,
+            y: m[0][1] * n.x + m[1][1] * n.y + m[2][1] * n.z,
+            z: m[0][2] * n.x + m[1][2] * n.y + m[2][2] * n.z,
+        }
+    }
+
+    // 逆変換による点と方向。ワールド座標のレイを物体の座標系に持ち込むのに使う。
+    pub fn inverse_point(&self, p: &Vec) -> Vec {
+        transform_point(&self.inverse, p)
+    }
+
+    pub fn inverse_vector(&self, v: &Vec) -> Vec {
+        transform_vector(&self.inverse, v)
+    }
+
+    // 左上3x3部分の行列式。負なら鏡映を含み、面の表裏が入れ替わる。
+    pub fn determinant(&self) -> f64 {
+        determinant3(&self.matrix)
+    }
+
+    // 軸に平行な境界ボックス(min, max)を変換したものを囲む、軸に平行な境界ボックス。
+    pub fn bounds(&self, min: &Vec, max: &Vec) -> (Vec, Vec) {
+        let mut lower = Vec { x: ::constant::K_INF, y: ::constant::K_INF, z: ::constant::K_INF };
+        let mut upper = Vec { x: -::constant::K_INF, y: -::constant::K_INF, z: -::constant::K_INF };
+        for i in 0..8 {
+            let corner = Vec {
+                x: if i & 1 == 0 { min.x } else { max.x },
+                y: if i & 2 == 0 { min.y } else { max.y },
+                z: if i & 4 == 0 { min.z } else { max.z },
+            };
+            let p = self.point(&corner);
+            lower = Vec { x: lower.x.min(p.x), y: lower.y.min(p.y), z: lower.z.min(p.z) };
+            upper = Vec { x: upper.x.max(p.x), y: upper.y.max(p.y), z: upper.z.max(p.z) };
+        }
+        (lower, upper)
+    }
+}
+
+fn transform_point(m: &Matrix, p: &Vec) -> Vec {
+    Vec {
+        x: m[0][0] * p.x + m[0][1] * p.y + m[0][2] * p.z + m[0][3],
+        y: m[1][0] * p.x + m[1][1] * p.y + m[1][2] * p.z + m[1][3],
+        z: m[2][0] * p.x + m[2][1] * p.y + m[2][2] * p.z + m[2][3],
+    }
+}
+
+fn transform_vector(m: &Matrix, v: &Vec) -> Vec {
+    Vec {
+        x: m[0][0] * v.x + m[0][1] * v.y + m[0][2] * v.z,
+        y: m[1][0] * v.x + m[1][1] * v.y + m[1][2] * v.z,
+        z: m[2][0] * v.x + m[2][1] * v.y + m[2][2] * v.z,
+    }
+}
+
+fn multiply(a: &Matrix, b: &Matrix) -> Matrix {
+    let mut m: Matrix = [[0.0; 4]; 4];
+    for r in 0..4 {
+        for c in 0..4 {
+            m[r][c] = (0..4).map(|k| a[r][k] * b[k][c]).sum();
+        }
+    }
+    m
+}
+
+fn transpose(m: &Matrix) -> Matrix {
+    let mut t: Matrix = [[0.0; 4]; 4];
+    for r in 0..4 {
+        for c in 0..4 {
+            t[r][c] = m[c][r];
+        }
+    }
+    t
+}
+
+fn determinant3(m: &Matrix) -> f64 {
+    m[0][0] * (m[1][1] * m[2][2] - m[1][2] * m[2][1]) -
+    m[0][1] * (m[1][0] * m[2][2] - m[1][2] * m[2][0]) +
+    m[0][2] * (m[1][0] * m[2][1] - m[1][1] * m[2][0])
+}
+
+// アフィン変換の逆行列。左上3x3の逆行列を余因子から求め、平行移動はその逆行列で戻す。
+fn affine_inverse(m: &Matrix) -> Option<Matrix> {
+    let det: f64 = determinant3(m);
+    if det == 0.0 {
+        return None
+    }
+    let inv_det: f64 = 1.0 / det;
+
+    let mut inverse: Matrix = IDENTITY;
+    inverse[0][0] = (m[1][1] * m[2][2] - m[1][2] * m[2][1]) * inv_det;
+    inverse[0][1] = (m[0][2] * m[2][1] - m[0][1] * m[2][2]) * inv_det;
+    inverse[0][2] = (m[0][1] * m[1][2] - m[0][2] * m[1][1]) * inv_det;
+    inverse[1][0] = (m[1][2] * m[2][0] - m[1][0] * m[2][2]) * inv_det;
+    inverse[1][1] = (m[0][0] * m[2][2] - m[0][2] * m[2][0]) * inv_det;
+    inverse[1][2] = (m[0][2] * m[1][0] - m[0][0] * m[1][2]) * inv_det;
+    inverse[2][0] = (m[1][0] * m[2][1] - m[1][1] * m[2][0]) * inv_det;
+    inverse[2][1] = (m[0][1] * m[2][0] - m[0][0] * m[2][1]) * inv_det;
+    inverse[2][2] = (m[0][0] * m[1][1] - m[0][1] * m[1][0]) * inv_det;
+
+    for row in inverse.iter_mut().take(3) {
+        row[3] = -(row[0] * m[0][3] + row[1] * m[1][3] + row[2] * m[2][3]);
+    }
+    Some(inverse)
+}
+
+// 時刻0から1の間に、startからendへ動く変換。モーションブラーに使う。
+// 行列をそのまま線形補間すると回転の途中で物体が縮むので、
+// M = 平行移動 * 回転 * 拡大縮小 に分解し、平行移動と拡大縮小は線形に、回転は四元数の球面線形補間で補間する。
+// Ken Shoemake, Tom Duff. Matrix animation and polar decomposition. Graphics Interface 1992.
+#[derive(Debug, Clone)]
+pub struct AnimatedTransform {
+    start: Transform,
+    end: Transform,
+    is_animated: bool,
+    // startとendの分解。
+    translation: (Vec, Vec),
+    rotation: ([f64; 4], [f64; 4]), // 四元数(w, x, y, z)。
+    scale: (Matrix, Matrix),
+}
+
+// bounds（動いている間の境界ボックス）を求めるときに、時刻を刻む数。
+const NUM_BOUNDS_STEPS: usize = 32;
+
+impl AnimatedTransform {
+    pub fn new(start: Transform, end: Transform) -> AnimatedTransform {
+        let is_animated: bool = start.matrix != end.matrix;
+        let (t0, r0, s0) = decompose(&start.matrix);
+        let (t1, r1, s1) = decompose(&end.matrix);
+        AnimatedTransform {
+            start,
+            end,
+            is_animated,
+            translation: (t0, t1),
+            rotation: (r0, r1),
+            scale: (s0, s1),
+        }
+    }
+
+    // 動かない変換。
+    pub fn fixed(transform: Transform) -> AnimatedTransform {
+        AnimatedTransform::new(transform.clone(), transform)
+    }
+
+    pub fn is_animated(&self) -> bool {
+        self.is_animated
+    }
+
+    pub fn start(&self) -> &Transform {
+        &self.start
+    }
+
+    pub fn end(&self) -> &Transform {
+        &self.end
+    }
+
+    // 時刻time（[0, 1]）での変換。範囲外の時刻は端に寄せる。
+    pub fn at(&self, time: f64) -> Transform {
+        if !self.is_animated || time <= 0.0 {
+            return self.start.clone()
+        }
+        if time >= 1.0 {
+            return self.end.clone()
+        }
+
+        let translation: Vec = &self.translation.0 * (1.0 - time) + &self.translation.1 * time;
+        let rotation: Matrix = quaternion_to_matrix(&slerp(&self.rotation.0, &self.rotation.1, time));
+        let mut scale: Matrix = IDENTITY;
+        for (r, row) in scale.iter_mut().enumerate().take(3) {
+            for (c, value) in row.iter_mut().enumerate().take(3) {
+                *value = self.scale.0[r][c] * (1.0 - time) + self.scale.1[r][c] * time;
+            }
+        }
+
+        let mut matrix: Matrix = multiply(&rotation, &scale);
+        matrix[0][3] = translation.x;
+        matrix[1][3] = translation.y;
+        matrix[2][3] = translation.z;
+        // 両端が可逆なら補間した拡大縮小も普通は可逆。潰れたときは近い方の端を使う。
+        match Transform::from_matrix(matrix) {
+            Some(transform) => transform,
+            None => if time < 0.5 { self.start.clone() } else { self.end.clone() }
+        }
+    }
+
+    // 軸に平行な境界ボックス(min, max)が、時刻0から1の間に動いて通る範囲を囲む、軸に平行な境界ボックス。
+    // 時刻を刻んで各時刻の箱を合わせ、刻みの間に動く量だけ広げる。
+    // 箱の中の点の速さは角の速さの最大値以下なので、刻みの間に角が動く距離だけ広げれば取りこぼさない。
+    pub fn bounds(&self, min: &Vec, max: &Vec) -> (Vec, Vec) {
+        if !self.is_animated {
+            return self.start.bounds(min, max)
+        }
+
+        let corners: std::vec::Vec<Vec> = (0..8).map(|i| Vec {
+            x: if i & 1 == 0 { min.x } else { max.x },
+            y: if i & 2 == 0 { min.y } else { max.y },
+            z: if i & 4 == 0 { min.z } else { max.z },
+        }).collect();
+
+        let (mut lower, mut upper) = self.start.bounds(min, max);
+        let mut previous: std::vec::Vec<Vec> = corners.iter().map(|corner| self.start.point(corner)).collect();
+        let mut margin: f64 = 0.0;
+        for step in 1..(NUM_BOUNDS_STEPS + 1) {
+            let transform = self.at(step as f64 / NUM_BOUNDS_STEPS as f64);
+            let (l, u) = transform.bounds(min, max);
+            lower = Vec { x: lower.x.min(l.x), y: lower.y.min(l.y), z: lower.z.min(l.z) };
+            upper = Vec { x: upper.x.max(u.x), y: upper.y.max(u.y), z: upper.z.max(u.z) };
+
+            let current: std::vec::Vec<Vec> = corners.iter().map(|corner| transform.point(corner)).collect();
+            for (p, q) in previous.iter().zip(current.iter()) {
+                margin = margin.max(Vec::distance(p, q));
+            }
+            previous = current;
+        }
+
+        let margin = Vec { x: margin, y: margin, z: margin };
+        (&lower - &margin, &upper + &margin)
+    }
+}
+
+// アフィン変換を(平行移動, 回転の四元数, 拡大縮小)に分解する。
+// 左上3x3を極分解 A = R S し、R^-T との平均を取る反復でRを求める。
+// 鏡映を含むときは R と S の両方の符号を反転させて、Rを回転にする。
+fn decompose(m: &Matrix) -> (Vec, [f64; 4], Matrix) {
+    let translation = Vec { x: m[0][3], y: m[1][3], z: m[2][3] };
+
+    let mut a: Matrix = *m;
+    for row in a.iter_mut().take(3) {
+        row[3] = 0.0;
+    }
+
+    let mut rotation: Matrix = a;
+    for _ in 0..100 {
+        let inverse = match affine_inverse(&rotation) {
+            Some(inverse) => inverse,
+            None => break
+        };
+        let inverse_transpose: Matrix = transpose(&inverse);
+        let mut next: Matrix = IDENTITY;
+        let mut change: f64 = 0.0;
+        for r in 0..3 {
+            for c in 0..3 {
+                next[r][c] = 0.5 * (rotation[r][c] + inverse_transpose[r][c]);
+                change += (next[r][c] - rotation[r][c]).abs();
+            }
+        }
+        rotation = next;
+        if change < 1e-12 {
+            break;
+        }
+    }
+
+    if determinant3(&rotation) < 0.0 {
+        for row in rotation.iter_mut().take(3) {
+            for value in row.iter_mut().take(3) {
+                *value = -*value;
+            }
+        }
+    }
+
+    // S = R^-1 A = R^T A。
+    let scale: Matrix = multiply(&transpose(&rotation), &a);
+    (translation, matrix_to_quaternion(&rotation), scale)
+}
+
+fn matrix_to_quaternion(m: &Matrix) -> [f64; 4] {
+    let trace: f64 = m[0][0] + m[1][1] + m[2][2];
+    let q: [f64; 4] = if trace > 0.0 {
+        let s: f64 = (trace + 1.0).sqrt() * 2.0;
+        [0.25 * s, (m[2][1] - m[1][2]) / s, (m[0][2] - m[2][0]) / s, (m[1][0] - m[0][1]) / s]
+    } else if m[0][0] > m[1][1] && m[0][0] > m[2][2] {
+        let s: f64 = (1.0 + m[0][0] - m[1][1] - m[2][2]).sqrt() * 2.0;
+        [(m[2][1] - m[1][2]) / s, 0.25 * s, (m[0][1] + m[1][0]) / s, (m[0][2] + m[2][0]) / s]
+    } else if m[1][1] > m[2][2] {
+        let s: f64 = (1.0 + m[1][1] - m[0][0] - m[2][2]).sqrt() * 2.0;
+        [(m[0][2] - m[2][0]) / s, (m[0][1] + m[1][0]) / s, 0.25 * s, (m[1][2] + m[2][1]) / s]
+    } else {
+        let s: f64 = (1.0 + m[2][2] - m[0][0] - m[1][1]).sqrt() * 2.0;
+        [(m[1][0] - m[0][1]) / s, (m[0][2] + m[2][0]) / s, (m[1][2] + m[2][1]) / s, 0.25 * s]
+    };
+    normalize_quaternion(&q)
+}
+
+fn quaternion_to_matrix(q: &[f64; 4]) -> Matrix {
+    let (w, x, y, z) = (q[0], q[1], q[2], q[3]);
+    let mut m: Matrix = IDENTITY;
+    m[0][0] = 1.0 - 2.0 * (y * y + z * z); m[0][1] = 2.0 * (x * y - w * z);       m[0][2] = 2.0 * (x * z + w * y);
+    m[1][0] = 2.0 * (x * y + w * z);       m[1][1] = 1.0 - 2.0 * (x * x + z * z); m[1][2] = 2.0 * (y * z - w * x);
+    m[2][0] = 2.0 * (x * z - w * y);       m[2][1] = 2.0 * (y * z + w * x);       m[2][2] = 1.0 - 2.0 * (x * x + y * y);
+    m
+}
+
+fn normalize_quaternion(q: &[f64; 4]) -> [f64; 4] {
+    let length: f64 = (q[0] * q[0] + q[1] * q[1] + q[2] * q[2] + q[3] * q[3]).sqrt();
+    [q[0] / length, q[1] / length, q[2] / length, q[3] / length]
+}
+
+// 球面線形補間。qと-qは同じ回転なので、近い方の向きで補間する。
+fn slerp(a: &[f64; 4], b: &[f64; 4], t: f64) -> [f64; 4] {
+    let mut cos_theta: f64 = a[0] * b[0] + a[1] * b[1] + a[2] * b[2] + a[3] * b[3];
+    let mut b: [f64; 4] = *b;
+    if cos_theta < 0.0 {
+        cos_theta = -cos_theta;
+        b = [-b[0], -b[1], -b[2], -b[3]];
+    }
+
+    // ほとんど同じ向きのときは線形補間で十分（sinθで割ると誤差が大きい）。
+    if cos_theta > 0.9995 {
+        return normalize_quaternion(&[
+            a[0] * (1.0 - t) + b[0] * t, a[1] * (1.0 - t) + b[1] * t,
+            a[2] * (1.0 - t) + b[2] * t, a[3] * (1.0 - t) + b[3] * t])
+    }
+
+    let theta: f64 = cos_theta.acos();
+    let wa: f64 = ((1.0 - t) * theta).sin() / theta.sin();
+    let wb: f64 = (t * theta).sin() / theta.sin();
+    [a[0] * wa + b[0] * wb, a[1] * wa + b[1] * wb, a[2] * wa + b[2] * wb, a[3] * wa + b[3] * wb]
+}
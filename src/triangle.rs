@@ -0,0 +1,144 @@
+#![allow(dead_code)]
+
+use vec::{Vec, Dot, Normalize, Cross};
+use ray::Ray;
+use hitpoint::Hitpoint;
+use sampler::Sampler;
+use sampling::Sampling;
+
+// 三角形の幾何学的な情報を持つ
+// 頂点を反時計回りに見た側が表で、法線は表側を向く。
+#[derive(Debug)]
+pub struct Triangle {
+    v0_: Vec,
+    v1_: Vec,
+    v2_: Vec,
+    normal_: Vec,
+}
+
+impl Triangle {
+    pub fn new(v0: Vec, v1: Vec, v2: Vec) -> Triangle {
+        let normal = Vec::normalize(Vec::cross(&(&v1 - &v0), &(&v2 - &v0)));
+        Triangle { v0_: v0, v1_: v1, v2_: v2, normal_: normal }
+    }
+
+    pub fn vertices(&self) -> (&Vec, &Vec, &Vec) {
+        (&self.v0_, &self.v1_, &self.v2_)
+    }
+
+    pub fn normal(&self) -> &Vec {
+        &self.normal_
+    }
+
+    // 軸に平行な境界ボックスの(最小の角, 最大の角)。
+    pub fn bounds(&self) -> (Vec, Vec) {
+        let (v0, v1, v2) = (&self.v0_, &self.v1_, &self.v2_);
+        (Vec { x: v0.x.min(v1.x).min(v2.x), y: v0.y.min(v1.y).min(v2.y), z: v0.z.min(v1.z).min(v2.z) },
+         Vec { x: v0.x.max(v1.x).max(v2.x), y: v0.y.max(v1.y).max(v2.y), z: v0.z.max(v1.z).max(v2.z) })
+    }
+
+    pub fn area(&self) -> f64 {
+        Vec::cross(&(&self.v1_ - &self.v0_), &(&self.v2_ - &self.v0_)).length() * 0.5
+    }
+
+    // 三角形上の点を一様にサンプリングする。
+    // (位置, 法線, 面積測度のpdf)を返す。
+    pub fn sample_surface(&self, random: &mut dyn Sampler) -> (Vec, Vec, f64) {
+        let (b0, b1) = Sampling::uniform_barycentric(random);
+        let b2: f64 = 1.0 - b0 - b1;
+        let position: Vec = &self.v0_ * b0 + &self.v1_ * b1 + &self.v2_ * b2;
+
+        (position, self.normal_.clone(), 1.0 / self.area())
+    }
+
+    // 交差点hitpointの接線を求める。テクスチャ座標はintersectで求めてある。
+    // uが増える向きはv0からv1への辺の向きになる。
+    pub fn surface_details(&self, hitpoint: &mut Hitpoint) {
+        let tangent: Vec = Vec::normalize(&self.v1_ - &self.v0_);
+        let bitangent: Vec = Vec::cross(&self.normal_, &tangent);
+        hitpoint.tangent_frame = Some((tangent, bitangent));
+    }
+
+    // 入力のrayに対する交差点までの距離を得る。
+    // 交差したらその情報を,さもなくばNoneを返す。
+    // 裏側からレイが当たった場合も交差とする。法線は常に表側を向いたまま。
+    // 自己交差はレイの始点をずらして避ける（Hitpoint::offset_origin）ので、距離が正なら交差とする。
+    // Tomas Möller, Ben Trumbore. Fast, minimum storage ray-triangle intersection. 1997.
+    pub fn intersect(&self, ray: &Ray) -> Option<Hitpoint> {
+        self.intersect_with_t_min(ray, 0.0)
+    }
+
+    // intersectと同じだが、距離がt_min以下の交差は無視する。
+    pub fn intersect_with_t_min(&self, ray: &Ray, t_min: f64) -> Option<Hitpoint> {
+        let edge1: Vec = &self.v1_ - &self.v0_;
+        let edge2: Vec = &self.v2_ - &self.v0_;
+        let p: Vec = Vec::cross(ray.dir, &edge2);
+        let det: f64 = Vec::dot(&edge1, &p);
+
+        // レイが三角形と平行。
+        // detは三角形の大きさに比例するので、固定の閾値ではなく0かどうかで判定する。
+        if det == 0.0 {
+            return None
+        }
+
+        let inv_det: f64 = 1.0 / det;
+        let s: Vec = ray.org - &self.v0_;
+        let u: f64 = Vec::dot(&s, &p) * inv_det;
+        if !(0.0..=1.0).contains(&u) {
+            return None
+        }
+
+        let q: Vec = Vec::cross(&s, &edge1);
+        let v: f64 = Vec::dot(ray.dir, &q) * inv_det;
+        if v < 0.0 || u + v > 1.0 {
+            return None
+        }
+
+        let t: f64 = Vec::dot(&edge2, &q) * inv_det;
+        if t <= t_min {
+            return None
+        }
+
+        let mut hitpoint = Hitpoint::new();
+        hitpoint.distance = t;
+        // ray.org + t * ray.dirより誤差の小さい、重心座標からの位置にする（誤差が頂点座標の大きさに比例する）。
+        hitpoint.position = &self.v0_ + &edge1 * u + &edge2 * v;
+        hitpoint.normal = self.normal_.clone();
+        // 頂点ごとのテクスチャ座標は持たないので、重心座標をそのまま使う（v0が(0, 0)、v1が(1, 0)、v2が(0, 1)）。
+        hitpoint.uv = (u, v);
+
+        Some(hitpoint)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 交差点の接線は法線に直交する単位ベクトルで、その向きに進むとテクスチャ座標のuが増える（vは変わらない）。
+    #[test]
+    fn tangent_is_orthogonal_to_normal_and_follows_u() {
+        let triangle = Triangle::new(
+            Vec { x: 0.2, y: -0.3, z: 0.1 }, Vec { x: 1.7, y: 0.4, z: -0.5 }, Vec { x: 0.5, y: 1.6, z: 0.9 });
+        let normal: Vec = triangle.normal().clone();
+        let target: Vec = Vec { x: 0.7, y: 0.5, z: 0.2 };
+        let dir: Vec = Vec::normalize(Vec { x: 0.3, y: -0.2, z: -1.0 });
+        let hit_at = |point: &Vec| -> Hitpoint {
+            let origin: Vec = point - &dir * 5.0;
+            let mut hitpoint: Hitpoint = triangle.intersect(&Ray::new(&origin, &dir)).unwrap();
+            triangle.surface_details(&mut hitpoint);
+            hitpoint
+        };
+
+        let hitpoint: Hitpoint = hit_at(&target);
+        let (tangent, bitangent) = hitpoint.tangent_frame.clone().unwrap();
+        assert!((tangent.length() - 1.0).abs() < 1e-12 && (bitangent.length() - 1.0).abs() < 1e-12);
+        assert!(Vec::dot(&tangent, &normal).abs() < 1e-12);
+        assert!(Vec::dot(&bitangent, &normal).abs() < 1e-12 && Vec::dot(&bitangent, &tangent).abs() < 1e-12);
+        assert!((&Vec::cross(&normal, &tangent) - &bitangent).length() < 1e-12);
+
+        let moved: Hitpoint = hit_at(&(&hitpoint.position + &tangent * 0.05));
+        assert!(moved.uv.0 > hitpoint.uv.0, "{:?} {:?}", hitpoint.uv, moved.uv);
+        assert!((moved.uv.1 - hitpoint.uv.1).abs() < 1e-12, "{:?} {:?}", hitpoint.uv, moved.uv);
+    }
+}
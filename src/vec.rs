@@ -3,6 +3,14 @@
 use std::ops::{Add, Sub, Mul, Div, Neg};
 use std::f64;
 
+#[cfg(feature = "serde")]
+use serde::{Serialize, Serializer, Deserialize, Deserializer};
+#[cfg(feature = "serde")]
+use serde::ser::SerializeTuple;
+
+// is_unitで単位ベクトルとみなす長さの二乗の誤差。正規化したベクトルの丸め誤差は十分に下回る。
+const UNIT_LENGTH_SQUARED_TOLERANCE: f64 = 1e-6;
+
 // ベクトル演算用クラス
 #[derive(Debug, Clone)]
 pub struct Vec {
@@ -11,9 +19,30 @@ pub struct Vec {
     pub z: f64,
 }
 
+// serdeフィーチャーが有効なときは[x, y, z]の配列としてシリアライズする。
+#[cfg(feature = "serde")]
+impl Serialize for Vec {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error> where S: Serializer {
+        let mut tuple = serializer.serialize_tuple(3)?;
+        tuple.serialize_element(&self.x)?;
+        tuple.serialize_element(&self.y)?;
+        tuple.serialize_element(&self.z)?;
+        tuple.end()
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> Deserialize<'de> for Vec {
+    fn deserialize<D>(deserializer: D) -> Result<Vec, D::Error> where D: Deserializer<'de> {
+        let [x, y, z] = <[f64; 3]>::deserialize(deserializer)?;
+        Ok(Vec { x, y, z })
+    }
+}
+
 impl Add for Vec {
     type Output = Vec;
 
+    #[inline]
     fn add(self, other: Vec) -> Vec {
         Vec { x: self.x + other.x, y: self.y + other.y, z: self.z + other.z }
     }
@@ -22,6 +51,7 @@ impl Add for Vec {
 impl<'a, 'b> Add<&'a Vec> for &'b Vec {
     type Output = Vec;
 
+    #[inline]
     fn add(self, other: &'a Vec) -> Vec {
         Vec { x: self.x + other.x, y: self.y + other.y, z: self.z + other.z }
     }
@@ -30,6 +60,7 @@ impl<'a, 'b> Add<&'a Vec> for &'b Vec {
 impl<'a> Add<Vec> for &'a Vec {
     type Output = Vec;
 
+    #[inline]
     fn add(self, other: Vec) -> Vec {
         Vec { x: self.x + other.x, y: self.y + other.y, z: self.z + other.z }
     }
@@ -38,6 +69,7 @@ impl<'a> Add<Vec> for &'a Vec {
 impl<'a> Add<&'a Vec> for Vec {
     type Output = Vec;
 
+    #[inline]
     fn add(self, other: &'a Vec) -> Vec {
         Vec { x: self.x + other.x, y: self.y + other.y, z: self.z + other.z }
     }
@@ -46,6 +78,7 @@ impl<'a> Add<&'a Vec> for Vec {
 impl<'a, 'b> Sub<&'a Vec> for &'b Vec {
     type Output = Vec;
 
+    #[inline]
     fn sub(self, other: &'a Vec) -> Vec {
         Vec { x: self.x - other.x, y: self.y - other.y, z: self.z - other.z }
     }
@@ -54,6 +87,7 @@ impl<'a, 'b> Sub<&'a Vec> for &'b Vec {
 impl Sub for Vec {
     type Output = Vec;
 
+    #[inline]
     fn sub(self, other: Vec) -> Vec {
         Vec { x: self.x - other.x, y: self.y - other.y, z: self.z - other.z }
     }
@@ -62,6 +96,7 @@ impl Sub for Vec {
 impl<'a> Sub<Vec> for &'a Vec {
     type Output = Vec;
 
+    #[inline]
     fn sub(self, other: Vec) -> Vec {
         Vec { x: self.x - other.x, y: self.y - other.y, z: self.z - other.z }
     }
@@ -70,6 +105,7 @@ impl<'a> Sub<Vec> for &'a Vec {
 impl<'a> Sub<&'a Vec> for Vec {
     type Output = Vec;
 
+    #[inline]
     fn sub(self, other: &'a Vec) -> Vec {
         Vec { x: self.x - other.x, y: self.y - other.y, z: self.z - other.z }
     }
@@ -78,6 +114,7 @@ impl<'a> Sub<&'a Vec> for Vec {
 impl<'a, 'b> Mul<&'a f64> for &'b Vec {
     type Output = Vec;
 
+    #[inline]
     fn mul(self, x: &'a f64) -> Vec {
         Vec { x: self.x * x, y: self.y * x, z: self.z * x }
     }
@@ -86,6 +123,7 @@ impl<'a, 'b> Mul<&'a f64> for &'b Vec {
 impl<'a> Mul<f64> for &'a Vec {
     type Output = Vec;
 
+    #[inline]
     fn mul(self, x: f64) -> Vec {
         Vec { x: self.x * x, y: self.y * x, z: self.z * x }
     }
@@ -94,6 +132,7 @@ impl<'a> Mul<f64> for &'a Vec {
 impl<'a> Mul<&'a f64> for Vec {
     type Output = Vec;
 
+    #[inline]
     fn mul(self, x: &'a f64) -> Vec {
         Vec { x: self.x * x, y: self.y * x, z: self.z * x }
     }
@@ -102,6 +141,7 @@ impl<'a> Mul<&'a f64> for Vec {
 impl Mul<f64> for Vec {
     type Output = Vec;
 
+    #[inline]
     fn mul(self, x: f64) -> Vec {
         Vec { x: self.x * x, y: self.y * x, z: self.z * x }
     }
@@ -110,6 +150,7 @@ impl Mul<f64> for Vec {
 impl<'a> Div<f64> for &'a Vec {
     type Output = Vec;
 
+    #[inline]
     fn div(self, x: f64) -> Vec {
         Vec { x: self.x / x, y: self.y / x, z: self.z / x }
     }
@@ -118,6 +159,7 @@ impl<'a> Div<f64> for &'a Vec {
 impl Div<f64> for Vec {
     type Output = Vec;
 
+    #[inline]
     fn div(self, x: f64) -> Vec {
         Vec { x: self.x / x, y: self.y / x, z: self.z / x }
     }
@@ -126,6 +168,7 @@ impl Div<f64> for Vec {
 impl<'a> Neg for &'a Vec {
     type Output = Vec;
 
+    #[inline]
     fn neg(self) -> Vec {
         Vec { x: -self.x, y: -self.y, z: -self.z }
     }
@@ -134,24 +177,120 @@ impl<'a> Neg for &'a Vec {
 impl Neg for Vec {
     type Output = Vec;
 
+    #[inline]
     fn neg(self) -> Vec {
         Vec { x: -self.x, y: -self.y, z: -self.z }
     }
 }
 
 impl Vec {
+    #[inline]
     pub fn length_squared(&self) -> f64 {
         self.x*self.x + self.y*self.y + self.z*self.z
     }
 
+    #[inline]
     pub fn length(&self) -> f64 {
         (self.length_squared()).sqrt()
     }
+
+    // 長さがほぼ1か。マテリアルなどで単位ベクトルを前提とする引数の確認（debug_assert!）に使う。
+    #[inline]
+    pub fn is_unit(&self) -> bool {
+        (self.length_squared() - 1.0).abs() <= UNIT_LENGTH_SQUARED_TOLERANCE
+    }
+
+    // 最大の成分。
+    #[inline]
+    pub fn max_component(&self) -> f64 {
+        self.x.max(self.y).max(self.z)
+    }
+
+    // 色として見たときの、Rec. 709の係数による輝度。
+    #[inline]
+    pub fn luminance(&self) -> f64 {
+        0.2126 * self.x + 0.7152 * self.y + 0.0722 * self.z
+    }
+
+    // 成分ごとにe乗する。負の成分は0とみなす（負の数の非整数乗はNaNになるため）。
+    // 色のガンマ補正やトーンカーブに使う。
+    #[inline]
+    pub fn pow(&self, e: f64) -> Vec {
+        Vec { x: self.x.max(0.0).powf(e), y: self.y.max(0.0).powf(e), z: self.z.max(0.0).powf(e) }
+    }
+
+    // powと同じだが、成分ごとに別の指数eを使う。
+    #[inline]
+    pub fn powv(&self, e: &Vec) -> Vec {
+        Vec { x: self.x.max(0.0).powf(e.x), y: self.y.max(0.0).powf(e.y), z: self.z.max(0.0).powf(e.z) }
+    }
+
+    // 二点a, b間の距離。
+    #[inline]
+    pub fn distance(a: &Vec, b: &Vec) -> f64 {
+        Vec::distance_squared(a, b).sqrt()
+    }
+
+    // 二点a, b間の距離の二乗。距離を比べるだけなら平方根を取らずに済む。
+    #[inline]
+    pub fn distance_squared(a: &Vec, b: &Vec) -> f64 {
+        let (dx, dy, dz) = (a.x - b.x, a.y - b.y, a.z - b.z);
+        dx * dx + dy * dy + dz * dz
+    }
+
+    // vと逆向きになるように向きを揃えたn。dot(n, v) < 0ならnを、さもなくば-nを返す。
+    // 裏側から当たったレイに対して、レイの来た側を向いた法線を得るのに使う。
+    #[inline]
+    pub fn faceforward(n: &Vec, v: &Vec) -> Vec {
+        if Vec::dot(n, v) < 0.0 { n.clone() } else { -n }
+    }
+
+    // 成分ごとに a * b + c を求める。
+    // 基底ベクトルの線形結合を組み立てるときに、一時的なVecを作らずに済む。
+    #[inline]
+    pub fn mul_add(a: &Vec, b: f64, c: &Vec) -> Vec {
+        Vec { x: a.x * b + c.x, y: a.y * b + c.y, z: a.z * b + c.z }
+    }
+
+    // 単位ベクトルaxisを軸に、右ねじの向きにangle（ラジアン）だけ回転したベクトル。
+    // Rodriguesの回転公式 v cosθ + (k × v) sinθ + k (k・v)(1 - cosθ) による。
+    #[inline]
+    pub fn rotate_around(&self, axis: &Vec, angle: f64) -> Vec {
+        debug_assert!(axis.is_unit(), "axis must be a unit vector: {:?}", axis);
+        let (sin, cos) = angle.sin_cos();
+        let along: Vec = axis * (Vec::dot(axis, self) * (1.0 - cos));
+        Vec::mul_add(self, cos, &Vec::mul_add(&Vec::cross(axis, self), sin, &along))
+    }
+
+    // ontoの方向への射影（ontoに平行な成分）。ontoは単位ベクトルでなくてよい。
+    // ontoが零ベクトルのときは零ベクトルを返す。
+    #[inline]
+    pub fn project(&self, onto: &Vec) -> Vec {
+        let length_squared: f64 = onto.length_squared();
+        if length_squared == 0.0 {
+            return Vec { x: 0.0, y: 0.0, z: 0.0 }
+        }
+        onto * (Vec::dot(self, onto) / length_squared)
+    }
+
+    // fromに垂直な成分。self.project(from) + self.reject(from) == selfとなる。
+    #[inline]
+    pub fn reject(&self, from: &Vec) -> Vec {
+        self - self.project(from)
+    }
+
+    // 法線がplane_normalの原点を通る平面についての鏡像。長さは変わらず、二回行うと元に戻る。
+    // Reflect::reflectと違い正規化しないので、方向以外のベクトルにも使える。
+    #[inline]
+    pub fn reflect_about_plane(&self, plane_normal: &Vec) -> Vec {
+        self - self.project(plane_normal) * 2.0
+    }
 }
 
 impl<'a, 'b> Mul<&'a Vec> for &'b f64 {
     type Output = Vec;
 
+    #[inline]
     fn mul(self, v: &'a Vec) -> Vec {
         v * *self
     }
@@ -160,6 +299,7 @@ impl<'a, 'b> Mul<&'a Vec> for &'b f64 {
 impl<'a> Mul<&'a Vec> for f64 {
     type Output = Vec;
 
+    #[inline]
     fn mul(self, v: &'a Vec) -> Vec {
         v * self
     }
@@ -168,6 +308,7 @@ impl<'a> Mul<&'a Vec> for f64 {
 impl<'a> Mul<Vec> for &'a f64 {
     type Output = Vec;
 
+    #[inline]
     fn mul(self, v: Vec) -> Vec {
         v * *self
     }
@@ -176,6 +317,7 @@ impl<'a> Mul<Vec> for &'a f64 {
 impl Mul<Vec> for f64 {
     type Output = Vec;
 
+    #[inline]
     fn mul(self, v: Vec) -> Vec {
         v * self
     }
@@ -186,12 +328,14 @@ pub trait Normalize<T> {
 }
 
 impl Normalize<Vec> for Vec {
+    #[inline]
     fn normalize(v: Vec) -> Vec {
         &v * (1.0 / v.length())
     }
 }
 
 impl<'a> Normalize<&'a Vec> for Vec {
+    #[inline]
     fn normalize(v: &'a Vec) -> Vec {
         v * (1.0 / v.length())
     }
@@ -202,12 +346,14 @@ pub trait Multiply<T> {
 }
 
 impl<'a> Multiply<&'a Vec> for Vec {
+    #[inline]
     fn multiply(v1: &Vec, v2: &Vec) -> Vec {
         Vec { x: v1.x*v2.x, y: v1.y*v2.y, z: v1.z*v2.z }
     }
 }
 
 impl Multiply<Vec> for Vec {
+    #[inline]
     fn multiply(v1: Vec, v2: Vec) -> Vec {
         Vec { x: v1.x*v2.x, y: v1.y*v2.y, z: v1.z*v2.z }
     }
@@ -218,12 +364,14 @@ pub trait Dot<T> {
 }
 
 impl<'a> Dot<&'a Vec> for Vec {
+    #[inline]
     fn dot(v1: &Vec, v2: &Vec) -> f64 {
         v1.x*v2.x + v1.y*v2.y + v1.z*v2.z
     }
 }
 
 impl Dot<Vec> for Vec {
+    #[inline]
     fn dot(v1: Vec, v2: Vec) -> f64 {
         v1.x*v2.x + v1.y*v2.y + v1.z*v2.z
     }
@@ -234,6 +382,7 @@ pub trait Cross<T> {
 }
 
 impl<'a> Cross<&'a Vec> for Vec {
+    #[inline]
     fn cross(v1: &Vec, v2: &Vec) -> Vec {
         Vec {
             x: (v1.y * v2.z) - (v1.z * v2.y),
@@ -244,6 +393,7 @@ impl<'a> Cross<&'a Vec> for Vec {
 }
 
 impl Cross<Vec> for Vec {
+    #[inline]
     fn cross(v1: Vec, v2: Vec) -> Vec {
         Vec {
             x: (v1.y * v2.z) - (v1.z * v2.y),
@@ -258,25 +408,255 @@ pub trait Reflect<T> {
 }
 
 impl<'a> Reflect<&'a Vec> for Vec {
+    #[inline]
     fn reflect(v: &Vec, normal: &Vec) -> Vec {
-        Vec::normalize(v - normal * 2.0 * Vec::dot(normal, v))
+        Vec::reflect_with_cos(v, normal, Vec::dot(normal, v))
     }
 }
 
 impl Reflect<Vec> for Vec {
+    #[inline]
     fn reflect(v: Vec, normal: Vec) -> Vec {
-        Vec::normalize(&v - &normal * 2.0 * Vec::dot(&normal, &v))
+        Vec::reflect_with_cos(&v, &normal, Vec::dot(&normal, &v))
+    }
+}
+
+impl Vec {
+    // reflectと同じだが、cos = dot(normal, v)を呼び出し側で求めてあるときに使う。
+    #[inline]
+    pub fn reflect_with_cos(v: &Vec, normal: &Vec, cos: f64) -> Vec {
+        Vec::normalize(v - normal * 2.0 * cos)
+    }
+}
+
+// 法線を軸とする正規直交基底。tangent, binormal, normalがそれぞれ局所座標のx, y, z軸になる。
+// create_ortho_normal_basisと同じ基底を、出力引数を使わずに作る。
+#[derive(Debug, Clone)]
+pub struct OrthoBasis {
+    pub tangent: Vec,
+    pub binormal: Vec,
+    pub normal: Vec,
+}
+
+impl OrthoBasis {
+    // normalは単位ベクトルであること。
+    pub fn from_normal(normal: &Vec) -> OrthoBasis {
+        let mut tangent: Vec = Vec { x: 0.0, y: 0.0, z: 0.0 };
+        let mut binormal: Vec = Vec { x: 0.0, y: 0.0, z: 0.0 };
+        create_ortho_normal_basis(normal, &mut tangent, &mut binormal);
+        OrthoBasis { tangent, binormal, normal: normal.clone() }
+    }
+
+    // 局所座標のベクトルをワールド座標にする。
+    #[inline]
+    pub fn to_world(&self, local: &Vec) -> Vec {
+        Vec::mul_add(
+            &self.tangent, local.x,
+            &Vec::mul_add(&self.binormal, local.y, &(&self.normal * local.z)))
+    }
+
+    // ワールド座標のベクトルを局所座標にする。基底が正規直交なのでto_worldの逆になる。
+    #[inline]
+    pub fn to_local(&self, world: &Vec) -> Vec {
+        Vec {
+            x: Vec::dot(&self.tangent, world),
+            y: Vec::dot(&self.binormal, world),
+            z: Vec::dot(&self.normal, world),
+        }
     }
 }
 
 // 正規直交基底を作る
+// normalは単位ベクトルであること。デバッグビルドでは確かめる。
+// 軸を選んで外積を取る方法は、法線がその軸に近いと精度が落ちるので、分岐の無い式で直接作る。
+// tangent × binormal = normal となる右手系の基底になる。
+// Tom Duff, et al. Building an Orthonormal Basis, Revisited. Journal of Computer Graphics Techniques, 2017.
 pub fn create_ortho_normal_basis(
     normal: &Vec, tangent: &mut Vec, binormal: &mut Vec) {
-    if f64::abs(normal.x) > f64::abs(normal.y) {
-        *tangent = Vec::normalize(Vec::cross(&Vec{x: 0.0, y: 1.0, z: 0.0}, normal));
-    } else {
-        *tangent = Vec::normalize(Vec::cross(&Vec{x: 1.0, y: 0.0, z: 0.0}, normal));
+    debug_assert!(normal.is_unit(), "normal must be a unit vector: {:?}", normal);
+    let sign: f64 = 1.0f64.copysign(normal.z);
+    let a: f64 = -1.0 / (sign + normal.z);
+    let b: f64 = normal.x * normal.y * a;
+    *tangent = Vec { x: 1.0 + sign * normal.x * normal.x * a, y: sign * b, z: -sign * normal.x };
+    *binormal = Vec { x: b, y: sign + normal.y * normal.y * a, z: -normal.y };
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[cfg(feature = "serde")]
+    use serde_json;
+
+    fn same(a: &Vec, b: &Vec) -> bool {
+        (a.x, a.y, a.z) == (b.x, b.y, b.z)
+    }
+
+    // 所有したVecの演算と参照の演算は同じ結果になり、mul_addは演算子を連ねた式と一致する。
+    #[test]
+    fn owned_and_borrowed_ops_agree() {
+        let a: Vec = Vec { x: 1.5, y: -2.0, z: 0.25 };
+        let b: Vec = Vec { x: -0.5, y: 3.0, z: 4.0 };
+        let s: f64 = 0.3;
+
+        let sum: Vec = &a + &b;
+        assert!(same(&(a.clone() + b.clone()), &sum));
+        assert!(same(&(&a + b.clone()), &sum));
+        assert!(same(&(a.clone() + &b), &sum));
+
+        let difference: Vec = &a - &b;
+        assert!(same(&(a.clone() - b.clone()), &difference));
+        assert!(same(&(&a - b.clone()), &difference));
+        assert!(same(&(a.clone() - &b), &difference));
+
+        let scaled: Vec = &a * s;
+        assert!(same(&(a.clone() * s), &scaled));
+        assert!(same(&(s * &a), &scaled));
+        assert!(same(&(s * a.clone()), &scaled));
+        assert!(same(&(&a / 2.0), &(a.clone() / 2.0)));
+        assert!(same(&(-&a), &(-a.clone())));
+
+        assert!(same(&Vec::mul_add(&a, s, &b), &(&a * s + &b)));
+    }
+
+    // serde_jsonで[x, y, z]の配列に書き出し、読み戻すと元のVecになる。
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serde_json_round_trip() {
+        let v: Vec = Vec { x: 1.5, y: -0.25, z: 1e-300 };
+        let json: String = serde_json::to_string(&v).unwrap();
+        assert_eq!(json, "[1.5,-0.25,1e-300]");
+
+        let back: Vec = serde_json::from_str(&json).unwrap();
+        assert_eq!((back.x, back.y, back.z), (v.x, v.y, v.z));
+
+        // 要素の数が三つでなければ読み込めない。
+        assert!(serde_json::from_str::<Vec>("[1.0, 2.0]").is_err());
+        assert!(serde_json::from_str::<Vec>("[1.0, 2.0, 3.0, 4.0]").is_err());
+    }
+
+    // distanceとdistance_squaredは、差のベクトルのlengthとlength_squaredに一致し、a, bを入れ替えても変わらない。
+    #[test]
+    fn distance_matches_length_of_difference() {
+        let a: Vec = Vec { x: 1.5, y: -2.0, z: 0.25 };
+        let b: Vec = Vec { x: -0.5, y: 3.0, z: 4.0 };
+        let difference: Vec = &a - &b;
+        assert!((Vec::distance_squared(&a, &b) - difference.length_squared()).abs() < 1e-12);
+        assert!((Vec::distance(&a, &b) - difference.length()).abs() < 1e-12);
+        assert_eq!(Vec::distance(&a, &b), Vec::distance(&b, &a));
+        assert_eq!(Vec::distance(&a, &a), 0.0);
+    }
+
+    // faceforwardは、vと向かい合うnならそのまま、同じ向きなら裏返して返す。
+    #[test]
+    fn faceforward_flips_only_normals_facing_away() {
+        let n: Vec = Vec { x: 0.0, y: 0.0, z: 1.0 };
+        let into_front: Vec = Vec { x: 0.6, y: 0.0, z: -0.8 };
+        let from_behind: Vec = Vec { x: 0.6, y: 0.0, z: 0.8 };
+        assert!(same(&Vec::faceforward(&n, &into_front), &n));
+        assert!(same(&Vec::faceforward(&n, &from_behind), &(-&n)));
+        assert!(Vec::dot(&Vec::faceforward(&n, &from_behind), &from_behind) < 0.0);
+    }
+
+    // 求めておいたcosを渡すreflect_with_cosは、reflectと同じベクトルを返す。
+    #[test]
+    fn reflect_with_cos_matches_reflect() {
+        let normal: Vec = Vec { x: 0.0, y: 0.6, z: 0.8 };
+        for v in &[Vec { x: 0.3, y: -0.5, z: -0.81 }, Vec { x: -1.0, y: 0.0, z: 0.0 }, Vec { x: 0.0, y: 0.6, z: 0.8 }] {
+            let v: Vec = Vec::normalize(v);
+            let cos: f64 = Vec::dot(&normal, &v);
+            let expected: Vec = Vec::reflect(&v, &normal);
+            assert!(same(&Vec::reflect_with_cos(&v, &normal, cos), &expected));
+            assert!(same(&Vec::reflect(v.clone(), normal.clone()), &expected));
+        }
+    }
+
+    // デバッグビルドでは、create_ortho_normal_basisに単位ベクトルでない法線を渡すとdebug_assert!で止まる。
+    #[cfg(debug_assertions)]
+    #[test]
+    #[should_panic(expected = "normal must be a unit vector")]
+    fn ortho_normal_basis_rejects_non_unit_normal() {
+        let mut tangent: Vec = Vec { x: 0.0, y: 0.0, z: 0.0 };
+        let mut binormal: Vec = Vec { x: 0.0, y: 0.0, z: 0.0 };
+        create_ortho_normal_basis(&Vec { x: 0.5, y: 0.0, z: 0.0 }, &mut tangent, &mut binormal);
+    }
+
+    // x軸の単位ベクトルをz軸の周りに90°回すとy軸になり、軸に平行な成分は変わらない。
+    #[test]
+    fn rotating_x_by_90_degrees_around_z_gives_y() {
+        use constant::K_PI;
+        let z: Vec = Vec { x: 0.0, y: 0.0, z: 1.0 };
+        let rotated: Vec = Vec { x: 1.0, y: 0.0, z: 0.5 }.rotate_around(&z, K_PI / 2.0);
+        assert!((&rotated - &Vec { x: 0.0, y: 1.0, z: 0.5 }).length() < 1e-12, "{:?}", rotated);
+    }
+
+    // 局所座標に直してからワールド座標に戻すと、元のベクトルになる。逆の順でも同じ。
+    #[test]
+    fn ortho_basis_round_trips_between_local_and_world() {
+        let basis = OrthoBasis::from_normal(&Vec::normalize(Vec { x: 0.3, y: -0.4, z: 0.8 }));
+        let v: Vec = Vec { x: 1.5, y: -2.0, z: 0.25 };
+        assert!((&basis.to_local(&basis.to_world(&v)) - &v).length() < 1e-12);
+        assert!((&basis.to_world(&basis.to_local(&v)) - &v).length() < 1e-12);
+        assert!((&basis.to_world(&Vec { x: 0.0, y: 0.0, z: 1.0 }) - &basis.normal).length() < 1e-12);
+    }
+
+    // 軸に平行な法線と、-z軸のすぐ近くの法線と、ランダムな法線のどれでも、
+    // create_ortho_normal_basisの基底は正規直交で、tangent × binormal = normalの右手系になる。
+    #[test]
+    fn ortho_normal_basis_is_orthonormal_and_right_handed() {
+        use random::Random;
+        use sampling::Sampling;
+        let mut normals: std::vec::Vec<Vec> = vec![
+            Vec { x: 1.0, y: 0.0, z: 0.0 }, Vec { x: -1.0, y: 0.0, z: 0.0 },
+            Vec { x: 0.0, y: 1.0, z: 0.0 }, Vec { x: 0.0, y: -1.0, z: 0.0 },
+            Vec { x: 0.0, y: 0.0, z: 1.0 }, Vec { x: 0.0, y: 0.0, z: -1.0 },
+            Vec::normalize(Vec { x: 1e-9, y: -1e-9, z: -1.0 }),
+        ];
+        let mut random = Random::new(5);
+        normals.extend((0..1000).map(|_| Sampling::uniform_sphere_surface(&mut random)));
+
+        for normal in &normals {
+            let mut tangent: Vec = Vec { x: 0.0, y: 0.0, z: 0.0 };
+            let mut binormal: Vec = Vec { x: 0.0, y: 0.0, z: 0.0 };
+            create_ortho_normal_basis(normal, &mut tangent, &mut binormal);
+            assert!(tangent.is_unit() && binormal.is_unit(), "{:?} {:?} {:?}", normal, tangent, binormal);
+            assert!(Vec::dot(&tangent, &binormal).abs() < 1e-12 && Vec::dot(&tangent, normal).abs() < 1e-12 &&
+                    Vec::dot(&binormal, normal).abs() < 1e-12, "{:?} {:?} {:?}", normal, tangent, binormal);
+            assert!((&Vec::cross(&tangent, &binormal) - normal).length() < 1e-12, "{:?} {:?} {:?}", normal, tangent, binormal);
+        }
     }
-    *binormal = Vec::normalize(Vec::cross(normal, tangent));
-}
 
+    // powは成分ごとに累乗し、負の成分は0として扱う。powvは成分ごとに別の指数を使う。
+    #[test]
+    fn pow_raises_each_component() {
+        let v: Vec = Vec { x: 0.25, y: 0.5, z: 1.0 };
+        let root: Vec = v.pow(0.5);
+        assert!((&root - &Vec { x: 0.5, y: 0.5_f64.sqrt(), z: 1.0 }).length() < 1e-15, "{:?}", root);
+        assert!(same(&Vec { x: -4.0, y: 4.0, z: 0.0 }.pow(0.5), &Vec { x: 0.0, y: 2.0, z: 0.0 }));
+        assert!(same(&v.powv(&Vec { x: 0.5, y: 2.0, z: 3.0 }), &Vec { x: 0.5, y: 0.25, z: 1.0 }));
+    }
+
+    // projectとrejectの和は元のベクトルで、rejectは射影先に垂直。
+    // reflect_about_planeは長さを変えず、二回行うと元に戻る。射影先が零ベクトルなら射影も零ベクトル。
+    #[test]
+    fn project_plus_reject_is_self_and_plane_reflection_is_an_involution() {
+        let v: Vec = Vec { x: 1.5, y: -2.0, z: 0.25 };
+        let onto: Vec = Vec { x: 0.3, y: 4.0, z: -1.2 };
+        let close = |a: &Vec, b: &Vec| (a - b).length() < 1e-12;
+
+        let parallel: Vec = v.project(&onto);
+        let perpendicular: Vec = v.reject(&onto);
+        assert!(close(&(&parallel + &perpendicular), &v));
+        assert!(Vec::dot(&perpendicular, &onto).abs() < 1e-12);
+        assert!(Vec::cross(&parallel, &onto).length() < 1e-12);
+
+        let mirrored: Vec = v.reflect_about_plane(&onto);
+        assert!((mirrored.length() - v.length()).abs() < 1e-12);
+        assert!(close(&mirrored.reflect_about_plane(&onto), &v));
+        assert!(close(&mirrored, &(&perpendicular - &parallel)));
+
+        let zero: Vec = Vec { x: 0.0, y: 0.0, z: 0.0 };
+        assert!(same(&v.project(&zero), &zero));
+        assert!(same(&v.reject(&zero), &v));
+    }
+}
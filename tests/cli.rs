@@ -0,0 +1,46 @@
+// gemsptコマンドを実際に起動して、コーネルボックスを画像ファイルに書き出せることを確かめる。
+extern crate gemspt_rs;
+
+use std::fs;
+use std::path::PathBuf;
+use std::process::Command;
+
+use gemspt_rs::ppm::load_ppm_file;
+
+fn output_path(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("gemspt-cli-{}-{}.ppm", name, std::process::id()))
+}
+
+// 小さな解像度でコーネルボックスをレンダリングすると、指定した大きさのPPMができる。
+#[test]
+fn cornell_box_preset_writes_the_output_file() {
+    let path = output_path("cornell-box");
+    let status = Command::new(env!("CARGO_BIN_EXE_gemspt"))
+        .args(["--preset", "cornell-box", "--width", "16", "--height", "12", "--spp", "1", "--subpixels", "1",
+                "--max-depth", "4", "--seed", "3", "--threads", "1", "--output"])
+        .arg(&path)
+        .status()
+        .unwrap();
+    let loaded = load_ppm_file(path.to_str().unwrap());
+    let _ = fs::remove_file(&path);
+
+    assert!(status.success(), "{:?}", status);
+    let (image, width, height) = loaded.unwrap();
+    assert_eq!((width, height), (16, 12));
+    assert_eq!(image.len(), 16 * 12);
+    assert!(image.iter().any(|pixel| pixel.x > 0.0 || pixel.y > 0.0 || pixel.z > 0.0));
+}
+
+// 知らないオプションではエラーを出して失敗し、ファイルは作らない。
+#[test]
+fn unknown_option_fails_without_writing() {
+    let path = output_path("unknown-option");
+    let output = Command::new(env!("CARGO_BIN_EXE_gemspt"))
+        .args(["--no-such-option", "1", "--output"])
+        .arg(&path)
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("unknown option --no-such-option"));
+    assert!(!path.exists());
+}